@@ -0,0 +1,97 @@
+//! Measures bytes on the wire of an initial sync of a document edited by several peers, with
+//! and without compression. Run with `cargo bench --features compression --bench compression`.
+
+use std::time::Instant;
+use yrs::sync::{Message, SyncMessage};
+use yrs::updates::decoder::Decode;
+use yrs::updates::encoder::Encode;
+use yrs::{Doc, GetString, ReadTxn, StateVector, Text, Transact, Update};
+use yrs_axum::ws::Compression;
+
+const WORDS: [&str; 24] = [
+    "the", "document", "is", "edited", "by", "several", "peers", "at", "once", "and", "every",
+    "change", "becomes", "a", "separate", "update", "which", "carries", "its", "own", "client",
+    "clock", "and", "origin",
+];
+
+/// Simple deterministic generator, so that every run measures the same document.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: usize) -> usize {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) as usize) % bound.max(1)
+    }
+}
+
+/// Builds a document edited by `peers` in `rounds` of typing words and deleting ranges at
+/// random positions, with peers exchanging updates after every round.
+fn edited_doc(peers: u64, rounds: usize) -> Doc {
+    let mut rng = Lcg(42);
+    let docs: Vec<Doc> = (1..=peers).map(Doc::with_client_id).collect();
+    for _ in 0..rounds {
+        for doc in docs.iter() {
+            let text = doc.get_or_insert_text("content");
+            let mut txn = doc.transact_mut();
+            for _ in 0..8 {
+                let len = text.len(&txn) as usize;
+                if len > 64 && rng.next(5) == 0 {
+                    let at = rng.next(len - 16);
+                    text.remove_range(&mut txn, at as u32, 1 + rng.next(16) as u32);
+                } else {
+                    // type a word one character at a time, like an editor does
+                    let at = rng.next(len + 1) as u32;
+                    let word = WORDS[rng.next(WORDS.len())];
+                    for (at, c) in (at..).zip(word.chars().chain([' '])) {
+                        text.insert(&mut txn, at, &c.to_string());
+                    }
+                }
+            }
+        }
+        for a in docs.iter() {
+            for b in docs.iter() {
+                let update = a
+                    .transact()
+                    .encode_state_as_update_v1(&b.transact().state_vector());
+                let update = Update::decode_v1(&update).unwrap();
+                b.transact_mut().apply_update(update);
+            }
+        }
+    }
+    docs.into_iter().next().unwrap()
+}
+
+fn main() {
+    println!(
+        "{:>5} {:>7} {:>10} {:>10} {:>6} {:>11} {:>6} {:>12}",
+        "peers", "rounds", "text", "plain", "level", "compressed", "ratio", "compress"
+    );
+    for (peers, rounds) in [(1, 50), (1, 500), (1, 2000), (3, 50), (3, 500), (3, 2000)] {
+        let doc = edited_doc(peers, rounds);
+        let txn = doc.transact();
+        let text_len = txn.get_text("content").unwrap().get_string(&txn).len();
+        let update = txn.encode_state_as_update_v1(&StateVector::default());
+        let msg = Message::Sync(SyncMessage::SyncStep2(update)).encode_v1();
+        for level in [1, 6] {
+            let compression = Compression::default().with_level(level);
+            let start = Instant::now();
+            let frame = compression.compress(msg.clone()).unwrap();
+            let elapsed = start.elapsed();
+            assert_eq!(Compression::decompress(&frame, 0).unwrap(), msg);
+            println!(
+                "{:>5} {:>7} {:>10} {:>10} {:>6} {:>11} {:>5.1}x {:>10.2?}",
+                peers,
+                rounds,
+                text_len,
+                msg.len(),
+                level,
+                frame.len(),
+                msg.len() as f64 / frame.len() as f64,
+                elapsed
+            );
+        }
+    }
+}
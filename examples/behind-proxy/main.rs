@@ -0,0 +1,68 @@
+use axum::{
+    extract::{
+        ws::{WebSocket, WebSocketUpgrade},
+        ConnectInfo, State,
+    },
+    http::HeaderMap,
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use futures_util::StreamExt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use yrs::Doc;
+use yrs_axum::broadcast::BroadcastGroup;
+use yrs_axum::ws::{AxumSink, AxumStream, ClientInfo};
+
+/// Addresses of reverse proxies terminating TLS in front of this server. Forwarding headers sent
+/// by anyone else are ignored.
+const TRUSTED_PROXIES: [IpAddr; 1] = [IpAddr::V4(Ipv4Addr::LOCALHOST)];
+
+#[tokio::main]
+async fn main() {
+    let bcast = Arc::new(BroadcastGroup::from_doc(Doc::new(), 32).await);
+
+    let app = Router::new()
+        .route("/my-room", get(ws_handler))
+        .with_state(bcast);
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:8000")
+        .await
+        .unwrap();
+    println!("Listening on {}", listener.local_addr().unwrap());
+
+    // connect info is required for handlers to know the address of a connected peer
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(bcast): State<Arc<BroadcastGroup>>,
+) -> impl IntoResponse {
+    let client = ClientInfo::resolve(addr, &headers, &TRUSTED_PROXIES);
+    ws.on_upgrade(move |socket| peer(socket, bcast, client))
+}
+
+async fn peer(ws: WebSocket, bcast: Arc<BroadcastGroup>, client: ClientInfo) {
+    println!(
+        "client {} connected via {} (secure: {})",
+        client.client_ip, client.peer_addr, client.secure
+    );
+    let (sink, stream) = ws.split();
+    let sink = Arc::new(Mutex::new(AxumSink::from(sink)));
+    let stream = AxumStream::from(stream);
+    let sub = bcast.subscribe(sink, stream);
+    match sub.completed().await {
+        Ok(_) => println!("client {} disconnected", client.client_ip),
+        Err(e) => eprintln!("client {} failed: {}", client.client_ip, e),
+    }
+}
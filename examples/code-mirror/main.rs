@@ -1,42 +1,31 @@
 use futures_util::StreamExt;
 use std::sync::Arc;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::Mutex;
 use axum::{
     extract::{ws::{WebSocket, WebSocketUpgrade}, State}, 
     response::IntoResponse, 
     routing::get, Router
 };
 use tower_http::services::ServeDir;
-use yrs::sync::Awareness;
-use yrs::{Doc, Text, Transact};
 use yrs_axum::broadcast::BroadcastGroup;
 use yrs_axum::ws::{AxumSink, AxumStream};
-use yrs_axum::AwarenessRef;
 
 const STATIC_FILES_DIR: &str = "examples/code-mirror/frontend/dist";
 
 #[tokio::main]
 async fn main() {
-    // We're using a single static document shared among all the peers.
-    let awareness: AwarenessRef = {
-        let doc = Doc::new();
-        {
-            // pre-initialize code mirror document with some text
-            let txt = doc.get_or_insert_text("codemirror");
-            let mut txn = doc.transact_mut();
-            txt.push(
-                &mut txn,
-                r#"function hello() {
+    // We're using a single static document shared among all the peers,
+    // pre-initialized with some code mirror text.
+    let awareness = yrs_axum::new_awareness_with_text(
+        "codemirror",
+        r#"function hello() {
   console.log('hello world');
 }"#,
-            );
-        }
-        Arc::new(RwLock::new(Awareness::new(doc)))
-    };
+    );
 
     // open a broadcast group that listens to awareness and document updates
     // and has a pending message buffer of up to 32 updates
-    let bcast = Arc::new(BroadcastGroup::new(awareness.clone(), 32).await);
+    let bcast = Arc::new(BroadcastGroup::new(awareness, 32).await);
 
     // Create a router with our WebSocket handler and static file service
     let app = Router::new()
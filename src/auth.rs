@@ -0,0 +1,102 @@
+use axum::extract::Query;
+use axum::http::request::Parts;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::sync::Arc;
+
+/// Access rights granted to an authorized peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerPermissions {
+    /// Peer receives document and awareness updates, but its own document updates are discarded.
+    /// See [crate::broadcast::BroadcastGroup::subscribe_readonly].
+    ReadOnly,
+    /// Peer can both receive and make document changes.
+    ReadWrite,
+}
+
+/// Error returned by an authorization callback for peers, which are not allowed to connect.
+/// The `reason` is passed on to the peer, so it shouldn't contain any sensitive details.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthError {
+    pub reason: String,
+}
+
+impl AuthError {
+    pub fn new<S: Into<String>>(reason: S) -> Self {
+        AuthError {
+            reason: reason.into(),
+        }
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unauthorized: {}", self.reason)
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// An identity of a subscriber resolved by an [IdentityProvider], eg. a user ID. It's attached to
+/// a subscription and can be read via [crate::broadcast::Subscription::identity].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SubscriberId(Arc<str>);
+
+impl SubscriberId {
+    pub fn new<S: Into<Arc<str>>>(id: S) -> Self {
+        SubscriberId(id.into())
+    }
+
+    /// Returns this identity as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for SubscriberId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Derives an identity of a subscriber from an upgrade request of its web socket connection, eg.
+/// from a JWT subject, a session cookie or a client's address. It's called by
+/// [crate::ws::subscribe_identified] and [crate::manager::BroadcastManager::handler] before a
+/// connection is subscribed, and the result is attached to its subscription.
+///
+/// Implementations can define `identify` as an `async fn`, as long as its future is `Send`.
+/// Returning `None` subscribes a connection anonymously - use [crate::ws::subscribe_authorized]
+/// to reject connections instead.
+pub trait IdentityProvider: Send + Sync {
+    fn identify(&self, parts: &Parts) -> impl Future<Output = Option<SubscriberId>> + Send;
+}
+
+/// An [IdentityProvider], which takes an identity from a query parameter of a given name, eg.
+/// `user` for `ws://localhost:8000/my-room?user=alice`. A missing or empty parameter results
+/// in an anonymous subscriber.
+///
+/// Query parameters can be set by any client at will, so this is only fit for trusted networks
+/// and testing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryParam {
+    name: String,
+}
+
+impl QueryParam {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        QueryParam { name: name.into() }
+    }
+}
+
+impl IdentityProvider for QueryParam {
+    async fn identify(&self, parts: &Parts) -> Option<SubscriberId> {
+        let Query(mut params) = Query::<HashMap<String, String>>::try_from_uri(&parts.uri).ok()?;
+        let id = params.remove(&self.name)?;
+        if id.is_empty() {
+            None
+        } else {
+            Some(SubscriberId::new(id))
+        }
+    }
+}
@@ -1,30 +1,192 @@
 #![allow(dead_code)]
+use crate::auth::{PeerPermissions, SubscriberId};
+use crate::error::{CloseReason, Error};
+use crate::manager::DEFAULT_BUFFER_CAPACITY;
+use crate::metrics::{ConnectionMetrics, Direction, Metrics, NoopMetrics};
+use crate::rate_limit::TokenBucket;
 use crate::AwarenessRef;
 use futures_util::{SinkExt, StreamExt};
+use std::cell::Cell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::select;
-use tokio::sync::broadcast::error::SendError;
+use tokio::sync::broadcast::error::{RecvError, SendError, TryRecvError};
 use tokio::sync::broadcast::{channel, Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::{watch, Mutex, Notify, RwLock};
 use tokio::task::JoinHandle;
+use tokio::time::{interval, interval_at, timeout, Instant, Interval};
+use tokio_util::sync::CancellationToken;
+use yrs::block::ClientID;
 use yrs::encoding::write::Write;
 use yrs::sync::protocol::{MSG_SYNC, MSG_SYNC_UPDATE};
-use yrs::sync::{DefaultProtocol, Error, Message, Protocol, SyncMessage};
+use yrs::sync::{
+    Awareness, AwarenessUpdate, DefaultProtocol, Error as SyncError, Message, Protocol, SyncMessage,
+};
+use yrs::types::Delta;
 use yrs::updates::decoder::Decode;
 use yrs::updates::encoder::{Encode, Encoder, EncoderV1};
-use yrs::Update;
+use yrs::{
+    Doc, Observable, Options, Origin, ReadTxn, StateVector, Transact, TransactionMut, Update,
+};
+
+/// Binary payload of a keepalive frame periodically sent to idle subscribers, when enabled via
+/// [BroadcastGroup::with_keepalive].
+///
+/// It's a regular y-sync update message - `[MSG_SYNC, MSG_SYNC_UPDATE]` tags followed by
+/// a length-prefixed, empty v1 update (`[0, 0]`: zero blocks and an empty delete set). Both Yjs
+/// and Yrs clients apply it as a no-op, which doesn't produce any document changes or events.
+pub const KEEPALIVE_MSG: [u8; 5] = [MSG_SYNC, MSG_SYNC_UPDATE, 2, 0, 0];
+
+/// Payload of a ping periodically sent to every subscriber, when enabled via
+/// [BroadcastGroup::with_ping_interval].
+///
+/// It's an empty message, which is never a valid y-sync message. Transports are expected to
+/// translate it into their own ping frames and report received pongs back as empty messages, like
+/// [AxumSink](crate::ws::AxumSink) and [AxumStream](crate::ws::AxumStream) do with web socket
+/// Ping/Pong frames. Empty messages received from subscribers are never applied.
+pub const PING_MSG: [u8; 0] = [];
+
+tokio::task_local! {
+    static CLOSE_REASON: Cell<Option<CloseReason>>;
+}
+
+/// Returns a reason of a subscriber sink being closed by a [BroadcastGroup], which transports
+/// supporting close frames can call from within their `Sink::poll_close` - like
+/// [AxumSink](crate::ws::AxumSink) does. The reason is cleared, so that it's only sent once.
+/// Returns `None` when called outside of such `poll_close` call.
+pub fn take_close_reason() -> Option<CloseReason> {
+    CLOSE_REASON.try_with(Cell::take).ok().flatten()
+}
+
+/// Checks if there's a close reason to be taken via [take_close_reason].
+pub(crate) fn has_close_reason() -> bool {
+    CLOSE_REASON
+        .try_with(|reason| reason.get().is_some())
+        .unwrap_or(false)
+}
+
+/// Closes a subscriber `sink` with a given `reason`, see [take_close_reason].
+async fn close_sink<S>(sink: &mut S, reason: CloseReason) -> Result<(), S::Error>
+where
+    S: futures_util::Sink<Vec<u8>> + Unpin,
+{
+    CLOSE_REASON
+        .scope(Cell::new(Some(reason)), sink.close())
+        .await
+}
+
+/// Interval in which a local awareness state set via [BroadcastGroup::set_local_awareness] is
+/// renewed. Yjs clients consider remote awareness states outdated after 30 seconds without update.
+const LOCAL_AWARENESS_RENEWAL: Duration = Duration::from_secs(15);
+
+/// Default limit of a binary size of messages received from subscribers. See
+/// [BroadcastGroup::with_max_message_size].
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Default number of consecutive messages over the inbound rate limit, after which a subscriber
+/// is closed. See [BroadcastGroup::with_max_rate_violations].
+pub const DEFAULT_MAX_RATE_VIOLATIONS: u32 = 16;
+
+/// Number of lifecycle events buffered for every receiver of [BroadcastGroup::events].
+const EVENTS_CAPACITY: usize = 64;
+
+/// Maximum time [BroadcastGroup::close] waits for subscribers to acknowledge closing.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Behavior of a [BroadcastGroup], when one of its subscribers doesn't keep up with broadcasted
+/// messages and their number exceeds a buffer capacity of the group. See
+/// [BroadcastGroup::with_buffer_overflow_policy].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BufferOverflowPolicy {
+    /// Closes a lagging subscriber. Its [Subscription::completed] resolves with
+    /// [Error::Lagged], so that the peer can reconnect and resync its state from scratch.
+    #[default]
+    DisconnectSlow,
+    /// Skips the oldest messages a lagging subscriber didn't receive yet. Since the skipped
+    /// document updates are lost for that subscriber, its state stays out of sync until it sends
+    /// another SyncStep1 request.
+    DropOldest,
+    /// Stops processing messages received from all subscribers, until every one has room in its
+    /// buffer again. This way a single stalled subscriber slows down the whole group. Changes made
+    /// to the document locally are never blocked, lagging behind them skips the oldest messages
+    /// just like [BufferOverflowPolicy::DropOldest].
+    Block,
+}
+
+/// Order in which a [BroadcastGroup] sends broadcasted messages to its subscribers. See
+/// [BroadcastGroup::with_broadcast_priority].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BroadcastPriority {
+    /// Messages are sent in the order they were broadcasted.
+    #[default]
+    Fifo,
+    /// Awareness updates of the group are sent ahead of document updates still waiting in
+    /// a subscriber's buffer, so that presence stays responsive under heavy document churn.
+    AwarenessFirst,
+}
+
+/// A callback used by [BroadcastGroup::with_awareness_filter].
+type AwarenessFilter = Arc<dyn Fn(ClientID, &str) -> Option<String> + Send + Sync>;
+
+/// Callbacks registered via [BroadcastGroup::on_empty].
+type EmptyCallbacks = std::sync::Mutex<Vec<Arc<dyn Fn() + Send + Sync>>>;
+
+/// Subscribers, which have announced awareness states of given clients, along with the last clock
+/// of each state they've announced.
+type AwarenessOwners = std::sync::Mutex<HashMap<ClientID, (SubscriptionId, u32)>>;
 
 /// A broadcast group can be used to propagate updates produced by yrs [yrs::Doc] and [Awareness]
 /// structures in a binary form that conforms to a y-sync protocol.
 ///
 /// New receivers can subscribe to a broadcasting group via [BroadcastGroup::subscribe] method.
+/// A SyncStep1 message of a subscriber is answered with a SyncStep2 carrying only the changes
+/// missing from its state vector, so peers reconnecting with a partially synced document don't
+/// receive the whole document again.
 pub struct BroadcastGroup {
     awareness_sub: yrs::Subscription,
     doc_sub: yrs::Subscription,
+    observers: std::sync::Mutex<Vec<yrs::Subscription>>,
     awareness_ref: AwarenessRef,
     sender: Sender<Vec<u8>>,
-    receiver: Receiver<Vec<u8>>,
+    awareness_sender: Sender<Vec<u8>>,
+    awareness_first: Arc<AtomicBool>,
+    buffer_capacity: usize,
+    buffer_high_water_mark: Arc<AtomicUsize>,
+    overflow_policy: BufferOverflowPolicy,
+    drained: Arc<Notify>,
     awareness_updater: JoinHandle<()>,
+    keepalive: Option<Duration>,
+    ping: Option<(Duration, u32)>,
+    sync_step1_cooldown: Option<Duration>,
+    handshake_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    max_message_size: usize,
+    inbound_rate: (u32, u32),
+    max_rate_violations: u32,
+    local_awareness: std::sync::Mutex<Option<JoinHandle<()>>>,
+    subscribers: Arc<watch::Sender<usize>>,
+    on_empty: Arc<EmptyCallbacks>,
+    max_subscribers: usize,
+    max_awareness_entries: Arc<AtomicUsize>,
+    awareness_ttl: watch::Sender<Duration>,
+    snapshots: watch::Sender<(Duration, usize)>,
+    snapshotter: JoinHandle<()>,
+    paused: watch::Sender<bool>,
+    awareness_changes: UnboundedSender<Vec<ClientID>>,
+    awareness_owners: Arc<AwarenessOwners>,
+    departures: UnboundedSender<Vec<ClientID>>,
+    update_sink: Arc<std::sync::Mutex<Option<UnboundedSender<Vec<u8>>>>>,
+    awareness_filter: Arc<std::sync::Mutex<Option<AwarenessFilter>>>,
+    closing: CancellationToken,
+    events: Sender<BroadcastEvent>,
+    metrics: Arc<dyn Metrics>,
+    subdocs: Arc<std::sync::Mutex<HashMap<Arc<str>, Arc<BroadcastGroup>>>>,
 }
 
 unsafe impl Send for BroadcastGroup {}
@@ -36,12 +198,19 @@ impl BroadcastGroup {
     /// which have been registered via [BroadcastGroup::subscribe] method.
     ///
     /// The overflow of the incoming events that needs to be propagates will be buffered up to a
-    /// provided `buffer_capacity` size.
+    /// provided `buffer_capacity` size. What happens once a subscriber falls behind it, is decided by
+    /// [BroadcastGroup::with_buffer_overflow_policy].
     pub async fn new(awareness: AwarenessRef, buffer_capacity: usize) -> Self {
-        let (sender, receiver) = channel(buffer_capacity);
+        let (sender, _) = channel(buffer_capacity);
         let awareness_c = Arc::downgrade(&awareness);
         let mut lock = awareness.write().await;
         let sink = sender.clone();
+        let (events, _) = channel(EVENTS_CAPACITY);
+        let update_events = events.clone();
+        let update_sink: Arc<std::sync::Mutex<Option<UnboundedSender<Vec<u8>>>>> = Arc::default();
+        let persisted = update_sink.clone();
+        let buffer_high_water_mark = Arc::new(AtomicUsize::new(0));
+        let high_water_mark = buffer_high_water_mark.clone();
         let doc_sub = {
             lock.doc_mut()
                 .observe_update_v1(move |_txn, u| {
@@ -52,13 +221,40 @@ impl BroadcastGroup {
                     encoder.write_buf(&u.update);
                     let msg = encoder.to_vec();
                     if let Err(_e) = sink.send(msg) {
-                        // current broadcast group is being closed
+                        // there are no subscribers at the moment
+                    }
+                    Self::record_buffer_len(&sink, &high_water_mark);
+                    if let Some(persisted) = persisted.lock().unwrap().as_ref() {
+                        let _ = persisted.send(u.update.clone());
+                    }
+                    let _ = update_events.send(BroadcastEvent::Updated {
+                        bytes: u.update.len(),
+                    });
+                })
+                .unwrap()
+        };
+        let subdocs: Arc<std::sync::Mutex<HashMap<Arc<str>, Arc<BroadcastGroup>>>> = Arc::default();
+        let subdocs_sub = {
+            let subdocs = subdocs.clone();
+            lock.doc()
+                .observe_subdocs(move |_txn, e| {
+                    for doc in e.removed() {
+                        let removed = subdocs.lock().unwrap().remove(doc.guid());
+                        if let Some(group) = removed {
+                            group.closing.cancel();
+                        }
                     }
                 })
                 .unwrap()
         };
         let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let awareness_changes = tx.clone();
         let sink = sender.clone();
+        let (awareness_sender, _) = channel(buffer_capacity);
+        let awareness_sink = awareness_sender.clone();
+        let awareness_first = Arc::new(AtomicBool::new(false));
+        let prioritized = awareness_first.clone();
+        let high_water_mark = buffer_high_water_mark.clone();
         let awareness_sub = lock.on_update(move |e| {
             let added = e.added();
             let updated = e.updated();
@@ -68,38 +264,503 @@ impl BroadcastGroup {
             changed.extend_from_slice(updated);
             changed.extend_from_slice(removed);
 
-            if let Err(_) = tx.send(changed) {
+            if tx.send(changed).is_err() {
                 tracing::warn!("failed to send awareness update");
             }
         });
+        let mut last_seen: HashMap<ClientID, Instant> = {
+            let now = Instant::now();
+            lock.clients()
+                .keys()
+                .map(|&client_id| (client_id, now))
+                .collect()
+        };
         drop(lock);
+        let max_awareness_entries = Arc::new(AtomicUsize::new(0));
+        let max_entries = max_awareness_entries.clone();
+        let awareness_filter: Arc<std::sync::Mutex<Option<AwarenessFilter>>> = Arc::default();
+        let filter = awareness_filter.clone();
+        let (awareness_ttl, mut ttl) = watch::channel(Duration::ZERO);
+        let awareness_owners: Arc<AwarenessOwners> = Arc::default();
+        let owners = awareness_owners.clone();
+        let (departures, mut departed) = tokio::sync::mpsc::unbounded_channel();
         let awareness_updater = tokio::task::spawn(async move {
-            while let Some(changed_clients) = rx.recv().await {
+            let mut expiry: Option<Interval> = None;
+            loop {
+                let changed_clients = select! {
+                    res = rx.recv() => match res {
+                        Some(changed_clients) => changed_clients,
+                        None => return,
+                    },
+                    res = ttl.changed() => {
+                        if res.is_err() {
+                            return;
+                        }
+                        let ttl = *ttl.borrow_and_update();
+                        expiry = if ttl.is_zero() {
+                            None
+                        } else {
+                            Some(interval(ttl / 2))
+                        };
+                        continue;
+                    },
+                    res = departed.recv() => {
+                        let (Some(clients), Some(awareness)) = (res, awareness_c.upgrade()) else {
+                            return;
+                        };
+                        // removed entries will be broadcasted in the next iteration
+                        let mut lock = awareness.write().await;
+                        Self::remove_departed(&mut lock, &owners, clients);
+                        continue;
+                    },
+                    _ = Self::tick(&mut expiry) => {
+                        let Some(awareness) = awareness_c.upgrade() else {
+                            return;
+                        };
+                        // removed entries will be broadcasted in the next iteration
+                        let mut lock = awareness.write().await;
+                        let ttl = *ttl.borrow();
+                        Self::expire_awareness(&mut lock, &mut last_seen, ttl);
+                        continue;
+                    }
+                };
                 if let Some(awareness) = awareness_c.upgrade() {
-                    let awareness = awareness.read().await;
-                    match awareness.update_with_clients(changed_clients) {
+                    let lock = awareness.read().await;
+                    let now = Instant::now();
+                    for client_id in changed_clients.iter() {
+                        if lock.clients().contains_key(client_id) {
+                            last_seen.insert(*client_id, now);
+                        } else {
+                            last_seen.remove(client_id);
+                        }
+                    }
+                    let max_entries = max_entries.load(Ordering::Relaxed);
+                    let overflow = max_entries != 0 && lock.clients().len() > max_entries;
+                    match lock.update_with_clients(changed_clients) {
                         Ok(update) => {
-                            if let Err(_) = sink.send(Message::Awareness(update).encode_v1()) {
-                                tracing::warn!("couldn't broadcast awareness update");
+                            if let Some(update) = Self::filter_awareness(&filter, update) {
+                                let msg = Message::Awareness(update).encode_v1();
+                                if prioritized.load(Ordering::Relaxed) {
+                                    let _ = awareness_sink.send(msg);
+                                } else {
+                                    if let Err(_e) = sink.send(msg) {
+                                        // there are no subscribers at the moment
+                                    }
+                                    Self::record_buffer_len(&sink, &high_water_mark);
+                                }
                             }
                         }
                         Err(e) => {
                             tracing::warn!("error while computing awareness update: {}", e)
                         }
                     }
+                    drop(lock);
+                    if overflow {
+                        // removed entries will be broadcasted in the next iteration
+                        let mut lock = awareness.write().await;
+                        Self::prune_awareness(&mut lock, &mut last_seen, max_entries);
+                    }
                 } else {
                     return;
                 }
             }
         });
+        let (snapshots, mut snapshot_config) = watch::channel((Duration::ZERO, 0));
+        let mut updates = events.subscribe();
+        let snapshot_events = events.clone();
+        let snapshotted = Arc::downgrade(&awareness);
+        let snapshotter = tokio::task::spawn(async move {
+            let mut period: Option<Interval> = None;
+            // number of updates since the last snapshot, approximate if events were skipped
+            let mut pending = 0;
+            loop {
+                select! {
+                    res = snapshot_config.changed() => {
+                        if res.is_err() {
+                            return;
+                        }
+                        let (every, _) = *snapshot_config.borrow_and_update();
+                        period = if every.is_zero() {
+                            None
+                        } else {
+                            Some(interval_at(Instant::now() + every, every))
+                        };
+                        continue;
+                    },
+                    res = updates.recv() => {
+                        pending += match res {
+                            Ok(BroadcastEvent::Updated { .. }) => 1,
+                            Ok(_) => continue,
+                            Err(RecvError::Lagged(skipped)) => skipped as usize,
+                            Err(RecvError::Closed) => return,
+                        };
+                        let (_, max_updates) = *snapshot_config.borrow();
+                        if max_updates == 0 || pending < max_updates {
+                            continue;
+                        }
+                    },
+                    _ = Self::tick(&mut period) => {
+                        if pending == 0 {
+                            // document didn't change since the last snapshot
+                            continue;
+                        }
+                    },
+                }
+                let Some(awareness) = snapshotted.upgrade() else {
+                    return;
+                };
+                pending = 0;
+                let snapshot = {
+                    let lock = awareness.read().await;
+                    let txn = lock.doc().transact();
+                    txn.encode_state_as_update_v1(&StateVector::default())
+                };
+                let _ = snapshot_events.send(BroadcastEvent::DocumentSnapshot(snapshot));
+            }
+        });
         BroadcastGroup {
             awareness_ref: awareness,
             awareness_updater,
             sender,
-            receiver,
+            awareness_sender,
+            awareness_first,
+            buffer_capacity,
+            buffer_high_water_mark,
+            overflow_policy: BufferOverflowPolicy::default(),
+            drained: Arc::new(Notify::new()),
             awareness_sub,
             doc_sub,
+            observers: std::sync::Mutex::new(vec![subdocs_sub]),
+            keepalive: None,
+            ping: None,
+            sync_step1_cooldown: None,
+            handshake_timeout: None,
+            max_lifetime: None,
+            idle_timeout: None,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            inbound_rate: (0, 0),
+            max_rate_violations: DEFAULT_MAX_RATE_VIOLATIONS,
+            local_awareness: std::sync::Mutex::new(None),
+            subscribers: Arc::new(watch::channel(0).0),
+            on_empty: Arc::default(),
+            max_subscribers: 0,
+            max_awareness_entries,
+            awareness_ttl,
+            snapshots,
+            snapshotter,
+            paused: watch::channel(false).0,
+            awareness_changes,
+            awareness_owners,
+            departures,
+            update_sink,
+            awareness_filter,
+            closing: CancellationToken::new(),
+            events,
+            metrics: Arc::new(NoopMetrics),
+            subdocs,
+        }
+    }
+
+    /// Registers a callback invoked with a binary v1 encoded update every time an underlying
+    /// document is changed, no matter if the change was made locally or received from one of the
+    /// subscribers. This can be used to persist the document in an external storage.
+    ///
+    /// Updates are buffered and passed to a `sink` in order from a separate task, so a slow
+    /// callback doesn't block broadcasting. Buffered updates are still delivered after this group
+    /// has been dropped. Registering a new callback replaces the previous one.
+    pub fn with_update_sink<F>(self, sink: F) -> Self
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+        tokio::spawn(async move {
+            while let Some(update) = rx.recv().await {
+                sink(&update);
+            }
+        });
+        *self.update_sink.lock().unwrap() = Some(tx);
+        self
+    }
+
+    /// Registers a `filter` invoked with a client ID and JSON encoded awareness state of every
+    /// client entry, before it's sent to subscribers - either as a broadcasted awareness update or
+    /// as a reply to an awareness query. Returning `None` omits that client from the outgoing
+    /// message, while `Some` replaces its state. This can be used to redact private fields, like
+    /// user emails, from presence information.
+    ///
+    /// The filter is applied to removed clients as well, whose state is `"null"`. It only changes
+    /// what subscribers see: awareness states tracked by this group are never modified.
+    /// Registering a new filter replaces the previous one.
+    pub fn with_awareness_filter<F>(self, filter: F) -> Self
+    where
+        F: Fn(ClientID, &str) -> Option<String> + Send + Sync + 'static,
+    {
+        *self.awareness_filter.lock().unwrap() = Some(Arc::new(filter));
+        self
+    }
+
+    /// Limits the number of awareness client entries tracked by this group. Once exceeded, the
+    /// least recently updated entries are removed - and their removal broadcasted to subscribers -
+    /// until the limit is satisfied again. A local awareness state of this server is never pruned.
+    ///
+    /// This bounds memory usage and awareness update sizes in case of reconnect storms of clients,
+    /// which don't reuse their client IDs. By default, the number of entries is not limited. A zero
+    /// `max` also means no limit.
+    pub fn with_max_awareness_entries(self, max: usize) -> Self {
+        self.max_awareness_entries.store(max, Ordering::Relaxed);
+        self
+    }
+
+    /// Removes awareness states of clients, which didn't refresh them within a given `ttl`,
+    /// broadcasting their removal to subscribers. This cleans up presence of peers, which
+    /// disconnected without removing their state. Yjs clients renew their awareness state every
+    /// 15 seconds, so the `ttl` should be at least 30 seconds. A local awareness state of this
+    /// server is never removed.
+    ///
+    /// Expiry is disabled by default. A zero `ttl` disables it as well.
+    pub fn with_awareness_ttl(self, ttl: Duration) -> Self {
+        self.awareness_ttl.send_replace(ttl);
+        self
+    }
+
+    /// Emits a [BroadcastEvent::DocumentSnapshot] with a whole state of the group's document every
+    /// `interval`, eg. to checkpoint it in an external storage without replaying all its updates.
+    /// No snapshot is emitted, if the document didn't change since the previous one. Snapshots
+    /// are encoded from a separate task under a read lock of the group's [Awareness], so they
+    /// don't block broadcasting of updates.
+    ///
+    /// Periodic snapshots are disabled by default. A zero `interval` disables them as well.
+    pub fn with_snapshot_interval(self, interval: Duration) -> Self {
+        self.snapshots.send_modify(|(every, _)| *every = interval);
+        self
+    }
+
+    /// Emits a [BroadcastEvent::DocumentSnapshot] with a whole state of the group's document once
+    /// it has received a given number of `updates` since the previous snapshot. It can be combined
+    /// with [BroadcastGroup::with_snapshot_interval], so that a document changed frequently is
+    /// checkpointed more often. Updates are counted from their [BroadcastEvent::Updated] events,
+    /// so the count is approximate once snapshotting falls behind the events.
+    ///
+    /// Snapshots are disabled by default. A zero number of `updates` disables them as well.
+    pub fn with_snapshot_every(self, updates: usize) -> Self {
+        self.snapshots
+            .send_modify(|(_, max_updates)| *max_updates = updates);
+        self
+    }
+
+    /// Creates a new [BroadcastGroup] over a provided `doc`, which will be wrapped into a new
+    /// [Awareness] instance owned by this group. See [BroadcastGroup::new] for details.
+    ///
+    /// The awareness instance can be still accessed via [BroadcastGroup::awareness] method.
+    pub async fn from_doc(doc: Doc, buffer_capacity: usize) -> Self {
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        Self::new(awareness, buffer_capacity).await
+    }
+
+    /// Returns a [BroadcastGroupBuilder], which allows to configure a new group step by step.
+    pub fn builder() -> BroadcastGroupBuilder {
+        BroadcastGroupBuilder::new()
+    }
+
+    /// Creates a new [BroadcastGroup] over a new document configured with given `options`. See
+    /// [BroadcastGroup::new] for details.
+    ///
+    /// This is mainly useful for controlling garbage collection of deleted items via
+    /// [Options::skip_gc], which can't be changed once a document is created. GC is enabled by
+    /// default and keeps long-lived documents from accumulating deleted content, while disabling it
+    /// is required for restoring past document versions, eg. via
+    /// [ReadTxn::encode_state_from_snapshot].
+    pub async fn from_options(options: Options, buffer_capacity: usize) -> Self {
+        Self::from_doc(Doc::with_options(options), buffer_capacity).await
+    }
+
+    /// Returns true if deleted items of the group's document are garbage collected. See
+    /// [BroadcastGroup::from_options].
+    pub async fn gc_enabled(&self) -> bool {
+        let awareness = self.awareness_ref.read().await;
+        !awareness.doc().options().skip_gc
+    }
+
+    /// Creates a new [BroadcastGroup] over a provided `awareness` instance, rehydrating its
+    /// document with a binary v1 encoded `update` first - eg. a snapshot previously stored via
+    /// [BroadcastGroup::with_update_sink]. See [BroadcastGroup::new] for details.
+    ///
+    /// The update is applied before the group starts observing the document, so it's not
+    /// broadcasted: subscribers receive the restored state as part of their regular sync.
+    pub async fn from_state(
+        awareness: AwarenessRef,
+        buffer_capacity: usize,
+        update: &[u8],
+    ) -> Result<Self, Error> {
+        let update = Update::decode_v1(update)?;
+        {
+            let lock = awareness.write().await;
+            lock.doc().transact_mut().apply_update(update);
         }
+        Ok(Self::new(awareness, buffer_capacity).await)
+    }
+
+    /// Enables sending a [KEEPALIVE_MSG] data frame to every subscriber, which didn't receive any
+    /// other message for a given `period`. This is useful in front of proxies, which reap
+    /// connections that don't see any data frames, ignoring ping/pong control frames.
+    ///
+    /// Keepalive is disabled by default. A zero `period` disables it as well.
+    pub fn with_keepalive(mut self, period: Duration) -> Self {
+        self.keepalive = if period.is_zero() { None } else { Some(period) };
+        self
+    }
+
+    /// Enables sending a [PING_MSG] to every subscriber each `period`. A subscriber, which didn't
+    /// answer `max_missed` consecutive pings, is closed and its [Subscription::completed] resolves
+    /// with [Error::Timeout]. Any message received from a subscriber counts as an answer. This
+    /// cleans up subscriptions of peers, which are gone without closing their connection.
+    /// A subscriber has to miss at least one ping to be closed, so a zero `max_missed` is treated
+    /// as 1.
+    ///
+    /// Pings are disabled by default. A zero `period` disables them as well.
+    pub fn with_ping_interval(mut self, period: Duration, max_missed: u32) -> Self {
+        self.ping = if period.is_zero() {
+            None
+        } else {
+            Some((period, max_missed.max(1)))
+        };
+        self
+    }
+
+    /// Sets a minimum interval between SyncStep1 requests honored for a single subscription.
+    /// Replying to SyncStep1 requires encoding a document state, which can be expensive for large
+    /// documents: requests received within a `cooldown` since the last honored one are ignored.
+    ///
+    /// By default all SyncStep1 requests are honored. A zero `cooldown` restores that behavior.
+    /// Note that a cooldown applies to full resync requests of peers recovering from missed
+    /// updates as well.
+    pub fn with_sync_step1_cooldown(mut self, cooldown: Duration) -> Self {
+        self.sync_step1_cooldown = if cooldown.is_zero() {
+            None
+        } else {
+            Some(cooldown)
+        };
+        self
+    }
+
+    /// Sets a time, in which a new subscriber has to start a y-sync handshake by sending
+    /// a SyncStep1 or SyncStep2 message - or any message in case of
+    /// [awareness-only](BroadcastGroup::subscribe_awareness_only) subscribers. A subscriber, which
+    /// didn't, is closed and its [Subscription::completed] resolves with [Error::Timeout], so that
+    /// half-open connections don't take up capacity of the group.
+    ///
+    /// Handshake timeout is disabled by default. A zero `timeout` disables it as well.
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = if timeout.is_zero() {
+            None
+        } else {
+            Some(timeout)
+        };
+        self
+    }
+
+    /// Sets a maximum time a subscriber stays connected, in order to make clients reconnect
+    /// periodically - eg. to pick up renewed auth tokens or to rebalance connections across
+    /// servers. Once it elapses, a subscriber is closed with [CloseReason::Expired] and its
+    /// [Subscription::completed] resolves with [DisconnectReason::Expired].
+    ///
+    /// Connections live for as long as they're open by default. A zero `lifetime` disables
+    /// the limit as well.
+    pub fn with_max_connection_lifetime(mut self, lifetime: Duration) -> Self {
+        self.max_lifetime = if lifetime.is_zero() {
+            None
+        } else {
+            Some(lifetime)
+        };
+        self
+    }
+
+    /// Sets a time after which a subscriber, which didn't send any messages, is considered idle.
+    /// Pongs and [KEEPALIVE_MSG] don't count, so that connections kept open by pings alone - eg.
+    /// of abandoned browser tabs - get closed with [CloseReason::Idle] and their
+    /// [Subscription::completed] resolves with [DisconnectReason::Idle]. See
+    /// [Subscription::last_activity].
+    ///
+    /// Idle timeout is disabled by default. A zero `timeout` disables it as well.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = if timeout.is_zero() {
+            None
+        } else {
+            Some(timeout)
+        };
+        self
+    }
+
+    /// Limits the number of connections subscribed to this group at the same time. Subscribing
+    /// over the limit closes the new connection's sink right away and returns a subscription,
+    /// which completes with [Error::LimitExceeded]. By default, and when `max` is zero, there's no
+    /// limit.
+    pub fn with_max_subscribers(mut self, max: usize) -> Self {
+        self.max_subscribers = max;
+        self
+    }
+
+    /// Sets a maximum binary size of a message accepted from a subscriber. A subscriber sending
+    /// a bigger message is closed without the message being applied, as its processing could
+    /// stall all other subscribers of this group. Defaults to [DEFAULT_MAX_MESSAGE_SIZE].
+    ///
+    /// This check happens only once a whole message has been received. Web socket transports
+    /// should limit the message size on their own as well, eg. via axum's
+    /// `WebSocketUpgrade::max_message_size`, so that oversized messages are not buffered at all.
+    pub fn with_max_message_size(mut self, max: usize) -> Self {
+        self.max_message_size = max;
+        self
+    }
+
+    /// Limits a number of `messages` and a total of `bytes` a single subscriber can send per
+    /// second. Messages over either limit are dropped without being applied, so that a peer
+    /// flooding tiny updates can't stall the group with transactions and rebroadcasts. Short bursts
+    /// of up to a second worth of traffic are allowed. By default, and when set to zero, neither is
+    /// limited.
+    ///
+    /// A subscriber, which keeps exceeding the limits, is closed - see
+    /// [BroadcastGroup::with_max_rate_violations].
+    pub fn with_inbound_rate_limit(mut self, messages: u32, bytes: u32) -> Self {
+        self.inbound_rate = (messages, bytes);
+        self
+    }
+
+    /// Sets a number of consecutive messages, which a subscriber can send over the limits of
+    /// [BroadcastGroup::with_inbound_rate_limit] before it's closed. Its [Subscription::completed]
+    /// resolves with [Error::LimitExceeded] then. Defaults to [DEFAULT_MAX_RATE_VIOLATIONS], zero
+    /// `max` means that subscribers are never closed and only their messages are dropped.
+    pub fn with_max_rate_violations(mut self, max: u32) -> Self {
+        self.max_rate_violations = max;
+        self
+    }
+
+    /// Sets a behavior of this group, when one of its subscribers doesn't keep up with broadcasted
+    /// messages and their number exceeds a `buffer_capacity` passed to [BroadcastGroup::new].
+    /// Defaults to [BufferOverflowPolicy::DisconnectSlow].
+    pub fn with_buffer_overflow_policy(mut self, policy: BufferOverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Sets an order in which broadcasted messages are sent to subscribers. Defaults to
+    /// [BroadcastPriority::Fifo].
+    ///
+    /// With [BroadcastPriority::AwarenessFirst], awareness updates of the group are buffered
+    /// separately from document updates, up to the same `buffer_capacity`, and a subscriber
+    /// always sends pending awareness updates first. This applies to subscribers created after
+    /// this call. Messages passed to [BroadcastGroup::broadcast] directly are never prioritized.
+    pub fn with_broadcast_priority(self, priority: BroadcastPriority) -> Self {
+        let awareness_first = priority == BroadcastPriority::AwarenessFirst;
+        self.awareness_first
+            .store(awareness_first, Ordering::Relaxed);
+        self
+    }
+
+    /// Sets a recorder of metrics like subscribed connections and exchanged messages of this
+    /// group. By default, no metrics are recorded.
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = metrics;
+        self
     }
 
     /// Returns a reference to an underlying [Awareness] instance.
@@ -107,15 +768,435 @@ impl BroadcastGroup {
         &self.awareness_ref
     }
 
+    /// Encodes a whole state of the group's document as a binary v1 update, eg. to back it up.
+    /// It can be restored via [BroadcastGroup::from_state].
+    ///
+    /// This takes a read lock on the group's [Awareness] for the duration of encoding, so it can
+    /// run concurrently with other readers, but waits for pending document changes made by
+    /// subscribers. It must not be called while holding a write lock on
+    /// [BroadcastGroup::awareness], as that would deadlock.
+    pub async fn encode_state_as_update(&self) -> Vec<u8> {
+        let awareness = self.awareness_ref.read().await;
+        let txn = awareness.doc().transact();
+        txn.encode_state_as_update_v1(&StateVector::default())
+    }
+
+    /// Applies a binary v1 encoded `update` to the group's document, eg. a change made by
+    /// a server-side agent or a scheduled job, and broadcasts it to all subscribers - once, just
+    /// like any other change of the document. Changes of an update, which depend on changes not
+    /// known to the document yet, are kept pending until those arrive.
+    ///
+    /// Returns [Error::Protocol] if the `update` couldn't be decoded, in which case the document
+    /// is left intact. Like [BroadcastGroup::encode_state_as_update], it must not be called while
+    /// holding a lock on [BroadcastGroup::awareness].
+    pub async fn apply_update(&self, update: &[u8]) -> Result<(), Error> {
+        let awareness = self.awareness_ref.write().await;
+        // decoded under the lock, as an update can't be held across await points of Send futures
+        let update = Update::decode_v1(update)?;
+        awareness.doc().transact_mut().apply_update(update);
+        Ok(())
+    }
+
+    /// Encodes a state vector of the group's document using v1 encoding. Locking behavior is the
+    /// same as in [BroadcastGroup::encode_state_as_update].
+    pub async fn encode_state_vector(&self) -> Vec<u8> {
+        let awareness = self.awareness_ref.read().await;
+        let txn = awareness.doc().transact();
+        txn.state_vector().encode_v1()
+    }
+
+    /// Returns IDs of all clients, which have an awareness entry tracked by this group.
+    pub async fn awareness_clients(&self) -> Vec<ClientID> {
+        let awareness = self.awareness_ref.read().await;
+        let mut clients: Vec<_> = awareness.clients().keys().copied().collect();
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Returns a JSON encoded awareness state of a given `client`, if it's tracked by this group.
+    pub async fn awareness_state(&self, client: ClientID) -> Option<String> {
+        let awareness = self.awareness_ref.read().await;
+        awareness.clients().get(&client).cloned()
+    }
+
+    /// Returns a number of clients with a non-null awareness state, ie. ones which are online.
+    pub async fn awareness_online_count(&self) -> usize {
+        let awareness = self.awareness_ref.read().await;
+        awareness
+            .clients()
+            .values()
+            .filter(|state| state.as_str() != "null")
+            .count()
+    }
+
+    /// Pauses applying and broadcasting updates in this group, eg. for the time of a maintenance
+    /// or migration of its document, without disconnecting any subscribers. While paused:
+    ///
+    /// - Messages received from every subscriber are held without being processed, up to
+    ///   a `buffer_capacity` passed to [BroadcastGroup::new] per subscriber. A subscriber over
+    ///   that limit isn't read from until the group is resumed.
+    /// - Messages broadcasted to subscribers, eg. due to local changes of the document, are held
+    ///   in their buffers and are subject to [BroadcastGroup::with_buffer_overflow_policy].
+    ///
+    /// Pings and keepalive messages are still exchanged, so that paused peers are not considered
+    /// gone. Pausing an already paused group has no effect.
+    pub fn pause(&self) {
+        self.paused.send_replace(true);
+    }
+
+    /// Resumes a group paused via [BroadcastGroup::pause]. Messages held in the meantime are
+    /// processed and delivered in the order they were received.
+    pub fn resume(&self) {
+        self.paused.send_replace(false);
+    }
+
+    /// Returns true if this group has been paused via [BroadcastGroup::pause].
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Returns a receiver of lifecycle events of this group, like peers joining or leaving and
+    /// document updates. Events are never awaited by the group: a receiver, which falls behind by
+    /// more than a fixed number of events, skips the oldest ones and gets
+    /// [RecvError::Lagged](tokio::sync::broadcast::error::RecvError::Lagged) reported instead.
+    pub fn events(&self) -> Receiver<BroadcastEvent> {
+        self.events.subscribe()
+    }
+
+    /// Returns a maximum number of broadcasted messages buffered for subscribers, which haven't
+    /// received them yet, as configured via [BroadcastGroup::new].
+    ///
+    /// The capacity can't be changed once a group is created, as subscribers already receive
+    /// messages from its buffer. Use [BroadcastGroup::buffer_high_water_mark] to pick the right
+    /// size for a workload.
+    pub fn buffer_capacity(&self) -> usize {
+        self.buffer_capacity
+    }
+
+    /// Returns a number of broadcasted messages, which haven't been received by all subscribers
+    /// yet.
+    pub fn buffer_len(&self) -> usize {
+        self.sender.len()
+    }
+
+    /// Returns the highest [BroadcastGroup::buffer_len] observed since this group was created or
+    /// [BroadcastGroup::reset_buffer_high_water_mark] was called. A value reaching
+    /// [BroadcastGroup::buffer_capacity] means that the slowest subscriber fell behind and - unless
+    /// [BufferOverflowPolicy::Block] is used - missed messages.
+    pub fn buffer_high_water_mark(&self) -> usize {
+        self.buffer_high_water_mark.load(Ordering::Relaxed)
+    }
+
+    /// Resets [BroadcastGroup::buffer_high_water_mark] to the current buffer length, returning its
+    /// previous value. This can be used to sample buffer usage in periodic intervals.
+    pub fn reset_buffer_high_water_mark(&self) -> usize {
+        self.buffer_high_water_mark
+            .swap(self.sender.len(), Ordering::Relaxed)
+    }
+
+    /// Returns a number of currently active subscriptions.
+    pub fn subscriber_count(&self) -> usize {
+        *self.subscribers.borrow()
+    }
+
+    /// Returns true if there are no active subscriptions in this group.
+    pub fn is_empty(&self) -> bool {
+        self.subscriber_count() == 0
+    }
+
+    /// Returns a receiver notified about every change of the active subscriptions number.
+    pub(crate) fn watch_subscribers(&self) -> watch::Receiver<usize> {
+        self.subscribers.subscribe()
+    }
+
+    /// Returns a future, which resolves once this group has at least one active subscriber. If
+    /// there are any subscribers already, it resolves immediately.
+    ///
+    /// This can be used to defer expensive room initialization until a first peer connects.
+    pub fn first_subscriber(&self) -> impl Future<Output = ()> {
+        let mut subscribers = self.subscribers.subscribe();
+        async move {
+            // error means that the group has been dropped, there's nothing to wait for
+            let _ = subscribers.wait_for(|count| *count > 0).await;
+        }
+    }
+
+    /// Registers a `callback` invoked every time the number of active subscribers of this group
+    /// drops to zero, eg. to persist the document or record metrics once a room becomes idle,
+    /// even if the group itself is kept alive. The callback stays registered for as long as this
+    /// group lives.
+    ///
+    /// The `callback` runs synchronously within the task of the last subscriber leaving, so it
+    /// should be cheap. It's just a signal: a new subscriber may join while it runs.
+    pub fn on_empty<F>(&self, callback: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.on_empty.lock().unwrap().push(Arc::new(callback));
+    }
+
+    /// Registers a `callback` invoked with every update of an underlying document decoded, no matter
+    /// if the change was made locally or received from one of the subscribers. Unlike
+    /// [BroadcastGroup::with_update_sink], it gives access to the inserted blocks and deleted
+    /// ranges of an update. The callback stays registered for as long as this group lives.
+    ///
+    /// This is opt-in, as each update is decoded once more - in addition to being applied - and
+    /// the `callback` runs synchronously within a committing transaction, delaying its broadcast.
+    /// Callbacks should be cheap and hand off expensive work to other tasks.
+    pub async fn on_update_decoded<F>(&self, callback: F)
+    where
+        F: Fn(&Update) + Send + Sync + 'static,
+    {
+        let lock = self.awareness_ref.write().await;
+        let sub = lock
+            .doc()
+            .observe_update_v1(move |_txn, e| match Update::decode_v1(&e.update) {
+                Ok(update) => callback(&update),
+                Err(e) => tracing::warn!("failed to decode document update: {e}"),
+            })
+            .unwrap();
+        self.observers.lock().unwrap().push(sub);
+    }
+
+    /// Registers a `callback` invoked with a delta of inserted, deleted and retained ranges every
+    /// time a root-level text of a given `name` is changed. The text is created if it doesn't exist
+    /// yet. The callback stays registered for as long as this group lives.
+    ///
+    /// Like [BroadcastGroup::on_update_decoded], the `callback` runs synchronously within
+    /// a committing transaction, and computing a delta has a cost proportional to the size of
+    /// the change, so this is opt-in.
+    pub async fn on_text_delta<F>(&self, name: &str, callback: F)
+    where
+        F: Fn(&TransactionMut, &[Delta]) + Send + Sync + 'static,
+    {
+        let lock = self.awareness_ref.write().await;
+        let text = lock.doc().get_or_insert_text(name);
+        let sub = text.observe(move |txn, e| callback(txn, e.delta(txn)));
+        self.observers.lock().unwrap().push(sub);
+    }
+
+    /// Sets an awareness state of this server, using client ID of an underlying document. This way
+    /// server-side agents can be visible to all subscribers alongside other peers. The state is
+    /// broadcasted immediately and then periodically renewed, so that clients don't consider it
+    /// outdated, until [BroadcastGroup::clear_local_awareness] is called.
+    pub async fn set_local_awareness(&self, state: serde_json::Value) {
+        let json = state.to_string();
+        {
+            let mut awareness = self.awareness_ref.write().await;
+            awareness.set_local_state(json.clone());
+        }
+        let awareness = Arc::downgrade(&self.awareness_ref);
+        let renewal = tokio::spawn(async move {
+            let mut interval = interval_at(
+                Instant::now() + LOCAL_AWARENESS_RENEWAL,
+                LOCAL_AWARENESS_RENEWAL,
+            );
+            loop {
+                interval.tick().await;
+                match awareness.upgrade() {
+                    Some(awareness) => awareness.write().await.set_local_state(json.clone()),
+                    None => return,
+                }
+            }
+        });
+        let prev = self.local_awareness.lock().unwrap().replace(renewal);
+        if let Some(prev) = prev {
+            prev.abort();
+        }
+    }
+
+    /// Removes an awareness state of this server set previously via
+    /// [BroadcastGroup::set_local_awareness] and broadcasts its removal to all subscribers.
+    pub async fn clear_local_awareness(&self) {
+        let renewal = self.local_awareness.lock().unwrap().take();
+        if let Some(renewal) = renewal {
+            renewal.abort();
+        }
+        let mut awareness = self.awareness_ref.write().await;
+        awareness.clean_local_state();
+    }
+
+    /// Returns a nested [BroadcastGroup] of a subdocument with a given `guid`, creating it on first
+    /// use. Returns `None` if the group's document doesn't contain such subdocument.
+    ///
+    /// Like y-websocket, every subdocument is synchronized over its own connection: peers should
+    /// subscribe to the returned group with the subdocument's own [Doc] to receive and send its
+    /// updates, while the parent group only carries the subdocument's reference. Nested groups
+    /// share this group's buffer capacity and are closed once their subdocument is removed from
+    /// the parent document or this group is closed.
+    pub async fn subdoc(&self, guid: &str) -> Option<Arc<BroadcastGroup>> {
+        if let Some(group) = self.subdocs.lock().unwrap().get(guid) {
+            return Some(group.clone());
+        }
+        let doc = {
+            let awareness = self.awareness_ref.read().await;
+            let txn = awareness.doc().transact();
+            let doc = txn.subdocs().find(|doc| &**doc.guid() == guid)?.clone();
+            doc
+        };
+        let mut group = BroadcastGroup::from_doc(doc, self.buffer_capacity).await;
+        group.closing = self.closing.child_token();
+        let mut subdocs = self.subdocs.lock().unwrap();
+        // another caller might have created the group in the meantime
+        let group = subdocs
+            .entry(guid.into())
+            .or_insert_with(|| Arc::new(group));
+        Some(group.clone())
+    }
+
+    /// Returns GUIDs of subdocuments, which currently have a nested group created via
+    /// [BroadcastGroup::subdoc].
+    pub fn subdoc_guids(&self) -> Vec<Arc<str>> {
+        self.subdocs.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Relays document updates between this group and an `other` one in both directions - eg. to
+    /// mirror a room hosted by another server - until a returned [Bridge] is dropped. Documents
+    /// of both groups are synced with each other first, then every change made to one of them,
+    /// locally or by its subscribers, is applied to the other and broadcasted to its subscribers.
+    ///
+    /// Updates are relayed within transactions of a bridge's own origin, which it never relays
+    /// back, so that updates don't bounce between the groups. Bridges can be chained, eg. A to B
+    /// and B to C, in order to relay updates across more groups. Awareness states aren't relayed.
+    pub async fn bridge(&self, other: &BroadcastGroup) -> Bridge {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let origin = Origin::from(format!("yrs-axum-bridge-{id}"));
+        let (sub, updates, state) = Self::observe_bridged(&self.awareness_ref, &origin).await;
+        let (other_sub, other_updates, other_state) =
+            Self::observe_bridged(&other.awareness_ref, &origin).await;
+        // changes made in between are relayed along with the states, applying them twice is a no-op
+        let relays = [
+            Self::relay(&other.awareness_ref, state, updates, origin.clone()),
+            Self::relay(&self.awareness_ref, other_state, other_updates, origin),
+        ];
+        Bridge {
+            _observers: [sub, other_sub],
+            relays,
+        }
+    }
+
+    /// Starts observing updates of a bridged document, which don't come from a bridge of a given
+    /// `origin`. Returns them along with a full state of the document at the time.
+    async fn observe_bridged(
+        awareness: &AwarenessRef,
+        origin: &Origin,
+    ) -> (yrs::Subscription, UnboundedReceiver<Vec<u8>>, Vec<u8>) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let origin = origin.clone();
+        let lock = awareness.write().await;
+        let sub = lock
+            .doc()
+            .observe_update_v1(move |txn, e| {
+                if txn.origin() != Some(&origin) {
+                    let _ = tx.send(e.update.clone());
+                }
+            })
+            .unwrap();
+        let state = lock
+            .doc()
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        (sub, rx, state)
+    }
+
+    /// Spawns a task applying a `state` and then all `updates` of a bridged document to a `target`
+    /// document within transactions of a given `origin`.
+    fn relay(
+        target: &AwarenessRef,
+        state: Vec<u8>,
+        mut updates: UnboundedReceiver<Vec<u8>>,
+        origin: Origin,
+    ) -> JoinHandle<()> {
+        let target = target.clone();
+        tokio::spawn(async move {
+            let mut state = Some(state);
+            loop {
+                let update = match state.take() {
+                    Some(state) => state,
+                    None => match updates.recv().await {
+                        Some(update) => update,
+                        None => break,
+                    },
+                };
+                let lock = target.write().await;
+                match Update::decode_v1(&update) {
+                    Ok(update) => lock
+                        .doc()
+                        .transact_mut_with(origin.clone())
+                        .apply_update(update),
+                    Err(e) => tracing::warn!("failed to decode bridged update: {e}"),
+                }
+            }
+        })
+    }
+
+    /// Closes this group, closing the sinks of all its subscribers - for web socket connections
+    /// this means sending a close frame - and waiting a while for the peers to close their side of
+    /// the connection as well. Closing is done concurrently, so peers which fail or don't respond
+    /// don't hold back closing of the others.
+    ///
+    /// All pending [Subscription::completed] futures of this group resolve with `Ok`, unless
+    /// their connection failed before it could be closed.
+    ///
+    /// Dropping a group closes its subscribers and stops its background tasks the same way, just
+    /// without waiting for the peers.
+    pub async fn close(self) {
+        self.closing.cancel();
+        let mut subscribers = self.subscribers.subscribe();
+        if timeout(CLOSE_TIMEOUT, subscribers.wait_for(|count| *count == 0))
+            .await
+            .is_err()
+        {
+            let remaining = *subscribers.borrow();
+            tracing::debug!("{remaining} subscribers didn't close their connection in time");
+        }
+    }
+
     /// Broadcasts user message to all active subscribers. Returns error if message could not have
     /// been broadcasted.
     pub fn broadcast(&self, msg: Vec<u8>) -> Result<(), SendError<Vec<u8>>> {
         self.sender.send(msg)?;
+        Self::record_buffer_len(&self.sender, &self.buffer_high_water_mark);
         Ok(())
     }
 
+    /// Broadcasts a y-sync protocol message to all active subscribers, eg. an application specific
+    /// notification sent as [Message::Custom]. Returns error if there are no subscribers. Like any
+    /// other broadcasted message, it's delivered in order with document and awareness updates.
+    /// Subscribers, which fail to receive it, are closed.
+    ///
+    /// Custom messages must use tags, which don't collide with the ones of y-sync protocol
+    /// ([MSG_SYNC], [MSG_AWARENESS](yrs::sync::protocol::MSG_AWARENESS),
+    /// [MSG_AUTH](yrs::sync::protocol::MSG_AUTH) and
+    /// [MSG_QUERY_AWARENESS](yrs::sync::protocol::MSG_QUERY_AWARENESS)). Clients need to handle
+    /// such tags on their own, eg. by registering a `messageHandlers` entry of y-websocket provider.
+    pub fn broadcast_custom(&self, msg: Message) -> Result<(), SendError<Vec<u8>>> {
+        self.broadcast(msg.encode_v1())
+    }
+
     /// Subscribes a new connection - represented by `sink`/`stream` pair implementing a futures
-    /// Sink and Stream protocols - to a current broadcast group.
+    /// Sink and Stream protocols - to a current broadcast group. Unless the awareness is empty,
+    /// a newly joined connection first receives its full current state, so that it doesn't have
+    /// to wait for the next awareness change to learn about other peers.
+    ///
+    /// A connection receives broadcasted messages from the moment this method is called, so that
+    /// no change is missed, even when joining during a burst of updates. Its SyncStep1 requests
+    /// are answered in line with broadcasted updates: a SyncStep2 reply is computed under the
+    /// awareness lock, which document changes are made under, and document updates queued for
+    /// the connection by then are discarded, as the reply already includes them. This way no
+    /// update included in a reply is received again after it - although updates received before
+    /// a reply may be included in it, which peers ignore, as applying them again is a no-op.
+    ///
+    /// Once a connection is gone, awareness states it has announced are removed and the removal
+    /// is broadcasted to the remaining peers, so that their cursors disappear right away. States
+    /// announced again by another connection in the meantime, eg. by the same client after
+    /// reconnecting, are kept.
+    ///
+    /// A connection can send SyncStep1 requests at any time, not just during a handshake. That's
+    /// how a peer, which missed some updates, eg. because it lagged behind with
+    /// [BufferOverflowPolicy::DropOldest], recovers: it's answered with a diff of everything it's
+    /// missing, which - for an empty state vector - is a complete document state.
     ///
     /// Returns a subscription structure, which can be dropped in order to unsubscribe or awaited
     /// via [Subscription::completed] method in order to complete of its own volition (due to
@@ -130,105 +1211,782 @@ impl BroadcastGroup {
         self.subscribe_with(sink, stream, DefaultProtocol)
     }
 
-    /// Subscribes a new connection - represented by `sink`/`stream` pair implementing a futures
-    /// Sink and Stream protocols - to a current broadcast group.
-    ///
-    /// Returns a subscription structure, which can be dropped in order to unsubscribe or awaited
-    /// via [Subscription::completed] method in order to complete of its own volition (due to
-    /// an internal connection error or closed connection).
-    ///
-    /// Unlike [BroadcastGroup::subscribe], this method can take [Protocol] parameter that allows to
-    /// customize the y-sync protocol behavior.
-    pub fn subscribe_with<Sink, Stream, E, P>(
+    /// Subscribes a new read-only connection to a current broadcast group. Such connection
+    /// receives document and awareness updates like any other, but document updates sent by it are
+    /// discarded instead of being applied. See [BroadcastGroup::subscribe] for details.
+    pub fn subscribe_readonly<Sink, Stream, E>(
         &self,
         sink: Arc<Mutex<Sink>>,
-        mut stream: Stream,
-        protocol: P,
+        stream: Stream,
     ) -> Subscription
     where
         Sink: SinkExt<Vec<u8>> + Send + Sync + Unpin + 'static,
         Stream: StreamExt<Item = Result<Vec<u8>, E>> + Send + Sync + Unpin + 'static,
         <Sink as futures_util::Sink<Vec<u8>>>::Error: std::error::Error + Send + Sync,
         E: std::error::Error + Send + Sync + 'static,
-        P: Protocol + Send + Sync + 'static,
     {
-        let sink_task = {
-            let sink = sink.clone();
-            let mut receiver = self.sender.subscribe();
-            tokio::spawn(async move {
-                while let Ok(msg) = receiver.recv().await {
-                    let mut sink = sink.lock().await;
-                    if let Err(e) = sink.send(msg).await {
-                        println!("broadcast failed to sent sync message");
-                        return Err(Error::Other(Box::new(e)));
-                    }
+        self.subscribe_with(sink, stream, ReadOnlyProtocol(DefaultProtocol))
+    }
+
+    /// Subscribes a new awareness-only connection to a current broadcast group, eg. of a widget
+    /// showing active users, which doesn't need the document itself. Awareness updates are
+    /// exchanged with such connection like with any other - so its own awareness state is shared
+    /// with other subscribers - but it never receives document sync messages or updates, while
+    /// ones sent by it are ignored. See [BroadcastGroup::subscribe] for details.
+    pub fn subscribe_awareness_only<Sink, Stream, E>(
+        &self,
+        sink: Arc<Mutex<Sink>>,
+        stream: Stream,
+    ) -> Subscription
+    where
+        Sink: SinkExt<Vec<u8>> + Send + Sync + Unpin + 'static,
+        Stream: StreamExt<Item = Result<Vec<u8>, E>> + Send + Sync + Unpin + 'static,
+        <Sink as futures_util::Sink<Vec<u8>>>::Error: std::error::Error + Send + Sync,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        self.subscribe_filtered(sink, stream, AwarenessOnlyProtocol(DefaultProtocol), true)
+    }
+
+    /// Subscribes a new connection with given `permissions` to a current broadcast group. This
+    /// is either [BroadcastGroup::subscribe] or [BroadcastGroup::subscribe_readonly].
+    pub fn subscribe_as<Sink, Stream, E>(
+        &self,
+        sink: Arc<Mutex<Sink>>,
+        stream: Stream,
+        permissions: PeerPermissions,
+    ) -> Subscription
+    where
+        Sink: SinkExt<Vec<u8>> + Send + Sync + Unpin + 'static,
+        Stream: StreamExt<Item = Result<Vec<u8>, E>> + Send + Sync + Unpin + 'static,
+        <Sink as futures_util::Sink<Vec<u8>>>::Error: std::error::Error + Send + Sync,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        match permissions {
+            PeerPermissions::ReadOnly => self.subscribe_readonly(sink, stream),
+            PeerPermissions::ReadWrite => self.subscribe(sink, stream),
+        }
+    }
+
+    /// Subscribes a new connection - represented by `sink`/`stream` pair implementing a futures
+    /// Sink and Stream protocols - to a current broadcast group.
+    ///
+    /// Returns a subscription structure, which can be dropped in order to unsubscribe or awaited
+    /// via [Subscription::completed] method in order to complete of its own volition (due to
+    /// an internal connection error or closed connection).
+    ///
+    /// Unlike [BroadcastGroup::subscribe], this method can take [Protocol] parameter that allows to
+    /// customize the y-sync protocol behavior.
+    pub fn subscribe_with<Sink, Stream, E, P>(
+        &self,
+        sink: Arc<Mutex<Sink>>,
+        stream: Stream,
+        protocol: P,
+    ) -> Subscription
+    where
+        Sink: SinkExt<Vec<u8>> + Send + Sync + Unpin + 'static,
+        Stream: StreamExt<Item = Result<Vec<u8>, E>> + Send + Sync + Unpin + 'static,
+        <Sink as futures_util::Sink<Vec<u8>>>::Error: std::error::Error + Send + Sync,
+        E: std::error::Error + Send + Sync + 'static,
+        P: Protocol + Send + Sync + 'static,
+    {
+        self.subscribe_filtered(sink, stream, protocol, false)
+    }
+
+    /// Subscribes a new connection like [BroadcastGroup::subscribe_with]. Document sync messages
+    /// broadcasted by the group are not sent to `awareness_only` connections.
+    fn subscribe_filtered<Sink, Stream, E, P>(
+        &self,
+        sink: Arc<Mutex<Sink>>,
+        mut stream: Stream,
+        protocol: P,
+        awareness_only: bool,
+    ) -> Subscription
+    where
+        Sink: SinkExt<Vec<u8>> + Send + Sync + Unpin + 'static,
+        Stream: StreamExt<Item = Result<Vec<u8>, E>> + Send + Sync + Unpin + 'static,
+        <Sink as futures_util::Sink<Vec<u8>>>::Error: std::error::Error + Send + Sync,
+        E: std::error::Error + Send + Sync + 'static,
+        P: Protocol + Send + Sync + 'static,
+    {
+        let error: Arc<std::sync::Mutex<Option<String>>> = Arc::default();
+        let Some(guard) = SubscriberGuard::try_new(
+            self.subscribers.clone(),
+            self.on_empty.clone(),
+            self.max_subscribers,
+            self.events.clone(),
+            error.clone(),
+            self.metrics.clone(),
+        ) else {
+            return Self::reject(sink, self.max_subscribers);
+        };
+        let id = SubscriptionId::next();
+        let claims = AwarenessClaims {
+            id,
+            owners: self.awareness_owners.clone(),
+            departures: self.departures.clone(),
+        };
+        let missed_pongs = Arc::new(AtomicU32::new(0));
+        let traffic = Arc::new(Traffic::default());
+        let closing = self.closing.child_token();
+        let protocol = Arc::new(protocol);
+        let (handshakes, mut handshake_requests) = tokio::sync::mpsc::unbounded_channel();
+        let sink_task = {
+            let sink = sink.clone();
+            let error = error.clone();
+            let protocol = protocol.clone();
+            let mut receiver = BufferReceiver::new(self.sender.subscribe(), self.drained.clone());
+            let mut prioritized = self.awareness_first.load(Ordering::Relaxed).then(|| {
+                BufferReceiver::new(self.awareness_sender.subscribe(), self.drained.clone())
+            });
+            let mut keepalive = self
+                .keepalive
+                .map(|period| interval_at(Instant::now() + period, period));
+            let group_closing = self.closing.clone();
+            let closing = closing.clone();
+            let mut ping = self
+                .ping
+                .map(|(period, _)| interval_at(Instant::now() + period, period));
+            let max_missed = self
+                .ping
+                .map(|(_, max_missed)| max_missed)
+                .unwrap_or_default();
+            let missed_pongs = missed_pongs.clone();
+            let expires_at = self.max_lifetime.map(|lifetime| Instant::now() + lifetime);
+            let idle_timeout = self.idle_timeout;
+            let overflow_policy = self.overflow_policy;
+            let metrics = self.metrics.clone();
+            let traffic = traffic.clone();
+            let awareness = self.awareness().clone();
+            let awareness_filter = self.awareness_filter.clone();
+            let mut paused = self.paused.subscribe();
+            tokio::spawn(async move {
+                if let Some(snapshot) =
+                    Self::awareness_snapshot(&awareness, &awareness_filter).await
+                {
+                    let len = snapshot.len();
+                    let mut sink = sink.lock().await;
+                    if let Err(e) = sink.send(snapshot).await {
+                        metrics.on_publish_fail();
+                        error.lock().unwrap().get_or_insert_with(|| e.to_string());
+                        return Err(Error::Transport(Box::new(e)));
+                    }
+                    metrics.on_message(Direction::Outbound, len);
+                    traffic.record(Direction::Outbound, len);
+                }
+                loop {
+                    let outbound_paused = *paused.borrow();
+                    let msg = select! {
+                        res = Self::next_broadcast(&mut receiver, &mut prioritized), if !outbound_paused => match res {
+                            Ok(msg) if awareness_only && msg.first() == Some(&MSG_SYNC) => continue,
+                            Ok(msg) => msg,
+                            Err(RecvError::Closed) => break,
+                            Err(RecvError::Lagged(n)) => {
+                                if overflow_policy != BufferOverflowPolicy::DisconnectSlow {
+                                    tracing::debug!("subscriber lagged behind, skipped {n} messages");
+                                    continue;
+                                }
+                                tracing::warn!("closing subscriber lagging behind by {n} messages");
+                                let e = Error::Lagged(n);
+                                error.lock().unwrap().get_or_insert_with(|| e.to_string());
+                                let mut sink = sink.lock().await;
+                                let _ = timeout(CLOSE_TIMEOUT, close_sink(&mut *sink, CloseReason::Lagged)).await;
+                                return Err(e);
+                            }
+                        },
+                        _ = Self::pause_changed(&mut paused) => continue,
+                        Some(state_vector) = handshake_requests.recv() => {
+                            let res =
+                                Self::sync_step1_reply(&*protocol, &awareness, &mut receiver, state_vector)
+                                    .await;
+                            let (pending, reply) = match res {
+                                Ok(res) => res,
+                                Err(e) => {
+                                    error.lock().unwrap().get_or_insert_with(|| e.to_string());
+                                    return Err(e);
+                                }
+                            };
+                            let mut sink = sink.lock().await;
+                            for msg in pending {
+                                let len = msg.len();
+                                if let Err(e) = sink.send(msg).await {
+                                    metrics.on_publish_fail();
+                                    error.lock().unwrap().get_or_insert_with(|| e.to_string());
+                                    return Err(Error::Transport(Box::new(e)));
+                                }
+                                metrics.on_message(Direction::Outbound, len);
+                                traffic.record(Direction::Outbound, len);
+                            }
+                            match reply {
+                                Some(reply) => reply.encode_v1(),
+                                None => continue,
+                            }
+                        }
+                        _ = Self::deadline(expires_at) => {
+                            tracing::debug!("closing subscriber, which reached its maximum lifetime");
+                            let mut sink = sink.lock().await;
+                            let _ = timeout(CLOSE_TIMEOUT, close_sink(&mut *sink, CloseReason::Expired)).await;
+                            return Ok(DisconnectReason::Expired);
+                        }
+                        _ = Self::idle(&traffic, idle_timeout) => {
+                            tracing::debug!("closing subscriber, which has been idle for {idle_timeout:?}");
+                            let mut sink = sink.lock().await;
+                            let _ = timeout(CLOSE_TIMEOUT, close_sink(&mut *sink, CloseReason::Idle)).await;
+                            return Ok(DisconnectReason::Idle);
+                        }
+                        _ = Self::tick(&mut keepalive) => KEEPALIVE_MSG.to_vec(),
+                        _ = Self::tick(&mut ping) => {
+                            let mut sink = sink.lock().await;
+                            if missed_pongs.fetch_add(1, Ordering::AcqRel) >= max_missed {
+                                tracing::info!("closing subscriber, which missed {max_missed} pings");
+                                let e = Error::Timeout(format!("missed {max_missed} pings"));
+                                error.lock().unwrap().get_or_insert_with(|| e.to_string());
+                                let _ = timeout(CLOSE_TIMEOUT, close_sink(&mut *sink, CloseReason::Timeout)).await;
+                                return Err(e);
+                            }
+                            if let Err(e) = sink.send(PING_MSG.to_vec()).await {
+                                error.lock().unwrap().get_or_insert_with(|| e.to_string());
+                                return Err(Error::Transport(Box::new(e)));
+                            }
+                            continue;
+                        },
+                        _ = closing.cancelled() => {
+                            let reason = if group_closing.is_cancelled() {
+                                CloseReason::GoingAway
+                            } else {
+                                CloseReason::Normal
+                            };
+                            let mut sink = sink.lock().await;
+                            match timeout(CLOSE_TIMEOUT, close_sink(&mut *sink, reason)).await {
+                                Ok(Ok(())) => {}
+                                Ok(Err(e)) => tracing::debug!("failed to close subscriber: {e}"),
+                                Err(_) => tracing::debug!("timed out closing subscriber"),
+                            }
+                            if group_closing.is_cancelled() {
+                                break;
+                            }
+                            // closed individually via SubscriptionHandle::close
+                            return Err(Error::Closed);
+                        }
+                    };
+                    let mut sink = sink.lock().await;
+                    let len = msg.len();
+                    if let Err(e) = sink.send(msg).await {
+                        println!("broadcast failed to sent sync message");
+                        metrics.on_publish_fail();
+                        error.lock().unwrap().get_or_insert_with(|| e.to_string());
+                        return Err(Error::Transport(Box::new(e)));
+                    }
+                    metrics.on_message(Direction::Outbound, len);
+                    traffic.record(Direction::Outbound, len);
+                    if let Some(keepalive) = keepalive.as_mut() {
+                        keepalive.reset();
+                    }
                 }
-                Ok(())
+                Ok(DisconnectReason::GroupClosed)
             })
         };
         let stream_task = {
             let awareness = self.awareness().clone();
-            tokio::spawn(async move {
-                while let Some(res) = stream.next().await {
-                    let msg = Message::decode_v1(&res.map_err(|e| Error::Other(Box::new(e)))?)?;
-                    let reply = Self::handle_msg(&protocol, &awareness, msg).await?;
+            let awareness_changes = self.awareness_changes.clone();
+            let owners = self.awareness_owners.clone();
+            let metrics = self.metrics.clone();
+            let traffic = traffic.clone();
+            let missed_pongs = missed_pongs.clone();
+            let awareness_filter = self.awareness_filter.clone();
+            let sync_step1_cooldown = self.sync_step1_cooldown;
+            let mut handshake_deadline = self
+                .handshake_timeout
+                .map(|timeout| Instant::now() + timeout);
+            let max_message_size = self.max_message_size;
+            let (message_rate, byte_rate) = self.inbound_rate;
+            let max_rate_violations = self.max_rate_violations;
+            let backpressure = (self.overflow_policy == BufferOverflowPolicy::Block).then(|| {
+                (
+                    self.sender.clone(),
+                    self.buffer_capacity,
+                    self.drained.clone(),
+                )
+            });
+            let buffer_capacity = self.buffer_capacity;
+            let mut paused = self.paused.subscribe();
+            let process = async move {
+                let mut last_sync_step1: Option<Instant> = None;
+                let mut message_rate = TokenBucket::new(message_rate);
+                let mut byte_rate = TokenBucket::new(byte_rate);
+                let mut rate_violations = 0;
+                let mut held = VecDeque::new();
+                loop {
+                    let resumed = if *paused.borrow() {
+                        None
+                    } else {
+                        held.pop_front()
+                    };
+                    let msg = match resumed {
+                        // flush messages held while the group was paused, in order
+                        Some(msg) => msg,
+                        None => {
+                            let res = select! {
+                                res = stream.next(), if held.len() < buffer_capacity => res,
+                                _ = Self::pause_changed(&mut paused) => continue,
+                                _ = Self::deadline(handshake_deadline) => {
+                                    tracing::info!("closing subscriber, which didn't start a handshake in time");
+                                    let mut sink = sink.lock().await;
+                                    let _ = timeout(CLOSE_TIMEOUT, close_sink(&mut *sink, CloseReason::Timeout)).await;
+                                    return Err(Error::Timeout("handshake not started".into()));
+                                }
+                            };
+                            let Some(res) = res else {
+                                break;
+                            };
+                            let data = res.map_err(|e| Error::Transport(Box::new(e)))?;
+                            missed_pongs.store(0, Ordering::Release);
+                            if data.is_empty() {
+                                // a pong reported by transport, see PING_MSG
+                                continue;
+                            }
+                            metrics.on_message(Direction::Inbound, data.len());
+                            traffic.record(Direction::Inbound, data.len());
+                            if data[..] != KEEPALIVE_MSG {
+                                traffic.touch();
+                            }
+                            if data.len() > max_message_size {
+                                let len = data.len();
+                                tracing::warn!(
+                                    "closing subscriber after message of {len} bytes over the limit of {max_message_size} bytes"
+                                );
+                                let mut sink = sink.lock().await;
+                                let _ = timeout(
+                                    CLOSE_TIMEOUT,
+                                    close_sink(&mut *sink, CloseReason::MessageTooBig),
+                                )
+                                .await;
+                                return Err(Error::LimitExceeded(format!(
+                                    "message of {len} bytes exceeds the size limit"
+                                )));
+                            }
+                            if !(message_rate.try_acquire()
+                                && byte_rate.try_acquire_many(data.len()))
+                            {
+                                rate_violations += 1;
+                                if max_rate_violations != 0 && rate_violations > max_rate_violations
+                                {
+                                    tracing::warn!(
+                                        "closing subscriber after {rate_violations} messages over the rate limit"
+                                    );
+                                    let mut sink = sink.lock().await;
+                                    let _ = timeout(
+                                        CLOSE_TIMEOUT,
+                                        close_sink(&mut *sink, CloseReason::Policy),
+                                    )
+                                    .await;
+                                    return Err(Error::LimitExceeded(
+                                        "inbound rate limit exceeded".into(),
+                                    ));
+                                }
+                                tracing::debug!("dropping message over the rate limit");
+                                continue;
+                            }
+                            rate_violations = 0;
+                            let msg = Message::decode_v1(&data)?;
+                            if awareness_only
+                                || matches!(
+                                    msg,
+                                    Message::Sync(
+                                        SyncMessage::SyncStep1(_) | SyncMessage::SyncStep2(_)
+                                    )
+                                )
+                            {
+                                handshake_deadline = None;
+                            }
+                            if *paused.borrow() {
+                                held.push_back(msg);
+                                continue;
+                            }
+                            msg
+                        }
+                    };
+                    if let Some((sender, capacity, drained)) = &backpressure {
+                        Self::wait_for_capacity(sender, *capacity, drained).await;
+                    }
+                    if let (Message::Sync(SyncMessage::SyncStep1(_)), Some(cooldown)) =
+                        (&msg, sync_step1_cooldown)
+                    {
+                        let now = Instant::now();
+                        if matches!(last_sync_step1, Some(last) if now < last + cooldown) {
+                            tracing::debug!("ignoring SyncStep1 request received within cooldown");
+                            continue;
+                        }
+                        last_sync_step1 = Some(now);
+                    }
+                    let msg = match msg {
+                        Message::Sync(SyncMessage::SyncStep1(state_vector)) if !awareness_only => {
+                            // answered by the sink task, so that the reply is ordered with
+                            // broadcasted updates, see sync_step1_reply
+                            let _ = handshakes.send(state_vector);
+                            continue;
+                        }
+                        msg => msg,
+                    };
+                    if let Message::Awareness(update) = &msg {
+                        Self::claim_awareness(&owners, id, update);
+                    }
+                    let reply =
+                        Self::handle_msg(&*protocol, &awareness, &awareness_changes, msg).await?;
+                    let reply = match reply {
+                        Some(Message::Awareness(update)) => {
+                            Self::filter_awareness(&awareness_filter, update)
+                                .map(Message::Awareness)
+                        }
+                        reply => reply,
+                    };
                     match reply {
                         None => {}
                         Some(reply) => {
+                            // awareness lock is released by now, so that a peer slow to receive
+                            // a reply doesn't block the others
+                            let reply = reply.encode_v1();
+                            let len = reply.len();
                             let mut sink = sink.lock().await;
-                            sink.send(reply.encode_v1())
+                            sink.send(reply)
                                 .await
-                                .map_err(|e| Error::Other(Box::new(e)))?;
+                                .map_err(|e| Error::Transport(Box::new(e)))?;
+                            metrics.on_message(Direction::Outbound, len);
+                            traffic.record(Direction::Outbound, len);
                         }
                     }
                 }
-                Ok(())
+                Ok(DisconnectReason::PeerClosed)
+            };
+            tokio::spawn(async move {
+                let _guard = guard;
+                let _claims = claims;
+                let res: Result<DisconnectReason, Error> = process.await;
+                if let Err(e) = &res {
+                    error.lock().unwrap().get_or_insert_with(|| e.to_string());
+                }
+                res
             })
         };
 
         Subscription {
+            id,
+            sink_task,
+            stream_task,
+            closing,
+            traffic,
+            identity: None,
+        }
+    }
+
+    /// Returns a subscription of a connection rejected because of [BroadcastGroup::with_max_subscribers].
+    fn reject<Sink>(sink: Arc<Mutex<Sink>>, max_subscribers: usize) -> Subscription
+    where
+        Sink: SinkExt<Vec<u8>> + Send + Sync + Unpin + 'static,
+    {
+        tracing::info!("rejecting subscriber over the limit of {max_subscribers} subscribers");
+        let full =
+            move || Error::LimitExceeded(format!("group is full: {max_subscribers} subscribers"));
+        let sink_task = tokio::spawn(async move {
+            let mut sink = sink.lock().await;
+            let _ = timeout(CLOSE_TIMEOUT, close_sink(&mut *sink, CloseReason::Policy)).await;
+            Err(full())
+        });
+        let stream_task = tokio::spawn(std::future::pending());
+        Subscription {
+            id: SubscriptionId::next(),
             sink_task,
             stream_task,
+            closing: CancellationToken::new(),
+            traffic: Arc::default(),
+            identity: None,
+        }
+    }
+
+    /// Encodes a full awareness state of a group, which is sent to newly joined subscribers before
+    /// any incremental updates. Returns `None` if there are no awareness states to send.
+    async fn awareness_snapshot(
+        awareness: &AwarenessRef,
+        filter: &std::sync::Mutex<Option<AwarenessFilter>>,
+    ) -> Option<Vec<u8>> {
+        let update = {
+            let awareness = awareness.read().await;
+            if awareness.clients().is_empty() {
+                return None;
+            }
+            match awareness.update() {
+                Ok(update) => update,
+                Err(e) => {
+                    tracing::warn!("failed to encode awareness snapshot: {e}");
+                    return None;
+                }
+            }
+        };
+        let update = Self::filter_awareness(filter, update)?;
+        Some(Message::Awareness(update).encode_v1())
+    }
+
+    /// Applies an awareness filter registered via [BroadcastGroup::with_awareness_filter] to
+    /// an outgoing `update`. Returns `None` if all client entries have been filtered out.
+    fn filter_awareness(
+        filter: &std::sync::Mutex<Option<AwarenessFilter>>,
+        mut update: AwarenessUpdate,
+    ) -> Option<AwarenessUpdate> {
+        let Some(filter) = filter.lock().unwrap().clone() else {
+            return Some(update);
+        };
+        update
+            .clients
+            .retain(|client_id, entry| match filter(*client_id, &entry.json) {
+                Some(json) => {
+                    entry.json = json;
+                    true
+                }
+                None => false,
+            });
+        if update.clients.is_empty() {
+            None
+        } else {
+            Some(update)
         }
     }
 
+    fn prune_awareness(
+        awareness: &mut Awareness,
+        last_seen: &mut HashMap<ClientID, Instant>,
+        max_entries: usize,
+    ) {
+        let excess = awareness.clients().len().saturating_sub(max_entries);
+        if excess == 0 {
+            return;
+        }
+        let local = awareness.client_id();
+        let mut oldest: Vec<_> = last_seen
+            .iter()
+            .filter(|(client_id, _)| **client_id != local)
+            .map(|(client_id, seen)| (*seen, *client_id))
+            .collect();
+        oldest.sort_unstable();
+        for (_, client_id) in oldest.into_iter().take(excess) {
+            last_seen.remove(&client_id);
+            awareness.remove_state(client_id);
+        }
+        tracing::info!("pruned {excess} oldest awareness entries over the limit of {max_entries}");
+    }
+
+    fn expire_awareness(
+        awareness: &mut Awareness,
+        last_seen: &mut HashMap<ClientID, Instant>,
+        ttl: Duration,
+    ) {
+        let local = awareness.client_id();
+        let now = Instant::now();
+        let expired: Vec<_> = last_seen
+            .iter()
+            .filter(|(client_id, seen)| **client_id != local && now.duration_since(**seen) >= ttl)
+            .map(|(client_id, _)| *client_id)
+            .collect();
+        for client_id in expired.iter() {
+            last_seen.remove(client_id);
+            awareness.remove_state(*client_id);
+        }
+        if !expired.is_empty() {
+            tracing::debug!("removed expired awareness states of clients {expired:?}");
+        }
+    }
+
+    /// Updates a `high_water_mark` with a number of messages currently buffered by a `sender`.
+    fn record_buffer_len(sender: &Sender<Vec<u8>>, high_water_mark: &AtomicUsize) {
+        high_water_mark.fetch_max(sender.len(), Ordering::Relaxed);
+    }
+
+    /// Records a subscriber `id` as an owner of awareness states changed by its `update`, ie. the
+    /// ones with a clock higher than previously announced. States of other clients relayed by
+    /// a peer keep their clock, so they stay owned by the subscriber which announced them.
+    fn claim_awareness(owners: &AwarenessOwners, id: SubscriptionId, update: &AwarenessUpdate) {
+        let mut owners = owners.lock().unwrap();
+        for (&client_id, entry) in update.clients.iter() {
+            match owners.entry(client_id) {
+                Entry::Occupied(mut e) => {
+                    if e.get().1 < entry.clock {
+                        e.insert((id, entry.clock));
+                    }
+                }
+                Entry::Vacant(e) => {
+                    e.insert((id, entry.clock));
+                }
+            }
+        }
+    }
+
+    /// Removes awareness states of `clients` announced by a disconnected subscriber, unless
+    /// another subscriber has claimed them in the meantime - eg. a peer which reconnected with
+    /// the same client ID. A local state of the group is never removed.
+    fn remove_departed(
+        awareness: &mut Awareness,
+        owners: &AwarenessOwners,
+        clients: Vec<ClientID>,
+    ) {
+        let departed: Vec<ClientID> = {
+            let owners = owners.lock().unwrap();
+            clients
+                .into_iter()
+                .filter(|client_id| {
+                    !owners.contains_key(client_id)
+                        && *client_id != awareness.client_id()
+                        && awareness.clients().contains_key(client_id)
+                })
+                .collect()
+        };
+        for &client_id in departed.iter() {
+            awareness.remove_state(client_id);
+        }
+        if !departed.is_empty() {
+            tracing::debug!("removed awareness states of disconnected clients {departed:?}");
+        }
+    }
+
+    /// Waits until broadcasted messages not yet received by all subscribers fit into a buffer
+    /// `capacity` again.
+    async fn wait_for_capacity(sender: &Sender<Vec<u8>>, capacity: usize, drained: &Notify) {
+        loop {
+            let notified = drained.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if sender.len() < capacity {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Receives the next broadcasted message, preferring awareness updates of a `prioritized`
+    /// receiver, if there's one.
+    async fn next_broadcast(
+        receiver: &mut BufferReceiver,
+        prioritized: &mut Option<BufferReceiver>,
+    ) -> Result<Vec<u8>, RecvError> {
+        let Some(prioritized) = prioritized else {
+            return receiver.recv().await;
+        };
+        select! {
+            biased;
+            res = prioritized.recv() => res,
+            res = receiver.recv() => res,
+        }
+    }
+
+    /// Waits until a `deadline`, or forever if there's none.
+    async fn deadline(deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => tokio::time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Waits until a subscriber hasn't sent anything for `idle_timeout`, or forever if it's
+    /// disabled.
+    async fn idle(traffic: &Traffic, idle_timeout: Option<Duration>) {
+        let Some(idle_timeout) = idle_timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let deadline = traffic.last_activity() + idle_timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+
+    /// Waits for a group to be paused or resumed, or forever once the group is dropped.
+    async fn pause_changed(paused: &mut watch::Receiver<bool>) {
+        if paused.changed().await.is_err() {
+            std::future::pending().await
+        }
+    }
+
+    async fn tick(interval: &mut Option<Interval>) {
+        match interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Answers a SyncStep1 request of a subscriber, discarding document updates queued by its
+    /// `receiver` in the meantime. No updates can be broadcasted while an awareness lock is held,
+    /// so all queued ones are already included in the reply, and all the later ones will be
+    /// received after it: this way a subscriber receives every update exactly once, even when
+    /// it joins during a burst of updates. Returns other queued messages, like awareness updates,
+    /// which are meant to be sent before the reply.
+    async fn sync_step1_reply<P: Protocol>(
+        protocol: &P,
+        awareness: &AwarenessRef,
+        receiver: &mut BufferReceiver,
+        state_vector: StateVector,
+    ) -> Result<(Vec<Vec<u8>>, Option<Message>), Error> {
+        let awareness = awareness.read().await;
+        let mut pending = Vec::new();
+        loop {
+            match receiver.try_recv() {
+                Ok(msg) if msg.first() == Some(&MSG_SYNC) => {}
+                Ok(msg) => pending.push(msg),
+                Err(TryRecvError::Lagged(n)) => {
+                    tracing::debug!("subscriber lagged behind, skipped {n} messages");
+                }
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+        let reply = protocol.handle_sync_step1(&awareness, state_vector)?;
+        Ok((pending, reply))
+    }
+
+    /// Applies a message received from a peer and returns a reply to it, if any. Awareness lock is
+    /// held only for the time a message is being applied and never while a reply is sent.
     async fn handle_msg<P: Protocol>(
         protocol: &P,
         awareness: &AwarenessRef,
+        awareness_changes: &UnboundedSender<Vec<ClientID>>,
         msg: Message,
-    ) -> Result<Option<Message>, Error> {
+    ) -> Result<Option<Message>, SyncError> {
         match msg {
             Message::Sync(msg) => match msg {
                 SyncMessage::SyncStep1(state_vector) => {
                     let awareness = awareness.read().await;
-                    protocol.handle_sync_step1(&*awareness, state_vector)
+                    protocol.handle_sync_step1(&awareness, state_vector)
                 }
                 SyncMessage::SyncStep2(update) => {
                     let mut awareness = awareness.write().await;
                     let update = Update::decode_v1(&update)?;
-                    protocol.handle_sync_step2(&mut *awareness, update)
+                    protocol.handle_sync_step2(&mut awareness, update)
                 }
                 SyncMessage::Update(update) => {
                     let mut awareness = awareness.write().await;
                     let update = Update::decode_v1(&update)?;
-                    protocol.handle_sync_step2(&mut *awareness, update)
+                    protocol.handle_sync_step2(&mut awareness, update)
                 }
             },
             Message::Auth(deny_reason) => {
                 let awareness = awareness.read().await;
-                protocol.handle_auth(&*awareness, deny_reason)
+                protocol.handle_auth(&awareness, deny_reason)
             }
             Message::AwarenessQuery => {
                 let awareness = awareness.read().await;
-                protocol.handle_awareness_query(&*awareness)
+                protocol.handle_awareness_query(&awareness)
             }
             Message::Awareness(update) => {
+                // Awareness::apply_update doesn't notify its on_update observers about remote
+                // changes (yrs 0.18), so the changed clients are reported to the updater directly
+                let changed = update.clients.keys().copied().collect();
                 let mut awareness = awareness.write().await;
-                protocol.handle_awareness_update(&mut *awareness, update)
+                let reply = protocol.handle_awareness_update(&mut awareness, update)?;
+                let _ = awareness_changes.send(changed);
+                Ok(reply)
             }
             Message::Custom(tag, data) => {
                 let mut awareness = awareness.write().await;
-                protocol.missing_handle(&mut *awareness, tag, data)
+                protocol.missing_handle(&mut awareness, tag, data)
             }
         }
     }
@@ -236,141 +1994,3058 @@ impl BroadcastGroup {
 
 impl Drop for BroadcastGroup {
     fn drop(&mut self) {
+        // subscribers of a dropped group are closed just like by BroadcastGroup::close, as their
+        // tasks could otherwise keep pinging peers and applying their updates for good
+        self.closing.cancel();
         self.awareness_updater.abort();
+        self.snapshotter.abort();
+        if let Some(renewal) = self.local_awareness.get_mut().unwrap().take() {
+            renewal.abort();
+        }
     }
 }
 
-/// A subscription structure returned from [BroadcastGroup::subscribe], which represents a
-/// subscribed connection. It can be dropped in order to unsubscribe or awaited via
-/// [Subscription::completed] method in order to complete of its own volition (due to an internal
-/// connection error or closed connection).
-#[derive(Debug)]
-pub struct Subscription {
-    sink_task: JoinHandle<Result<(), Error>>,
-    stream_task: JoinHandle<Result<(), Error>>,
+/// A deferred call of one of [BroadcastGroup] `with_*` methods.
+type Configure = Box<dyn FnOnce(BroadcastGroup) -> BroadcastGroup + Send>;
+
+/// A builder of a [BroadcastGroup], returned by [BroadcastGroup::builder]. Apart from
+/// a document and a buffer capacity, its setters correspond to `with_*` methods of a group, which
+/// are applied in order once a group is built - so a setter called later wins.
+///
+/// By default, a group is created over a new empty document with [DEFAULT_BUFFER_CAPACITY].
+///
+/// # Example
+///
+/// ```rust
+/// use std::time::Duration;
+/// use yrs_axum::broadcast::{BroadcastGroup, BufferOverflowPolicy};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let group = BroadcastGroup::builder()
+///     .with_awareness(yrs_axum::new_awareness_with_text("codemirror", "hello"))
+///     .with_buffer_capacity(64)
+///     .with_ping_interval(Duration::from_secs(30), 2)
+///     .with_max_subscribers(100)
+///     .with_buffer_overflow_policy(BufferOverflowPolicy::DropOldest)
+///     .build()
+///     .await;
+/// assert_eq!(group.buffer_capacity(), 64);
+/// # }
+/// ```
+pub struct BroadcastGroupBuilder {
+    awareness: Option<AwarenessRef>,
+    buffer_capacity: usize,
+    configure: Vec<Configure>,
 }
 
-impl Subscription {
-    /// Consumes current subscription, waiting for it to complete. If an underlying connection was
-    /// closed because of failure, an error which caused it to happen will be returned.
-    ///
-    /// This method doesn't invoke close procedure. If you need that, drop current subscription instead.
-    pub async fn completed(self) -> Result<(), Error> {
-        let res = select! {
-            r1 = self.sink_task => r1,
-            r2 = self.stream_task => r2,
-        };
-        res.map_err(|e| Error::Other(e.into()))?
+impl BroadcastGroupBuilder {
+    /// Creates a new builder with default settings.
+    pub fn new() -> Self {
+        BroadcastGroupBuilder {
+            awareness: None,
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            configure: Vec::new(),
+        }
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::broadcast::BroadcastGroup;
-    use futures_util::{ready, SinkExt, StreamExt};
-    use std::collections::HashMap;
-    use std::pin::Pin;
-    use std::sync::Arc;
-    use std::task::{Context, Poll};
-    use tokio::sync::{Mutex, RwLock};
-    use tokio_util::sync::PollSender;
-    use yrs::sync::awareness::AwarenessUpdateEntry;
-    use yrs::sync::{Awareness, AwarenessUpdate, Error, Message, SyncMessage};
-    use yrs::updates::decoder::Decode;
-    use yrs::updates::encoder::Encode;
-    use yrs::{Doc, StateVector, Text, Transact};
+    /// Sets an awareness instance, over which a group is created. See [BroadcastGroup::new].
+    pub fn with_awareness(mut self, awareness: AwarenessRef) -> Self {
+        self.awareness = Some(awareness);
+        self
+    }
 
-    #[derive(Debug)]
-    pub struct ReceiverStream<T> {
-        inner: tokio::sync::mpsc::Receiver<T>,
+    /// Sets a document, over which a group is created. See [BroadcastGroup::from_doc].
+    pub fn with_doc(self, doc: Doc) -> Self {
+        self.with_awareness(Arc::new(RwLock::new(Awareness::new(doc))))
     }
 
-    impl<T> ReceiverStream<T> {
-        /// Create a new `ReceiverStream`.
-        pub fn new(recv: tokio::sync::mpsc::Receiver<T>) -> Self {
-            Self { inner: recv }
-        }
+    /// Creates a group over a new document configured with given `options`. See
+    /// [BroadcastGroup::from_options].
+    pub fn with_options(self, options: Options) -> Self {
+        self.with_doc(Doc::with_options(options))
     }
 
-    impl<T> futures_util::Stream for ReceiverStream<T> {
-        type Item = Result<T, Error>;
+    /// Sets a buffer capacity of a group. See [BroadcastGroup::new].
+    pub fn with_buffer_capacity(mut self, buffer_capacity: usize) -> Self {
+        self.buffer_capacity = buffer_capacity;
+        self
+    }
 
-        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-            match ready!(self.inner.poll_recv(cx)) {
-                None => Poll::Ready(None),
-                Some(v) => Poll::Ready(Some(Ok(v))),
-            }
-        }
+    /// See [BroadcastGroup::with_update_sink].
+    pub fn with_update_sink<F>(self, sink: F) -> Self
+    where
+        F: Fn(&[u8]) + Send + Sync + 'static,
+    {
+        self.configure(move |group| group.with_update_sink(sink))
     }
 
-    fn test_channel(capacity: usize) -> (PollSender<Vec<u8>>, ReceiverStream<Vec<u8>>) {
-        let (s, r) = tokio::sync::mpsc::channel::<Vec<u8>>(capacity);
-        let s = PollSender::new(s);
-        let r = ReceiverStream::new(r);
-        (s, r)
+    /// See [BroadcastGroup::with_awareness_filter].
+    pub fn with_awareness_filter<F>(self, filter: F) -> Self
+    where
+        F: Fn(ClientID, &str) -> Option<String> + Send + Sync + 'static,
+    {
+        self.configure(move |group| group.with_awareness_filter(filter))
     }
 
-    #[tokio::test]
-    async fn broadcast_changes() -> Result<(), Box<dyn std::error::Error>> {
-        let doc = Doc::with_client_id(1);
-        let text = doc.get_or_insert_text("test");
-        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
-        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+    /// See [BroadcastGroup::with_max_awareness_entries].
+    pub fn with_max_awareness_entries(self, max: usize) -> Self {
+        self.configure(move |group| group.with_max_awareness_entries(max))
+    }
 
-        let (server_sender, mut client_receiver) = test_channel(1);
-        let (mut client_sender, server_receiver) = test_channel(1);
-        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+    /// See [BroadcastGroup::with_awareness_ttl].
+    pub fn with_awareness_ttl(self, ttl: Duration) -> Self {
+        self.configure(move |group| group.with_awareness_ttl(ttl))
+    }
 
-        // check update propagation
-        {
-            let a = awareness.write().await;
-            text.push(&mut a.doc().transact_mut(), "a");
-        }
+    /// See [BroadcastGroup::with_snapshot_interval].
+    pub fn with_snapshot_interval(self, interval: Duration) -> Self {
+        self.configure(move |group| group.with_snapshot_interval(interval))
+    }
+
+    /// See [BroadcastGroup::with_snapshot_every].
+    pub fn with_snapshot_every(self, updates: usize) -> Self {
+        self.configure(move |group| group.with_snapshot_every(updates))
+    }
+
+    /// See [BroadcastGroup::with_keepalive].
+    pub fn with_keepalive(self, period: Duration) -> Self {
+        self.configure(move |group| group.with_keepalive(period))
+    }
+
+    /// See [BroadcastGroup::with_ping_interval].
+    pub fn with_ping_interval(self, period: Duration, max_missed: u32) -> Self {
+        self.configure(move |group| group.with_ping_interval(period, max_missed))
+    }
+
+    /// See [BroadcastGroup::with_sync_step1_cooldown].
+    pub fn with_sync_step1_cooldown(self, cooldown: Duration) -> Self {
+        self.configure(move |group| group.with_sync_step1_cooldown(cooldown))
+    }
+
+    /// See [BroadcastGroup::with_handshake_timeout].
+    pub fn with_handshake_timeout(self, timeout: Duration) -> Self {
+        self.configure(move |group| group.with_handshake_timeout(timeout))
+    }
+
+    /// See [BroadcastGroup::with_max_connection_lifetime].
+    pub fn with_max_connection_lifetime(self, lifetime: Duration) -> Self {
+        self.configure(move |group| group.with_max_connection_lifetime(lifetime))
+    }
+
+    /// See [BroadcastGroup::with_idle_timeout].
+    pub fn with_idle_timeout(self, timeout: Duration) -> Self {
+        self.configure(move |group| group.with_idle_timeout(timeout))
+    }
+
+    /// See [BroadcastGroup::with_max_subscribers].
+    pub fn with_max_subscribers(self, max: usize) -> Self {
+        self.configure(move |group| group.with_max_subscribers(max))
+    }
+
+    /// See [BroadcastGroup::with_max_message_size].
+    pub fn with_max_message_size(self, max: usize) -> Self {
+        self.configure(move |group| group.with_max_message_size(max))
+    }
+
+    /// See [BroadcastGroup::with_inbound_rate_limit].
+    pub fn with_inbound_rate_limit(self, messages: u32, bytes: u32) -> Self {
+        self.configure(move |group| group.with_inbound_rate_limit(messages, bytes))
+    }
+
+    /// See [BroadcastGroup::with_max_rate_violations].
+    pub fn with_max_rate_violations(self, max: u32) -> Self {
+        self.configure(move |group| group.with_max_rate_violations(max))
+    }
+
+    /// See [BroadcastGroup::with_buffer_overflow_policy].
+    pub fn with_buffer_overflow_policy(self, policy: BufferOverflowPolicy) -> Self {
+        self.configure(move |group| group.with_buffer_overflow_policy(policy))
+    }
+
+    /// See [BroadcastGroup::with_broadcast_priority].
+    pub fn with_broadcast_priority(self, priority: BroadcastPriority) -> Self {
+        self.configure(move |group| group.with_broadcast_priority(priority))
+    }
+
+    /// See [BroadcastGroup::with_metrics].
+    pub fn with_metrics(self, metrics: Arc<dyn Metrics>) -> Self {
+        self.configure(move |group| group.with_metrics(metrics))
+    }
+
+    fn configure<F>(mut self, f: F) -> Self
+    where
+        F: FnOnce(BroadcastGroup) -> BroadcastGroup + Send + 'static,
+    {
+        self.configure.push(Box::new(f));
+        self
+    }
+
+    /// Creates a new [BroadcastGroup] with all the settings of this builder.
+    pub async fn build(self) -> Arc<BroadcastGroup> {
+        let awareness = match self.awareness {
+            Some(awareness) => awareness,
+            None => Arc::new(RwLock::new(Awareness::new(Doc::new()))),
+        };
+        let group = BroadcastGroup::new(awareness, self.buffer_capacity).await;
+        let group = self.configure.into_iter().fold(group, |group, f| f(group));
+        Arc::new(group)
+    }
+}
+
+impl Default for BroadcastGroupBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for BroadcastGroupBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BroadcastGroupBuilder")
+            .field("buffer_capacity", &self.buffer_capacity)
+            .field("settings", &self.configure.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Lifecycle events of a [BroadcastGroup], which can be received via [BroadcastGroup::events].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BroadcastEvent {
+    /// A new connection has subscribed to the group.
+    PeerJoined,
+    /// A subscribed connection has been closed or dropped. If it was closed because of failure,
+    /// the `error` describes it.
+    PeerLeft { error: Option<String> },
+    /// An update of a given binary size has been applied to the group's document.
+    Updated { bytes: usize },
+    /// A whole state of the group's document encoded as a binary v1 update, emitted as configured
+    /// by [BroadcastGroup::with_snapshot_interval] and [BroadcastGroup::with_snapshot_every]. It
+    /// can be restored via [BroadcastGroup::from_state].
+    DocumentSnapshot(Vec<u8>),
+}
+
+/// A reason of a [Subscription] being completed, see [Subscription::completed].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DisconnectReason {
+    /// Peer has closed its connection.
+    PeerClosed,
+    /// Subscription has been closed via [SubscriptionHandle::close] or [Subscription::close].
+    Closed,
+    /// Group has been closed via [BroadcastGroup::close] or dropped.
+    GroupClosed,
+    /// Peer didn't answer pings or start a handshake in time.
+    Timeout,
+    /// Peer has been evicted for lagging behind broadcasted messages, see
+    /// [BufferOverflowPolicy::DisconnectSlow].
+    Evicted,
+    /// Peer exceeded one of the configured limits, eg. a message size or rate limit.
+    LimitExceeded,
+    /// Peer sent a message violating the y-sync protocol.
+    ProtocolError,
+    /// Connection failed to send or receive a message.
+    TransportError,
+    /// Connection reached its maximum lifetime, see [BroadcastGroup::with_max_connection_lifetime].
+    Expired,
+    /// Connection has been idle for too long, see [BroadcastGroup::with_idle_timeout].
+    Idle,
+}
+
+impl DisconnectReason {
+    /// Returns a reason of a connection being closed with a given result of
+    /// [Subscription::completed], categorizing errors as well.
+    ///
+    /// ```rust
+    /// use yrs_axum::broadcast::{DisconnectReason, Subscription};
+    ///
+    /// async fn log_outcome(sub: Subscription) {
+    ///     let res = sub.completed().await;
+    ///     match DisconnectReason::of(&res) {
+    ///         DisconnectReason::PeerClosed | DisconnectReason::Closed => {}
+    ///         reason => eprintln!("peer disconnected: {reason:?} ({res:?})"),
+    ///     }
+    /// }
+    /// ```
+    pub fn of(res: &Result<DisconnectReason, Error>) -> Self {
+        match res {
+            Ok(reason) => *reason,
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl From<&Error> for DisconnectReason {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::Closed => DisconnectReason::Closed,
+            Error::Timeout(_) => DisconnectReason::Timeout,
+            Error::Lagged(_) => DisconnectReason::Evicted,
+            Error::LimitExceeded(_) => DisconnectReason::LimitExceeded,
+            Error::Protocol(_)
+            | Error::Serialization(_)
+            | Error::MalformedSignal(_)
+            | Error::UnsupportedVersion(_) => DisconnectReason::ProtocolError,
+            Error::Transport(_) => DisconnectReason::TransportError,
+        }
+    }
+}
+
+/// Keeps a subscription registered in a subscriber counter of its [BroadcastGroup] for as long as
+/// its connection is being processed, reporting its join and leave events.
+struct SubscriberGuard {
+    subscribers: Arc<watch::Sender<usize>>,
+    on_empty: Arc<EmptyCallbacks>,
+    events: Sender<BroadcastEvent>,
+    error: Arc<std::sync::Mutex<Option<String>>>,
+    _metrics: ConnectionMetrics,
+}
+
+impl SubscriberGuard {
+    /// Registers a new subscription, unless there are already `max` subscriptions registered.
+    /// Zero `max` means no limit.
+    fn try_new(
+        subscribers: Arc<watch::Sender<usize>>,
+        on_empty: Arc<EmptyCallbacks>,
+        max: usize,
+        events: Sender<BroadcastEvent>,
+        error: Arc<std::sync::Mutex<Option<String>>>,
+        metrics: Arc<dyn Metrics>,
+    ) -> Option<Self> {
+        let admitted = subscribers.send_if_modified(|count| {
+            if max != 0 && *count >= max {
+                return false;
+            }
+            *count += 1;
+            true
+        });
+        if !admitted {
+            return None;
+        }
+        let _ = events.send(BroadcastEvent::PeerJoined);
+        Some(SubscriberGuard {
+            subscribers,
+            on_empty,
+            events,
+            error,
+            _metrics: ConnectionMetrics::new(metrics),
+        })
+    }
+}
+
+impl Drop for SubscriberGuard {
+    fn drop(&mut self) {
+        let mut empty = false;
+        self.subscribers.send_modify(|count| {
+            *count -= 1;
+            empty = *count == 0;
+        });
+        let error = self.error.lock().unwrap().take();
+        let _ = self.events.send(BroadcastEvent::PeerLeft { error });
+        if empty {
+            // callbacks may register other callbacks
+            let callbacks = self.on_empty.lock().unwrap().clone();
+            for callback in callbacks {
+                callback();
+            }
+        }
+    }
+}
+
+/// Releases awareness states claimed by a subscriber once it's gone, passing them to the awareness
+/// updater of a group to be removed and broadcasted as such to the remaining subscribers.
+struct AwarenessClaims {
+    id: SubscriptionId,
+    owners: Arc<AwarenessOwners>,
+    departures: UnboundedSender<Vec<ClientID>>,
+}
+
+impl Drop for AwarenessClaims {
+    fn drop(&mut self) {
+        let mut departed = Vec::new();
+        self.owners
+            .lock()
+            .unwrap()
+            .retain(|&client_id, (owner, _)| {
+                if *owner == self.id {
+                    departed.push(client_id);
+                    false
+                } else {
+                    true
+                }
+            });
+        if !departed.is_empty() {
+            let _ = self.departures.send(departed);
+        }
+    }
+}
+
+/// A receiver of broadcasted messages used by a subscriber, which notifies producers waiting
+/// because of [BufferOverflowPolicy::Block] every time it makes room in the buffer.
+struct BufferReceiver {
+    receiver: Option<Receiver<Vec<u8>>>,
+    drained: Arc<Notify>,
+}
+
+impl BufferReceiver {
+    fn new(receiver: Receiver<Vec<u8>>, drained: Arc<Notify>) -> Self {
+        BufferReceiver {
+            receiver: Some(receiver),
+            drained,
+        }
+    }
+
+    async fn recv(&mut self) -> Result<Vec<u8>, RecvError> {
+        let receiver = self.receiver.as_mut().ok_or(RecvError::Closed)?;
+        let res = receiver.recv().await;
+        self.drained.notify_waiters();
+        res
+    }
+
+    fn try_recv(&mut self) -> Result<Vec<u8>, TryRecvError> {
+        let receiver = self.receiver.as_mut().ok_or(TryRecvError::Closed)?;
+        let res = receiver.try_recv();
+        self.drained.notify_waiters();
+        res
+    }
+}
+
+impl Drop for BufferReceiver {
+    fn drop(&mut self) {
+        // messages are released from the buffer only once the receiver is gone
+        drop(self.receiver.take());
+        self.drained.notify_waiters();
+    }
+}
+
+/// A [Protocol] wrapper, which discards all document updates received from a remote peer, while
+/// still answering its sync requests. Used by [BroadcastGroup::subscribe_readonly], but can be
+/// combined with custom protocols via [BroadcastGroup::subscribe_with] as well.
+#[derive(Debug, Default)]
+pub struct ReadOnlyProtocol<P = DefaultProtocol>(pub P);
+
+impl<P: Protocol> Protocol for ReadOnlyProtocol<P> {
+    fn start<E: Encoder>(&self, awareness: &Awareness, encoder: &mut E) -> Result<(), SyncError> {
+        self.0.start(awareness, encoder)
+    }
+
+    fn handle_sync_step1(
+        &self,
+        awareness: &Awareness,
+        sv: StateVector,
+    ) -> Result<Option<Message>, SyncError> {
+        self.0.handle_sync_step1(awareness, sv)
+    }
+
+    fn handle_sync_step2(
+        &self,
+        _awareness: &mut Awareness,
+        _update: Update,
+    ) -> Result<Option<Message>, SyncError> {
+        tracing::debug!("discarding document update from read-only peer");
+        Ok(None)
+    }
+
+    fn handle_update(
+        &self,
+        _awareness: &mut Awareness,
+        _update: Update,
+    ) -> Result<Option<Message>, SyncError> {
+        tracing::debug!("discarding document update from read-only peer");
+        Ok(None)
+    }
+
+    fn handle_auth(
+        &self,
+        awareness: &Awareness,
+        deny_reason: Option<String>,
+    ) -> Result<Option<Message>, SyncError> {
+        self.0.handle_auth(awareness, deny_reason)
+    }
+
+    fn handle_awareness_query(&self, awareness: &Awareness) -> Result<Option<Message>, SyncError> {
+        self.0.handle_awareness_query(awareness)
+    }
+
+    fn handle_awareness_update(
+        &self,
+        awareness: &mut Awareness,
+        update: AwarenessUpdate,
+    ) -> Result<Option<Message>, SyncError> {
+        self.0.handle_awareness_update(awareness, update)
+    }
+
+    fn missing_handle(
+        &self,
+        awareness: &mut Awareness,
+        tag: u8,
+        data: Vec<u8>,
+    ) -> Result<Option<Message>, SyncError> {
+        self.0.missing_handle(awareness, tag, data)
+    }
+}
+
+/// A [Protocol] wrapper, which ignores all document sync requests and updates received from
+/// a remote peer, handling only its awareness. Used by [BroadcastGroup::subscribe_awareness_only],
+/// which also withholds document updates broadcasted to such peer.
+#[derive(Debug, Default)]
+pub struct AwarenessOnlyProtocol<P = DefaultProtocol>(pub P);
+
+impl<P: Protocol> Protocol for AwarenessOnlyProtocol<P> {
+    fn start<E: Encoder>(&self, awareness: &Awareness, encoder: &mut E) -> Result<(), SyncError> {
+        self.0.start(awareness, encoder)
+    }
+
+    fn handle_sync_step1(
+        &self,
+        _awareness: &Awareness,
+        _sv: StateVector,
+    ) -> Result<Option<Message>, SyncError> {
+        tracing::debug!("ignoring sync request from awareness-only peer");
+        Ok(None)
+    }
+
+    fn handle_sync_step2(
+        &self,
+        _awareness: &mut Awareness,
+        _update: Update,
+    ) -> Result<Option<Message>, SyncError> {
+        tracing::debug!("discarding document update from awareness-only peer");
+        Ok(None)
+    }
+
+    fn handle_update(
+        &self,
+        _awareness: &mut Awareness,
+        _update: Update,
+    ) -> Result<Option<Message>, SyncError> {
+        tracing::debug!("discarding document update from awareness-only peer");
+        Ok(None)
+    }
+
+    fn handle_auth(
+        &self,
+        awareness: &Awareness,
+        deny_reason: Option<String>,
+    ) -> Result<Option<Message>, SyncError> {
+        self.0.handle_auth(awareness, deny_reason)
+    }
+
+    fn handle_awareness_query(&self, awareness: &Awareness) -> Result<Option<Message>, SyncError> {
+        self.0.handle_awareness_query(awareness)
+    }
+
+    fn handle_awareness_update(
+        &self,
+        awareness: &mut Awareness,
+        update: AwarenessUpdate,
+    ) -> Result<Option<Message>, SyncError> {
+        self.0.handle_awareness_update(awareness, update)
+    }
+
+    fn missing_handle(
+        &self,
+        awareness: &mut Awareness,
+        tag: u8,
+        data: Vec<u8>,
+    ) -> Result<Option<Message>, SyncError> {
+        self.0.missing_handle(awareness, tag, data)
+    }
+}
+
+/// A relay of document updates between two groups returned by [BroadcastGroup::bridge]. Updates
+/// are relayed until it's dropped.
+pub struct Bridge {
+    _observers: [yrs::Subscription; 2],
+    relays: [JoinHandle<()>; 2],
+}
+
+impl std::fmt::Debug for Bridge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Bridge").finish_non_exhaustive()
+    }
+}
+
+impl Drop for Bridge {
+    fn drop(&mut self) {
+        for relay in &self.relays {
+            relay.abort();
+        }
+    }
+}
+
+/// A unique identifier of a [Subscription] within a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SubscriptionId(u64);
+
+impl SubscriptionId {
+    fn next() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        SubscriptionId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl std::fmt::Display for SubscriptionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A subscription structure returned from [BroadcastGroup::subscribe], which represents a
+/// subscribed connection. It can be dropped in order to unsubscribe or awaited via
+/// [Subscription::completed] method in order to complete of its own volition (due to an internal
+/// connection error or closed connection).
+///
+/// A subscription can be also closed from outside of a task awaiting it via a
+/// [SubscriptionHandle], eg. to disconnect a banned peer.
+#[derive(Debug)]
+pub struct Subscription {
+    id: SubscriptionId,
+    sink_task: JoinHandle<Result<DisconnectReason, Error>>,
+    stream_task: JoinHandle<Result<DisconnectReason, Error>>,
+    closing: CancellationToken,
+    traffic: Arc<Traffic>,
+    identity: Option<SubscriberId>,
+}
+
+impl Subscription {
+    /// Returns an identifier of this subscription.
+    pub fn id(&self) -> SubscriptionId {
+        self.id
+    }
+
+    /// Attaches an `identity` of a subscriber to this subscription, eg. one resolved by an
+    /// [IdentityProvider](crate::auth::IdentityProvider). It's carried by handles returned from
+    /// [Subscription::handle] afterwards.
+    pub fn with_identity(mut self, identity: SubscriberId) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Returns an identity of a subscriber attached via [Subscription::with_identity], if any.
+    pub fn identity(&self) -> Option<&SubscriberId> {
+        self.identity.as_ref()
+    }
+
+    /// Returns a handle, which can be used to close this subscription from another task.
+    pub fn handle(&self) -> SubscriptionHandle {
+        SubscriptionHandle {
+            id: self.id,
+            closing: self.closing.clone(),
+            traffic: self.traffic.clone(),
+            identity: self.identity.clone(),
+        }
+    }
+
+    /// Returns the number of bytes sent to and received from a connection so far. Since
+    /// [Subscription::completed] consumes a subscription, use [SubscriptionHandle::traffic] to
+    /// read the totals once it resolves.
+    pub fn traffic(&self) -> TrafficStats {
+        self.traffic.stats()
+    }
+
+    /// Returns a time of the last message received from a connection - or of subscribing, if it
+    /// hasn't sent any yet. Pongs and [KEEPALIVE_MSG] are not counted, see
+    /// [BroadcastGroup::with_idle_timeout].
+    pub fn last_activity(&self) -> Instant {
+        self.traffic.last_activity()
+    }
+
+    /// Closes a connection's sink and waits for this subscription to complete. See
+    /// [SubscriptionHandle::close].
+    pub async fn close(self) -> Result<(), Error> {
+        self.closing.cancel();
+        match self.completed().await {
+            Ok(_) | Err(Error::Closed) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Consumes current subscription, waiting for it to complete. Resolves with a reason of
+    /// a connection closed gracefully - ie. [DisconnectReason::PeerClosed] or
+    /// [DisconnectReason::GroupClosed]. If an underlying connection was closed because of failure,
+    /// or via [SubscriptionHandle::close], an error which caused it to happen will be returned.
+    /// Both can be categorized via [DisconnectReason::of].
+    ///
+    /// This method doesn't invoke close procedure. If you need that, drop current subscription instead.
+    pub async fn completed(mut self) -> Result<DisconnectReason, Error> {
+        let res = select! {
+            r1 = &mut self.sink_task => r1,
+            r2 = &mut self.stream_task => r2,
+        };
+        match res {
+            Ok(res) => res,
+            Err(e) if e.is_cancelled() => Err(Error::Closed),
+            Err(e) => Err(Error::Transport(e.into())),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.sink_task.abort();
+        self.stream_task.abort();
+    }
+}
+
+/// A cloneable handle of a [Subscription], which allows to close it from outside of a task
+/// awaiting its completion. It can be kept eg. in a map of connected peers.
+#[derive(Debug, Clone)]
+pub struct SubscriptionHandle {
+    id: SubscriptionId,
+    closing: CancellationToken,
+    traffic: Arc<Traffic>,
+    identity: Option<SubscriberId>,
+}
+
+impl SubscriptionHandle {
+    /// Returns an identifier of a subscription.
+    pub fn id(&self) -> SubscriptionId {
+        self.id
+    }
+
+    /// Returns an identity of a subscriber, see [Subscription::identity].
+    pub fn identity(&self) -> Option<&SubscriberId> {
+        self.identity.as_ref()
+    }
+
+    /// Requests a subscription to close its connection's sink. Its [Subscription::completed]
+    /// resolves with [Error::Closed] once done. Closing a completed subscription is a no-op.
+    pub fn close(&self) {
+        self.closing.cancel();
+    }
+
+    /// Returns true if closing has been requested, either via this handle or by closing the
+    /// whole [BroadcastGroup].
+    pub fn is_closed(&self) -> bool {
+        self.closing.is_cancelled()
+    }
+
+    /// Returns the number of bytes sent to and received from a connection so far, see
+    /// [Subscription::traffic]. Counters stop changing once a subscription completes.
+    pub fn traffic(&self) -> TrafficStats {
+        self.traffic.stats()
+    }
+
+    /// Returns a time of the last message received from a connection, see
+    /// [Subscription::last_activity].
+    pub fn last_activity(&self) -> Instant {
+        self.traffic.last_activity()
+    }
+}
+
+/// Traffic of a single subscription returned by [Subscription::traffic].
+///
+/// Sizes of binary payloads of data messages are counted - the same ones reported via
+/// [Metrics::on_message] - without websocket framing and control frames, like pings. Received
+/// messages are counted even if they're dropped afterwards, eg. for exceeding a rate limit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficStats {
+    /// Total size of messages sent to a peer, including replies and broadcasted messages.
+    pub bytes_sent: u64,
+    /// Total size of messages received from a peer.
+    pub bytes_received: u64,
+}
+
+/// Byte counters and the last activity of a connection shared by a [Subscription], its handles
+/// and connection tasks.
+#[derive(Debug)]
+struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+    last_activity: std::sync::Mutex<Instant>,
+}
+
+impl Default for Traffic {
+    fn default() -> Self {
+        Traffic {
+            sent: AtomicU64::default(),
+            received: AtomicU64::default(),
+            last_activity: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Traffic {
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    fn last_activity(&self) -> Instant {
+        *self.last_activity.lock().unwrap()
+    }
+
+    fn record(&self, direction: Direction, bytes: usize) {
+        let counter = match direction {
+            Direction::Inbound => &self.received,
+            Direction::Outbound => &self.sent,
+        };
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> TrafficStats {
+        TrafficStats {
+            bytes_sent: self.sent.load(Ordering::Relaxed),
+            bytes_received: self.received.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::broadcast::{
+        BroadcastEvent, BroadcastGroup, BroadcastPriority, BufferOverflowPolicy, DisconnectReason,
+        Subscription, TrafficStats, KEEPALIVE_MSG, PING_MSG,
+    };
+    use crate::metrics::{Direction, Metrics};
+    use crate::test_util::assert_converged;
+    use futures_util::{ready, SinkExt, StreamExt};
+    use std::collections::HashMap;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tokio::sync::{Mutex, RwLock};
+    use tokio::time::error::Elapsed;
+    use tokio::time::timeout;
+    use tokio_util::sync::PollSender;
+    use yrs::sync::awareness::AwarenessUpdateEntry;
+    use yrs::sync::{Awareness, AwarenessUpdate, Error, Message, SyncMessage};
+    use yrs::types::Delta;
+    use yrs::updates::decoder::Decode;
+    use yrs::updates::encoder::Encode;
+    use yrs::{Doc, GetString, Map, Options, ReadTxn, StateVector, Text, Transact, Update};
+
+    #[derive(Debug)]
+    pub struct ReceiverStream<T> {
+        inner: tokio::sync::mpsc::Receiver<T>,
+    }
+
+    impl<T> ReceiverStream<T> {
+        /// Create a new `ReceiverStream`.
+        pub fn new(recv: tokio::sync::mpsc::Receiver<T>) -> Self {
+            Self { inner: recv }
+        }
+    }
+
+    impl<T> futures_util::Stream for ReceiverStream<T> {
+        type Item = Result<T, Error>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            match ready!(self.inner.poll_recv(cx)) {
+                None => Poll::Ready(None),
+                Some(v) => Poll::Ready(Some(Ok(v))),
+            }
+        }
+    }
+
+    fn test_channel(capacity: usize) -> (PollSender<Vec<u8>>, ReceiverStream<Vec<u8>>) {
+        let (s, r) = tokio::sync::mpsc::channel::<Vec<u8>>(capacity);
+        let s = PollSender::new(s);
+        let r = ReceiverStream::new(r);
+        (s, r)
+    }
+
+    /// A sink of a peer, which accepts messages but never acknowledges closing.
+    struct UnclosableSink;
+
+    impl futures_util::Sink<Vec<u8>> for UnclosableSink {
+        type Error = std::io::Error;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _: Vec<u8>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcast_changes() -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        // check update propagation
+        {
+            let a = awareness.write().await;
+            text.push(&mut a.doc().transact_mut(), "a");
+        }
+        let msg = client_receiver.next().await;
+        let msg = msg.map(|x| Message::decode_v1(&x.unwrap()).unwrap());
+        assert_eq!(
+            msg,
+            Some(Message::Sync(SyncMessage::Update(vec![
+                1, 1, 1, 0, 4, 1, 4, 116, 101, 115, 116, 1, 97, 0,
+            ])))
+        );
+
+        // check awareness update propagation
+        {
+            let mut a = awareness.write().await;
+            a.set_local_state(r#"{"key":"value"}"#)
+        }
+
+        let msg = client_receiver.next().await;
+        let msg = msg.map(|x| Message::decode_v1(&x.unwrap()).unwrap());
+        assert_eq!(
+            msg,
+            Some(Message::Awareness(AwarenessUpdate {
+                clients: HashMap::from([(
+                    1,
+                    AwarenessUpdateEntry {
+                        clock: 1,
+                        json: r#"{"key":"value"}"#.to_string(),
+                    },
+                )]),
+            }))
+        );
+
+        // check sync state request/response
+        {
+            client_sender
+                .send(Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1())
+                .await?;
+            let msg = client_receiver.next().await;
+            let msg = msg.map(|x| Message::decode_v1(&x.unwrap()).unwrap());
+            assert_eq!(
+                msg,
+                Some(Message::Sync(SyncMessage::SyncStep2(vec![
+                    1, 1, 1, 0, 4, 1, 4, 116, 101, 115, 116, 1, 97, 0,
+                ])))
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keepalive_is_sent_to_idle_subscribers() -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 1)
+            .await
+            .with_keepalive(Duration::from_millis(50));
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        let msg = msg.unwrap()?;
+        assert_eq!(msg, KEEPALIVE_MSG.to_vec());
+
+        // keepalive frame is a no-op update
+        match Message::decode_v1(&msg)? {
+            Message::Sync(SyncMessage::Update(update)) => {
+                let doc = Doc::new();
+                let update = Update::decode_v1(&update)?;
+                doc.transact_mut().apply_update(update);
+                assert_eq!(doc.transact().state_vector(), StateVector::default());
+            }
+            other => panic!("unexpected keepalive message: {other:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn local_awareness_is_broadcasted() -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        // let the subscriber join before the awareness changes, so it doesn't receive a snapshot
+        tokio::task::yield_now().await;
+
+        group
+            .set_local_awareness(serde_json::json!({"user": "assistant"}))
+            .await;
+        let msg = client_receiver.next().await;
+        let msg = msg.map(|x| Message::decode_v1(&x.unwrap()).unwrap());
+        assert_eq!(
+            msg,
+            Some(Message::Awareness(AwarenessUpdate {
+                clients: HashMap::from([(
+                    1,
+                    AwarenessUpdateEntry {
+                        clock: 1,
+                        json: r#"{"user":"assistant"}"#.to_string(),
+                    },
+                )]),
+            }))
+        );
+
+        group.clear_local_awareness().await;
         let msg = client_receiver.next().await;
         let msg = msg.map(|x| Message::decode_v1(&x.unwrap()).unwrap());
         assert_eq!(
-            msg,
-            Some(Message::Sync(SyncMessage::Update(vec![
-                1, 1, 1, 0, 4, 1, 4, 116, 101, 115, 116, 1, 97, 0,
-            ])))
+            msg,
+            Some(Message::Awareness(AwarenessUpdate {
+                clients: HashMap::from([(
+                    1,
+                    AwarenessUpdateEntry {
+                        clock: 2,
+                        json: "null".to_string(),
+                    },
+                )]),
+            }))
+        );
+        assert!(awareness.read().await.local_state().is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn first_subscriber_resolves_on_join() -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+
+        let first = tokio::spawn(group.first_subscriber());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!first.is_finished());
+
+        let (server_sender, _client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        timeout(Duration::from_secs(1), first).await??;
+        // there's already a subscriber, so it resolves immediately
+        timeout(Duration::from_millis(10), group.first_subscriber()).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn update_sink_receives_local_and_remote_updates(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let (tx, mut persisted) = tokio::sync::mpsc::unbounded_channel();
+        let group = BroadcastGroup::new(awareness.clone(), 10)
+            .await
+            .with_update_sink(move |update| tx.send(update.to_vec()).unwrap());
+
+        let (server_sender, _client_receiver) = test_channel(10);
+        let (mut client_sender, server_receiver) = test_channel(10);
+        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        // local change
+        {
+            let lock = awareness.write().await;
+            text.push(&mut lock.doc().transact_mut(), "abc");
+        }
+        // remote change
+        let remote = Doc::with_client_id(2);
+        let remote_text = remote.get_or_insert_text("test");
+        let update = {
+            let mut txn = remote.transact_mut();
+            remote_text.push(&mut txn, "def");
+            txn.encode_update_v1()
+        };
+        client_sender
+            .send(Message::Sync(SyncMessage::Update(update)).encode_v1())
+            .await?;
+
+        let restored = Doc::with_client_id(3);
+        for _ in 0..2 {
+            let update = timeout(Duration::from_secs(1), persisted.recv())
+                .await?
+                .unwrap();
+            restored
+                .transact_mut()
+                .apply_update(Update::decode_v1(&update)?);
+        }
+        let lock = awareness.read().await;
+        crate::test_util::assert_converged(lock.doc(), &restored);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn initial_state_is_not_broadcasted() -> Result<(), Box<dyn std::error::Error>> {
+        let stored = Doc::with_client_id(2);
+        let text = stored.get_or_insert_text("test");
+        text.push(&mut stored.transact_mut(), "abc");
+        let state = stored
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::from_state(awareness.clone(), 1, &state).await?;
+        {
+            let lock = awareness.read().await;
+            crate::test_util::assert_converged(lock.doc(), &stored);
+        }
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        assert!(
+            timeout(Duration::from_millis(100), client_receiver.next())
+                .await
+                .is_err(),
+            "nothing should be broadcasted"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn readonly_subscribers_cannot_change_document() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        text.push(&mut doc.transact_mut(), "abc");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let _sub1 = group.subscribe_readonly(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        let remote = Doc::with_client_id(2);
+        let remote_text = remote.get_or_insert_text("test");
+        let update = {
+            let mut txn = remote.transact_mut();
+            remote_text.push(&mut txn, "def");
+            txn.encode_update_v1()
+        };
+        client_sender
+            .send(Message::Sync(SyncMessage::Update(update)).encode_v1())
+            .await?;
+
+        // sync requests are still answered
+        let sv = remote.transact().state_vector();
+        client_sender
+            .send(Message::Sync(SyncMessage::SyncStep1(sv)).encode_v1())
+            .await?;
+        let msg = timeout(Duration::from_secs(1), client_receiver.next())
+            .await?
+            .unwrap()?;
+        let msg = Message::decode_v1(&msg)?;
+        assert!(matches!(msg, Message::Sync(SyncMessage::SyncStep2(_))));
+
+        let lock = awareness.read().await;
+        assert_eq!(text.get_string(&lock.doc().transact()), "abc");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn close_completes_subscriptions() -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (client_sender, server_receiver) = test_channel(1);
+        let sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        let close = tokio::spawn(group.close());
+        // sink has been closed
+        assert!(timeout(Duration::from_secs(1), client_receiver.next())
+            .await?
+            .is_none());
+        timeout(Duration::from_secs(1), sub1.completed()).await??;
+        // peer acknowledges closing
+        drop(client_sender);
+        timeout(Duration::from_secs(1), close).await??;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn awareness_states_are_exposed() -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+        {
+            let mut lock = awareness.write().await;
+            lock.set_local_state(r#"{"user":"server"}"#);
+            let entry = |json: &str| AwarenessUpdateEntry {
+                clock: 1,
+                json: json.to_string(),
+            };
+            lock.apply_update(AwarenessUpdate {
+                clients: HashMap::from([(2, entry(r#"{"user":"test"}"#)), (3, entry("null"))]),
+            })?;
+        }
+
+        assert_eq!(group.awareness_clients().await, vec![1, 2, 3]);
+        assert_eq!(
+            group.awareness_state(2).await.as_deref(),
+            Some(r#"{"user":"test"}"#)
+        );
+        assert_eq!(group.awareness_state(4).await, None);
+        assert_eq!(group.awareness_online_count().await, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lifecycle_events_are_emitted() -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+        let mut events = group.events();
+
+        let (server_sender, _client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        assert_eq!(events.recv().await?, BroadcastEvent::PeerJoined);
+
+        let remote = Doc::with_client_id(2);
+        let text = remote.get_or_insert_text("test");
+        let update = {
+            let mut txn = remote.transact_mut();
+            text.push(&mut txn, "abc");
+            txn.encode_update_v1()
+        };
+        let bytes = update.len();
+        client_sender
+            .send(Message::Sync(SyncMessage::Update(update)).encode_v1())
+            .await?;
+        let e = timeout(Duration::from_secs(1), events.recv()).await??;
+        assert_eq!(e, BroadcastEvent::Updated { bytes });
+
+        // malformed message fails the connection
+        client_sender.send(vec![255]).await?;
+        assert!(timeout(Duration::from_secs(1), sub1.completed())
+            .await?
+            .is_err());
+        let e = timeout(Duration::from_secs(1), events.recv()).await??;
+        assert!(matches!(e, BroadcastEvent::PeerLeft { error: Some(_) }));
+
+        Ok(())
+    }
+
+    async fn next_snapshot(
+        events: &mut tokio::sync::broadcast::Receiver<BroadcastEvent>,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        loop {
+            if let BroadcastEvent::DocumentSnapshot(snapshot) = events.recv().await? {
+                return Ok(snapshot);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshots_are_emitted_every_n_updates() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::builder()
+            .with_doc(Doc::with_client_id(1))
+            .with_snapshot_every(2)
+            .build()
+            .await;
+        let mut events = group.events();
+
+        let remote = Doc::with_client_id(2);
+        let text = remote.get_or_insert_text("test");
+        let push = |chunk: &str| {
+            let mut txn = remote.transact_mut();
+            text.push(&mut txn, chunk);
+            txn.encode_update_v1()
+        };
+        group.apply_update(&push("abc")).await?;
+        group.apply_update(&push("def")).await?;
+
+        let snapshot = timeout(Duration::from_secs(1), next_snapshot(&mut events)).await??;
+        let restored = Doc::new();
+        let restored_text = restored.get_or_insert_text("test");
+        restored
+            .transact_mut()
+            .apply_update(Update::decode_v1(&snapshot)?);
+        assert_eq!(restored_text.get_string(&restored.transact()), "abcdef");
+
+        // a single update since the last snapshot doesn't make another one
+        group.apply_update(&push("ghi")).await?;
+        assert!(timeout(Duration::from_secs(1), next_snapshot(&mut events))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn snapshots_are_emitted_periodically() -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 1)
+            .await
+            .with_snapshot_interval(Duration::from_secs(10));
+        let mut events = group.events();
+
+        {
+            let lock = awareness.write().await;
+            let text = lock.doc().get_or_insert_text("test");
+            text.push(&mut lock.doc().transact_mut(), "abc");
+        }
+        let started = tokio::time::Instant::now();
+        let snapshot = timeout(Duration::from_secs(11), next_snapshot(&mut events)).await??;
+        assert!(started.elapsed() >= Duration::from_secs(10));
+        let restored = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+        let _restored_group = BroadcastGroup::from_state(restored.clone(), 1, &snapshot).await?;
+        let lock = restored.read().await;
+        let restored_text = lock.doc().get_or_insert_text("test");
+        assert_eq!(restored_text.get_string(&lock.doc().transact()), "abc");
+        drop(lock);
+
+        // an unchanged document isn't snapshotted again
+        assert!(timeout(Duration::from_secs(30), next_snapshot(&mut events))
+            .await
+            .is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn oversized_messages_dont_wait_for_unclosable_sinks(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+        let group = BroadcastGroup::new(awareness, 1)
+            .await
+            .with_max_message_size(100);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let sub = group.subscribe(Arc::new(Mutex::new(UnclosableSink)), server_receiver);
+
+        let msg = Message::Custom(100, vec![0; 200]).encode_v1();
+        client_sender.send(msg).await?;
+        let res = timeout(Duration::from_secs(10), sub.completed()).await?;
+        assert!(matches!(res, Err(crate::error::Error::LimitExceeded(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_messages_close_only_sender() -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 1)
+            .await
+            .with_max_message_size(100);
+
+        let (server_sender1, mut client_receiver1) = test_channel(1);
+        let (mut client_sender1, server_receiver1) = test_channel(1);
+        let sub1 = group.subscribe(Arc::new(Mutex::new(server_sender1)), server_receiver1);
+        let (server_sender2, mut client_receiver2) = test_channel(1);
+        let (_client_sender2, server_receiver2) = test_channel(1);
+        let _sub2 = group.subscribe(Arc::new(Mutex::new(server_sender2)), server_receiver2);
+
+        let remote = Doc::with_client_id(2);
+        let remote_text = remote.get_or_insert_text("test");
+        let update = {
+            let mut txn = remote.transact_mut();
+            remote_text.push(&mut txn, &"a".repeat(200));
+            txn.encode_update_v1()
+        };
+        client_sender1
+            .send(Message::Sync(SyncMessage::Update(update)).encode_v1())
+            .await?;
+        let res = timeout(Duration::from_secs(1), sub1.completed()).await?;
+        assert!(matches!(res, Err(crate::error::Error::LimitExceeded(_))));
+        assert!(client_receiver1.next().await.is_none());
+        {
+            let lock = awareness.read().await;
+            assert_eq!(text.get_string(&lock.doc().transact()), "");
+        }
+
+        // other subscribers are unaffected
+        {
+            let lock = awareness.write().await;
+            text.push(&mut lock.doc().transact_mut(), "abc");
+        }
+        let msg = timeout(Duration::from_secs(1), client_receiver2.next()).await?;
+        assert!(msg.is_some());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_awareness_states_expire() -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 10)
+            .await
+            .with_awareness_ttl(Duration::from_millis(100));
+
+        let (server_sender, mut client_receiver) = test_channel(10);
+        let (mut client_sender, server_receiver) = test_channel(10);
+        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let update = AwarenessUpdate {
+            clients: HashMap::from([(
+                2,
+                AwarenessUpdateEntry {
+                    clock: 1,
+                    json: r#"{"user":"test"}"#.to_string(),
+                },
+            )]),
+        };
+        client_sender
+            .send(Message::Awareness(update).encode_v1())
+            .await?;
+        // client 2 never refreshes its state, as if it has been disconnected
+        drop(client_sender);
+
+        let mut removed = false;
+        while !removed {
+            let msg = timeout(Duration::from_secs(1), client_receiver.next())
+                .await?
+                .unwrap()?;
+            if let Message::Awareness(update) = Message::decode_v1(&msg)? {
+                removed = update.clients.get(&2).map(|e| e.json.as_str()) == Some("null");
+            }
+        }
+        assert_eq!(group.awareness_clients().await, Vec::<u64>::new());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn document_state_is_encoded() -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        text.push(&mut doc.transact_mut(), "abc");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+
+        let restored = Doc::with_client_id(2);
+        let update = group.encode_state_as_update().await;
+        restored
+            .transact_mut()
+            .apply_update(Update::decode_v1(&update)?);
+        let lock = awareness.read().await;
+        crate::test_util::assert_converged(lock.doc(), &restored);
+
+        let sv = StateVector::decode_v1(&group.encode_state_vector().await)?;
+        assert_eq!(sv, lock.doc().transact().state_vector());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_are_disconnected() -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let server_sender = Arc::new(Mutex::new(server_sender));
+        let sub = group.subscribe(server_sender.clone(), server_receiver);
+
+        // stall the subscriber while changes keep coming
+        let stalled = server_sender.lock().await;
+        for chunk in ["a", "b", "c"] {
+            let lock = awareness.write().await;
+            text.push(&mut lock.doc().transact_mut(), chunk);
+        }
+        drop(stalled);
+
+        let res = timeout(Duration::from_secs(1), sub.completed()).await?;
+        assert!(matches!(res, Err(crate::error::Error::Lagged(_))));
+        while client_receiver.next().await.is_some() {}
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disconnect_reasons_are_reported() -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+
+        // peer closing its connection
+        let (server_sender, _client_receiver) = test_channel(16);
+        let (client_sender, server_receiver) = test_channel(1);
+        let closed = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        drop(client_sender);
+        let res = timeout(Duration::from_secs(1), closed.completed()).await?;
+        assert_eq!(res?, DisconnectReason::PeerClosed);
+
+        // peer evicted for lagging behind
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let server_sender = Arc::new(Mutex::new(server_sender));
+        let evicted = group.subscribe(server_sender.clone(), server_receiver);
+        let stalled = server_sender.lock().await;
+        for chunk in ["a", "b", "c"] {
+            let lock = awareness.write().await;
+            text.push(&mut lock.doc().transact_mut(), chunk);
+        }
+        drop(stalled);
+        let res = timeout(Duration::from_secs(1), evicted.completed()).await?;
+        assert_eq!(DisconnectReason::of(&res), DisconnectReason::Evicted);
+        while client_receiver.next().await.is_some() {}
+
+        // peer closed by the server
+        let (server_sender, _client_receiver) = test_channel(16);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let kicked = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        kicked.handle().close();
+        let res = timeout(Duration::from_secs(1), kicked.completed()).await?;
+        assert_eq!(DisconnectReason::of(&res), DisconnectReason::Closed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocking_policy_waits_for_lagging_subscribers(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 1)
+            .await
+            .with_buffer_overflow_policy(BufferOverflowPolicy::Block);
+
+        let (server_sender1, _client_receiver1) = test_channel(10);
+        let (_client_sender1, server_receiver1) = test_channel(1);
+        let server_sender1 = Arc::new(Mutex::new(server_sender1));
+        let sub1 = group.subscribe(server_sender1.clone(), server_receiver1);
+        let (server_sender2, _client_receiver2) = test_channel(10);
+        let (mut client_sender2, server_receiver2) = test_channel(10);
+        let _sub2 = group.subscribe(Arc::new(Mutex::new(server_sender2)), server_receiver2);
+
+        let stalled = server_sender1.lock().await;
+        let remote = Doc::with_client_id(2);
+        let remote_text = remote.get_or_insert_text("test");
+        for chunk in ["a", "b", "c"] {
+            let update = {
+                let mut txn = remote.transact_mut();
+                remote_text.push(&mut txn, chunk);
+                txn.encode_update_v1()
+            };
+            client_sender2
+                .send(Message::Sync(SyncMessage::Update(update)).encode_v1())
+                .await?;
+        }
+
+        // the first update is already picked by the stalled subscriber, the second one fills
+        // the buffer, so the last one has to wait
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        {
+            let lock = awareness.read().await;
+            assert_eq!(text.get_string(&lock.doc().transact()), "ab");
+        }
+
+        drop(stalled);
+        timeout(Duration::from_secs(1), async {
+            loop {
+                {
+                    let lock = awareness.read().await;
+                    if text.get_string(&lock.doc().transact()) == "abc" {
+                        break;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert_eq!(group.subscriber_count(), 2);
+        drop(sub1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn metrics_are_recorded() -> Result<(), Box<dyn std::error::Error>> {
+        #[derive(Default)]
+        struct Recorder {
+            connected: AtomicUsize,
+            disconnected: AtomicUsize,
+            inbound: AtomicUsize,
+            outbound: AtomicUsize,
+        }
+
+        impl Metrics for Recorder {
+            fn on_connect(&self) {
+                self.connected.fetch_add(1, Ordering::Relaxed);
+            }
+
+            fn on_disconnect(&self) {
+                self.disconnected.fetch_add(1, Ordering::Relaxed);
+            }
+
+            fn on_message(&self, direction: Direction, bytes: usize) {
+                match direction {
+                    Direction::Inbound => self.inbound.fetch_add(bytes, Ordering::Relaxed),
+                    Direction::Outbound => self.outbound.fetch_add(bytes, Ordering::Relaxed),
+                };
+            }
+        }
+
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let recorder = Arc::new(Recorder::default());
+        let group = BroadcastGroup::new(awareness.clone(), 1)
+            .await
+            .with_metrics(recorder.clone());
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        tokio::task::yield_now().await;
+        assert_eq!(recorder.connected.load(Ordering::Relaxed), 1);
+
+        let sync_step1 = Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1();
+        client_sender.send(sync_step1.clone()).await?;
+        let reply = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        let reply_len = reply.unwrap()?.len();
+        {
+            let lock = awareness.write().await;
+            text.push(&mut lock.doc().transact_mut(), "abc");
+        }
+        let update = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        let update_len = update.unwrap()?.len();
+        tokio::task::yield_now().await;
+        assert_eq!(recorder.inbound.load(Ordering::Relaxed), sync_step1.len());
+        assert_eq!(
+            recorder.outbound.load(Ordering::Relaxed),
+            reply_len + update_len
+        );
+
+        drop(sub);
+        tokio::task::yield_now().await;
+        assert_eq!(recorder.disconnected.load(Ordering::Relaxed), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscription_traffic_is_counted() -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let handle = sub.handle();
+        assert_eq!(sub.traffic(), TrafficStats::default());
+
+        let sync_step1 = Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1();
+        let awareness_query = Message::AwarenessQuery.encode_v1();
+        client_sender.send(sync_step1.clone()).await?;
+        client_sender.send(awareness_query.clone()).await?;
+        let mut received = 0;
+        for _ in 0..2 {
+            let reply = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+            received += reply.unwrap()?.len();
+        }
+        {
+            let lock = awareness.write().await;
+            text.push(&mut lock.doc().transact_mut(), "abc");
+        }
+        let update = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        received += update.unwrap()?.len();
+
+        // totals remain available once a subscription completes
+        drop(client_sender);
+        let res = timeout(Duration::from_secs(1), sub.completed()).await?;
+        assert_eq!(res?, DisconnectReason::PeerClosed);
+        assert_eq!(
+            handle.traffic(),
+            TrafficStats {
+                bytes_sent: received as u64,
+                bytes_received: (sync_step1.len() + awareness_query.len()) as u64,
+            }
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unresponsive_subscribers_are_closed() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::new(), 1)
+            .await
+            .with_ping_interval(Duration::from_millis(50), 2);
+
+        // responsive subscriber answers every ping with a pong
+        let (server_sender1, mut client_receiver1) = test_channel(1);
+        let (mut client_sender1, server_receiver1) = test_channel(1);
+        let sub1 = group.subscribe(Arc::new(Mutex::new(server_sender1)), server_receiver1);
+        let pongs = tokio::spawn(async move {
+            while let Some(Ok(msg)) = client_receiver1.next().await {
+                assert_eq!(msg, PING_MSG);
+                if client_sender1.send(Vec::new()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let (server_sender2, mut client_receiver2) = test_channel(1);
+        let (_client_sender2, server_receiver2) = test_channel(1);
+        let sub2 = group.subscribe(Arc::new(Mutex::new(server_sender2)), server_receiver2);
+        for _ in 0..2 {
+            let msg = timeout(Duration::from_secs(1), client_receiver2.next()).await?;
+            assert_eq!(msg.unwrap()?, PING_MSG);
+        }
+        let res = timeout(Duration::from_secs(1), sub2.completed()).await?;
+        assert!(matches!(res, Err(crate::error::Error::Timeout(_))));
+
+        // responsive subscriber is still there once the closed one is released
+        tokio::task::yield_now().await;
+        assert_eq!(group.subscriber_count(), 1);
+        drop(sub1);
+        let _ = pongs.await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn zero_missed_pings_close_after_first_ping() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::new(), 1)
+            .await
+            .with_ping_interval(Duration::from_millis(50), 0);
+
+        // responsive subscriber survives many pings
+        let (server_sender1, mut client_receiver1) = test_channel(1);
+        let (mut client_sender1, server_receiver1) = test_channel(1);
+        let sub1 = group.subscribe(Arc::new(Mutex::new(server_sender1)), server_receiver1);
+        let pongs = tokio::spawn(async move {
+            let mut count = 0;
+            while let Some(Ok(msg)) = client_receiver1.next().await {
+                assert_eq!(msg, PING_MSG);
+                count += 1;
+                if client_sender1.send(Vec::new()).await.is_err() {
+                    break;
+                }
+            }
+            count
+        });
+
+        // unresponsive subscriber gets a ping before being closed
+        let (server_sender2, mut client_receiver2) = test_channel(1);
+        let (_client_sender2, server_receiver2) = test_channel(1);
+        let sub2 = group.subscribe(Arc::new(Mutex::new(server_sender2)), server_receiver2);
+        let msg = timeout(Duration::from_secs(1), client_receiver2.next()).await?;
+        assert_eq!(msg.unwrap()?, PING_MSG);
+        let res = timeout(Duration::from_secs(1), sub2.completed()).await?;
+        assert!(matches!(res, Err(crate::error::Error::Timeout(_))));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(group.subscriber_count(), 1);
+        drop(sub1);
+        assert!(pongs.await? >= 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn awareness_filter_redacts_outgoing_states() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 1)
+            .await
+            .with_awareness_filter(|client_id, json| {
+                if client_id == 3 {
+                    return None;
+                }
+                let mut state: serde_json::Value = serde_json::from_str(json).ok()?;
+                if let Some(state) = state.as_object_mut() {
+                    state.remove("email");
+                }
+                Some(state.to_string())
+            });
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let _sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        let entry = |json: &str| AwarenessUpdateEntry {
+            clock: 1,
+            json: json.to_string(),
+        };
+        let update = AwarenessUpdate {
+            clients: HashMap::from([
+                (2, entry(r#"{"name":"alice","email":"alice@example.com"}"#)),
+                (3, entry(r#"{"name":"bob"}"#)),
+            ]),
+        };
+        client_sender
+            .send(Message::Awareness(update).encode_v1())
+            .await?;
+
+        let expected = || AwarenessUpdate {
+            clients: HashMap::from([(2, entry(r#"{"name":"alice"}"#))]),
+        };
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        assert_eq!(
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Awareness(expected())
+        );
+
+        // awareness query replies are filtered as well
+        client_sender
+            .send(Message::AwarenessQuery.encode_v1())
+            .await?;
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        assert_eq!(
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Awareness(expected())
+        );
+
+        // while the authoritative state is left intact
+        assert_eq!(
+            group.awareness_state(2).await.as_deref(),
+            Some(r#"{"name":"alice","email":"alice@example.com"}"#)
+        );
+        assert_eq!(
+            group.awareness_state(3).await.as_deref(),
+            Some(r#"{"name":"bob"}"#)
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribers_over_the_limit_are_rejected() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::new(), 1)
+            .await
+            .with_max_subscribers(2);
+
+        let mut subs = Vec::new();
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let (server_sender, client_receiver) = test_channel(1);
+            let (client_sender, server_receiver) = test_channel(1);
+            subs.push(group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver));
+            clients.push((client_sender, client_receiver));
+        }
+        assert_eq!(group.subscriber_count(), 2);
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let rejected = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let res = timeout(Duration::from_secs(1), rejected.completed()).await?;
+        assert!(matches!(res, Err(crate::error::Error::LimitExceeded(_))));
+        assert!(client_receiver.next().await.is_none());
+        assert_eq!(group.subscriber_count(), 2);
+
+        // once a subscriber leaves, there's room for a new one
+        drop(subs.pop());
+        tokio::task::yield_now().await;
+        let (server_sender, _client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let _sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        assert_eq!(group.subscriber_count(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn custom_messages_are_broadcasted() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::new(), 1).await;
+
+        let (server_sender1, mut client_receiver1) = test_channel(1);
+        let (_client_sender1, server_receiver1) = test_channel(1);
+        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender1)), server_receiver1);
+        let (server_sender2, mut client_receiver2) = test_channel(1);
+        let (_client_sender2, server_receiver2) = test_channel(1);
+        let _sub2 = group.subscribe(Arc::new(Mutex::new(server_sender2)), server_receiver2);
+
+        let notification = || Message::Custom(100, b"server restarting in 5m".to_vec());
+        group.broadcast_custom(notification())?;
+        for client_receiver in [&mut client_receiver1, &mut client_receiver2] {
+            let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+            assert_eq!(Message::decode_v1(&msg.unwrap()?)?, notification());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscriptions_can_be_closed_via_handle() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::new(), 1).await;
+
+        let (server_sender1, mut client_receiver1) = test_channel(1);
+        let (_client_sender1, server_receiver1) = test_channel(1);
+        let sub1 = group.subscribe(Arc::new(Mutex::new(server_sender1)), server_receiver1);
+        let (server_sender2, _client_receiver2) = test_channel(1);
+        let (_client_sender2, server_receiver2) = test_channel(1);
+        let sub2 = group.subscribe(Arc::new(Mutex::new(server_sender2)), server_receiver2);
+        assert_ne!(sub1.id(), sub2.id());
+
+        let handles = HashMap::from([(sub1.id(), sub1.handle()), (sub2.id(), sub2.handle())]);
+        let peer = tokio::spawn(sub1.completed());
+        let banned = handles.values().find(|h| h.id() != sub2.id()).unwrap();
+        banned.close();
+
+        let res = timeout(Duration::from_secs(1), peer).await??;
+        assert!(matches!(res, Err(crate::error::Error::Closed)));
+        assert!(client_receiver1.next().await.is_none());
+        assert!(!handles[&sub2.id()].is_closed());
+
+        sub2.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn awareness_is_sent_on_join() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 1).await;
+
+        // awareness is empty, so nothing is sent before the first broadcast
+        let (a_sender, mut a_receiver) = test_channel(1);
+        let (mut a_client, a_stream) = test_channel(1);
+        let _a = group.subscribe(Arc::new(Mutex::new(a_sender)), a_stream);
+        assert!(timeout(Duration::from_millis(50), a_receiver.next())
+            .await
+            .is_err());
+
+        let update = AwarenessUpdate {
+            clients: HashMap::from([(
+                2,
+                AwarenessUpdateEntry {
+                    clock: 1,
+                    json: r#"{"cursor":3}"#.to_string(),
+                },
+            )]),
+        };
+        a_client
+            .send(Message::Awareness(update).encode_v1())
+            .await?;
+        timeout(Duration::from_secs(1), a_receiver.next()).await?;
+
+        let (b_sender, mut b_receiver) = test_channel(1);
+        let (_b_client, b_stream) = test_channel(1);
+        let _b = group.subscribe(Arc::new(Mutex::new(b_sender)), b_stream);
+        let msg = timeout(Duration::from_secs(1), b_receiver.next()).await?;
+        match Message::decode_v1(&msg.unwrap()?)? {
+            Message::Awareness(update) => {
+                assert_eq!(update.clients.len(), 1);
+                assert_eq!(update.clients[&2].json, r#"{"cursor":3}"#);
+            }
+            other => panic!("unexpected message on join: {other:?}"),
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn decoded_changes_are_observed() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 1).await;
+        let updates = Arc::new(AtomicUsize::new(0));
+        let deltas = Arc::new(std::sync::Mutex::new(Vec::new()));
+        {
+            let updates = updates.clone();
+            group
+                .on_update_decoded(move |update| {
+                    assert_eq!(update.state_vector().get(&1), 3);
+                    updates.fetch_add(1, Ordering::SeqCst);
+                })
+                .await;
+            let deltas = deltas.clone();
+            group
+                .on_text_delta("text", move |txn, delta| {
+                    for d in delta {
+                        if let Delta::Inserted(value, _) = d {
+                            deltas.lock().unwrap().push(value.clone().to_string(txn));
+                        }
+                    }
+                })
+                .await;
+        }
+
+        {
+            let lock = group.awareness().write().await;
+            let text = lock.doc().get_or_insert_text("text");
+            text.insert(&mut lock.doc().transact_mut(), 0, "abc");
+        }
+        assert_eq!(updates.load(Ordering::SeqCst), 1);
+        assert_eq!(*deltas.lock().unwrap(), vec!["abc".to_string()]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reconnecting_peers_receive_only_missing_changes(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 1).await;
+        let server_text = group
+            .awareness()
+            .read()
+            .await
+            .doc()
+            .get_or_insert_text("text");
+        let insert = |chunk: &'static str| {
+            let group = &group;
+            let text = server_text.clone();
+            async move {
+                let lock = group.awareness().write().await;
+                let mut txn = lock.doc().transact_mut();
+                let len = text.len(&txn);
+                text.insert(&mut txn, len, chunk);
+            }
+        };
+
+        // the peer synced "abc" before disconnecting
+        insert("abc").await;
+        let client = Doc::with_client_id(2);
+        let client_text = client.get_or_insert_text("text");
+        let full = group.encode_state_as_update().await;
+        client
+            .transact_mut()
+            .apply_update(Update::decode_v1(&full)?);
+        insert("def").await;
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let _sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let sv = client.transact().state_vector();
+        client_sender
+            .send(Message::Sync(SyncMessage::SyncStep1(sv)).encode_v1())
+            .await?;
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        let Message::Sync(SyncMessage::SyncStep2(diff)) = Message::decode_v1(&msg.unwrap()?)?
+        else {
+            panic!("expected SyncStep2 reply");
+        };
+
+        // the diff can't be applied without previously synced changes
+        let fresh = Doc::new();
+        let fresh_text = fresh.get_or_insert_text("text");
+        fresh.transact_mut().apply_update(Update::decode_v1(&diff)?);
+        assert_eq!(fresh_text.get_string(&fresh.transact()), "");
+        assert!(diff.len() < group.encode_state_as_update().await.len());
+
+        client
+            .transact_mut()
+            .apply_update(Update::decode_v1(&diff)?);
+        assert_eq!(client_text.get_string(&client.transact()), "abcdef");
+
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flooding_doesnt_wait_for_unclosable_sinks() -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+        let group = BroadcastGroup::new(awareness, 16)
+            .await
+            .with_inbound_rate_limit(1, 0)
+            .with_max_rate_violations(1);
+        let (mut client_sender, server_receiver) = test_channel(16);
+        let sub = group.subscribe(Arc::new(Mutex::new(UnclosableSink)), server_receiver);
+
+        for _ in 0..3 {
+            client_sender
+                .send(Message::AwarenessQuery.encode_v1())
+                .await?;
+        }
+        let res = timeout(Duration::from_secs(10), sub.completed()).await?;
+        assert!(matches!(res, Err(crate::error::Error::LimitExceeded(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn flooding_subscribers_are_throttled_and_evicted(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 16)
+            .await
+            .with_inbound_rate_limit(5, 0)
+            .with_max_rate_violations(3);
+
+        let (server_sender, mut client_receiver) = test_channel(16);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        let client = Doc::with_client_id(2);
+        let client_text = client.get_or_insert_text("test");
+        for c in ["a", "b", "c", "d", "e", "f", "g", "h", "i"] {
+            let update = {
+                let mut txn = client.transact_mut();
+                client_text.push(&mut txn, c);
+                txn.encode_update_v1()
+            };
+            client_sender
+                .send(Message::Sync(SyncMessage::Update(update)).encode_v1())
+                .await?;
+        }
+
+        // 5 updates within the rate are applied, 3 are dropped and the next one closes the sender
+        let res = timeout(Duration::from_secs(1), sub.completed()).await?;
+        assert!(matches!(res, Err(crate::error::Error::LimitExceeded(_))));
+        {
+            let lock = awareness.read().await;
+            assert_eq!(text.get_string(&lock.doc().transact()), "abcde");
+        }
+        // drain broadcasted updates until the sink gets closed
+        while let Some(msg) = timeout(Duration::from_secs(1), client_receiver.next()).await? {
+            msg?;
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deleted_items_are_collected_only_with_gc() {
+        async fn deleted_content(gc: bool) -> bool {
+            let options = Options {
+                skip_gc: !gc,
+                ..Options::default()
+            };
+            let group = BroadcastGroup::from_options(options, 1).await;
+            assert_eq!(group.gc_enabled().await, gc);
+            {
+                let lock = group.awareness().write().await;
+                let text = lock.doc().get_or_insert_text("test");
+                text.push(&mut lock.doc().transact_mut(), "deleted content");
+                text.remove_range(&mut lock.doc().transact_mut(), 0, 15);
+            }
+            let update = group.encode_state_as_update().await;
+            update
+                .windows(b"deleted content".len())
+                .any(|w| w == b"deleted content")
+        }
+
+        assert!(!deleted_content(true).await);
+        assert!(deleted_content(false).await);
+    }
+
+    #[tokio::test]
+    async fn paused_group_holds_updates_until_resumed() -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 16).await;
+
+        let (server_sender1, _client_receiver1) = test_channel(16);
+        let (mut client_sender1, server_receiver1) = test_channel(16);
+        let sub1 = group.subscribe(Arc::new(Mutex::new(server_sender1)), server_receiver1);
+        let (server_sender2, mut client_receiver2) = test_channel(16);
+        let (_client_sender2, server_receiver2) = test_channel(16);
+        let _sub2 = group.subscribe(Arc::new(Mutex::new(server_sender2)), server_receiver2);
+
+        group.pause();
+        assert!(group.is_paused());
+        let client = Doc::with_client_id(2);
+        let client_text = client.get_or_insert_text("test");
+        for c in ["a", "b", "c"] {
+            let update = {
+                let mut txn = client.transact_mut();
+                client_text.push(&mut txn, c);
+                txn.encode_update_v1()
+            };
+            client_sender1
+                .send(Message::Sync(SyncMessage::Update(update)).encode_v1())
+                .await?;
+        }
+        // a local change is held for subscribers as well
+        {
+            let lock = awareness.write().await;
+            text.insert(&mut lock.doc().transact_mut(), 0, "x");
+        }
+
+        // nothing is applied or delivered while paused, but peers stay connected
+        assert!(timeout(Duration::from_millis(100), client_receiver2.next())
+            .await
+            .is_err());
+        {
+            let lock = awareness.read().await;
+            assert_eq!(text.get_string(&lock.doc().transact()), "x");
+        }
+        assert!(!sub1.stream_task.is_finished());
+
+        group.resume();
+        let peer = Doc::with_client_id(3);
+        for _ in 0..4 {
+            let msg = timeout(Duration::from_secs(1), client_receiver2.next())
+                .await?
+                .unwrap()?;
+            let Message::Sync(SyncMessage::Update(update)) = Message::decode_v1(&msg)? else {
+                panic!("expected an update");
+            };
+            peer.transact_mut()
+                .apply_update(Update::decode_v1(&update)?);
+        }
+        let lock = awareness.read().await;
+        crate::test_util::assert_converged(lock.doc(), &peer);
+        assert_eq!(text.get_string(&lock.doc().transact()), "xabc");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn awareness_only_subscribers_dont_receive_document(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 16).await;
+
+        let (server_sender, mut editor_receiver) = test_channel(16);
+        let (_editor_sender, server_receiver) = test_channel(16);
+        let _editor = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let (server_sender, mut presence_receiver) = test_channel(16);
+        let (mut presence_sender, server_receiver) = test_channel(16);
+        let _presence =
+            group.subscribe_awareness_only(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        {
+            let lock = awareness.write().await;
+            text.push(&mut lock.doc().transact_mut(), "abc");
+        }
+        let msg = timeout(Duration::from_secs(1), editor_receiver.next()).await?;
+        assert!(matches!(
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Sync(SyncMessage::Update(_))
+        ));
+
+        // document requests and updates of the awareness-only peer are ignored
+        presence_sender
+            .send(Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1())
+            .await?;
+        let update = {
+            let doc = Doc::with_client_id(5);
+            let text = doc.get_or_insert_text("test");
+            let mut txn = doc.transact_mut();
+            text.push(&mut txn, "ignored");
+            txn.encode_update_v1()
+        };
+        presence_sender
+            .send(Message::Sync(SyncMessage::Update(update)).encode_v1())
+            .await?;
+        // while its awareness is shared both ways
+        let update = || AwarenessUpdate {
+            clients: HashMap::from([(
+                5,
+                AwarenessUpdateEntry {
+                    clock: 1,
+                    json: r#"{"name":"viewer"}"#.to_string(),
+                },
+            )]),
+        };
+        presence_sender
+            .send(Message::Awareness(update()).encode_v1())
+            .await?;
+        for receiver in [&mut presence_receiver, &mut editor_receiver] {
+            let msg = timeout(Duration::from_secs(1), receiver.next()).await?;
+            assert_eq!(
+                Message::decode_v1(&msg.unwrap()?)?,
+                Message::Awareness(update())
+            );
+        }
+        assert!(
+            timeout(Duration::from_millis(100), presence_receiver.next())
+                .await
+                .is_err()
         );
+        let lock = awareness.read().await;
+        assert_eq!(text.get_string(&lock.doc().transact()), "abc");
 
-        // check awareness update propagation
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn silent_subscribers_are_dropped_after_handshake_timeout(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 1)
+            .await
+            .with_handshake_timeout(Duration::from_millis(100));
+
+        let (server_sender, mut silent_receiver) = test_channel(1);
+        let (_silent_sender, server_receiver) = test_channel(1);
+        let silent = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let client = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        client_sender
+            .send(Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1())
+            .await?;
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        assert!(matches!(
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Sync(SyncMessage::SyncStep2(_))
+        ));
+
+        let res = timeout(Duration::from_secs(1), silent.completed()).await?;
+        assert!(matches!(res, Err(crate::error::Error::Timeout(_))));
+        assert!(timeout(Duration::from_secs(1), silent_receiver.next())
+            .await?
+            .is_none());
+        assert_eq!(group.subscriber_count(), 1);
+        assert!(!client.stream_task.is_finished());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribers_sending_only_pongs_are_idle() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 1)
+            .await
+            .with_idle_timeout(Duration::from_millis(200));
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let handle = sub.handle();
+        client_sender
+            .send(Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1())
+            .await?;
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        assert!(matches!(
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Sync(SyncMessage::SyncStep2(_))
+        ));
+        let last_activity = handle.last_activity();
+        assert!(last_activity.elapsed() < Duration::from_millis(200));
+
+        let completed = tokio::spawn(sub.completed());
+        for _ in 0..20 {
+            if completed.is_finished() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            // pongs and keepalives keep a connection open, but don't make it active
+            let _ = client_sender.send(PING_MSG.to_vec()).await;
+            let _ = client_sender.send(KEEPALIVE_MSG.to_vec()).await;
+        }
+        let res = timeout(Duration::from_secs(1), completed).await??;
+        assert_eq!(res?, DisconnectReason::Idle);
+        assert_eq!(handle.last_activity(), last_activity);
+        assert!(last_activity.elapsed() >= Duration::from_millis(200));
+        assert!(timeout(Duration::from_secs(1), client_receiver.next())
+            .await?
+            .is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscribers_are_closed_after_max_lifetime() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 1)
+            .await
+            .with_max_connection_lifetime(Duration::from_millis(200));
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let started = tokio::time::Instant::now();
+        client_sender
+            .send(Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1())
+            .await?;
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        assert!(matches!(
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Sync(SyncMessage::SyncStep2(_))
+        ));
+
+        // an active connection is closed all the same
+        let res = timeout(Duration::from_secs(1), sub.completed()).await?;
+        assert_eq!(res?, DisconnectReason::Expired);
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        assert!(timeout(Duration::from_secs(1), client_receiver.next())
+            .await?
+            .is_none());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dropped_groups_stop_their_tasks() -> Result<(), Box<dyn std::error::Error>> {
+        // with blocking backpressure a subscriber keeps a sender of its group's broadcasts, so
+        // that it wouldn't notice the group being dropped on its own
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 1)
+            .await
+            .with_buffer_overflow_policy(BufferOverflowPolicy::Block)
+            .with_ping_interval(Duration::from_millis(50), 100)
+            .with_awareness_ttl(Duration::from_millis(50));
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (mut client_sender, server_receiver) = test_channel(1);
+        let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        client_sender
+            .send(Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1())
+            .await?;
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        assert!(matches!(
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Sync(SyncMessage::SyncStep2(_))
+        ));
+        // owners of awareness states are shared by all tasks of a group
+        let tasks = Arc::downgrade(&group.awareness_owners);
+
+        drop(group);
+        let res = timeout(Duration::from_secs(1), sub.completed()).await?;
+        assert_eq!(res?, DisconnectReason::GroupClosed);
+        timeout(Duration::from_secs(1), async {
+            // skip pings sent before the sink has been closed
+            while client_receiver.next().await.is_some() {}
+            while tasks.strong_count() != 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subdocs_are_synced_through_nested_groups() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 8).await;
+        let a = Doc::with_client_id(2);
+        let docs = a.get_or_insert_map("docs");
+        let sub_a = docs.insert(&mut a.transact_mut(), "child", Doc::with_client_id(2));
+        let guid = sub_a.guid().clone();
+        let content = sub_a.get_or_insert_text("content");
+        content.insert(&mut sub_a.transact_mut(), 0, "hello");
+        assert!(group.subdoc(&guid).await.is_none());
+
+        // sends an update and waits until it has been applied, by following it with a sync step 1.
+        // Returned channels keep the connection open
+        async fn push(
+            group: &BroadcastGroup,
+            doc: &Doc,
+        ) -> Result<(Subscription, impl Sized), Box<dyn std::error::Error>> {
+            let (server_sender, mut receiver) = test_channel(8);
+            let (mut sender, server_receiver) = test_channel(8);
+            let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+            let txn = doc.transact();
+            let update = txn.encode_state_as_update_v1(&StateVector::default());
+            sender
+                .send(Message::Sync(SyncMessage::Update(update)).encode_v1())
+                .await?;
+            sender
+                .send(Message::Sync(SyncMessage::SyncStep1(txn.state_vector())).encode_v1())
+                .await?;
+            timeout(Duration::from_secs(1), receiver.next()).await?;
+            Ok((sub, (sender, receiver)))
+        }
+        // sends a sync step 1 and applies a sync step 2 received in reply
+        async fn pull(
+            group: &BroadcastGroup,
+            doc: &Doc,
+        ) -> Result<(Subscription, impl Sized), Box<dyn std::error::Error>> {
+            let (server_sender, mut receiver) = test_channel(8);
+            let (mut sender, server_receiver) = test_channel(8);
+            let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+            let sv = doc.transact().state_vector();
+            sender
+                .send(Message::Sync(SyncMessage::SyncStep1(sv)).encode_v1())
+                .await?;
+            let msg = timeout(Duration::from_secs(1), receiver.next()).await?;
+            let Message::Sync(SyncMessage::SyncStep2(update)) = Message::decode_v1(&msg.unwrap()?)?
+            else {
+                panic!("expected sync step 2");
+            };
+            doc.transact_mut().apply_update(Update::decode_v1(&update)?);
+            Ok((sub, (sender, receiver)))
+        }
+
+        let _a_parent = push(&group, &a).await?;
+        let nested = group.subdoc(&guid).await.expect("subdoc group");
+        assert!(Arc::ptr_eq(&nested, &group.subdoc(&guid).await.unwrap()));
+        assert_eq!(group.subdoc_guids(), vec![guid.clone()]);
+        let _a_child = push(&nested, &sub_a).await?;
+
+        let b = Doc::with_client_id(3);
+        let _b_parent = pull(&group, &b).await?;
+        let sub_b = b.transact().subdocs().next().expect("subdoc").clone();
+        assert_eq!(sub_b.guid(), &guid);
+        let (b_child, _b_child) = pull(&nested, &sub_b).await?;
+        assert_converged(&sub_a, &sub_b);
+        let content = sub_b.get_or_insert_text("content");
+        assert_eq!(content.get_string(&sub_b.transact()), "hello");
+
+        // removing a subdocument from the parent closes its nested group
+        docs.remove(&mut a.transact_mut(), "child");
+        let _a_parent = push(&group, &a).await?;
+        let res = timeout(Duration::from_secs(1), b_child.completed()).await?;
+        assert!(matches!(res, Ok(DisconnectReason::GroupClosed)));
+        assert!(group.subdoc(&guid).await.is_none());
+        assert!(group.subdoc_guids().is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn bridged_groups_relay_updates_both_ways() -> Result<(), Box<dyn std::error::Error>> {
+        async fn wait_for_text(group: &BroadcastGroup, expected: &str) -> Result<(), Elapsed> {
+            timeout(Duration::from_secs(1), async {
+                loop {
+                    {
+                        let lock = group.awareness().read().await;
+                        let text = lock.doc().get_or_insert_text("test");
+                        if text.get_string(&lock.doc().transact()) == expected {
+                            break;
+                        }
+                    }
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+        }
+
+        async fn count_updates(group: &BroadcastGroup) -> Arc<AtomicUsize> {
+            let count = Arc::new(AtomicUsize::new(0));
+            let counter = count.clone();
+            group
+                .on_update_decoded(move |_| {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                })
+                .await;
+            count
+        }
+
+        async fn push(group: &BroadcastGroup, chunk: &str) {
+            let lock = group.awareness().write().await;
+            let text = lock.doc().get_or_insert_text("test");
+            text.push(&mut lock.doc().transact_mut(), chunk);
+        }
+
+        let a = BroadcastGroup::from_doc(Doc::with_client_id(1), 8).await;
+        let b = BroadcastGroup::from_doc(Doc::with_client_id(2), 8).await;
+        // changes made before bridging are synced as well
+        push(&a, "a").await;
+        let a_updates = count_updates(&a).await;
+        let b_updates = count_updates(&b).await;
+        let bridge = a.bridge(&b).await;
+        wait_for_text(&b, "a").await?;
+
+        push(&b, "b").await;
+        wait_for_text(&a, "ab").await?;
+        push(&a, "c").await;
+        wait_for_text(&b, "abc").await?;
+
+        // every change is applied once by each group, without bouncing back
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(a_updates.load(Ordering::Relaxed), 2);
+        assert_eq!(b_updates.load(Ordering::Relaxed), 3);
+
+        drop(bridge);
+        push(&b, "d").await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(wait_for_text(&a, "abcd").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn buffer_high_water_mark_tracks_slow_subscribers(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 8).await;
+        assert_eq!(group.buffer_capacity(), 8);
+        assert_eq!(group.buffer_high_water_mark(), 0);
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let _sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
         {
-            let mut a = awareness.write().await;
-            a.set_local_state(r#"{"key":"value"}"#)
+            // updates are produced faster than subscriber task gets a chance to receive them
+            let lock = awareness.write().await;
+            for i in 0..5 {
+                text.insert(&mut lock.doc().transact_mut(), i, "a");
+            }
         }
+        assert_eq!(group.buffer_len(), 5);
+        assert_eq!(group.buffer_high_water_mark(), 5);
 
-        let msg = client_receiver.next().await;
-        let msg = msg.map(|x| Message::decode_v1(&x.unwrap()).unwrap());
+        for _ in 0..5 {
+            timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        }
+        assert_eq!(group.buffer_len(), 0);
+        assert_eq!(group.buffer_high_water_mark(), 5);
+        assert_eq!(group.reset_buffer_high_water_mark(), 5);
+        assert_eq!(group.buffer_high_water_mark(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prioritized_awareness_overtakes_document_updates(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 64)
+            .await
+            .with_broadcast_priority(BroadcastPriority::AwarenessFirst);
+
+        let (server_sender, mut client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let _sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        // make sure that the subscriber is past its initial awareness snapshot
+        text.push(&mut awareness.write().await.doc().transact_mut(), "a");
+        timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        {
+            let lock = awareness.write().await;
+            for i in 0..50 {
+                text.insert(&mut lock.doc().transact_mut(), i, "a");
+            }
+        }
+        awareness.write().await.set_local_state(r#"{"cursor":50}"#);
+        // let the awareness update be broadcasted, while the subscriber is stuck on a full sink
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut received = Vec::new();
+        while received.len() < 51 {
+            let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+            received.push(Message::decode_v1(&msg.unwrap()?)?);
+        }
+        let position = received
+            .iter()
+            .position(|msg| matches!(msg, Message::Awareness(_)))
+            .unwrap();
+        // only messages already handed over to the sink were sent before it
+        assert!(
+            position <= 2,
+            "awareness update sent as {position}. message"
+        );
+        let updates = received
+            .iter()
+            .filter(|msg| matches!(msg, Message::Sync(SyncMessage::Update(_))))
+            .count();
+        assert_eq!(updates, 50);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn awareness_of_disconnected_peers_is_removed() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 8).await;
+        let (server_sender, mut observer_receiver) = test_channel(8);
+        let (_observer_sender, server_receiver) = test_channel(8);
+        let _observer = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let update = |clock: u32, json: &str| AwarenessUpdate {
+            clients: HashMap::from([(
+                2,
+                AwarenessUpdateEntry {
+                    clock,
+                    json: json.to_string(),
+                },
+            )]),
+        };
+        let connect = |clock: u32| {
+            let (server_sender, client_receiver) = test_channel(8);
+            let (mut client_sender, server_receiver) = test_channel(8);
+            let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+            let msg = Message::Awareness(update(clock, r#"{"name":"peer"}"#)).encode_v1();
+            async move {
+                client_sender.send(msg).await.unwrap();
+                (sub, client_sender, client_receiver)
+            }
+        };
+
+        let (peer, _peer_sender, _peer_receiver) = connect(1).await;
+        let msg = timeout(Duration::from_secs(1), observer_receiver.next()).await?;
         assert_eq!(
-            msg,
-            Some(Message::Awareness(AwarenessUpdate {
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Awareness(update(1, r#"{"name":"peer"}"#))
+        );
+
+        // the same client reconnects before its previous connection is gone
+        let (reconnected, _reconnected_sender, _reconnected_receiver) = connect(2).await;
+        let msg = timeout(Duration::from_secs(1), observer_receiver.next()).await?;
+        assert_eq!(
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Awareness(update(2, r#"{"name":"peer"}"#))
+        );
+        drop(peer);
+        assert!(
+            timeout(Duration::from_millis(100), observer_receiver.next())
+                .await
+                .is_err()
+        );
+        assert_eq!(group.awareness_clients().await, vec![2]);
+
+        drop(reconnected);
+        let msg = timeout(Duration::from_secs(1), observer_receiver.next()).await?;
+        let Message::Awareness(removal) = Message::decode_v1(&msg.unwrap()?)? else {
+            panic!("expected awareness update");
+        };
+        assert_eq!(removal.clients[&2].json, "null");
+        assert!(group.awareness_clients().await.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn builder_applies_settings_in_order() -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::builder()
+            .with_doc(Doc::with_client_id(1))
+            .with_buffer_capacity(4)
+            .with_max_subscribers(2)
+            .with_max_subscribers(1)
+            .build()
+            .await;
+        assert_eq!(group.buffer_capacity(), 4);
+        assert_eq!(group.awareness().read().await.client_id(), 1);
+
+        let (server_sender, _client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let _first = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let (server_sender, _client_receiver) = test_channel(1);
+        let (_client_sender, server_receiver) = test_channel(1);
+        let second = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let res = timeout(Duration::from_secs(1), second.completed()).await?;
+        assert!(matches!(res, Err(crate::error::Error::LimitExceeded(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stalled_peers_dont_hold_awareness_lock() -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 8)
+            .await
+            .with_buffer_overflow_policy(BufferOverflowPolicy::DropOldest);
+
+        // a peer, which never reads replies to its requests
+        let (server_sender, _stalled_receiver) = test_channel(1);
+        let (mut stalled_sender, server_receiver) = test_channel(8);
+        let _stalled = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let sync_step1 = Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1();
+        for _ in 0..3 {
+            stalled_sender.send(sync_step1.clone()).await?;
+        }
+        group
+            .set_local_awareness(serde_json::json!({"name":"server"}))
+            .await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // both reading and writing awareness is possible while the peer is stuck
+        let (server_sender, mut client_receiver) = test_channel(8);
+        let (_client_sender, server_receiver) = test_channel(8);
+        let _client = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        assert!(matches!(
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Awareness(_)
+        ));
+        {
+            let lock = timeout(Duration::from_millis(100), awareness.write()).await?;
+            text.insert(&mut lock.doc().transact_mut(), 0, "abc");
+        }
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        assert!(matches!(
+            Message::decode_v1(&msg.unwrap()?)?,
+            Message::Sync(SyncMessage::Update(_))
+        ));
+        assert!(timeout(Duration::from_millis(100), awareness.read())
+            .await
+            .is_ok());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn empty_callback_fires_when_last_subscriber_leaves(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let group = BroadcastGroup::from_doc(Doc::with_client_id(1), 1).await;
+        let (emptied, mut empties) = tokio::sync::mpsc::unbounded_channel();
+        group.on_empty(move || {
+            let _ = emptied.send(());
+        });
+        let subscribe = || {
+            let (server_sender, client_receiver) = test_channel(1);
+            let (client_sender, server_receiver) = test_channel(1);
+            let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+            (sub, client_sender, client_receiver)
+        };
+
+        let (sub1, client1, _receiver1) = subscribe();
+        let (sub2, client2, _receiver2) = subscribe();
+        drop(client1);
+        timeout(Duration::from_secs(1), sub1.completed()).await??;
+        // there's still one subscriber left
+        assert!(empties.try_recv().is_err());
+
+        drop(client2);
+        timeout(Duration::from_secs(1), sub2.completed()).await??;
+        timeout(Duration::from_secs(1), empties.recv()).await?;
+
+        // the group can become empty again
+        let (sub3, client3, _receiver3) = subscribe();
+        drop(client3);
+        timeout(Duration::from_secs(1), sub3.completed()).await??;
+        timeout(Duration::from_secs(1), empties.recv()).await?;
+        assert!(empties.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn subscriber_count_tracks_active_subscriptions() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 1).await;
+        assert!(group.is_empty());
+
+        let (server_sender1, _client_receiver1) = test_channel(1);
+        let (client_sender1, server_receiver1) = test_channel(1);
+        let sub1 = group.subscribe(Arc::new(Mutex::new(server_sender1)), server_receiver1);
+        let (server_sender2, _client_receiver2) = test_channel(1);
+        let (_client_sender2, server_receiver2) = test_channel(1);
+        let sub2 = group.subscribe(Arc::new(Mutex::new(server_sender2)), server_receiver2);
+        assert_eq!(group.subscriber_count(), 2);
+
+        // closing the incoming stream completes the subscription
+        drop(client_sender1);
+        timeout(Duration::from_secs(1), sub1.completed()).await??;
+        assert_eq!(group.subscriber_count(), 1);
+
+        // dropped subscription is unsubscribed
+        drop(sub2);
+        let mut subscribers = group.subscribers.subscribe();
+        timeout(
+            Duration::from_secs(1),
+            subscribers.wait_for(|count| *count == 0),
+        )
+        .await??;
+        assert!(group.is_empty());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oldest_awareness_entries_are_pruned() -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 10)
+            .await
+            .with_max_awareness_entries(2);
+
+        let (server_sender, _client_receiver) = test_channel(10);
+        let (mut client_sender, server_receiver) = test_channel(10);
+        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        for client_id in [2, 3, 4] {
+            let update = AwarenessUpdate {
                 clients: HashMap::from([(
-                    1,
+                    client_id,
                     AwarenessUpdateEntry {
                         clock: 1,
-                        json: r#"{"key":"value"}"#.to_string(),
+                        json: r#"{"user":"test"}"#.to_string(),
                     },
                 )]),
-            }))
-        );
+            };
+            client_sender
+                .send(Message::Awareness(update).encode_v1())
+                .await?;
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
 
-        // check sync state request/response
+        let awareness = awareness.read().await;
+        let mut clients: Vec<_> = awareness.clients().keys().copied().collect();
+        clients.sort();
+        assert_eq!(clients, vec![3, 4]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_updates_reach_all_peers_once() -> Result<(), Box<dyn std::error::Error>> {
+        let group = Arc::new(BroadcastGroup::from_doc(Doc::with_client_id(1), 8).await);
+        let mut peers = Vec::new();
+        for _ in 0..2 {
+            let (server_sender, client_receiver) = test_channel(8);
+            let (client_sender, server_receiver) = test_channel(8);
+            let sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+            peers.push((sub, client_sender, client_receiver));
+        }
+
+        let agent = Doc::with_client_id(2);
+        let text = agent.get_or_insert_text("test");
+        let update = {
+            let mut txn = agent.transact_mut();
+            text.push(&mut txn, "written by agent");
+            txn.encode_update_v1()
+        };
+        // applied from a spawned task, like a scheduled job would do
+        let applied = {
+            let group = group.clone();
+            let update = update.clone();
+            tokio::spawn(async move { group.apply_update(&update).await })
+        };
+        applied.await??;
         {
-            client_sender
-                .send(Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1())
+            let lock = group.awareness().read().await;
+            let text = lock.doc().get_or_insert_text("test");
+            assert_eq!(text.get_string(&lock.doc().transact()), "written by agent");
+        }
+
+        for (_sub, _sender, receiver) in peers.iter_mut() {
+            let msg = timeout(Duration::from_secs(1), receiver.next()).await?;
+            let msg = Message::decode_v1(&msg.unwrap()?)?;
+            assert_eq!(msg, Message::Sync(SyncMessage::Update(update.clone())));
+            assert!(timeout(Duration::from_millis(100), receiver.next())
+                .await
+                .is_err());
+        }
+
+        let res = group.apply_update(&[0xff, 0xff]).await;
+        assert!(matches!(res, Err(crate::error::Error::Protocol(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn full_state_can_be_requested_mid_session() -> Result<(), Box<dyn std::error::Error>> {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        text.push(&mut doc.transact_mut(), "abc");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 8).await;
+
+        let (server_sender, mut client_receiver) = test_channel(8);
+        let (mut client_sender, server_receiver) = test_channel(8);
+        let _sub = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+        async fn next_step2(
+            client_receiver: &mut ReceiverStream<Vec<u8>>,
+        ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+            loop {
+                let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+                let msg = Message::decode_v1(&msg.ok_or("stream closed")??)?;
+                if let Message::Sync(SyncMessage::SyncStep2(update)) = msg {
+                    return Ok(update);
+                }
+            }
+        }
+
+        let client = Doc::with_client_id(2);
+        let step1 = Message::Sync(SyncMessage::SyncStep1(client.transact().state_vector()));
+        client_sender.send(step1.encode_v1()).await?;
+        let update = next_step2(&mut client_receiver).await?;
+        client
+            .transact_mut()
+            .apply_update(Update::decode_v1(&update)?);
+
+        // updates broadcasted in the meantime never make it to the client
+        for i in 0..3 {
+            let lock = awareness.write().await;
+            text.insert(&mut lock.doc().transact_mut(), i, "x");
+        }
+
+        let full_resync = Message::Sync(SyncMessage::SyncStep1(StateVector::default()));
+        client_sender.send(full_resync.encode_v1()).await?;
+        let update = next_step2(&mut client_receiver).await?;
+        client
+            .transact_mut()
+            .apply_update(Update::decode_v1(&update)?);
+        let lock = awareness.read().await;
+        assert_converged(lock.doc(), &client);
+        // the reply is a complete document state
+        let restored = Doc::with_client_id(3);
+        restored
+            .transact_mut()
+            .apply_update(Update::decode_v1(&update)?);
+        assert_converged(lock.doc(), &restored);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn peers_joining_during_updates_converge() -> Result<(), Box<dyn std::error::Error>> {
+        /// Syncs a peer `doc`, applying all received updates until none arrive for a while.
+        async fn peer(
+            doc: Doc,
+            mut sender: PollSender<Vec<u8>>,
+            mut receiver: ReceiverStream<Vec<u8>>,
+        ) -> Result<Doc, Box<dyn std::error::Error + Send + Sync>> {
+            let sv = doc.transact().state_vector();
+            sender
+                .send(Message::Sync(SyncMessage::SyncStep1(sv)).encode_v1())
                 .await?;
-            let msg = client_receiver.next().await;
-            let msg = msg.map(|x| Message::decode_v1(&x.unwrap()).unwrap());
-            assert_eq!(
-                msg,
-                Some(Message::Sync(SyncMessage::SyncStep2(vec![
-                    1, 1, 1, 0, 4, 1, 4, 116, 101, 115, 116, 1, 97, 0,
-                ])))
-            );
+            let mut synced = false;
+            while let Ok(Some(msg)) = timeout(Duration::from_millis(500), receiver.next()).await {
+                match Message::decode_v1(&msg?)? {
+                    Message::Sync(SyncMessage::SyncStep2(update)) => {
+                        doc.transact_mut().apply_update(Update::decode_v1(&update)?);
+                        synced = true;
+                    }
+                    Message::Sync(SyncMessage::Update(update)) => {
+                        let mut txn = doc.transact_mut();
+                        let before = txn.state_vector();
+                        txn.apply_update(Update::decode_v1(&update)?);
+                        // every update made by the server is new to a peer once it's synced
+                        if synced && txn.state_vector() == before {
+                            return Err("update included in SyncStep2 received again".into());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(doc)
+        }
+
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        let awareness = Arc::new(RwLock::new(Awareness::new(doc)));
+        let group = BroadcastGroup::new(awareness.clone(), 1024).await;
+
+        let mut subs = Vec::new();
+        let mut peers = Vec::new();
+        for i in 0..500 {
+            if i % 50 == 0 {
+                let (server_sender, client_receiver) = test_channel(1024);
+                let (client_sender, server_receiver) = test_channel(1024);
+                let sink = Arc::new(Mutex::new(server_sender));
+                subs.push(group.subscribe(sink, server_receiver));
+                let doc = Doc::with_client_id(100 + i);
+                peers.push(tokio::spawn(peer(doc, client_sender, client_receiver)));
+            }
+            let lock = awareness.write().await;
+            text.insert(&mut lock.doc().transact_mut(), 0, "x");
+            drop(lock);
+            tokio::task::yield_now().await;
         }
 
+        for peer in peers {
+            let doc = peer.await?.map_err(|e| e.to_string())?;
+            let lock = awareness.read().await;
+            assert_converged(lock.doc(), &doc);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sync_step1_requests_within_cooldown_are_ignored(
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(awareness.clone(), 1)
+            .await
+            .with_sync_step1_cooldown(Duration::from_secs(10));
+
+        let (server_sender, mut client_receiver) = test_channel(2);
+        let (mut client_sender, server_receiver) = test_channel(2);
+        let _sub1 = group.subscribe(Arc::new(Mutex::new(server_sender)), server_receiver);
+
+        let step1 = Message::Sync(SyncMessage::SyncStep1(StateVector::default())).encode_v1();
+        client_sender.send(step1.clone()).await?;
+        client_sender.send(step1).await?;
+
+        let msg = timeout(Duration::from_secs(1), client_receiver.next()).await?;
+        let msg = msg.map(|x| Message::decode_v1(&x.unwrap()).unwrap());
+        assert!(matches!(
+            msg,
+            Some(Message::Sync(SyncMessage::SyncStep2(_)))
+        ));
+        // second request has been ignored
+        assert!(timeout(Duration::from_millis(200), client_receiver.next())
+            .await
+            .is_err());
+
         Ok(())
     }
 }
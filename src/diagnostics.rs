@@ -0,0 +1,217 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use yrs::sync::{Awareness, DefaultProtocol, Error, Message, Protocol};
+use yrs::updates::encoder::Encoder;
+use yrs::{ReadTxn, StateVector, Transact, Update};
+
+/// A [Protocol] wrapper, which validates incoming state vectors and updates against the local
+/// document before/after delegating to an inner protocol, and logs detected anomalies.
+///
+/// Anomalies reported by this protocol are:
+///
+/// - A remote state vector claiming more updates authored by the local replica than the local
+///   replica has ever produced. This usually means that the server lost its state or two
+///   replicas are using the same client ID.
+/// - An incoming update which could not be fully integrated, because it depends on blocks that
+///   the local document doesn't have. Such updates are kept pending by yrs and would otherwise
+///   surface only as silently missing content.
+///
+/// Every detected anomaly is logged at `warn` level together with the relevant state vectors and
+/// counted, so that tests can assert that no divergence was observed.
+///
+/// # Example
+///
+/// ```rust
+/// use std::sync::atomic::AtomicUsize;
+/// use std::sync::Arc;
+/// use yrs::sync::DefaultProtocol;
+/// use yrs_axum::diagnostics::DiagnosticProtocol;
+///
+/// // share a single counter among all subscriptions of the same room
+/// let anomalies = Arc::new(AtomicUsize::new(0));
+/// let protocol = DiagnosticProtocol::with_counter(DefaultProtocol, anomalies.clone());
+/// // ... bcast.subscribe_with(sink, stream, protocol);
+/// assert_eq!(protocol.anomalies(), 0);
+/// ```
+#[derive(Debug)]
+pub struct DiagnosticProtocol<P = DefaultProtocol> {
+    inner: P,
+    anomalies: Arc<AtomicUsize>,
+}
+
+impl<P: Protocol> DiagnosticProtocol<P> {
+    /// Wraps an `inner` protocol with diagnostic checks using a fresh anomaly counter.
+    pub fn new(inner: P) -> Self {
+        Self::with_counter(inner, Arc::new(AtomicUsize::new(0)))
+    }
+
+    /// Wraps an `inner` protocol with diagnostic checks, which will increment a provided
+    /// `anomalies` counter whenever a divergence has been detected.
+    pub fn with_counter(inner: P, anomalies: Arc<AtomicUsize>) -> Self {
+        DiagnosticProtocol { inner, anomalies }
+    }
+
+    /// Returns a number of anomalies detected so far.
+    pub fn anomalies(&self) -> usize {
+        self.anomalies.load(Ordering::Acquire)
+    }
+
+    fn report(&self) {
+        self.anomalies.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn check_integrated<F>(
+        &self,
+        awareness: &mut Awareness,
+        apply: F,
+    ) -> Result<Option<Message>, Error>
+    where
+        F: FnOnce(&P, &mut Awareness) -> Result<Option<Message>, Error>,
+    {
+        let missing_before = Self::missing(awareness);
+        let reply = apply(&self.inner, awareness)?;
+        let missing_after = Self::missing(awareness);
+        if let Some(missing) = missing_after {
+            if missing_before.as_ref() != Some(&missing) {
+                let local = awareness.doc().transact().state_vector();
+                tracing::warn!(
+                    "incoming update could not be integrated - missing: {missing:?}, local state vector: {local:?}"
+                );
+                self.report();
+            }
+        }
+        Ok(reply)
+    }
+
+    fn missing(awareness: &Awareness) -> Option<StateVector> {
+        let txn = awareness.doc().transact();
+        let pending = txn.store().pending_update()?;
+        Some(pending.missing.clone())
+    }
+}
+
+impl Default for DiagnosticProtocol<DefaultProtocol> {
+    fn default() -> Self {
+        Self::new(DefaultProtocol)
+    }
+}
+
+impl<P: Protocol> Protocol for DiagnosticProtocol<P> {
+    fn start<E: Encoder>(&self, awareness: &Awareness, encoder: &mut E) -> Result<(), Error> {
+        self.inner.start(awareness, encoder)
+    }
+
+    fn handle_sync_step1(
+        &self,
+        awareness: &Awareness,
+        sv: StateVector,
+    ) -> Result<Option<Message>, Error> {
+        let client_id = awareness.client_id();
+        let local = awareness.doc().transact().state_vector();
+        if sv.get(&client_id) > local.get(&client_id) {
+            tracing::warn!(
+                "remote state vector claims unknown updates of local client {client_id} - remote: {sv:?}, local: {local:?}"
+            );
+            self.report();
+        }
+        self.inner.handle_sync_step1(awareness, sv)
+    }
+
+    fn handle_sync_step2(
+        &self,
+        awareness: &mut Awareness,
+        update: Update,
+    ) -> Result<Option<Message>, Error> {
+        self.check_integrated(awareness, move |p, a| p.handle_sync_step2(a, update))
+    }
+
+    fn handle_update(
+        &self,
+        awareness: &mut Awareness,
+        update: Update,
+    ) -> Result<Option<Message>, Error> {
+        self.check_integrated(awareness, move |p, a| p.handle_update(a, update))
+    }
+
+    fn handle_auth(
+        &self,
+        awareness: &Awareness,
+        deny_reason: Option<String>,
+    ) -> Result<Option<Message>, Error> {
+        self.inner.handle_auth(awareness, deny_reason)
+    }
+
+    fn handle_awareness_query(&self, awareness: &Awareness) -> Result<Option<Message>, Error> {
+        self.inner.handle_awareness_query(awareness)
+    }
+
+    fn handle_awareness_update(
+        &self,
+        awareness: &mut Awareness,
+        update: yrs::sync::AwarenessUpdate,
+    ) -> Result<Option<Message>, Error> {
+        self.inner.handle_awareness_update(awareness, update)
+    }
+
+    fn missing_handle(
+        &self,
+        awareness: &mut Awareness,
+        tag: u8,
+        data: Vec<u8>,
+    ) -> Result<Option<Message>, Error> {
+        self.inner.missing_handle(awareness, tag, data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::diagnostics::DiagnosticProtocol;
+    use yrs::sync::{Awareness, Protocol};
+    use yrs::updates::decoder::Decode;
+    use yrs::{Doc, ReadTxn, StateVector, Text, Transact, Update};
+
+    #[test]
+    fn detects_remote_claiming_unknown_local_updates() {
+        let doc = Doc::with_client_id(1);
+        let text = doc.get_or_insert_text("test");
+        text.push(&mut doc.transact_mut(), "abc");
+        let awareness = Awareness::new(doc);
+        let protocol = DiagnosticProtocol::default();
+
+        let local = awareness.doc().transact().state_vector();
+        protocol.handle_sync_step1(&awareness, local).unwrap();
+        assert_eq!(protocol.anomalies(), 0);
+
+        let mut remote = StateVector::default();
+        remote.set_max(1, 100);
+        protocol.handle_sync_step1(&awareness, remote).unwrap();
+        assert_eq!(protocol.anomalies(), 1);
+    }
+
+    #[test]
+    fn detects_updates_with_missing_dependencies() {
+        let remote = Doc::with_client_id(2);
+        let text = remote.get_or_insert_text("test");
+        text.push(&mut remote.transact_mut(), "abc");
+        let sv = remote.transact().state_vector();
+        text.push(&mut remote.transact_mut(), "def");
+        // only the second insert, which depends on the first one
+        let diff = remote.transact().encode_state_as_update_v1(&sv);
+
+        let mut awareness = Awareness::new(Doc::with_client_id(1));
+        let protocol = DiagnosticProtocol::default();
+        protocol
+            .handle_update(&mut awareness, Update::decode_v1(&diff).unwrap())
+            .unwrap();
+        assert_eq!(protocol.anomalies(), 1);
+
+        // full state fills the gap, no new anomaly is reported
+        let full = remote
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        protocol
+            .handle_sync_step2(&mut awareness, Update::decode_v1(&full).unwrap())
+            .unwrap();
+        assert_eq!(protocol.anomalies(), 1);
+    }
+}
@@ -0,0 +1,346 @@
+use axum::extract::ws::{close_code, CloseFrame};
+use std::fmt::{Display, Formatter};
+
+/// Errors returned by broadcast groups and signaling connections of this crate.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Underlying connection failed to send or receive a message. These are usually transient,
+    /// so the peer can try to reconnect.
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+    /// Peer sent a message, which violates y-sync protocol or couldn't be applied.
+    Protocol(yrs::sync::Error),
+    /// Peer sent a message, which couldn't be deserialized.
+    Serialization(serde_json::Error),
+    /// Peer sent a signaling message, which is not a valid signal. Unlike
+    /// [Error::Serialization], it carries a part of the message and a position of the failure.
+    MalformedSignal(MalformedSignal),
+    /// Connection has been closed or its processing aborted.
+    Closed,
+    /// Peer exceeded one of the configured limits, eg. a message size or rate limit.
+    LimitExceeded(String),
+    /// Subscriber didn't keep up with broadcasted messages and missed a given number of them.
+    Lagged(u64),
+    /// Peer didn't respond within a configured time.
+    Timeout(String),
+    /// Peer announced a protocol version, which is not supported, or didn't announce any while
+    /// a handshake is required.
+    UnsupportedVersion(Option<u32>),
+}
+
+impl Error {
+    /// Checks if this error is transient, like an interrupted or would-block I/O operation of
+    /// a [Error::Transport], so that the connection doesn't need to be closed and sending may
+    /// succeed when retried. All other errors are considered fatal for a connection.
+    pub fn is_recoverable(&self) -> bool {
+        let Error::Transport(e) = self else {
+            return false;
+        };
+        let mut source: Option<&(dyn std::error::Error + 'static)> = Some(e.as_ref());
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<std::io::Error>() {
+                return matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted
+                );
+            }
+            source = e.source();
+        }
+        false
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Transport(e) => write!(f, "transport error: {e}"),
+            Error::Protocol(e) => write!(f, "protocol error: {e}"),
+            Error::Serialization(e) => write!(f, "serialization error: {e}"),
+            Error::MalformedSignal(e) => write!(f, "malformed signal: {e}"),
+            Error::Closed => write!(f, "connection closed"),
+            Error::LimitExceeded(limit) => write!(f, "limit exceeded: {limit}"),
+            Error::Lagged(n) => write!(f, "subscriber lagged behind by {n} messages"),
+            Error::Timeout(reason) => write!(f, "timed out: {reason}"),
+            Error::UnsupportedVersion(Some(version)) => {
+                write!(f, "unsupported protocol version: {version}")
+            }
+            Error::UnsupportedVersion(None) => write!(f, "missing protocol version handshake"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Transport(e) => Some(e.as_ref()),
+            Error::Protocol(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            Error::MalformedSignal(e) => Some(e),
+            Error::Closed
+            | Error::LimitExceeded(_)
+            | Error::Lagged(_)
+            | Error::Timeout(_)
+            | Error::UnsupportedVersion(_) => None,
+        }
+    }
+}
+
+/// A signaling message, which couldn't be deserialized, returned as [Error::MalformedSignal].
+#[derive(Debug)]
+pub struct MalformedSignal {
+    error: serde_json::Error,
+    payload: String,
+    len: usize,
+    offset: usize,
+}
+
+impl MalformedSignal {
+    /// Maximum number of bytes of a malformed payload kept for diagnostics.
+    pub const MAX_PAYLOAD_LEN: usize = 128;
+
+    #[cfg(feature = "signaling")]
+    pub(crate) fn new(error: serde_json::Error, payload: &[u8]) -> Self {
+        let mut end = payload.len().min(Self::MAX_PAYLOAD_LEN);
+        // don't split a multi-byte UTF-8 character
+        while end > 0 && payload.get(end).is_some_and(|&b| b & 0xc0 == 0x80) {
+            end -= 1;
+        }
+        // lines and columns reported by serde_json are 1-based and count bytes
+        let line_start: usize = payload
+            .split(|&b| b == b'\n')
+            .take(error.line().saturating_sub(1))
+            .map(|line| line.len() + 1)
+            .sum();
+        let offset = (line_start + error.column().saturating_sub(1)).min(payload.len());
+        MalformedSignal {
+            error,
+            payload: String::from_utf8_lossy(&payload[..end]).into_owned(),
+            len: payload.len(),
+            offset,
+        }
+    }
+
+    /// Returns a byte offset within a payload, at which deserialization failed. Errors detected
+    /// after parsing a whole value, like a missing field, point at its end.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the beginning of a malformed payload, up to [MalformedSignal::MAX_PAYLOAD_LEN]
+    /// bytes of it, with invalid UTF-8 sequences replaced.
+    pub fn payload(&self) -> &str {
+        &self.payload
+    }
+
+    /// Returns a binary size of a whole malformed payload.
+    pub fn payload_len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns an underlying deserialization error, eg. to check its
+    /// [category](serde_json::Error::classify).
+    pub fn error(&self) -> &serde_json::Error {
+        &self.error
+    }
+}
+
+impl Display for MalformedSignal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} - at byte {} of {} bytes starting with {:?}",
+            self.error, self.offset, self.len, self.payload
+        )
+    }
+}
+
+impl std::error::Error for MalformedSignal {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A reason of a connection being closed by the server, sent to a peer as a code and a short
+/// description of a websocket close frame, so that clients can decide whether to reconnect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum CloseReason {
+    /// Connection has been closed on purpose, eg. via
+    /// [SubscriptionHandle::close](crate::broadcast::SubscriptionHandle::close).
+    Normal,
+    /// Server is shutting down or has closed a group or topic the peer was connected to.
+    GoingAway,
+    /// Peer sent a message violating the protocol.
+    ProtocolError,
+    /// Peer sent a message exceeding the size limit.
+    MessageTooBig,
+    /// Server failed to process or deliver messages.
+    ServerError,
+    /// Peer exceeded one of the configured limits, eg. a rate limit or maximum number of peers.
+    Policy,
+    /// Peer didn't respond to pings in time.
+    Timeout,
+    /// Peer didn't keep up with broadcasted messages.
+    Lagged,
+    /// Peer uses an unsupported protocol version.
+    UnsupportedVersion,
+    /// Connection reached its maximum lifetime and should reconnect, eg. to refresh its
+    /// credentials.
+    Expired,
+    /// Connection didn't send any messages other than pings for longer than an idle timeout.
+    Idle,
+}
+
+impl CloseReason {
+    /// Returns a websocket close code of this reason. Reasons without a standard close code use
+    /// application-specific codes: 4000 for [CloseReason::Timeout], 4001 for
+    /// [CloseReason::Lagged], 4002 for [CloseReason::UnsupportedVersion], 4003 for
+    /// [CloseReason::Expired] and 4004 for [CloseReason::Idle].
+    pub fn code(&self) -> u16 {
+        match self {
+            CloseReason::Normal => close_code::NORMAL,
+            CloseReason::GoingAway => close_code::AWAY,
+            CloseReason::ProtocolError => close_code::PROTOCOL,
+            CloseReason::MessageTooBig => close_code::SIZE,
+            CloseReason::ServerError => close_code::ERROR,
+            CloseReason::Policy => close_code::POLICY,
+            CloseReason::Timeout => 4000,
+            CloseReason::Lagged => 4001,
+            CloseReason::UnsupportedVersion => 4002,
+            CloseReason::Expired => 4003,
+            CloseReason::Idle => 4004,
+        }
+    }
+
+    /// Returns a short description of this reason, sent along with its [CloseReason::code].
+    pub fn reason(&self) -> &'static str {
+        match self {
+            CloseReason::Normal => "closed",
+            CloseReason::GoingAway => "going away",
+            CloseReason::ProtocolError => "protocol error",
+            CloseReason::MessageTooBig => "message too big",
+            CloseReason::ServerError => "server error",
+            CloseReason::Policy => "limit exceeded",
+            CloseReason::Timeout => "timed out",
+            CloseReason::Lagged => "lagged behind",
+            CloseReason::UnsupportedVersion => "unsupported protocol version",
+            CloseReason::Expired => "connection expired",
+            CloseReason::Idle => "connection idle",
+        }
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.reason(), self.code())
+    }
+}
+
+impl From<&Error> for CloseReason {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::Transport(_) => CloseReason::ServerError,
+            Error::Protocol(_) | Error::Serialization(_) | Error::MalformedSignal(_) => {
+                CloseReason::ProtocolError
+            }
+            Error::Closed => CloseReason::Normal,
+            Error::LimitExceeded(_) => CloseReason::Policy,
+            Error::Lagged(_) => CloseReason::Lagged,
+            Error::Timeout(_) => CloseReason::Timeout,
+            Error::UnsupportedVersion(_) => CloseReason::UnsupportedVersion,
+        }
+    }
+}
+
+impl From<CloseReason> for CloseFrame {
+    fn from(reason: CloseReason) -> Self {
+        CloseFrame {
+            code: reason.code(),
+            reason: reason.reason().into(),
+        }
+    }
+}
+
+impl From<axum::Error> for Error {
+    fn from(e: axum::Error) -> Self {
+        Error::Transport(e.into_inner())
+    }
+}
+
+impl From<yrs::sync::Error> for Error {
+    fn from(e: yrs::sync::Error) -> Self {
+        Error::Protocol(e)
+    }
+}
+
+impl From<yrs::encoding::read::Error> for Error {
+    fn from(e: yrs::encoding::read::Error) -> Self {
+        Error::Protocol(e.into())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Serialization(e)
+    }
+}
+
+impl From<Error> for axum::Error {
+    fn from(e: Error) -> Self {
+        axum::Error::new(e)
+    }
+}
+
+impl From<Error> for yrs::sync::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Protocol(e) => e,
+            other => yrs::sync::Error::Other(Box::new(other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    #[cfg(feature = "signaling")]
+    use crate::error::MalformedSignal;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn transient_transport_errors_are_recoverable() {
+        let io = |kind: ErrorKind| std::io::Error::new(kind, "test");
+        assert!(Error::Transport(Box::new(io(ErrorKind::WouldBlock))).is_recoverable());
+        // errors wrapped by a transport, like axum::Error
+        let wrapped = Error::from(axum::Error::new(io(ErrorKind::Interrupted)));
+        assert!(wrapped.is_recoverable());
+
+        assert!(!Error::Transport(Box::new(io(ErrorKind::ConnectionReset))).is_recoverable());
+        assert!(!Error::Closed.is_recoverable());
+        assert!(!Error::Timeout("send".into()).is_recoverable());
+    }
+
+    #[cfg(feature = "signaling")]
+    #[test]
+    fn malformed_signals_point_at_failure() {
+        let parse = |payload: &str| {
+            let e = serde_json::from_str::<serde_json::Value>(payload).unwrap_err();
+            MalformedSignal::new(e, payload.as_bytes())
+        };
+        let e = parse(r#"{"type":"publish","topic":x}"#);
+        assert_eq!(e.offset(), 26);
+        assert_eq!(e.payload(), r#"{"type":"publish","topic":x}"#);
+        assert!(e.to_string().contains("at byte 26 of 28 bytes"), "{e}");
+
+        let e = parse("{\n  \"type\": \"ping\",\n  oops\n}");
+        assert_eq!(e.offset(), 22);
+
+        // long payloads are truncated, without splitting characters
+        let payload = format!("[ \"{}\", x]", "ż".repeat(100));
+        let e = parse(&payload);
+        assert_eq!(e.offset(), payload.find('x').unwrap());
+        assert_eq!(e.payload_len(), payload.len());
+        assert_eq!(e.payload().len(), 127);
+        assert!(payload.starts_with(e.payload()));
+    }
+}
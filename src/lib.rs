@@ -1,9 +1,54 @@
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use yrs::sync::Awareness;
+use yrs::{Doc, Text, Transact};
 
+pub mod auth;
 pub mod broadcast;
 pub mod conn;
+pub mod diagnostics;
+pub mod error;
+pub mod manager;
+pub mod metrics;
+mod rate_limit;
+#[cfg(feature = "signaling")]
 pub mod signaling;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 pub mod ws;
 
 pub type AwarenessRef = Arc<RwLock<yrs::sync::Awareness>>;
+
+/// Wraps a `doc` into a new [AwarenessRef], eg. to be passed to
+/// [BroadcastGroup::new](broadcast::BroadcastGroup::new).
+pub fn new_awareness(doc: Doc) -> AwarenessRef {
+    Arc::new(RwLock::new(Awareness::new(doc)))
+}
+
+/// Creates a new [AwarenessRef] over a new document, which has its text `name` initialized with
+/// `initial` content.
+///
+/// # Example
+///
+/// ```rust
+/// use yrs::{GetString, Transact};
+/// use yrs_axum::broadcast::BroadcastGroup;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let awareness = yrs_axum::new_awareness_with_text("codemirror", "hello world");
+/// let group = BroadcastGroup::new(awareness, 32).await;
+///
+/// let awareness = group.awareness().read().await;
+/// let text = awareness.doc().get_or_insert_text("codemirror");
+/// assert_eq!(text.get_string(&awareness.doc().transact()), "hello world");
+/// # }
+/// ```
+pub fn new_awareness_with_text(name: &str, initial: &str) -> AwarenessRef {
+    let doc = Doc::new();
+    {
+        let text = doc.get_or_insert_text(name);
+        text.push(&mut doc.transact_mut(), initial);
+    }
+    new_awareness(doc)
+}
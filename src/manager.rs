@@ -0,0 +1,551 @@
+use crate::auth::{IdentityProvider, SubscriberId};
+use crate::broadcast::{BroadcastGroup, DisconnectReason};
+use crate::error::Error;
+use crate::ws::{subscribe_identified, AxumSink, AxumStream};
+use axum::extract::ws::{WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, State};
+use axum::http::request::Parts;
+use axum::response::Response;
+use futures_util::future::BoxFuture;
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use yrs::Doc;
+
+/// Default time for which a [BroadcastGroup] without subscribers is kept alive by
+/// [BroadcastManager].
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default buffer capacity of groups created by [BroadcastManager::handler].
+pub const DEFAULT_BUFFER_CAPACITY: usize = 32;
+
+/// A name of a room managed by [BroadcastManager].
+pub type RoomId = Arc<str>;
+
+/// A registry of [BroadcastGroup]s keyed by room name, which allows to serve multiple documents
+/// from a single endpoint.
+///
+/// Groups are created lazily on first request via [BroadcastManager::get_or_create]. Once the
+/// last subscriber of a group disconnects and no new one joins within an idle timeout, the group
+/// is removed from the registry together with its document and awareness.
+///
+/// The simplest way to serve the rooms is a ready-made [BroadcastManager::handler]:
+///
+/// ```rust
+/// use std::sync::Arc;
+/// use axum::{routing::get, Router};
+/// use yrs_axum::manager::BroadcastManager;
+///
+/// let rooms = BroadcastManager::new().with_completion_handler(|room, res| {
+///     if let Err(e) = res {
+///         eprintln!("peer of room '{room}' failed: {e}");
+///     }
+/// });
+/// let app: Router = Router::new()
+///     .route("/{room}", get(BroadcastManager::handler))
+///     .with_state(Arc::new(rooms));
+/// ```
+///
+/// Custom handlers can use the registry directly:
+///
+/// ```rust
+/// use std::sync::Arc;
+/// use axum::extract::ws::{WebSocket, WebSocketUpgrade};
+/// use axum::extract::{Path, State};
+/// use axum::response::IntoResponse;
+/// use futures_util::StreamExt;
+/// use tokio::sync::Mutex;
+/// use yrs_axum::manager::BroadcastManager;
+/// use yrs_axum::ws::{AxumSink, AxumStream};
+///
+/// // app = Router::new().route("/{room}", get(ws_handler)).with_state(Arc::new(BroadcastManager::new()))
+/// async fn ws_handler(
+///     ws: WebSocketUpgrade,
+///     Path(room): Path<String>,
+///     State(rooms): State<Arc<BroadcastManager>>,
+/// ) -> impl IntoResponse {
+///     let bcast = rooms.get_or_create(&room, 32).await;
+///     ws.on_upgrade(move |socket| async move {
+///         let (sink, stream) = socket.split();
+///         let sink = Arc::new(Mutex::new(AxumSink::from(sink)));
+///         let stream = AxumStream::from(stream);
+///         let sub = bcast.subscribe(sink, stream);
+///         let _ = sub.completed().await;
+///     })
+/// }
+/// ```
+#[derive(Debug)]
+pub struct BroadcastManager {
+    rooms: Arc<Mutex<HashMap<RoomId, Room>>>,
+    idle_timeout: Duration,
+    buffer_capacity: usize,
+    on_complete: CompletionHandler,
+    identity: Option<SharedIdentityProvider>,
+}
+
+/// A callback invoked by [BroadcastManager::handler] once a peer connection is finished.
+type CompletionFn = dyn Fn(&str, Result<DisconnectReason, Error>) + Send + Sync;
+
+#[derive(Clone)]
+struct CompletionHandler(Arc<CompletionFn>);
+
+impl Default for CompletionHandler {
+    fn default() -> Self {
+        CompletionHandler(Arc::new(|room_id, res| match res {
+            Ok(reason) => tracing::debug!("peer of room '{room_id}' disconnected: {reason:?}"),
+            Err(e) => tracing::warn!("peer of room '{room_id}' failed: {e}"),
+        }))
+    }
+}
+
+impl Debug for CompletionHandler {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("CompletionHandler")
+    }
+}
+
+/// An object safe counterpart of [IdentityProvider], so that [BroadcastManager] doesn't depend on
+/// a type of its provider.
+trait DynIdentityProvider: Send + Sync {
+    fn identify_boxed<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Option<SubscriberId>>;
+}
+
+impl<P: IdentityProvider> DynIdentityProvider for P {
+    fn identify_boxed<'a>(&'a self, parts: &'a Parts) -> BoxFuture<'a, Option<SubscriberId>> {
+        Box::pin(self.identify(parts))
+    }
+}
+
+/// An [IdentityProvider] set via [BroadcastManager::with_identity_provider].
+#[derive(Clone)]
+struct SharedIdentityProvider(Arc<dyn DynIdentityProvider>);
+
+impl IdentityProvider for SharedIdentityProvider {
+    async fn identify(&self, parts: &Parts) -> Option<SubscriberId> {
+        self.0.identify_boxed(parts).await
+    }
+}
+
+impl Debug for SharedIdentityProvider {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedIdentityProvider")
+    }
+}
+
+struct Room {
+    group: Arc<BroadcastGroup>,
+    evictor: JoinHandle<()>,
+}
+
+impl std::fmt::Debug for Room {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Room")
+            .field("subscribers", &self.group.subscriber_count())
+            .finish()
+    }
+}
+
+impl BroadcastManager {
+    /// Creates a new empty registry, which evicts idle groups after [DEFAULT_IDLE_TIMEOUT].
+    pub fn new() -> Self {
+        BroadcastManager {
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            on_complete: CompletionHandler::default(),
+            identity: None,
+        }
+    }
+
+    /// Sets a buffer capacity of groups created by [BroadcastManager::handler]. Defaults to
+    /// [DEFAULT_BUFFER_CAPACITY]. See [BroadcastGroup::new] for details.
+    pub fn with_buffer_capacity(mut self, buffer_capacity: usize) -> Self {
+        self.buffer_capacity = buffer_capacity;
+        self
+    }
+
+    /// Sets a callback invoked by [BroadcastManager::handler] with a room name and a result of
+    /// a peer connection, once it's finished. By default, the result is logged.
+    pub fn with_completion_handler<F>(mut self, on_complete: F) -> Self
+    where
+        F: Fn(&str, Result<DisconnectReason, Error>) + Send + Sync + 'static,
+    {
+        self.on_complete = CompletionHandler(Arc::new(on_complete));
+        self
+    }
+
+    /// Sets a provider used by [BroadcastManager::handler] to resolve identities of subscribers
+    /// from their upgrade requests - eg. [QueryParam](crate::auth::QueryParam). A resolved identity
+    /// is attached to a subscription, see
+    /// [Subscription::identity](crate::broadcast::Subscription::identity). By default,
+    /// subscribers are anonymous.
+    pub fn with_identity_provider<P>(mut self, provider: P) -> Self
+    where
+        P: IdentityProvider + 'static,
+    {
+        self.identity = Some(SharedIdentityProvider(Arc::new(provider)));
+        self
+    }
+
+    /// An axum handler, which upgrades a request to a web socket connection and subscribes it to
+    /// a broadcast group of a room given as the only path parameter, creating a group if
+    /// necessary. A subscriber is identified by a provider set via
+    /// [BroadcastManager::with_identity_provider]. Once the connection is finished, its result is
+    /// passed to a callback set via [BroadcastManager::with_completion_handler].
+    ///
+    /// It's meant to be routed with a manager as a state, eg.
+    /// `Router::new().route("/{room}", get(BroadcastManager::handler)).with_state(Arc::new(manager))`.
+    pub async fn handler(
+        ws: WebSocketUpgrade,
+        Path(room_id): Path<String>,
+        State(manager): State<Arc<BroadcastManager>>,
+        parts: Parts,
+    ) -> Response {
+        let group = manager
+            .get_or_create(&room_id, manager.buffer_capacity)
+            .await;
+        let on_complete = manager.on_complete.clone();
+        let identity = manager.identity.clone();
+        ws.on_upgrade(move |socket| async move {
+            let res = Self::peer(socket, group, &parts, identity).await;
+            (on_complete.0)(&room_id, res);
+        })
+    }
+
+    async fn peer(
+        socket: WebSocket,
+        group: Arc<BroadcastGroup>,
+        parts: &Parts,
+        identity: Option<SharedIdentityProvider>,
+    ) -> Result<DisconnectReason, Error> {
+        let sub = match identity {
+            Some(provider) => subscribe_identified(&group, socket, parts, &provider).await,
+            None => {
+                let (sink, stream) = socket.split();
+                let sink = Arc::new(Mutex::new(AxumSink::from(sink)));
+                let stream = AxumStream::from(stream);
+                group.subscribe(sink, stream)
+            }
+        };
+        if let Some(identity) = sub.identity() {
+            tracing::trace!("subscriber '{identity}' joined");
+        }
+        sub.completed().await
+    }
+
+    /// Sets a grace period for which a group without subscribers is kept alive, so that clients
+    /// reconnecting shortly after disconnect don't need to resync a fresh document. A zero
+    /// `timeout` evicts groups as soon as their last subscriber disconnects.
+    ///
+    /// This affects only groups created after this call.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Returns a broadcast group of a given `room_id`, creating a new one over an empty document
+    /// if it doesn't exist. A `buffer_capacity` is only used when a new group is being created -
+    /// see [BroadcastGroup::new] for details.
+    ///
+    /// A freshly created group, which doesn't receive any subscribers within an idle timeout, is
+    /// evicted as well.
+    pub async fn get_or_create(
+        &self,
+        room_id: &str,
+        buffer_capacity: usize,
+    ) -> Arc<BroadcastGroup> {
+        let mut rooms = self.rooms.lock().await;
+        if let Some(room) = rooms.get(room_id) {
+            return room.group.clone();
+        }
+        let group = Arc::new(BroadcastGroup::from_doc(Doc::new(), buffer_capacity).await);
+        let room_id: RoomId = room_id.into();
+        let evictor = tokio::spawn(Self::evict_when_idle(
+            Arc::downgrade(&self.rooms),
+            room_id.clone(),
+            group.watch_subscribers(),
+            self.idle_timeout,
+        ));
+        tracing::trace!("created broadcast group for room '{room_id}'");
+        rooms.insert(
+            room_id,
+            Room {
+                group: group.clone(),
+                evictor,
+            },
+        );
+        group
+    }
+
+    /// Returns a broadcast group of a given `room_id` if it exists.
+    pub async fn get(&self, room_id: &str) -> Option<Arc<BroadcastGroup>> {
+        let rooms = self.rooms.lock().await;
+        rooms.get(room_id).map(|room| room.group.clone())
+    }
+
+    /// Returns a snapshot of all rooms currently managed by this registry.
+    pub async fn rooms(&self) -> impl Iterator<Item = (RoomId, Arc<BroadcastGroup>)> {
+        let rooms = self.rooms.lock().await;
+        let snapshot: Vec<_> = rooms
+            .iter()
+            .map(|(room_id, room)| (room_id.clone(), room.group.clone()))
+            .collect();
+        snapshot.into_iter()
+    }
+
+    /// Returns rooms, which IDs start with a given `prefix` - eg. all rooms of a namespace like
+    /// `project-123/` - together with their current subscriber count. Rooms are listed while the
+    /// registry is locked, so that rooms being created or evicted at the same time are either
+    /// fully included or not at all.
+    pub async fn rooms_with_prefix(&self, prefix: &str) -> Vec<(RoomId, usize)> {
+        let rooms = self.rooms.lock().await;
+        rooms
+            .iter()
+            .filter(|(room_id, _)| room_id.starts_with(prefix))
+            .map(|(room_id, room)| (room_id.clone(), room.group.subscriber_count()))
+            .collect()
+    }
+
+    /// Encodes documents of all rooms currently managed by this registry as binary v1 updates,
+    /// eg. to persist them at shutdown. Every update can be restored via
+    /// [BroadcastGroup::from_state].
+    ///
+    /// The registry is locked only for as long as it takes to list rooms, so new connections
+    /// aren't blocked while documents are being encoded. Each room takes a read lock on its own
+    /// awareness - see [BroadcastGroup::encode_state_as_update]. Rooms created in the meantime are
+    /// not included.
+    pub async fn snapshot_all(&self) -> Vec<(RoomId, Vec<u8>)> {
+        let mut snapshots = Vec::new();
+        for (room_id, group) in self.rooms().await {
+            let update = group.encode_state_as_update().await;
+            snapshots.push((room_id, update));
+        }
+        snapshots
+    }
+
+    async fn evict_when_idle(
+        rooms: Weak<Mutex<HashMap<RoomId, Room>>>,
+        room_id: RoomId,
+        mut subscribers: watch::Receiver<usize>,
+        idle_timeout: Duration,
+    ) {
+        loop {
+            if subscribers.wait_for(|count| *count == 0).await.is_err() {
+                return;
+            }
+            select! {
+                _ = sleep(idle_timeout) => {}
+                res = subscribers.wait_for(|count| *count > 0) => {
+                    if res.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+            }
+            let Some(rooms) = rooms.upgrade() else {
+                return;
+            };
+            let mut rooms = rooms.lock().await;
+            if let Some(room) = rooms.get(&room_id) {
+                // group handed out by get_or_create, which has not been subscribed to yet
+                let in_use = Arc::strong_count(&room.group) > 1;
+                if room.group.is_empty() && !in_use {
+                    tracing::trace!("evicting idle broadcast group of room '{room_id}'");
+                    rooms.remove(&room_id);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Default for BroadcastManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Room {
+    fn drop(&mut self) {
+        self.evictor.abort();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::auth::{IdentityProvider, QueryParam, SubscriberId};
+    use crate::manager::BroadcastManager;
+    use axum::http::request::Parts;
+    use axum::routing::get;
+    use axum::Router;
+    use futures_util::{stream, Stream};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, Mutex};
+    use tokio::time::{sleep, timeout};
+    use yrs::updates::decoder::Decode;
+    use yrs::{Doc, GetString, Text, Transact, Update};
+
+    /// Returns a stream of incoming messages, which completes once returned sender is dropped.
+    fn incoming() -> (
+        mpsc::Sender<Vec<u8>>,
+        impl Stream<Item = Result<Vec<u8>, std::io::Error>> + Send + Sync + Unpin + 'static,
+    ) {
+        let (tx, rx) = mpsc::channel(1);
+        let stream = stream::unfold(rx, |mut rx| async move {
+            let msg = rx.recv().await?;
+            Some((Ok(msg), rx))
+        });
+        (tx, Box::pin(stream))
+    }
+
+    #[tokio::test]
+    async fn groups_are_shared_per_room() {
+        let manager = BroadcastManager::new();
+        let a1 = manager.get_or_create("a", 1).await;
+        let a2 = manager.get_or_create("a", 1).await;
+        let b = manager.get_or_create("b", 1).await;
+        assert!(Arc::ptr_eq(&a1, &a2));
+        assert!(!Arc::ptr_eq(&a1, &b));
+
+        let mut rooms: Vec<_> = manager.rooms().await.map(|(id, _)| id).collect();
+        rooms.sort();
+        assert_eq!(rooms, vec!["a".into(), "b".into()] as Vec<Arc<str>>);
+    }
+
+    #[tokio::test]
+    async fn rooms_are_listed_by_prefix() {
+        let manager = BroadcastManager::new();
+        for room_id in ["project-1/a", "project-1/b", "project-10/c", "other"] {
+            manager.get_or_create(room_id, 1).await;
+        }
+        let group = manager.get_or_create("project-1/b", 1).await;
+        let (_client, stream) = incoming();
+        let _sub = group.subscribe(Arc::new(Mutex::new(futures_util::sink::drain())), stream);
+
+        let mut rooms = manager.rooms_with_prefix("project-1/").await;
+        rooms.sort();
+        let expected: Vec<(Arc<str>, usize)> =
+            vec![("project-1/a".into(), 0), ("project-1/b".into(), 1)];
+        assert_eq!(rooms, expected);
+        assert_eq!(manager.rooms_with_prefix("").await.len(), 4);
+        assert!(manager.rooms_with_prefix("missing/").await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn all_rooms_are_snapshotted() {
+        let manager = BroadcastManager::new();
+        for (room_id, content) in [("a", "hello"), ("b", "world")] {
+            let group = manager.get_or_create(room_id, 1).await;
+            let awareness = group.awareness().write().await;
+            let text = awareness.doc().get_or_insert_text("text");
+            text.push(&mut awareness.doc().transact_mut(), content);
+        }
+
+        let mut snapshots = manager.snapshot_all().await;
+        snapshots.sort();
+        assert_eq!(snapshots.len(), 2);
+        for ((room_id, update), (expected_id, expected)) in
+            snapshots.into_iter().zip([("a", "hello"), ("b", "world")])
+        {
+            assert_eq!(&*room_id, expected_id);
+            let restored = Doc::new();
+            let update = Update::decode_v1(&update).unwrap();
+            restored.transact_mut().apply_update(update);
+            let text = restored.get_or_insert_text("text");
+            assert_eq!(text.get_string(&restored.transact()), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_groups_are_evicted() -> Result<(), Box<dyn std::error::Error>> {
+        let manager = BroadcastManager::new().with_idle_timeout(Duration::from_millis(100));
+        let group = manager.get_or_create("room", 1).await;
+        let (client, stream) = incoming();
+        let sub = group.subscribe(Arc::new(Mutex::new(futures_util::sink::drain())), stream);
+        drop(group);
+
+        // group is kept alive while it has subscribers
+        sleep(Duration::from_millis(200)).await;
+        assert!(manager.get("room").await.is_some());
+
+        drop(client);
+        timeout(Duration::from_secs(1), sub.completed()).await??;
+        // and for the idle timeout after the last one has left
+        assert!(manager.get("room").await.is_some());
+        sleep(Duration::from_millis(200)).await;
+        assert!(manager.get("room").await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handler_serves_rooms_by_path() -> Result<(), Box<dyn std::error::Error>> {
+        let (completed, mut completions) = mpsc::unbounded_channel();
+        let manager = Arc::new(BroadcastManager::new().with_completion_handler(
+            move |room, res| {
+                let _ = completed.send((room.to_string(), res.is_ok()));
+            },
+        ));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:16800").await?;
+        let app = Router::new()
+            .route("/{room}", get(BroadcastManager::handler))
+            .with_state(manager.clone());
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let (mut ws, _) = tokio_tungstenite::connect_async("ws://127.0.0.1:16800/room-a").await?;
+        let group = manager.get("room-a").await.expect("room has been created");
+        timeout(Duration::from_secs(1), group.first_subscriber()).await?;
+        assert!(manager.get("room-b").await.is_none());
+
+        ws.close(None).await?;
+        let completion = timeout(Duration::from_secs(1), completions.recv()).await?;
+        assert_eq!(completion, Some(("room-a".to_string(), true)));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handler_identifies_subscribers() -> Result<(), Box<dyn std::error::Error>> {
+        struct Recording(mpsc::UnboundedSender<Option<SubscriberId>>);
+
+        impl IdentityProvider for Recording {
+            async fn identify(&self, parts: &Parts) -> Option<SubscriberId> {
+                let identity = QueryParam::new("user").identify(parts).await;
+                let _ = self.0.send(identity.clone());
+                identity
+            }
+        }
+
+        let (identified, mut identities) = mpsc::unbounded_channel();
+        let manager =
+            Arc::new(BroadcastManager::new().with_identity_provider(Recording(identified)));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:16801").await?;
+        let app = Router::new()
+            .route("/{room}", get(BroadcastManager::handler))
+            .with_state(manager.clone());
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let (_ws, _) =
+            tokio_tungstenite::connect_async("ws://127.0.0.1:16801/room?user=bob").await?;
+        let identity = timeout(Duration::from_secs(1), identities.recv()).await?;
+        assert_eq!(identity, Some(Some(SubscriberId::new("bob"))));
+        let group = manager.get("room").await.expect("room has been created");
+        timeout(Duration::from_secs(1), group.first_subscriber()).await?;
+
+        Ok(())
+    }
+}
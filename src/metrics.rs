@@ -0,0 +1,95 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// A recorder of connection and traffic metrics, which can be plugged into a
+/// [BroadcastGroup](crate::broadcast::BroadcastGroup) or
+/// [SignalingService](crate::signaling::SignalingService) in order to forward them to an external
+/// metrics system, like the `metrics` or `prometheus` crates.
+///
+/// All methods have an empty default implementation, so that only the relevant ones need to be
+/// implemented. They're called inline from connection tasks, so they should be cheap and must not
+/// block.
+///
+/// # Example
+///
+/// ```rust
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+/// use yrs_axum::broadcast::BroadcastGroup;
+/// use yrs_axum::metrics::Metrics;
+///
+/// #[derive(Default)]
+/// struct Connections(AtomicUsize);
+///
+/// impl Metrics for Connections {
+///     fn on_connect(&self) {
+///         self.0.fetch_add(1, Ordering::Relaxed);
+///     }
+///
+///     fn on_disconnect(&self) {
+///         self.0.fetch_sub(1, Ordering::Relaxed);
+///     }
+/// }
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let connections = Arc::new(Connections::default());
+/// let group = BroadcastGroup::builder()
+///     .with_metrics(connections.clone())
+///     .build()
+///     .await;
+/// # }
+/// ```
+pub trait Metrics: Send + Sync {
+    /// Called when a new connection has been subscribed to a broadcast group or accepted by
+    /// a signaling service.
+    fn on_connect(&self) {}
+
+    /// Called when a connection has been closed, failed or dropped.
+    fn on_disconnect(&self) {}
+
+    /// Called for every data message of a given binary size received from or sent to a peer.
+    fn on_message(&self, _direction: Direction, _bytes: usize) {}
+
+    /// Called every time a message couldn't be delivered to one of the peers it was published
+    /// or broadcasted to.
+    fn on_publish_fail(&self) {}
+}
+
+impl Debug for dyn Metrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Metrics")
+    }
+}
+
+/// A direction of a message reported via [Metrics::on_message].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    /// Message received from a peer.
+    Inbound,
+    /// Message sent to a peer.
+    Outbound,
+}
+
+/// A [Metrics] implementation, which doesn't record anything. Used by default.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+/// Reports a connection via [Metrics::on_connect] for as long as it's alive, and
+/// [Metrics::on_disconnect] once it's dropped - including when a connection task gets aborted.
+pub(crate) struct ConnectionMetrics(Arc<dyn Metrics>);
+
+impl ConnectionMetrics {
+    pub(crate) fn new(metrics: Arc<dyn Metrics>) -> Self {
+        metrics.on_connect();
+        ConnectionMetrics(metrics)
+    }
+}
+
+impl Drop for ConnectionMetrics {
+    fn drop(&mut self) {
+        self.0.on_disconnect();
+    }
+}
@@ -0,0 +1,64 @@
+use tokio::time::Instant;
+
+/// A token bucket holding up to `rate` tokens, which are refilled at `rate` tokens per second.
+/// Bucket with zero rate never runs out of tokens.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: u32,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: u32) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub(crate) fn try_acquire(&mut self) -> bool {
+        self.try_acquire_many(1)
+    }
+
+    /// Takes `n` tokens from the bucket, if it holds enough of them. Requests for more tokens than
+    /// the bucket can ever hold succeed once it's full, leaving it in debt, so that they're still
+    /// limited to the `rate` on average instead of never passing.
+    pub(crate) fn try_acquire_many(&mut self, n: usize) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+        let rate = self.rate as f64;
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        let n = n as f64;
+        if self.tokens >= n.min(rate) {
+            self.tokens -= n;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rate_limit::TokenBucket;
+
+    #[test]
+    fn oversized_requests_pass_when_bucket_is_full() {
+        let mut bucket = TokenBucket::new(10);
+        assert!(bucket.try_acquire_many(4));
+        assert!(!bucket.try_acquire_many(25));
+        let mut bucket = TokenBucket::new(10);
+        assert!(bucket.try_acquire_many(25));
+        // bucket is in debt now
+        assert!(!bucket.try_acquire());
+
+        let mut unlimited = TokenBucket::new(0);
+        assert!(unlimited.try_acquire_many(usize::MAX));
+    }
+}
@@ -1,18 +1,46 @@
+use crate::error::{CloseReason, Error, MalformedSignal};
+use crate::metrics::{ConnectionMetrics, Direction, Metrics, NoopMetrics};
+use crate::rate_limit::TokenBucket;
+use axum::http::Extensions;
 use bytes::Bytes;
+use futures_util::future::{join_all, BoxFuture};
 use futures_util::stream::SplitSink;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::{FutureExt, SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
 use std::collections::{HashMap, HashSet};
-use std::hash::{Hash, Hasher};
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::select;
 use tokio::sync::{Mutex, RwLock};
-use tokio::time::interval;
-use axum::Error;
+use tokio::time::{interval, interval_at, timeout, Instant, Interval};
+use tracing::Instrument;
 use axum::extract::ws::{Message, WebSocket};
 
-const PING_TIMEOUT: Duration = Duration::from_secs(30);
+/// Default interval in which signaling connections are pinged. Peers which didn't respond to
+/// a previous ping until the next one is due are disconnected.
+pub const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default maximum time a message can take to be sent to a single peer. See
+/// [SignalingService::with_send_timeout].
+pub const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Default time between notifying subscribers of a drained topic and closing their connections.
+/// See [SignalingService::drain_topic].
+pub const DEFAULT_DRAIN_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Default maximum length of topic names in bytes. See [SignalingService::with_max_topic_length].
+pub const DEFAULT_MAX_TOPIC_LENGTH: usize = 256;
+
+/// Websocket subprotocol of connections exchanging MessagePack encoded signals. See
+/// [SignalFormat].
+pub const MSGPACK_PROTOCOL: &str = "y-webrtc.msgpack";
 
 /// Signaling service is used by y-webrtc protocol in order to exchange WebRTC offerings between
 /// clients subscribing to particular rooms.
@@ -70,68 +98,484 @@ const PING_TIMEOUT: Duration = Duration::from_secs(30);
 /// }
 /// ```
 #[derive(Debug, Clone)]
-pub struct SignalingService(Topics);
+pub struct SignalingService {
+    topics: Topics,
+    ping_timeout: Duration,
+    ping_jitter: Duration,
+    send_timeout: Duration,
+    drain_grace_period: Duration,
+    max_lifetime: Duration,
+    idle_timeout: Duration,
+    limits: Limits,
+    metrics: Arc<dyn Metrics>,
+    authorizer: Option<TopicAuthorizer>,
+    namespace: Namespace,
+    supported_versions: Option<Arc<[u32]>>,
+    publisher_echo: bool,
+    strict_signal_types: bool,
+    wildcard_topics: bool,
+    counters: Arc<Counters>,
+}
 
 impl SignalingService {
     pub fn new() -> Self {
-        SignalingService(Arc::new(RwLock::new(Default::default())))
+        SignalingService {
+            topics: Arc::new(RwLock::new(Default::default())),
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            ping_jitter: Duration::ZERO,
+            send_timeout: DEFAULT_SEND_TIMEOUT,
+            drain_grace_period: DEFAULT_DRAIN_GRACE_PERIOD,
+            max_lifetime: Duration::ZERO,
+            idle_timeout: Duration::ZERO,
+            limits: Limits {
+                max_topic_length: DEFAULT_MAX_TOPIC_LENGTH,
+                ..Limits::default()
+            },
+            metrics: Arc::new(NoopMetrics),
+            authorizer: None,
+            namespace: Namespace::default(),
+            supported_versions: None,
+            publisher_echo: true,
+            strict_signal_types: true,
+            wildcard_topics: false,
+            counters: Arc::new(Counters {
+                connections: AtomicUsize::new(0),
+                published: AtomicU64::new(0),
+                started: Instant::now(),
+                activity: Default::default(),
+            }),
+        }
+    }
+
+    /// Returns a service sharing topics and connections with this one, whose topic names are
+    /// isolated within a given `namespace`. This way independent applications can be served by
+    /// the same signaling service: a "room" topic of one namespace never receives messages
+    /// published on a "room" topic of another. Clients keep using plain topic names.
+    ///
+    /// Namespaces can be nested and must not contain control characters. Topic limits - like
+    /// [SignalingService::with_max_topics] - apply to all namespaces together, while
+    /// [SignalingService::topics] and [SignalingService::topic_count] only report topics of
+    /// a namespace they're called on.
+    pub fn namespaced(&self, namespace: &str) -> Self {
+        let mut svc = self.clone();
+        svc.namespace = self.namespace.nested(namespace);
+        svc
+    }
+
+    /// Sets an `authorizer` deciding whether a connection may subscribe or publish to a given
+    /// topic. It's called with a requested [TopicAction], a topic name and connection-scoped
+    /// extensions passed to [signaling_conn_with], eg. an authenticated user ID. Unauthorized
+    /// subscriptions are ignored and unauthorized publishes are not forwarded to other peers,
+    /// while the connection itself stays open.
+    ///
+    /// By default, all topics are accessible to all connections.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use yrs_axum::signaling::SignalingService;
+    ///
+    /// #[derive(Clone)]
+    /// struct User {
+    ///     room_prefix: String,
+    /// }
+    ///
+    /// let svc = SignalingService::new().with_topic_authorizer(|_action, topic, extensions| async move {
+    ///     match extensions.get::<User>() {
+    ///         Some(user) => topic.starts_with(&user.room_prefix),
+    ///         None => false,
+    ///     }
+    /// });
+    /// ```
+    pub fn with_topic_authorizer<F, Fut>(mut self, authorizer: F) -> Self
+    where
+        F: Fn(TopicAction, Arc<str>, Arc<Extensions>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        self.authorizer = Some(TopicAuthorizer(Arc::new(
+            move |action, topic, extensions| authorizer(action, topic, extensions).boxed(),
+        )));
+        self
+    }
+
+    /// Requires every connection to start with a [Signal::Hello] handshake announcing one of
+    /// the supported protocol `versions`, so that clients sending incompatible signals are
+    /// rejected upfront. A connection sending any other signal first, or announcing an unsupported
+    /// version, is closed with [CloseReason::UnsupportedVersion] and [signaling_conn] returns
+    /// [Error::UnsupportedVersion].
+    ///
+    /// By default no handshake is required, so that existing y-webrtc clients can connect.
+    /// A handshake sent anyway is accepted with any version then.
+    pub fn with_supported_versions<I: IntoIterator<Item = u32>>(mut self, versions: I) -> Self {
+        self.supported_versions = Some(versions.into_iter().collect());
+        self
+    }
+
+    /// Sets whether a message published by a connection is sent back to that connection too, in
+    /// case it's subscribed to the same topic. Disabling this avoids echo loops of clients, which
+    /// subscribe and publish on the same topic. Enabled by default.
+    pub fn with_publisher_echo(mut self, echo: bool) -> Self {
+        self.publisher_echo = echo;
+        self
+    }
+
+    /// Sets whether a connection sending a signal of an unknown `type` is closed with
+    /// [CloseReason::ProtocolError], like on any other malformed signal, which helps to catch
+    /// client bugs early. Otherwise such signals are logged and ignored, so that clients using
+    /// newer protocol extensions can still connect. Enabled by default.
+    ///
+    /// Signals of a known type are always validated, eg. a `publish` signal without a `topic`
+    /// closes a connection either way.
+    pub fn with_strict_signal_types(mut self, strict: bool) -> Self {
+        self.strict_signal_types = strict;
+        self
+    }
+
+    /// Sets whether connections can subscribe to all topics starting with a given prefix, by
+    /// subscribing to a pattern ending with [WILDCARD], eg. `project:123:*` to receive signals
+    /// published on all rooms of a project. Every signal is delivered once to a connection, even
+    /// if it matches several of its subscriptions. Disabled by default, when `*` is a regular
+    /// character of topic names.
+    ///
+    /// Patterns are authorized and unsubscribed like regular topics, and count against topic
+    /// limits. Signals published by peers on a pattern itself are dropped. Once enabled, every
+    /// publish scans all tracked topics for matching patterns, so it's more expensive with many
+    /// topics.
+    pub fn with_wildcard_topics(mut self, enabled: bool) -> Self {
+        self.wildcard_topics = enabled;
+        self
+    }
+
+    /// Sets a recorder of metrics like accepted connections, exchanged signals and failed
+    /// publishes of this service. By default, no metrics are recorded.
+    pub fn with_metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Limits the number of topics a single connection can be subscribed to at the same time.
+    /// Requests to subscribe to new topics over that limit are ignored, while existing
+    /// subscriptions keep working. By default, and when `max` is zero, there's no limit.
+    pub fn with_max_topics_per_connection(mut self, max: usize) -> Self {
+        self.limits.max_topics_per_connection = max;
+        self
+    }
+
+    /// Limits the number of distinct topics tracked by this service. Requests to subscribe to
+    /// topics, which don't exist yet, are ignored when the limit has been reached. By default, and
+    /// when `max` is zero, there's no limit.
+    pub fn with_max_topics(mut self, max: usize) -> Self {
+        self.limits.max_topics = max;
+        self
+    }
+
+    /// Sets a maximum length of topic names in bytes, defaults to [DEFAULT_MAX_TOPIC_LENGTH]. Zero
+    /// `max` means no limit. Subscriptions and publishes to topics over that limit, as well as
+    /// ones containing control characters, are ignored.
+    pub fn with_max_topic_length(mut self, max: usize) -> Self {
+        self.limits.max_topic_length = max;
+        self
+    }
+
+    /// Limits a number of publish signals a single connection can send per second. Publishes over
+    /// the `rate` are dropped, while a connection exceeding the `ceiling` of publishes per second,
+    /// including the dropped ones, is closed. Short bursts of up to `rate`/`ceiling` publishes are
+    /// allowed. By default, and when set to zero, neither is limited.
+    pub fn with_publish_rate_limit(mut self, rate: u32, ceiling: u32) -> Self {
+        self.limits.publish_rate = rate;
+        self.limits.publish_ceiling = ceiling;
+        self
+    }
+
+    /// Sets an interval in which connections of this service are pinged - peers which didn't
+    /// respond to a ping within this time are disconnected. Defaults to [DEFAULT_PING_TIMEOUT].
+    ///
+    /// A zero `timeout` disables pings entirely.
+    ///
+    /// Pings, like all other timers of this service, are driven by the clock of a tokio runtime,
+    /// so tests can advance them deterministically via `tokio::time::pause` and
+    /// `tokio::time::advance` instead of sleeping, once connections have been established.
+    pub fn with_ping_timeout(mut self, timeout: Duration) -> Self {
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Delays the first ping of every connection by a random time of up to `jitter`, so that
+    /// connections accepted at the same time - eg. all clients reconnecting after a restart -
+    /// don't get pinged in synchronized bursts every [SignalingService::with_ping_timeout].
+    /// A `jitter` equal to the ping timeout spreads pings evenly over it.
+    ///
+    /// By default there's no jitter: connections are pinged right after being accepted, and
+    /// then once per ping timeout.
+    pub fn with_ping_jitter(mut self, jitter: Duration) -> Self {
+        self.ping_jitter = jitter;
+        self
+    }
+
+    /// Sets a maximum time a connection stays open, in order to make clients reconnect
+    /// periodically - eg. to pick up renewed auth tokens or to rebalance connections across
+    /// servers. Once it elapses, a connection is unsubscribed from all its topics and closed with
+    /// [CloseReason::Expired], while [signaling_conn] returns `Ok`.
+    ///
+    /// A zero `lifetime`, which is the default, means no limit.
+    pub fn with_max_connection_lifetime(mut self, lifetime: Duration) -> Self {
+        self.max_lifetime = lifetime;
+        self
+    }
+
+    /// Sets a time after which a connection, which didn't send any signals, is considered idle:
+    /// it's unsubscribed from all its topics and closed with [CloseReason::Idle], while
+    /// [signaling_conn] returns `Ok`. Websocket pings and pongs, as well as [Signal::Ping] and
+    /// [Signal::Pong] keepalives, don't count as activity, so that connections kept open by
+    /// them alone - eg. of abandoned browser tabs - get closed.
+    ///
+    /// A zero `timeout`, which is the default, disables it.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Sets a maximum time a message can take to be sent to a single peer, defaults to
+    /// [DEFAULT_SEND_TIMEOUT]. A peer, which doesn't receive it in time - eg. because its socket
+    /// is wedged - is considered failed: it's closed and unsubscribed from a published topic,
+    /// so that it doesn't hold up delivery to other peers. Zero `timeout` means no limit.
+    pub fn with_send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = timeout;
+        self
+    }
+
+    /// Sets a time [SignalingService::drain_topic] waits after notifying subscribers of a topic,
+    /// before closing their connections. Defaults to [DEFAULT_DRAIN_GRACE_PERIOD].
+    pub fn with_drain_grace_period(mut self, period: Duration) -> Self {
+        self.drain_grace_period = period;
+        self
+    }
+
+    /// Returns all topics tracked by this service together with their current subscriber count.
+    pub async fn topics(&self) -> Vec<(Arc<str>, usize)> {
+        let topics = self.topics.read().await;
+        topics
+            .iter()
+            .filter_map(|(key, subs)| Some((self.namespace.topic(key)?, subs.len())))
+            .collect()
+    }
+
+    /// Returns topics of this service, which a connection with a given `conn_id` is currently
+    /// subscribed to. Unknown and closed connections aren't subscribed to any topics.
+    ///
+    /// This is meant for diagnosing clients, which don't receive expected messages. A connection
+    /// ID is attached to all tracing events of a connection as `conn_id`, and passed to a topic
+    /// authorizer among connection extensions - see [SignalingService::with_topic_authorizer].
+    pub async fn connection_topics(&self, conn_id: ConnectionId) -> Vec<Arc<str>> {
+        let topics = self.topics.read().await;
+        topics
+            .iter()
+            .filter(|(_, subs)| subs.iter().any(|sub| sub.id() == conn_id))
+            .filter_map(|(key, _)| self.namespace.topic(key))
+            .collect()
+    }
+
+    /// Returns a time of the last signal other than a keepalive received from a connection with
+    /// a given `conn_id` - or of connecting, if it hasn't sent any yet. Returns `None` for unknown
+    /// and closed connections. See [SignalingService::with_idle_timeout].
+    ///
+    /// Connections are shared by all clones and [namespaces](SignalingService::namespaced) of this
+    /// service.
+    pub fn connection_last_activity(&self, conn_id: ConnectionId) -> Option<Instant> {
+        let activity = self.counters.activity.lock().unwrap();
+        activity.get(&conn_id).copied()
+    }
+
+    /// Removes all topics without subscribers, returning a number of removed topics. Topics are
+    /// removed once their last subscriber leaves, but this can be used to clean up topics left
+    /// empty because of failed connections.
+    pub async fn prune_empty_topics(&self) -> usize {
+        prune_empty_topics(&self.topics).await
+    }
+
+    /// Starts a background task, which removes topics without subscribers every `period` - see
+    /// [SignalingService::prune_empty_topics]. The task stops once this service and all its clones
+    /// have been dropped. Must be called from within a tokio runtime.
+    pub fn with_topic_sweeper(self, period: Duration) -> Self {
+        let topics = Arc::downgrade(&self.topics);
+        tokio::spawn(async move {
+            let mut interval = interval(period);
+            loop {
+                interval.tick().await;
+                let Some(topics) = topics.upgrade() else {
+                    return;
+                };
+                let pruned = prune_empty_topics(&topics).await;
+                if pruned > 0 {
+                    tracing::debug!("pruned {pruned} empty signaling topics");
+                }
+            }
+        });
+        self
+    }
+
+    /// Returns a number of topics tracked by this service.
+    pub async fn topic_count(&self) -> usize {
+        let topics = self.topics.read().await;
+        topics
+            .keys()
+            .filter(|key| self.namespace.strip(key).is_some())
+            .count()
+    }
+
+    /// Returns statistics of this service, like a number of active connections. Connection and
+    /// message counters are shared by all clones and [namespaces](SignalingService::namespaced) of
+    /// this service, while a number of topics is reported only for a namespace it's called on.
+    pub async fn stats(&self) -> SignalingStats {
+        SignalingStats {
+            connections: self.counters.connections.load(Ordering::Relaxed),
+            topics: self.topic_count().await,
+            published: self.counters.published.load(Ordering::Relaxed),
+            uptime: self.counters.started.elapsed(),
+        }
     }
 
     pub async fn publish(&self, topic: &str, msg: Message) -> Result<(), Error> {
+        self.publish_acked(topic, msg).await?;
+        Ok(())
+    }
+
+    /// Publishes a message to all connections subscribed to a `topic` like
+    /// [SignalingService::publish], returning a report of how many of them actually received it.
+    /// Connections that failed to receive the message with a fatal error are unsubscribed from
+    /// a `topic`, while ones with a [recoverable](Error::is_recoverable) error are kept.
+    pub async fn publish_acked(&self, topic: &str, msg: Message) -> Result<PublishReport, Error> {
+        self.publish_many(&[topic], msg).await
+    }
+
+    /// Publishes a message to all connections subscribed to any of given `topics` at once, like
+    /// [SignalingService::publish_acked]. Connections subscribed to several of these topics
+    /// receive the message only once and are counted once in a returned report. Connections that
+    /// failed to receive the message with a fatal error are unsubscribed from all given `topics`.
+    #[allow(clippy::mutable_key_type)] // WsSink is hashed by pointer identity
+    pub async fn publish_many(
+        &self,
+        topics: &[&str],
+        msg: Message,
+    ) -> Result<PublishReport, Error> {
+        let mut report = PublishReport::default();
         let mut failed = Vec::new();
+        let mut keys: Vec<Arc<str>> = Vec::with_capacity(topics.len());
+        self.counters.published.fetch_add(1, Ordering::Relaxed);
         {
-            let topics = self.0.read().await;
-            if let Some(subs) = topics.get(topic) {
-                let client_count = subs.len();
-                tracing::info!("publishing message to {client_count} clients: {msg:?}");
-                for sub in subs {
-                    if let Err(e) = sub.try_send(msg.clone()).await {
-                        tracing::info!("failed to send {msg:?}: {e}");
-                        failed.push(sub.clone());
-                    }
+            let subscriptions = self.topics.read().await;
+            let mut subs: HashSet<&WsSink> = HashSet::new();
+            for topic in topics {
+                let key = self.namespace.key(topic);
+                for (key, topic_subs) in subscribers(&subscriptions, &key, self.wildcard_topics) {
+                    keys.push(key.clone());
+                    subs.extend(topic_subs);
                 }
             }
-        }
-        if !failed.is_empty() {
-            let mut topics = self.0.write().await;
-            if let Some(subs) = topics.get_mut(topic) {
-                for f in failed {
-                    subs.remove(&f);
+            if !subs.is_empty() {
+                let client_count = subs.len();
+                report.total = client_count;
+                tracing::info!(?topics, client_count, "publishing message: {msg:?}");
+                let msg = &msg;
+                let sent = fan_out(subs, |sub| sub.try_send(msg.clone())).await;
+                for (sub, res) in sent {
+                    if let Err(e) = res {
+                        tracing::info!(?topics, "failed to send {msg:?}: {e}");
+                        self.metrics.on_publish_fail();
+                        report.failed += 1;
+                        if !e.is_recoverable() {
+                            failed.push(sub.clone());
+                        }
+                    } else if let Some(len) = data_len(msg) {
+                        self.metrics.on_message(Direction::Outbound, len);
+                    }
                 }
             }
         }
-        Ok(())
+        report.delivered = report.total - report.failed;
+        remove_failed(&self.topics, &keys, failed).await;
+        Ok(report)
     }
 
     pub async fn close_topic(&self, topic: &str) -> Result<(), Error> {
-        let mut topics = self.0.write().await;
-        if let Some(subs) = topics.remove(topic) {
+        let mut topics = self.topics.write().await;
+        if let Some(subs) = topics.remove(&*self.namespace.key(topic)) {
             for sub in subs {
-                if let Err(e) = sub.close().await {
-                    tracing::warn!("failed to close connection on topic '{topic}': {e}");
+                if let Err(e) = sub.close(CloseReason::GoingAway).await {
+                    tracing::warn!(topic, "failed to close connection: {e}");
                 }
             }
         }
         Ok(())
     }
 
+    /// Gracefully closes all connections subscribed to a `topic`: sends them a `notice` first,
+    /// eg. an application-specific message telling clients to migrate to another topic, then
+    /// waits for a grace period set via [SignalingService::with_drain_grace_period] and closes
+    /// connections still subscribed like [SignalingService::close_topic] does.
+    ///
+    /// Connections, which failed to receive the notice, are pruned right away, just like when
+    /// publishing. Returns a delivery report of the notice.
+    pub async fn drain_topic(&self, topic: &str, notice: Message) -> Result<PublishReport, Error> {
+        let report = self.publish_acked(topic, notice).await?;
+        if report.total != 0 {
+            tracing::debug!(topic, "draining topic in {:?}", self.drain_grace_period);
+            tokio::time::sleep(self.drain_grace_period).await;
+        }
+        self.close_topic(topic).await?;
+        Ok(report)
+    }
+
+    /// Closes all connections subscribed to topics of this service. A service returned by
+    /// [SignalingService::namespaced] closes only connections subscribed within its namespace,
+    /// including nested ones.
     pub async fn close(self) -> Result<(), Error> {
-        let mut topics = self.0.write_owned().await;
-        let mut all_conns = HashSet::new();
-        for (_, subs) in topics.drain() {
-            for sub in subs {
-                all_conns.insert(sub);
+        for conn in self.take_connections().await {
+            if let Err(e) = conn.close(CloseReason::GoingAway).await {
+                tracing::warn!("failed to close connection: {e}");
             }
         }
 
-        for conn in all_conns {
-            if let Err(e) = conn.close().await {
+        Ok(())
+    }
+
+    /// Closes all connections like [SignalingService::close] does, but lets messages still being
+    /// sent to them complete and flushes each connection before sending it a close frame.
+    /// Connections are closed concurrently, each of them waiting up to `timeout` to flush.
+    /// Connections, which didn't make it in time, are closed without a close frame.
+    #[allow(clippy::mutable_key_type)] // WsSink is hashed by pointer identity
+    pub async fn close_graceful(self, timeout: Duration) -> Result<(), Error> {
+        let conns = self.take_connections().await;
+        let closes = conns
+            .iter()
+            .map(|conn| conn.close_graceful(CloseReason::GoingAway, timeout));
+        for res in join_all(closes).await {
+            if let Err(e) = res {
                 tracing::warn!("failed to close connection: {e}");
             }
         }
 
         Ok(())
     }
+
+    /// Unsubscribes all connections from topics within the namespace of this service and returns
+    /// them, each one once.
+    #[allow(clippy::mutable_key_type)] // WsSink is hashed by pointer identity
+    async fn take_connections(&self) -> HashSet<WsSink> {
+        let mut topics = self.topics.write().await;
+        let mut all_conns = HashSet::new();
+        let keys: Vec<_> = topics
+            .keys()
+            .filter(|key| self.namespace.contains(key))
+            .cloned()
+            .collect();
+        for key in keys {
+            for sub in topics.remove(&key).unwrap_or_default() {
+                all_conns.insert(sub);
+            }
+        }
+        all_conns
+    }
 }
 
 impl Default for SignalingService {
@@ -140,29 +584,292 @@ impl Default for SignalingService {
     }
 }
 
+/// A prefix of topic keys of a service returned by [SignalingService::namespaced]. Topic names
+/// containing control characters are rejected, so keys of different namespaces never collide.
+#[derive(Debug, Clone, Default)]
+struct Namespace(Option<Arc<str>>);
+
+impl Namespace {
+    const SEPARATOR: char = '\u{1f}';
+
+    fn nested(&self, name: &str) -> Self {
+        let prefix = self.0.as_deref().unwrap_or_default();
+        Namespace(Some(format!("{prefix}{name}{}", Self::SEPARATOR).into()))
+    }
+
+    /// Returns a key of a `topic` within this namespace.
+    fn key<'a>(&self, topic: &'a str) -> Cow<'a, str> {
+        match &self.0 {
+            None => Cow::Borrowed(topic),
+            Some(prefix) => Cow::Owned(format!("{prefix}{topic}")),
+        }
+    }
+
+    /// Returns a topic name of a `key`, if it belongs directly to this namespace.
+    fn strip<'a>(&self, key: &'a str) -> Option<&'a str> {
+        let topic = match &self.0 {
+            None => key,
+            Some(prefix) => key.strip_prefix(&**prefix)?,
+        };
+        (!topic.contains(Self::SEPARATOR)).then_some(topic)
+    }
+
+    /// Returns a topic name of a `key` like [Namespace::strip], reusing the key if possible.
+    fn topic(&self, key: &Arc<str>) -> Option<Arc<str>> {
+        let topic = self.strip(key)?;
+        if topic.len() == key.len() {
+            Some(key.clone())
+        } else {
+            Some(topic.into())
+        }
+    }
+
+    /// Checks if a `key` belongs to this namespace or one of its nested namespaces.
+    fn contains(&self, key: &str) -> bool {
+        self.0
+            .as_ref()
+            .is_none_or(|prefix| key.starts_with(&**prefix))
+    }
+}
+
+/// An action on a topic checked by an authorizer set via
+/// [SignalingService::with_topic_authorizer].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TopicAction {
+    /// Connection requested to subscribe to a topic.
+    Subscribe,
+    /// Connection published a signal on a topic.
+    Publish,
+}
+
+type AuthorizeFn =
+    dyn Fn(TopicAction, Arc<str>, Arc<Extensions>) -> BoxFuture<'static, bool> + Send + Sync;
+
+#[derive(Clone)]
+struct TopicAuthorizer(Arc<AuthorizeFn>);
+
+impl Debug for TopicAuthorizer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("TopicAuthorizer")
+    }
+}
+
 type Topics = Arc<RwLock<HashMap<Arc<str>, HashSet<WsSink>>>>;
 
+#[allow(clippy::mutable_key_type)] // WsSink is hashed by pointer identity
+async fn prune_empty_topics(topics: &Topics) -> usize {
+    let mut topics = topics.write().await;
+    let before = topics.len();
+    topics.retain(|_, subs| !subs.is_empty());
+    before - topics.len()
+}
+
+/// Statistics of a [SignalingService] returned by [SignalingService::stats].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SignalingStats {
+    /// Number of currently open connections, including ones not subscribed to any topic.
+    pub connections: usize,
+    /// Number of topics with at least one subscriber.
+    pub topics: usize,
+    /// Number of messages published since the service has been created, both by peers and via
+    /// [SignalingService::publish], regardless of whether anyone was subscribed to them.
+    pub published: u64,
+    /// Time since the service has been created.
+    pub uptime: Duration,
+}
+
+/// Counters shared by all clones of a [SignalingService], see [SignalingService::stats].
+#[derive(Debug)]
+struct Counters {
+    connections: AtomicUsize,
+    published: AtomicU64,
+    started: Instant,
+    /// Time of the last activity of every live connection, see
+    /// [SignalingService::connection_last_activity].
+    activity: std::sync::Mutex<HashMap<ConnectionId, Instant>>,
+}
+
+/// Counts a connection as active for as long as it's alive, see [SignalingStats::connections].
+#[derive(Debug)]
+struct ActiveConnection(Arc<Counters>, ConnectionId);
+
+impl ActiveConnection {
+    fn new(counters: Arc<Counters>, conn_id: ConnectionId) -> Self {
+        counters.connections.fetch_add(1, Ordering::Relaxed);
+        let now = Instant::now();
+        counters.activity.lock().unwrap().insert(conn_id, now);
+        ActiveConnection(counters, conn_id)
+    }
+
+    /// Records activity of a connection at a given time.
+    fn touch(&self, at: Instant) {
+        self.0.activity.lock().unwrap().insert(self.1, at);
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+        self.0.activity.lock().unwrap().remove(&self.1);
+    }
+}
+
+/// Delivery report of a message published via [SignalingService::publish_acked].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PublishReport {
+    /// Number of connections, which received the message.
+    pub delivered: usize,
+    /// Number of connections, which failed to receive the message.
+    pub failed: usize,
+    /// Number of connections subscribed to a topic at the time of publishing. Zero means that
+    /// there's no such topic.
+    pub total: usize,
+}
+
+/// Resource limits of a [SignalingService]. Zero means no limit.
+#[derive(Debug, Clone, Copy, Default)]
+struct Limits {
+    max_topics_per_connection: usize,
+    max_topics: usize,
+    publish_rate: u32,
+    publish_ceiling: u32,
+    max_topic_length: usize,
+}
+
+impl Limits {
+    fn exceeded(limit: usize, count: usize) -> bool {
+        limit != 0 && count >= limit
+    }
+
+    /// Checks if a `topic` name is within the length limit and free of control characters, logging
+    /// invalid ones in a shortened and escaped form.
+    fn valid_topic(&self, topic: &str) -> bool {
+        let too_long = self.max_topic_length != 0 && topic.len() > self.max_topic_length;
+        if too_long || topic.chars().any(char::is_control) {
+            let prefix: String = topic.chars().take(32).collect();
+            tracing::warn!(
+                "ignoring invalid topic name of {} bytes starting with {prefix:?}",
+                topic.len()
+            );
+            false
+        } else {
+            true
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-struct WsSink(Arc<Mutex<SplitSink<WebSocket, Message>>>);
+struct WsSink(
+    Arc<Mutex<SplitSink<WebSocket, Message>>>,
+    Duration,
+    SignalFormat,
+    ConnectionId,
+);
 
 impl WsSink {
-    fn new(sink: SplitSink<WebSocket, Message>) -> Self {
-        WsSink(Arc::new(Mutex::new(sink)))
+    fn new(
+        sink: SplitSink<WebSocket, Message>,
+        send_timeout: Duration,
+        format: SignalFormat,
+        conn_id: ConnectionId,
+    ) -> Self {
+        WsSink(Arc::new(Mutex::new(sink)), send_timeout, format, conn_id)
+    }
+
+    /// Returns a signal format negotiated by a peer.
+    fn format(&self) -> SignalFormat {
+        self.2
+    }
+
+    /// Returns an ID of a connection.
+    fn id(&self) -> ConnectionId {
+        self.3
     }
 
+    /// Sends a message to a peer, converting JSON signals into its format first. The connection
+    /// is closed, unless sending failed with a recoverable error - see [Error::is_recoverable].
     async fn try_send(&self, msg: Message) -> Result<(), Error> {
+        let msg = match self.2 {
+            SignalFormat::Json => msg,
+            _ if data_len(&msg).is_none() => msg,
+            format => match format.encode(&msg) {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    tracing::debug!(
+                        "sending a message, which couldn't be converted to {format:?}: {e}"
+                    );
+                    msg
+                }
+            },
+        };
         let mut sink = self.0.lock().await;
-        if let Err(e) = sink.send(msg).await {
-            sink.close().await?;
-            Err(e)
+        let res = match self.timed(sink.send(msg)).await {
+            Some(res) => res.map_err(Error::from),
+            None => Err(Error::Timeout(format!(
+                "send took longer than {:?}",
+                self.1
+            ))),
+        };
+        if let Err(e) = res {
+            if e.is_recoverable() {
+                return Err(e);
+            }
+            match self.timed(sink.close()).await {
+                Some(res) => res?,
+                // closing a wedged socket gets stuck as well
+                None => tracing::debug!("timed out closing connection after failed send"),
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Awaits `fut` within a send timeout of this sink. Returns `None` if it has been exceeded.
+    async fn timed<F: Future>(&self, fut: F) -> Option<F::Output> {
+        if self.1.is_zero() {
+            Some(fut.await)
         } else {
-            Ok(())
+            timeout(self.1, fut).await.ok()
         }
     }
 
-    async fn close(&self) -> Result<(), Error> {
+    /// Sends a close frame with a given `reason` to a peer and closes the connection.
+    async fn close(&self, reason: CloseReason) -> Result<(), Error> {
         let mut sink = self.0.lock().await;
-        sink.close().await
+        if let Err(e) = sink.send(Message::Close(Some(reason.into()))).await {
+            tracing::debug!("failed to send close frame: {e}");
+        }
+        Ok(sink.close().await?)
+    }
+
+    /// Waits for messages being sent to a peer, flushes them and closes the connection with
+    /// a given `reason`, unless it takes longer than `timeout`. In that case the connection is
+    /// closed without a close frame, if it's not still busy sending.
+    async fn close_graceful(&self, reason: CloseReason, timeout: Duration) -> Result<(), Error> {
+        let graceful = async {
+            // the lock is fair, so pending sends are done by the time it's acquired
+            let mut sink = self.0.lock().await;
+            sink.flush().await?;
+            sink.send(Message::Close(Some(reason.into()))).await?;
+            sink.close().await
+        };
+        match tokio::time::timeout(timeout, graceful).await {
+            Ok(res) => Ok(res?),
+            Err(_) => {
+                tracing::debug!("timed out flushing connection, closing it");
+                match self.0.try_lock() {
+                    Ok(mut sink) => match sink.close().now_or_never() {
+                        Some(res) => Ok(res?),
+                        None => Err(Error::Timeout(format!(
+                            "close took longer than {timeout:?}"
+                        ))),
+                    },
+                    Err(_) => Err(Error::Timeout(format!(
+                        "flush took longer than {timeout:?}"
+                    ))),
+                }
+            }
+        }
     }
 }
 
@@ -183,179 +890,2531 @@ impl Eq for WsSink {}
 
 /// Handle incoming signaling connection - it's a websocket connection used by y-webrtc protocol
 /// to exchange offering metadata between y-webrtc peers. It also manages topic/room access.
+///
+/// All tracing events of a connection are emitted within a `signaling_conn` span carrying its
+/// process-unique `conn_id`, while events concerning a topic carry its name as a `topic` field.
+/// The same ID is available to a topic authorizer as a [ConnectionId] extension, and can be used
+/// to inspect a connection via [SignalingService::connection_topics].
+///
+/// A peer sending a text message, which is not a valid signal, is considered misbehaving: its
+/// connection is closed and unsubscribed from all topics, without affecting other peers. In such
+/// case [Error::MalformedSignal] is returned, which can be used to tell protocol violations apart
+/// from transport failures, and which points at the malformed part of the message:
+///
+/// ```rust
+/// use yrs_axum::error::Error;
+///
+/// # async fn peer(ws: axum::extract::ws::WebSocket, svc: yrs_axum::signaling::SignalingService) {
+/// match yrs_axum::signaling::signaling_conn(ws, svc).await {
+///     Err(Error::MalformedSignal(e)) => {
+///         eprintln!("peer sent malformed signal at byte {}: {}", e.offset(), e.payload())
+///     }
+///     Err(e) => eprintln!("signaling connection failed: {e}"),
+///     Ok(()) => {}
+/// }
+/// # }
+/// ```
 pub async fn signaling_conn(ws: WebSocket, service: SignalingService) -> Result<(), Error> {
-    let mut topics: Topics = service.0;
+    signaling_conn_with(ws, service, Extensions::new()).await
+}
+
+/// Handle incoming signaling connection like [signaling_conn], passing a connection-scoped
+/// context - eg. an authenticated user id, a peer address or per-user limits - to a topic
+/// authorizer of a `service`, which can access it via `extensions.get::<T>()`. The `ctx` is kept
+/// for as long as the connection is open.
+///
+/// This is a shorthand of [signaling_conn_with] for a single context value.
+///
+/// # Example
+///
+/// ```rust
+/// use axum::extract::ws::WebSocket;
+/// use yrs_axum::signaling::{signaling_conn_with_ctx, SignalingService};
+///
+/// #[derive(Clone)]
+/// struct User {
+///     id: u64,
+/// }
+///
+/// async fn peer(ws: WebSocket, user: User) {
+///     let svc = SignalingService::new().with_topic_authorizer(|_action, topic, extensions| {
+///         async move {
+///             let user = extensions.get::<User>().unwrap();
+///             *topic == *format!("user-{}", user.id)
+///         }
+///     });
+///     let _ = signaling_conn_with_ctx(ws, svc, user).await;
+/// }
+/// ```
+pub async fn signaling_conn_with_ctx<T>(
+    ws: WebSocket,
+    service: SignalingService,
+    ctx: T,
+) -> Result<(), Error>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    let mut extensions = Extensions::new();
+    extensions.insert(ctx);
+    signaling_conn_with(ws, service, extensions).await
+}
+
+/// Handle incoming signaling connection like [signaling_conn], until a `shutdown` future
+/// completes - eg. a `CancellationToken::cancelled_owned` future, or a signal shared with
+/// axum's `with_graceful_shutdown`. The connection is then unsubscribed from all its topics and
+/// closed with [CloseReason::GoingAway], and `Ok` is returned, so that peers know to reconnect
+/// to another server.
+///
+/// # Example
+///
+/// ```rust
+/// use axum::extract::ws::WebSocket;
+/// use tokio_util::sync::CancellationToken;
+/// use yrs_axum::signaling::{signaling_conn_with_shutdown, SignalingService};
+///
+/// async fn peer(ws: WebSocket, svc: SignalingService, shutdown: CancellationToken) {
+///     let _ = signaling_conn_with_shutdown(ws, svc, shutdown.cancelled_owned()).await;
+/// }
+/// ```
+pub async fn signaling_conn_with_shutdown<F>(
+    ws: WebSocket,
+    service: SignalingService,
+    shutdown: F,
+) -> Result<(), Error>
+where
+    F: Future<Output = ()> + Send,
+{
+    serve_conn(ws, service, Extensions::new(), shutdown).await
+}
+
+/// Handle incoming signaling connection like [signaling_conn], passing connection-scoped
+/// `extensions` - eg. an authenticated user taken from an upgrade request - to a topic authorizer
+/// of a `service`. See [SignalingService::with_topic_authorizer].
+pub async fn signaling_conn_with(
+    ws: WebSocket,
+    service: SignalingService,
+    extensions: Extensions,
+) -> Result<(), Error> {
+    serve_conn(ws, service, extensions, std::future::pending()).await
+}
+
+/// Handles a signaling connection until it's closed, fails or a `shutdown` future completes.
+async fn serve_conn<F>(
+    ws: WebSocket,
+    service: SignalingService,
+    extensions: Extensions,
+    shutdown: F,
+) -> Result<(), Error>
+where
+    F: Future<Output = ()> + Send,
+{
+    let conn_id = ConnectionId::next();
+    let mut extensions = extensions;
+    extensions.insert(conn_id);
+    let mut state = ConnState::new(&service, conn_id, extensions);
+    let mut topics: Topics = service.topics;
+    let limits = service.limits;
+    let metrics = service.metrics;
+    let _conn = ConnectionMetrics::new(metrics.clone());
+    let format = SignalFormat::from_protocol(ws.protocol().and_then(|p| p.to_str().ok()));
     let (sink, mut stream) = ws.split();
-    let ws = WsSink::new(sink);
-    let mut ping_interval = interval(PING_TIMEOUT);
-    let mut state = ConnState::default();
-    loop {
-        select! {
-            _ = ping_interval.tick() => {
-                if !state.pong_received {
-                    ws.close().await?;
-                    drop(ping_interval);
-                    return Ok(());
-                } else {
-                    state.pong_received = false;
-                    if let Err(e) = ws.try_send(Message::Ping(Bytes::default())).await {
-                        ws.close().await?;
-                        return Err(e);
-                    }
-                }
-            },
-            res = stream.next() => {
-                match res {
-                    None => {
-                        ws.close().await?;
+    let ws = WsSink::new(sink, service.send_timeout, format, conn_id);
+    let mut ping_interval = ping_interval(service.ping_timeout, service.ping_jitter);
+    let expires_at = if service.max_lifetime.is_zero() {
+        None
+    } else {
+        Some(Instant::now() + service.max_lifetime)
+    };
+    let idle_timeout = service.idle_timeout;
+    let span = tracing::info_span!("signaling_conn", conn_id = conn_id.0);
+    async move {
+        tracing::debug!("signaling connection opened");
+        tokio::pin!(shutdown);
+        let res: Result<(), Error> = async {
+            loop {
+                let idle_at = (!idle_timeout.is_zero()).then(|| state.last_activity + idle_timeout);
+                select! {
+                    _ = &mut shutdown => {
+                        tracing::debug!("closing connection on shutdown");
+                        disconnect(&ws, &mut state, &topics, CloseReason::GoingAway).await;
+                        return Ok(());
+                    },
+                    _ = ping_tick(&mut ping_interval) => {
+                        if !state.pong_received {
+                            ws.close(CloseReason::Timeout).await?;
+                            drop(ping_interval);
+                            return Ok(());
+                        } else {
+                            state.pong_received = false;
+                            match ws.try_send(Message::Ping(Bytes::default())).await {
+                                Ok(()) => {}
+                                Err(e) if e.is_recoverable() => {
+                                    tracing::debug!("failed to send ping: {e}");
+                                }
+                                Err(e) => {
+                                    ws.close(CloseReason::from(&e)).await?;
+                                    return Err(e);
+                                }
+                            }
+                        }
+                    },
+                    _ = expiry(expires_at) => {
+                        tracing::debug!("closing connection, which reached its maximum lifetime");
+                        disconnect(&ws, &mut state, &topics, CloseReason::Expired).await;
                         return Ok(());
                     },
-                    Some(Err(e)) => {
-                        ws.close().await?;
-                        return Err(e);
+                    _ = expiry(idle_at) => {
+                        tracing::debug!(
+                            "closing connection, which has been idle for {idle_timeout:?}"
+                        );
+                        disconnect(&ws, &mut state, &topics, CloseReason::Idle).await;
+                        return Ok(());
                     },
-                    Some(Ok(msg)) => {
-                        process_msg(msg, &ws, &mut state, &mut topics).await?;
+                    res = stream.next() => {
+                        match res {
+                            None => {
+                                ws.close(CloseReason::Normal).await?;
+                                return Ok(());
+                            },
+                            Some(Err(e)) => {
+                                let e = Error::from(e);
+                                ws.close(CloseReason::from(&e)).await?;
+                                return Err(e);
+                            },
+                            Some(Ok(msg)) => {
+                                if let Some(len) = data_len(&msg) {
+                                    metrics.on_message(Direction::Inbound, len);
+                                }
+                                let res = process_msg(
+                                    msg, &ws, &mut state, &mut topics, &limits, &*metrics,
+                                )
+                                .await;
+                                match res {
+                                    Err(e) if e.is_recoverable() => {
+                                        tracing::debug!("failed to reply to a peer: {e}");
+                                    }
+                                    res => res?,
+                                }
+                            }
+                        }
                     }
                 }
             }
         }
+        .await;
+        // every exit, including failed sends, releases all topics of a connection
+        unsubscribe_all(&ws, &mut state, &topics).await;
+        res
+    }
+    .instrument(span)
+    .await
+}
+
+/// Returns a ping schedule of a new connection, see [SignalingService::with_ping_jitter].
+fn ping_interval(timeout: Duration, jitter: Duration) -> Option<Interval> {
+    if timeout.is_zero() {
+        return None;
+    }
+    let jitter = u64::try_from(jitter.as_nanos()).unwrap_or(u64::MAX);
+    let delay = if jitter == 0 {
+        Duration::ZERO
+    } else {
+        // every RandomState is seeded with different random keys
+        Duration::from_nanos(RandomState::new().build_hasher().finish() % jitter)
+    };
+    Some(interval_at(Instant::now() + delay, timeout))
+}
+
+async fn ping_tick(ping_interval: &mut Option<Interval>) {
+    match ping_interval {
+        Some(ping_interval) => {
+            ping_interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn expiry(expires_at: Option<Instant>) {
+    match expires_at {
+        Some(expires_at) => tokio::time::sleep_until(expires_at).await,
+        None => std::future::pending().await,
     }
 }
 
-const PING_MSG: &'static str = r#"{"type":"ping"}"#;
-const PONG_MSG: &'static str = r#"{"type":"pong"}"#;
+const PING_MSG: &str = r#"{"type":"ping"}"#;
+const PONG_MSG: &str = r#"{"type":"pong"}"#;
 
+#[allow(clippy::mutable_key_type)] // WsSink is hashed by pointer identity
 async fn process_msg(
     msg: Message,
     ws: &WsSink,
     state: &mut ConnState,
     topics: &mut Topics,
+    limits: &Limits,
+    metrics: &dyn Metrics,
 ) -> Result<(), Error> {
-    match msg {
-        Message::Text(txt) => {
-            let json = txt.as_str();
-            let msg = serde_json::from_str(json).unwrap();
-            match msg {
-                Signal::Subscribe {
-                    topics: topic_names,
-                } => {
-                    if !topic_names.is_empty() {
-                        let mut topics = topics.write().await;
-                        for topic in topic_names {
-                            tracing::trace!("subscribing new client to '{topic}'");
-                            if let Some((key, _)) = topics.get_key_value(topic) {
-                                state.subscribed_topics.insert(key.clone());
-                                let subs = topics.get_mut(topic).unwrap();
-                                subs.insert(ws.clone());
-                            } else {
-                                let topic: Arc<str> = topic.into();
-                                state.subscribed_topics.insert(topic.clone());
-                                let mut subs = HashSet::new();
-                                subs.insert(ws.clone());
-                                topics.insert(topic, subs);
-                            };
-                        }
-                    }
-                }
-                Signal::Unsubscribe {
-                    topics: topic_names,
-                } => {
-                    if !topic_names.is_empty() {
-                        let mut topics = topics.write().await;
-                        for topic in topic_names {
-                            if let Some(subs) = topics.get_mut(topic) {
-                                tracing::trace!("unsubscribing client from '{topic}'");
-                                subs.remove(ws);
-                            }
-                        }
-                    }
-                }
-                Signal::Publish { topic } => {
-                    let mut failed = Vec::new();
-                    {
-                        let topics = topics.read().await;
-                        if let Some(receivers) = topics.get(topic) {
-                            let client_count = receivers.len();
-                            tracing::trace!(
-                                "publishing on {client_count} clients at '{topic}': {json}"
-                            );
-                            for receiver in receivers.iter() {
-                                if let Err(e) = receiver.try_send(Message::text(json)).await {
-                                    tracing::info!(
-                                        "failed to publish message {json} on '{topic}': {e}"
-                                    );
-                                    failed.push(receiver.clone());
-                                }
-                            }
-                        }
-                    }
-                    if !failed.is_empty() {
-                        let mut topics = topics.write().await;
-                        if let Some(receivers) = topics.get_mut(topic) {
-                            for f in failed {
-                                receivers.remove(&f);
-                            }
-                        }
-                    }
-                }
-                Signal::Ping => {
-                    ws.try_send(Message::text(PONG_MSG)).await?;
-                }
-                Signal::Pong => {
-                    ws.try_send(Message::text(PING_MSG)).await?;
-                }
+    let msg = match ws.format() {
+        SignalFormat::Json => msg,
+        _ if data_len(&msg).is_none() => msg,
+        // signals are processed and forwarded as JSON, peers get them converted back on send
+        format => match format.decode(&msg) {
+            Ok(json) => json,
+            Err(e) => {
+                tracing::warn!(
+                    "closing connection after malformed {format:?} signaling message: {e}"
+                );
+                disconnect(ws, state, topics, CloseReason::ProtocolError).await;
+                return Err(e);
             }
         },
+    };
+    match &msg {
+        Message::Text(txt) => {
+            process_signal(txt.as_bytes(), &msg, ws, state, topics, limits, metrics).await?;
+        }
+        Message::Binary(bytes) => {
+            process_signal(bytes, &msg, ws, state, topics, limits, metrics).await?;
+        },
         Message::Close(_close_frame) => {
-            let mut topics = topics.write().await;
-            for topic in state.subscribed_topics.drain() {
-                if let Some(subs) = topics.get_mut(&topic) {
-                    subs.remove(ws);
-                    if subs.is_empty() {
-                        topics.remove(&topic);
-                    }
-                }
-            }
+            unsubscribe_all(ws, state, topics).await;
             state.closed = true;
         },
-        Message::Ping(_bytes) => {
-            ws.try_send(Message::Ping(Bytes::default())).await?;
-        }, 
-        _ => {}
-
+        Message::Ping(bytes) => {
+            // replaces a pong queued by the websocket itself, so that a peer gets a single one
+            ws.try_send(Message::Pong(bytes.clone())).await?;
+        }
+        Message::Pong(_bytes) => {
+            state.pong_received = true;
+        }
     }
     Ok(())
 }
 
-#[derive(Debug)]
-struct ConnState {
-    closed: bool,
-    pong_received: bool,
-    subscribed_topics: HashSet<Arc<str>>,
-}
-
-impl Default for ConnState {
-    fn default() -> Self {
-        ConnState {
+/// Handles a JSON encoded signal `payload` of a `raw` text or binary message. Published signals
+/// are forwarded, and replies sent, using the same message type.
+#[allow(clippy::mutable_key_type)] // WsSink is hashed by pointer identity
+async fn process_signal(
+    payload: &[u8],
+    raw: &Message,
+    ws: &WsSink,
+    state: &mut ConnState,
+    topics: &mut Topics,
+    limits: &Limits,
+    metrics: &dyn Metrics,
+) -> Result<(), Error> {
+    let json = String::from_utf8_lossy(payload);
+    let msg = match serde_json::from_slice(payload) {
+        Ok(msg) => msg,
+        Err(_) if !state.strict_signal_types && unknown_signal_type(payload) => {
+            tracing::debug!("ignoring signal of unknown type {json:?}");
+            return Ok(());
+        }
+        Err(e) => {
+            let e = MalformedSignal::new(e, payload);
+            tracing::debug!(
+                offset = e.offset(),
+                payload = e.payload(),
+                "malformed signaling message: {}",
+                e.error()
+            );
+            tracing::warn!(
+                "closing connection after malformed signaling message of {} bytes",
+                e.payload_len()
+            );
+            disconnect(ws, state, topics, CloseReason::ProtocolError).await;
+            return Err(Error::MalformedSignal(e));
+        }
+    };
+    if let (None, Some(supported)) = (state.version, &state.supported_versions) {
+        let version = match msg {
+            Signal::Hello { version } => Some(version),
+            _ => None,
+        };
+        if !version.is_some_and(|version| supported.contains(&version)) {
+            tracing::warn!("closing connection with unsupported protocol version {version:?}");
+            disconnect(ws, state, topics, CloseReason::UnsupportedVersion).await;
+            return Err(Error::UnsupportedVersion(version));
+        }
+    }
+    if !matches!(msg, Signal::Ping | Signal::Pong) {
+        state.touch();
+    }
+    match msg {
+        Signal::Hello { version } => {
+            if state.version.is_none() {
+                tracing::debug!(version, "protocol version announced");
+                state.version = Some(version);
+            } else {
+                tracing::debug!(version, "ignoring repeated protocol version handshake");
+            }
+        }
+        Signal::Subscribe {
+            topics: topic_names,
+        } => {
+            let mut authorized = Vec::with_capacity(topic_names.len());
+            for topic in topic_names.iter().map(AsRef::as_ref) {
+                if !limits.valid_topic(topic) {
+                    continue;
+                }
+                if state
+                    .subscribed_topics
+                    .contains(&*state.namespace.key(topic))
+                {
+                    // no need to lock topics for a no-op, chatty clients resubscribe a lot
+                    tracing::trace!(topic, "ignoring duplicate subscription");
+                    continue;
+                }
+                if state.authorize(TopicAction::Subscribe, topic).await {
+                    authorized.push(topic);
+                } else {
+                    tracing::debug!(topic, "ignoring unauthorized subscription");
+                }
+            }
+            if !authorized.is_empty() {
+                let mut topics = topics.write().await;
+                for topic in authorized {
+                    let key = state.namespace.key(topic);
+                    if state.subscribed_topics.contains(&*key) {
+                        continue;
+                    }
+                    let conn_topics = state.subscribed_topics.len();
+                    if Limits::exceeded(limits.max_topics_per_connection, conn_topics) {
+                        tracing::warn!(
+                            topic,
+                            "ignoring subscription: connection already subscribed to {conn_topics} topics"
+                        );
+                        continue;
+                    }
+                    if !topics.contains_key(&*key)
+                        && Limits::exceeded(limits.max_topics, topics.len())
+                    {
+                        tracing::warn!(
+                            topic,
+                            "ignoring subscription: limit of {} topics reached",
+                            limits.max_topics
+                        );
+                        continue;
+                    }
+                    tracing::trace!(topic, "subscribing client");
+                    if let Some((existing, _)) = topics.get_key_value(&*key) {
+                        state.subscribed_topics.insert(existing.clone());
+                        let subs = topics.get_mut(&*key).unwrap();
+                        subs.insert(ws.clone());
+                    } else {
+                        let key: Arc<str> = key.into();
+                        state.subscribed_topics.insert(key.clone());
+                        let mut subs = HashSet::new();
+                        subs.insert(ws.clone());
+                        topics.insert(key, subs);
+                    };
+                }
+            }
+        }
+        Signal::Unsubscribe {
+            topics: topic_names,
+        } => {
+            if !topic_names.is_empty() {
+                let mut topics = topics.write().await;
+                for topic in topic_names.iter().map(AsRef::as_ref) {
+                    let key = state.namespace.key(topic);
+                    state.subscribed_topics.remove(&*key);
+                    if let Some(subs) = topics.get_mut(&*key) {
+                        tracing::trace!(topic, "unsubscribing client");
+                        subs.remove(ws);
+                        if subs.is_empty() {
+                            topics.remove(&*key);
+                        }
+                    }
+                }
+            }
+        }
+        Signal::Publish { topic, .. } => {
+            let topic: &str = &topic;
+            if !limits.valid_topic(topic) {
+                return Ok(());
+            }
+            if !state.publish_ceiling.try_acquire() {
+                tracing::warn!("closing connection exceeding publish rate ceiling");
+                disconnect(ws, state, topics, CloseReason::Policy).await;
+                return Err(Error::LimitExceeded("publish rate ceiling exceeded".into()));
+            }
+            if !state.publish_rate.try_acquire() {
+                tracing::warn!(topic, "dropping publish over the rate limit");
+                return Ok(());
+            }
+            if state.wildcard_topics && topic.ends_with(WILDCARD) {
+                tracing::debug!(topic, "dropping publish on a wildcard pattern");
+                return Ok(());
+            }
+            if !state.authorize(TopicAction::Publish, topic).await {
+                tracing::debug!(topic, "dropping unauthorized publish");
+                return Ok(());
+            }
+            let mut failed = Vec::new();
+            let key = state.namespace.key(topic);
+            let mut keys = Vec::new();
+            state.counters.0.published.fetch_add(1, Ordering::Relaxed);
+            {
+                let topics = topics.read().await;
+                let mut receivers = HashSet::new();
+                for (key, subs) in subscribers(&topics, &key, state.wildcard_topics) {
+                    keys.push(key.clone());
+                    receivers.extend(subs);
+                }
+                if !receivers.is_empty() {
+                    let client_count = receivers.len();
+                    tracing::trace!(topic, client_count, "publishing message: {json}");
+                    let receivers = receivers
+                        .into_iter()
+                        .filter(|receiver| state.publisher_echo || *receiver != ws);
+                    let sent = fan_out(receivers, |receiver| receiver.try_send(raw.clone())).await;
+                    for (receiver, res) in sent {
+                        if let Err(e) = res {
+                            tracing::info!(topic, "failed to publish message {json}: {e}");
+                            metrics.on_publish_fail();
+                            if !e.is_recoverable() {
+                                failed.push(receiver.clone());
+                            }
+                        } else {
+                            metrics.on_message(Direction::Outbound, payload.len());
+                        }
+                    }
+                }
+            }
+            remove_failed(topics, &keys, failed).await;
+        }
+        Signal::Ping => {
+            ws.try_send(reply(raw, PONG_MSG)).await?;
+        }
+        Signal::Pong => {
+            ws.try_send(reply(raw, PING_MSG)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sends a message to all `receivers` concurrently via `send`, so that a slow receiver doesn't
+/// delay delivery to the others. Returns send results of every receiver in iteration order.
+async fn fan_out<'a, T: 'a, I, F, Fut>(receivers: I, send: F) -> Vec<(&'a T, Result<(), Error>)>
+where
+    I: IntoIterator<Item = &'a T>,
+    F: Fn(&'a T) -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    let sends = receivers.into_iter().map(|receiver| {
+        let send = send(receiver);
+        async move { (receiver, send.await) }
+    });
+    join_all(sends).await
+}
+
+/// A suffix of topic patterns matching all topics starting with the same prefix, see
+/// [SignalingService::with_wildcard_topics].
+pub const WILDCARD: char = '*';
+
+/// Returns subscriptions receiving signals published on a topic `key` together with their keys:
+/// the topic itself and, if `wildcards` are enabled, all patterns matching it.
+#[allow(clippy::mutable_key_type)] // WsSink is hashed by pointer identity
+fn subscribers<'a>(
+    topics: &'a HashMap<Arc<str>, HashSet<WsSink>>,
+    key: &str,
+    wildcards: bool,
+) -> Vec<(&'a Arc<str>, &'a HashSet<WsSink>)> {
+    if !wildcards {
+        return topics.get_key_value(key).into_iter().collect();
+    }
+    topics
+        .iter()
+        .filter(|(pattern, _)| &***pattern == key || matches_pattern(pattern, key))
+        .collect()
+}
+
+/// Checks if a topic `key` starts with a prefix of a wildcard `pattern` key within the same
+/// namespace, so that patterns don't match topics of nested namespaces.
+fn matches_pattern(pattern: &str, key: &str) -> bool {
+    let Some(prefix) = pattern.strip_suffix(WILDCARD) else {
+        return false;
+    };
+    key.strip_prefix(prefix)
+        .is_some_and(|rest| !rest.contains(Namespace::SEPARATOR))
+}
+
+/// Unsubscribes connections, which `failed` to receive a message published on given `keys`.
+#[allow(clippy::mutable_key_type)] // WsSink is hashed by pointer identity
+async fn remove_failed(topics: &Topics, keys: &[Arc<str>], failed: Vec<WsSink>) {
+    if failed.is_empty() {
+        return;
+    }
+    let mut topics = topics.write().await;
+    for key in keys {
+        if let Some(subs) = topics.get_mut(key) {
+            for f in failed.iter() {
+                subs.remove(f);
+            }
+        }
+    }
+}
+
+/// Returns a binary size of a text or binary message payload, or `None` for control messages.
+fn data_len(msg: &Message) -> Option<usize> {
+    match msg {
+        Message::Text(txt) => Some(txt.len()),
+        Message::Binary(bytes) => Some(bytes.len()),
+        _ => None,
+    }
+}
+
+fn reply(raw: &Message, json: &'static str) -> Message {
+    match raw {
+        Message::Binary(_) => Message::Binary(Bytes::from_static(json.as_bytes())),
+        _ => Message::text(json),
+    }
+}
+
+/// Removes connection from all topics it has subscribed to, dropping topics left without
+/// subscribers.
+#[allow(clippy::mutable_key_type)] // WsSink is hashed by pointer identity
+async fn unsubscribe_all(ws: &WsSink, state: &mut ConnState, topics: &Topics) {
+    let mut topics = topics.write().await;
+    for topic in state.subscribed_topics.drain() {
+        if let Some(subs) = topics.get_mut(&topic) {
+            subs.remove(ws);
+            if subs.is_empty() {
+                topics.remove(&topic);
+            }
+        }
+    }
+}
+
+/// Unsubscribes a misbehaving connection from all topics and closes it with a given `reason`.
+async fn disconnect(ws: &WsSink, state: &mut ConnState, topics: &Topics, reason: CloseReason) {
+    unsubscribe_all(ws, state, topics).await;
+    state.closed = true;
+    if let Err(e) = ws.close(reason).await {
+        tracing::debug!("failed to close connection: {e}");
+    }
+}
+
+/// A process-unique identifier of a signaling connection, attached to all of its tracing events
+/// as `conn_id`. See [SignalingService::connection_topics].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    fn next() -> Self {
+        static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+        ConnectionId(NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Allows to look up a connection by a `conn_id` found in tracing events.
+impl From<u64> for ConnectionId {
+    fn from(conn_id: u64) -> Self {
+        ConnectionId(conn_id)
+    }
+}
+
+impl From<ConnectionId> for u64 {
+    fn from(conn_id: ConnectionId) -> Self {
+        conn_id.0
+    }
+}
+
+#[derive(Debug)]
+struct ConnState {
+    closed: bool,
+    pong_received: bool,
+    /// Time of the last signal other than a keepalive, see [SignalingService::with_idle_timeout].
+    last_activity: Instant,
+    subscribed_topics: HashSet<Arc<str>>,
+    publish_rate: TokenBucket,
+    publish_ceiling: TokenBucket,
+    authorizer: Option<TopicAuthorizer>,
+    extensions: Arc<Extensions>,
+    namespace: Namespace,
+    /// Protocol version announced via [Signal::Hello], if any.
+    version: Option<u32>,
+    supported_versions: Option<Arc<[u32]>>,
+    publisher_echo: bool,
+    strict_signal_types: bool,
+    wildcard_topics: bool,
+    counters: ActiveConnection,
+}
+
+impl ConnState {
+    fn new(service: &SignalingService, conn_id: ConnectionId, extensions: Extensions) -> Self {
+        ConnState {
             closed: false,
             pong_received: true,
+            last_activity: Instant::now(),
             subscribed_topics: HashSet::new(),
+            publish_rate: TokenBucket::new(service.limits.publish_rate),
+            publish_ceiling: TokenBucket::new(service.limits.publish_ceiling),
+            authorizer: service.authorizer.clone(),
+            extensions: Arc::new(extensions),
+            namespace: service.namespace.clone(),
+            version: None,
+            supported_versions: service.supported_versions.clone(),
+            publisher_echo: service.publisher_echo,
+            strict_signal_types: service.strict_signal_types,
+            wildcard_topics: service.wildcard_topics,
+            counters: ActiveConnection::new(service.counters.clone(), conn_id),
+        }
+    }
+
+    /// Records a signal received from a peer, see [SignalingService::with_idle_timeout].
+    fn touch(&mut self) {
+        self.last_activity = Instant::now();
+        self.counters.touch(self.last_activity);
+    }
+
+    async fn authorize(&self, action: TopicAction, topic: &str) -> bool {
+        match &self.authorizer {
+            None => true,
+            Some(authorizer) => (authorizer.0)(action, topic.into(), self.extensions.clone()).await,
         }
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+/// A y-webrtc signaling message, as exchanged between peers and a [SignalingService]. It can be
+/// used to build or inspect signaling messages outside of this crate, eg. by integration tests
+/// or bridges to other signaling servers. Its serde representation is the JSON wire format of
+/// the y-webrtc protocol.
+///
+/// # Example
+///
+/// ```rust
+/// use yrs_axum::signaling::Signal;
+///
+/// let msg = Signal::subscribe(["room-a", "room-b"]).to_message().unwrap();
+/// assert_eq!(Signal::from_message(&msg).unwrap(), Signal::subscribe(["room-a", "room-b"]));
+/// ```
+#[derive(Debug, Clone, Serialize)]
 #[serde(tag = "type")]
-pub(crate) enum Signal<'a> {
+pub enum Signal<'a> {
+    /// Publishes `data` to all peers subscribed to a `topic`.
     #[serde(rename = "publish")]
-    Publish { topic: &'a str },
+    Publish {
+        topic: Cow<'a, str>,
+        /// Published payload. It's forwarded to subscribers verbatim and never parsed.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<&'a RawValue>,
+    },
+    /// Subscribes a peer to all given `topics`.
     #[serde(rename = "subscribe")]
-    Subscribe { topics: Vec<&'a str> },
+    Subscribe { topics: Vec<Cow<'a, str>> },
+    /// Unsubscribes a peer from all given `topics`.
     #[serde(rename = "unsubscribe")]
-    Unsubscribe { topics: Vec<&'a str> },
+    Unsubscribe { topics: Vec<Cow<'a, str>> },
+    /// Keep-alive request. Answered with [Signal::Pong].
     #[serde(rename = "ping")]
     Ping,
+    /// Keep-alive response.
     #[serde(rename = "pong")]
     Pong,
+    /// Handshake announcing a protocol `version` of a peer. It's optional, unless required via
+    /// [SignalingService::with_supported_versions].
+    #[serde(rename = "hello")]
+    Hello { version: u32 },
+}
+
+impl<'a> Signal<'a> {
+    /// Creates a [Signal::Publish] message with an already serialized JSON `data` payload.
+    pub fn publish<T: Into<Cow<'a, str>>>(topic: T, data: &'a RawValue) -> Self {
+        Signal::Publish {
+            topic: topic.into(),
+            data: Some(data),
+        }
+    }
+
+    /// Creates a [Signal::Subscribe] message.
+    pub fn subscribe<I, T>(topics: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Cow<'a, str>>,
+    {
+        Signal::Subscribe {
+            topics: topics.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Creates a [Signal::Unsubscribe] message.
+    pub fn unsubscribe<I, T>(topics: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Cow<'a, str>>,
+    {
+        Signal::Unsubscribe {
+            topics: topics.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Serializes this signal into a JSON text message.
+    pub fn to_message(&self) -> Result<Message, Error> {
+        Ok(Message::Text(serde_json::to_string(self)?.into()))
+    }
+
+    /// Serializes this signal into a message of a given `format`. See [SignalFormat::encode].
+    pub fn to_message_as(&self, format: SignalFormat) -> Result<Message, Error> {
+        format.encode(&self.to_message()?)
+    }
+
+    /// Deserializes a signal from a JSON text or binary message, borrowing its topics and data.
+    /// Returns [Error::Serialization] for malformed payloads and control messages.
+    pub fn from_message(msg: &'a Message) -> Result<Self, Error> {
+        match msg {
+            Message::Text(txt) => Ok(serde_json::from_str(txt.as_str())?),
+            Message::Binary(bytes) => Ok(serde_json::from_slice(bytes)?),
+            _ => Err(serde_error("expected a text or binary signaling message")),
+        }
+    }
+
+    /// Copies borrowed topics and data of this signal, so that it can outlive a message it has
+    /// been deserialized from.
+    pub fn into_owned(self) -> OwnedSignal {
+        match self {
+            Signal::Publish { topic, data } => OwnedSignal::Publish {
+                topic: topic.into_owned(),
+                data: data.map(ToOwned::to_owned),
+            },
+            Signal::Subscribe { topics } => OwnedSignal::Subscribe {
+                topics: topics.into_iter().map(Cow::into_owned).collect(),
+            },
+            Signal::Unsubscribe { topics } => OwnedSignal::Unsubscribe {
+                topics: topics.into_iter().map(Cow::into_owned).collect(),
+            },
+            Signal::Ping => OwnedSignal::Ping,
+            Signal::Pong => OwnedSignal::Pong,
+            Signal::Hello { version } => OwnedSignal::Hello { version },
+        }
+    }
+}
+
+impl PartialEq for Signal<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (
+                Signal::Publish { topic, data },
+                Signal::Publish {
+                    topic: other_topic,
+                    data: other_data,
+                },
+            ) => topic == other_topic && data.map(RawValue::get) == other_data.map(RawValue::get),
+            (Signal::Subscribe { topics }, Signal::Subscribe { topics: other })
+            | (Signal::Unsubscribe { topics }, Signal::Unsubscribe { topics: other }) => {
+                topics == other
+            }
+            (Signal::Ping, Signal::Ping) | (Signal::Pong, Signal::Pong) => true,
+            (Signal::Hello { version }, Signal::Hello { version: other }) => version == other,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Signal<'_> {}
+
+/// Types of all [Signal] variants, as used by their `type` field.
+const SIGNAL_TYPES: &[&str] = &[
+    "publish",
+    "subscribe",
+    "unsubscribe",
+    "ping",
+    "pong",
+    "hello",
+];
+
+/// Checks if a JSON `payload` is an object with a `type` field, which none of [SIGNAL_TYPES]
+/// matches.
+fn unknown_signal_type(payload: &[u8]) -> bool {
+    #[derive(Deserialize)]
+    struct Typed<'a> {
+        #[serde(rename = "type", borrow)]
+        kind: Cow<'a, str>,
+    }
+    serde_json::from_slice::<Typed>(payload)
+        .is_ok_and(|typed| !SIGNAL_TYPES.contains(&&*typed.kind))
+}
+
+/// Flat representation of all [Signal] fields. Internally tagged enums buffer their content,
+/// which can't hold a borrowed [RawValue], so signals are deserialized through this struct.
+#[derive(Deserialize)]
+struct RawSignal<'a> {
+    // topics are borrowed from the message, unless they contain escape sequences
+    #[serde(rename = "type", borrow)]
+    kind: Cow<'a, str>,
+    #[serde(borrow, default)]
+    topic: Option<Cow<'a, str>>,
+    #[serde(borrow, default)]
+    topics: Option<Vec<Cow<'a, str>>>,
+    #[serde(borrow, default)]
+    data: Option<&'a RawValue>,
+    #[serde(default)]
+    version: Option<u32>,
+}
+
+impl<'de> Deserialize<'de> for Signal<'de> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error as _;
+        let raw = RawSignal::deserialize(deserializer)?;
+        match &*raw.kind {
+            "publish" => Ok(Signal::Publish {
+                topic: raw.topic.ok_or_else(|| D::Error::missing_field("topic"))?,
+                data: raw.data,
+            }),
+            "subscribe" => Ok(Signal::Subscribe {
+                topics: raw
+                    .topics
+                    .ok_or_else(|| D::Error::missing_field("topics"))?,
+            }),
+            "unsubscribe" => Ok(Signal::Unsubscribe {
+                topics: raw
+                    .topics
+                    .ok_or_else(|| D::Error::missing_field("topics"))?,
+            }),
+            "ping" => Ok(Signal::Ping),
+            "pong" => Ok(Signal::Pong),
+            "hello" => Ok(Signal::Hello {
+                version: raw
+                    .version
+                    .ok_or_else(|| D::Error::missing_field("version"))?,
+            }),
+            other => Err(D::Error::unknown_variant(other, SIGNAL_TYPES)),
+        }
+    }
+}
+
+/// A [Signal], which owns its topics and data, eg. to be queued or retried after a message it
+/// has been deserialized from is gone. It's created via [Signal::into_owned] and serialized
+/// the same way a borrowed signal is.
+#[derive(Debug, Clone)]
+pub enum OwnedSignal {
+    /// See [Signal::Publish].
+    Publish {
+        topic: String,
+        data: Option<Box<RawValue>>,
+    },
+    /// See [Signal::Subscribe].
+    Subscribe { topics: Vec<String> },
+    /// See [Signal::Unsubscribe].
+    Unsubscribe { topics: Vec<String> },
+    /// See [Signal::Ping].
+    Ping,
+    /// See [Signal::Pong].
+    Pong,
+    /// See [Signal::Hello].
+    Hello { version: u32 },
+}
+
+impl OwnedSignal {
+    /// Returns a [Signal] borrowing topics and data of this one.
+    pub fn as_signal(&self) -> Signal<'_> {
+        match self {
+            OwnedSignal::Publish { topic, data } => Signal::Publish {
+                topic: Cow::Borrowed(topic),
+                data: data.as_deref(),
+            },
+            OwnedSignal::Subscribe { topics } => {
+                Signal::subscribe(topics.iter().map(String::as_str))
+            }
+            OwnedSignal::Unsubscribe { topics } => {
+                Signal::unsubscribe(topics.iter().map(String::as_str))
+            }
+            OwnedSignal::Ping => Signal::Ping,
+            OwnedSignal::Pong => Signal::Pong,
+            OwnedSignal::Hello { version } => Signal::Hello { version: *version },
+        }
+    }
+
+    /// Serializes this signal into a JSON text message. See [Signal::to_message].
+    pub fn to_message(&self) -> Result<Message, Error> {
+        self.as_signal().to_message()
+    }
+
+    /// Serializes this signal into a message of a given `format`. See [Signal::to_message_as].
+    pub fn to_message_as(&self, format: SignalFormat) -> Result<Message, Error> {
+        self.as_signal().to_message_as(format)
+    }
+
+    /// Deserializes a signal from a JSON text or binary message. See [Signal::from_message].
+    pub fn from_message(msg: &Message) -> Result<Self, Error> {
+        Ok(Signal::from_message(msg)?.into_owned())
+    }
+}
+
+impl From<Signal<'_>> for OwnedSignal {
+    fn from(signal: Signal<'_>) -> Self {
+        signal.into_owned()
+    }
+}
+
+impl Serialize for OwnedSignal {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.as_signal().serialize(serializer)
+    }
+}
+
+impl PartialEq for OwnedSignal {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_signal() == other.as_signal()
+    }
+}
+
+impl Eq for OwnedSignal {}
+
+/// An encoding of signals exchanged over a signaling connection. JSON is used by default and
+/// understood by all y-webrtc clients, while bandwidth-sensitive clients can opt into compact
+/// MessagePack binary frames by requesting a [MSGPACK_PROTOCOL] websocket subprotocol. Servers
+/// need to accept it when upgrading a connection:
+///
+/// ```rust
+/// use axum::extract::ws::WebSocketUpgrade;
+/// use axum::response::IntoResponse;
+/// use yrs_axum::signaling::{signaling_conn, SignalFormat, SignalingService};
+///
+/// async fn ws_handler(ws: WebSocketUpgrade, svc: SignalingService) -> impl IntoResponse {
+///     ws.protocols(SignalFormat::PROTOCOLS)
+///         .on_upgrade(move |socket| async move {
+///             let _ = signaling_conn(socket, svc).await;
+///         })
+/// }
+/// ```
+///
+/// A format is fixed once a connection is opened: a peer sending a message in a different format
+/// is disconnected with a protocol error. Peers of both formats can share topics, as published
+/// messages are converted for subscribers using the other format, which doesn't preserve order of
+/// object keys. For that reason MessagePack signals must be representable as JSON, ie. use string
+/// map keys and no binary or extension types.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum SignalFormat {
+    /// JSON text messages of the y-webrtc protocol.
+    #[default]
+    Json,
+    /// MessagePack encoded binary messages, negotiated via [MSGPACK_PROTOCOL].
+    MessagePack,
+}
+
+impl SignalFormat {
+    /// Websocket subprotocols of all non-default formats, to be passed to
+    /// [WebSocketUpgrade::protocols](axum::extract::ws::WebSocketUpgrade::protocols).
+    pub const PROTOCOLS: [&'static str; 1] = [MSGPACK_PROTOCOL];
+
+    /// Returns a format of a connection upgraded with a given websocket subprotocol. Connections
+    /// without a known subprotocol use [SignalFormat::Json].
+    pub fn from_protocol(protocol: Option<&str>) -> Self {
+        match protocol {
+            Some(MSGPACK_PROTOCOL) => SignalFormat::MessagePack,
+            _ => SignalFormat::Json,
+        }
+    }
+
+    /// Converts a JSON text or binary signaling message into a message of this format.
+    /// Returns [Error::Serialization] for malformed payloads and control messages.
+    pub fn encode(&self, json: &Message) -> Result<Message, Error> {
+        let payload = Self::payload(json)?;
+        match self {
+            SignalFormat::Json => Ok(json.clone()),
+            SignalFormat::MessagePack => {
+                let value: serde_json::Value = serde_json::from_slice(payload)?;
+                let bytes = rmp_serde::to_vec_named(&value).map_err(serde_error)?;
+                Ok(Message::Binary(bytes.into()))
+            }
+        }
+    }
+
+    /// Converts a message of this format into a JSON signaling message, which can be parsed via
+    /// [Signal::from_message]. Returns [Error::Serialization] for malformed payloads, control
+    /// messages and - in case of [SignalFormat::MessagePack] - text messages.
+    pub fn decode(&self, msg: &Message) -> Result<Message, Error> {
+        match (self, msg) {
+            (SignalFormat::Json, _) => {
+                Self::payload(msg)?;
+                Ok(msg.clone())
+            }
+            (SignalFormat::MessagePack, Message::Binary(bytes)) => {
+                let value: serde_json::Value = rmp_serde::from_slice(bytes).map_err(serde_error)?;
+                Ok(Message::Text(serde_json::to_string(&value)?.into()))
+            }
+            (SignalFormat::MessagePack, _) => Err(serde_error(
+                "expected a MessagePack encoded binary signaling message",
+            )),
+        }
+    }
+
+    fn payload(msg: &Message) -> Result<&[u8], Error> {
+        match msg {
+            Message::Text(txt) => Ok(txt.as_bytes()),
+            Message::Binary(bytes) => Ok(bytes),
+            _ => Err(serde_error("expected a text or binary signaling message")),
+        }
+    }
+}
+
+fn serde_error<T: std::fmt::Display>(e: T) -> Error {
+    Error::Serialization(serde::de::Error::custom(e))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::error::Error;
+    use crate::metrics::{Direction, Metrics};
+    use crate::signaling::{
+        fan_out, ping_interval, signaling_conn, signaling_conn_with, signaling_conn_with_ctx,
+        signaling_conn_with_shutdown, ConnectionId, OwnedSignal, PublishReport, Signal,
+        SignalFormat, SignalingService, DEFAULT_PING_TIMEOUT, MSGPACK_PROTOCOL,
+    };
+    use axum::extract::ws::{Message as AxumMessage, WebSocket, WebSocketUpgrade};
+    use axum::extract::State;
+    use axum::http::{Extensions, HeaderMap};
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use axum::Router;
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::value::RawValue;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpStream;
+    use tokio::task::JoinHandle;
+    use tokio::time::{sleep, timeout, Instant};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+    use tokio_util::sync::CancellationToken;
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    async fn start_server(addr: &str, svc: SignalingService) -> JoinHandle<()> {
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let app = Router::new()
+            .route("/signaling", get(ws_handler))
+            .with_state(svc);
+        tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        })
+    }
+
+    async fn ws_handler(
+        ws: WebSocketUpgrade,
+        State(svc): State<SignalingService>,
+    ) -> impl IntoResponse {
+        ws.protocols(SignalFormat::PROTOCOLS)
+            .on_upgrade(move |socket| peer(socket, svc))
+    }
+
+    async fn peer(ws: WebSocket, svc: SignalingService) {
+        if let Err(e) = signaling_conn(ws, svc).await {
+            eprintln!("signaling connection failed: {e}");
+        }
+    }
+
+    async fn client(addr: &str) -> Client {
+        let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/signaling"))
+            .await
+            .unwrap();
+        ws
+    }
+
+    async fn msgpack_client(addr: &str) -> Client {
+        let mut req = format!("ws://{addr}/signaling")
+            .into_client_request()
+            .unwrap();
+        req.headers_mut()
+            .insert("sec-websocket-protocol", MSGPACK_PROTOCOL.parse().unwrap());
+        let (ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+        ws
+    }
+
+    fn msgpack(signal: &Signal) -> Message {
+        match signal.to_message_as(SignalFormat::MessagePack).unwrap() {
+            AxumMessage::Binary(bytes) => Message::Binary(bytes.to_vec()),
+            other => panic!("expected a binary message, got {other:?}"),
+        }
+    }
+
+    /// Receives next data message, skipping control frames.
+    async fn recv(ws: &mut Client) -> Message {
+        loop {
+            let msg = timeout(Duration::from_secs(1), ws.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            if msg.is_text() || msg.is_binary() {
+                return msg;
+            }
+        }
+    }
+
+    async fn recv_text(ws: &mut Client) -> String {
+        recv(ws).await.into_text().unwrap()
+    }
+
+    /// Sends a list of signals followed by a ping and waits for pong, so that all previous
+    /// signals are known to be processed by the server.
+    async fn send_all(ws: &mut Client, signals: &[&str]) {
+        for signal in signals {
+            ws.send(Message::Text(signal.to_string())).await.unwrap();
+        }
+        ws.send(Message::Text(r#"{"type":"ping"}"#.into()))
+            .await
+            .unwrap();
+        assert_eq!(recv_text(ws).await, r#"{"type":"pong"}"#);
+    }
+
+    #[tokio::test]
+    async fn ping_timeout_is_configurable() {
+        let addr = "127.0.0.1:16701";
+        let svc = SignalingService::new().with_ping_timeout(Duration::from_millis(50));
+        let _server = start_server(addr, svc).await;
+        let mut ws = client(addr).await;
+        let msg = timeout(Duration::from_secs(1), ws.next()).await.unwrap();
+        assert!(matches!(msg, Some(Ok(Message::Ping(_)))), "{msg:?}");
+
+        let addr = "127.0.0.1:16702";
+        let svc = SignalingService::new().with_ping_timeout(Duration::ZERO);
+        let _server = start_server(addr, svc).await;
+        let mut ws = client(addr).await;
+        assert!(timeout(Duration::from_millis(200), ws.next())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn ping_timeout_follows_runtime_clock() {
+        let addr = "127.0.0.1:16727";
+        let svc = SignalingService::new();
+        let _server = start_server(addr, svc.clone()).await;
+        // the client doesn't read anything, so it never answers the ping sent on connect
+        let mut ws = client(addr).await;
+        while svc.stats().await.connections == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        tokio::time::pause();
+        tokio::time::advance(DEFAULT_PING_TIMEOUT).await;
+        while svc.stats().await.connections != 0 {
+            // in case the first ping hasn't been sent before pausing, the clock auto-advances
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let msg = ws.next().await;
+        assert!(matches!(msg, Some(Ok(Message::Ping(_)))), "{msg:?}");
+        match ws.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4000),
+            other => panic!("expected close frame, got {other:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ping_jitter_spreads_first_pings() {
+        // schedules of connections accepted at the same virtual instant
+        let start = Instant::now();
+        let jitter = DEFAULT_PING_TIMEOUT;
+        let mut a = ping_interval(DEFAULT_PING_TIMEOUT, jitter).unwrap();
+        let mut b = ping_interval(DEFAULT_PING_TIMEOUT, jitter).unwrap();
+        let first_a = a.tick().await;
+        let first_b = b.tick().await;
+        assert_ne!(first_a, first_b);
+        assert!(first_a < start + jitter && first_b < start + jitter);
+        // later pings keep the same period
+        assert_eq!(a.tick().await, first_a + DEFAULT_PING_TIMEOUT);
+
+        // without jitter, connections are pinged right away
+        let now = Instant::now();
+        let mut c = ping_interval(DEFAULT_PING_TIMEOUT, Duration::ZERO).unwrap();
+        assert_eq!(c.tick().await, now);
+        assert!(ping_interval(Duration::ZERO, jitter).is_none());
+    }
+
+    #[tokio::test]
+    async fn topic_limits_are_enforced() {
+        let addr = "127.0.0.1:16703";
+        let svc = SignalingService::new()
+            .with_max_topics_per_connection(2)
+            .with_max_topics(3);
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut a = client(addr).await;
+        let mut b = client(addr).await;
+        send_all(
+            &mut a,
+            &[r#"{"type":"subscribe","topics":["t1","t2","t3"]}"#],
+        )
+        .await;
+        send_all(
+            &mut b,
+            &[r#"{"type":"subscribe","topics":["t1","t4","t5"]}"#],
+        )
+        .await;
+
+        let publish = |topic: &'static str| {
+            let svc = svc.clone();
+            async move {
+                svc.publish(topic, axum::extract::ws::Message::text(topic))
+                    .await
+                    .unwrap()
+            }
+        };
+        // a is over per-connection limit on t3, b is over global limit on t5
+        publish("t3").await;
+        publish("t5").await;
+        publish("t2").await;
+        publish("t4").await;
+        assert_eq!(recv_text(&mut a).await, "t2");
+        assert_eq!(recv_text(&mut b).await, "t4");
+
+        // unsubscribing frees the per-connection slot
+        send_all(
+            &mut a,
+            &[
+                r#"{"type":"unsubscribe","topics":["t2"]}"#,
+                r#"{"type":"subscribe","topics":["t4"]}"#,
+            ],
+        )
+        .await;
+        publish("t4").await;
+        assert_eq!(recv_text(&mut a).await, "t4");
+        assert_eq!(recv_text(&mut b).await, "t4");
+    }
+
+    #[tokio::test]
+    async fn topics_are_listed_with_subscriber_counts() {
+        let addr = "127.0.0.1:16704";
+        let svc = SignalingService::new();
+        let _server = start_server(addr, svc.clone()).await;
+        assert_eq!(svc.topic_count().await, 0);
+
+        let mut a = client(addr).await;
+        let mut b = client(addr).await;
+        send_all(&mut a, &[r#"{"type":"subscribe","topics":["t1","t2"]}"#]).await;
+        send_all(&mut b, &[r#"{"type":"subscribe","topics":["t1"]}"#]).await;
+
+        let mut topics = svc.topics().await;
+        topics.sort();
+        assert_eq!(topics, vec![("t1".into(), 2), ("t2".into(), 1)]);
+        assert_eq!(svc.topic_count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn publishes_are_rate_limited() {
+        let addr = "127.0.0.1:16705";
+        let svc = SignalingService::new().with_publish_rate_limit(2, 4);
+        let _server = start_server(addr, svc).await;
+
+        let mut sub = client(addr).await;
+        let mut publisher = client(addr).await;
+        send_all(&mut sub, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+
+        let publish = r#"{"type":"publish","topic":"room"}"#;
+        send_all(&mut publisher, &[publish, publish, publish]).await;
+        // only publishes within the rate are delivered
+        assert_eq!(recv_text(&mut sub).await, publish);
+        assert_eq!(recv_text(&mut sub).await, publish);
+        send_all(&mut sub, &[]).await;
+
+        // exceeding the ceiling closes the connection
+        publisher.send(Message::Text(publish.into())).await.unwrap();
+        publisher.send(Message::Text(publish.into())).await.unwrap();
+        match timeout(Duration::from_secs(1), publisher.next())
+            .await
+            .unwrap()
+        {
+            None | Some(Err(_)) | Some(Ok(Message::Close(_))) => {}
+            Some(Ok(msg)) => panic!("unexpected message: {msg:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn binary_signals_are_supported() {
+        let addr = "127.0.0.1:16706";
+        let svc = SignalingService::new();
+        let _server = start_server(addr, svc).await;
+
+        let mut a = client(addr).await;
+        let mut b = client(addr).await;
+        let subscribe = r#"{"type":"subscribe","topics":["room"]}"#;
+        a.send(Message::Binary(subscribe.into())).await.unwrap();
+        a.send(Message::Binary(r#"{"type":"ping"}"#.into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            recv(&mut a).await,
+            Message::Binary(r#"{"type":"pong"}"#.into())
+        );
+
+        let publish = r#"{"type":"publish","topic":"room","data":{"offer":"x"}}"#;
+        b.send(Message::Binary(publish.into())).await.unwrap();
+        assert_eq!(recv(&mut a).await, Message::Binary(publish.into()));
+    }
+
+    #[tokio::test]
+    async fn empty_topics_are_removed() {
+        let addr = "127.0.0.1:16707";
+        let svc = SignalingService::new().with_topic_sweeper(Duration::from_millis(50));
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut ws = client(addr).await;
+        send_all(&mut ws, &[r#"{"type":"subscribe","topics":["t1","t2"]}"#]).await;
+        assert_eq!(svc.topic_count().await, 2);
+        send_all(&mut ws, &[r#"{"type":"unsubscribe","topics":["t1"]}"#]).await;
+        assert_eq!(svc.topic_count().await, 1);
+
+        // topics left empty by other means are removed by a sweeper
+        svc.topics.write().await.get_mut("t2").unwrap().clear();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(svc.topic_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn malformed_signal_closes_only_sender() {
+        let addr = "127.0.0.1:16700";
+        let svc = SignalingService::new();
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut good = client(addr).await;
+        let mut bad = client(addr).await;
+        let subscribe = r#"{"type":"subscribe","topics":["room"]}"#;
+        send_all(&mut good, &[subscribe]).await;
+        send_all(&mut bad, &[subscribe]).await;
+
+        bad.send(Message::Text("{not a signal".into()))
+            .await
+            .unwrap();
+        match timeout(Duration::from_secs(1), bad.next()).await.unwrap() {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 1002),
+            other => panic!("expected close frame, got {other:?}"),
+        }
+
+        // other peers are still served
+        svc.publish("room", axum::extract::ws::Message::text("hello"))
+            .await
+            .unwrap();
+        assert_eq!(recv_text(&mut good).await, "hello");
+    }
+
+    #[tokio::test]
+    async fn unknown_signal_types_are_rejected_in_strict_mode() {
+        let addr = "127.0.0.1:16728";
+        let svc = SignalingService::new();
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut ws = client(addr).await;
+        send_all(&mut ws, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+        ws.send(Message::Text(
+            r#"{"type":"announce","topic":"room"}"#.into(),
+        ))
+        .await
+        .unwrap();
+        match timeout(Duration::from_secs(1), ws.next()).await.unwrap() {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 1002),
+            other => panic!("expected close frame, got {other:?}"),
+        }
+        assert_eq!(svc.topic_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn unknown_signal_types_are_ignored_in_lenient_mode() {
+        let addr = "127.0.0.1:16729";
+        let svc = SignalingService::new().with_strict_signal_types(false);
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut ws = client(addr).await;
+        send_all(
+            &mut ws,
+            &[
+                r#"{"type":"subscribe","topics":["room"]}"#,
+                r#"{"type":"announce","topic":"room"}"#,
+            ],
+        )
+        .await;
+        assert_eq!(svc.topics().await, vec![("room".into(), 1)]);
+
+        // signals of known types are still validated
+        ws.send(Message::Text(r#"{"type":"publish"}"#.into()))
+            .await
+            .unwrap();
+        match timeout(Duration::from_secs(1), ws.next()).await.unwrap() {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 1002),
+            other => panic!("expected close frame, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn wildcard_subscriptions_receive_matching_topics() {
+        let addr = "127.0.0.1:16730";
+        let svc = SignalingService::new().with_wildcard_topics(true);
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut project = client(addr).await;
+        let mut publisher = client(addr).await;
+        let subscribe = r#"{"type":"subscribe","topics":["project:123:*","project:123:room"]}"#;
+        send_all(&mut project, &[subscribe]).await;
+
+        // matching both subscriptions, but delivered once
+        let publish = r#"{"type":"publish","topic":"project:123:room","data":"a"}"#;
+        send_all(&mut publisher, &[publish]).await;
+        assert_eq!(recv_text(&mut project).await, publish);
+        let publish = r#"{"type":"publish","topic":"project:123:other","data":"b"}"#;
+        send_all(&mut publisher, &[publish]).await;
+        assert_eq!(recv_text(&mut project).await, publish);
+        svc.publish("project:123:api", AxumMessage::text("c"))
+            .await
+            .unwrap();
+        assert_eq!(recv_text(&mut project).await, "c");
+
+        // unsubscribing from a pattern keeps other subscriptions
+        let unsubscribe = r#"{"type":"unsubscribe","topics":["project:123:*"]}"#;
+        send_all(&mut project, &[unsubscribe]).await;
+        let publish = r#"{"type":"publish","topic":"project:123:room","data":"d"}"#;
+        send_all(&mut publisher, &[publish]).await;
+        assert_eq!(recv_text(&mut project).await, publish);
+        assert_eq!(svc.topics().await, vec![("project:123:room".into(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn wildcard_subscriptions_ignore_other_topics() {
+        let addr = "127.0.0.1:16731";
+        let svc = SignalingService::new().with_wildcard_topics(true);
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut project = client(addr).await;
+        let mut publisher = client(addr).await;
+        let mut everything = client(addr).await;
+        send_all(
+            &mut project,
+            &[r#"{"type":"subscribe","topics":["project:123:*"]}"#],
+        )
+        .await;
+        send_all(&mut everything, &[r#"{"type":"subscribe","topics":["*"]}"#]).await;
+        // topics of nested namespaces don't match
+        let ns = svc.namespaced("app");
+        ns.publish("project:123:room", AxumMessage::text("nested"))
+            .await
+            .unwrap();
+        send_all(&mut everything, &[]).await;
+        // send_all fails if anything but a pong is received back, like a non-matching publish
+        send_all(
+            &mut publisher,
+            &[
+                r#"{"type":"publish","topic":"project:1234","data":"a"}"#,
+                r#"{"type":"publish","topic":"project:12","data":"b"}"#,
+                r#"{"type":"publish","topic":"project:123:*","data":"c"}"#,
+            ],
+        )
+        .await;
+        send_all(&mut project, &[]).await;
+
+        // patterns are plain topic names unless enabled
+        let addr = "127.0.0.1:16732";
+        let _server = start_server(addr, SignalingService::new()).await;
+        let mut project = client(addr).await;
+        let mut publisher = client(addr).await;
+        send_all(
+            &mut project,
+            &[r#"{"type":"subscribe","topics":["project:*"]}"#],
+        )
+        .await;
+        send_all(
+            &mut publisher,
+            &[r#"{"type":"publish","topic":"project:1","data":"a"}"#],
+        )
+        .await;
+        send_all(&mut project, &[]).await;
+        let publish = r#"{"type":"publish","topic":"project:*","data":"b"}"#;
+        send_all(&mut publisher, &[publish]).await;
+        assert_eq!(recv_text(&mut project).await, publish);
+    }
+
+    #[tokio::test]
+    async fn connections_are_closed_after_max_lifetime() {
+        let addr = "127.0.0.1:16733";
+        let svc = SignalingService::new()
+            .with_ping_timeout(Duration::ZERO)
+            .with_max_connection_lifetime(Duration::from_millis(200));
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut ws = client(addr).await;
+        let started = Instant::now();
+        send_all(&mut ws, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+        assert_eq!(svc.topic_count().await, 1);
+        match timeout(Duration::from_secs(1), ws.next()).await.unwrap() {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 4003),
+            other => panic!("expected close frame, got {other:?}"),
+        }
+        assert!(started.elapsed() >= Duration::from_millis(150));
+        assert_eq!(svc.topic_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn gone_connections_are_unsubscribed() {
+        async fn wait_for_no_topics(svc: &SignalingService) {
+            timeout(Duration::from_secs(1), async {
+                while svc.topic_count().await != 0 {
+                    sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+
+        let addr = "127.0.0.1:16736";
+        let svc = SignalingService::new().with_ping_timeout(Duration::from_millis(100));
+        let _server = start_server(addr, svc.clone()).await;
+
+        // connection dropped without a close frame
+        let mut ws = client(addr).await;
+        send_all(&mut ws, &[r#"{"type":"subscribe","topics":["dropped"]}"#]).await;
+        assert_eq!(svc.topic_count().await, 1);
+        drop(ws);
+        wait_for_no_topics(&svc).await;
+
+        // connection, which never answers pings, since it's not read from
+        let mut ws = client(addr).await;
+        send_all(&mut ws, &[r#"{"type":"subscribe","topics":["silent"]}"#]).await;
+        assert_eq!(svc.topic_count().await, 1);
+        wait_for_no_topics(&svc).await;
+        assert!(svc.topics().await.is_empty());
+        drop(ws);
+    }
+
+    #[tokio::test]
+    async fn connections_sending_only_pings_are_idle() {
+        let addr = "127.0.0.1:16735";
+        let conn_ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = conn_ids.clone();
+        let svc = SignalingService::new()
+            .with_ping_timeout(Duration::from_millis(150))
+            .with_idle_timeout(Duration::from_millis(500))
+            .with_topic_authorizer(move |_action, _topic, extensions| {
+                let conn_id = *extensions.get::<ConnectionId>().unwrap();
+                seen.lock().unwrap().push(conn_id);
+                async move { true }
+            });
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut ws = client(addr).await;
+        let started = Instant::now();
+        send_all(&mut ws, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+        assert_eq!(svc.topic_count().await, 1);
+        let conn_id = conn_ids.lock().unwrap()[0];
+        let last_activity = svc.connection_last_activity(conn_id).unwrap();
+        assert!(last_activity >= started);
+        let closed = timeout(Duration::from_secs(2), async {
+            let mut keepalive = tokio::time::interval(Duration::from_millis(50));
+            let mut closing = false;
+            loop {
+                tokio::select! {
+                    _ = keepalive.tick(), if !closing => {
+                        // both kinds of pings keep a connection open, but don't make it active
+                        let ping = Message::Text(r#"{"type":"ping"}"#.into());
+                        // a server may have closed the connection, with a close frame yet to be read
+                        closing = ws.send(ping).await.is_err()
+                            || ws.send(Message::Ping(Vec::new())).await.is_err();
+                        if let Some(at) = svc.connection_last_activity(conn_id) {
+                            assert_eq!(at, last_activity);
+                        }
+                    }
+                    msg = ws.next() => match msg {
+                        Some(Ok(Message::Close(Some(frame)))) => return u16::from(frame.code),
+                        Some(Ok(_)) => continue,
+                        other => panic!("expected close frame, got {other:?}"),
+                    }
+                }
+            }
+        });
+        assert_eq!(closed.await.unwrap(), 4004);
+        assert!(started.elapsed() >= Duration::from_millis(450));
+        assert_eq!(svc.topic_count().await, 0);
+        // closed connections are forgotten once their task ends
+        timeout(Duration::from_secs(1), async {
+            while svc.connection_last_activity(conn_id).is_some() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn connections_are_closed_on_shutdown() {
+        async fn handler(
+            ws: WebSocketUpgrade,
+            State((svc, shutdown)): State<(SignalingService, CancellationToken)>,
+        ) -> impl IntoResponse {
+            ws.on_upgrade(move |socket| async move {
+                let _ = signaling_conn_with_shutdown(socket, svc, shutdown.cancelled_owned()).await;
+            })
+        }
+
+        let addr = "127.0.0.1:16734";
+        let svc = SignalingService::new().with_ping_timeout(Duration::ZERO);
+        let shutdown = CancellationToken::new();
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let app = Router::new()
+            .route("/signaling", get(handler))
+            .with_state((svc.clone(), shutdown.clone()));
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let mut ws = client(addr).await;
+        send_all(&mut ws, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+        assert_eq!(svc.topic_count().await, 1);
+        shutdown.cancel();
+        match timeout(Duration::from_secs(1), ws.next()).await.unwrap() {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 1001),
+            other => panic!("expected close frame, got {other:?}"),
+        }
+        assert_eq!(svc.topic_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn metrics_are_recorded() {
+        #[derive(Default)]
+        struct Recorder {
+            connections: AtomicUsize,
+            inbound: AtomicUsize,
+            outbound: AtomicUsize,
+        }
+
+        impl Metrics for Recorder {
+            fn on_connect(&self) {
+                self.connections.fetch_add(1, Ordering::Relaxed);
+            }
+
+            fn on_disconnect(&self) {
+                self.connections.fetch_sub(1, Ordering::Relaxed);
+            }
+
+            fn on_message(&self, direction: Direction, _bytes: usize) {
+                match direction {
+                    Direction::Inbound => self.inbound.fetch_add(1, Ordering::Relaxed),
+                    Direction::Outbound => self.outbound.fetch_add(1, Ordering::Relaxed),
+                };
+            }
+        }
+
+        let addr = "127.0.0.1:16708";
+        let recorder = Arc::new(Recorder::default());
+        let svc = SignalingService::new().with_metrics(recorder.clone());
+        let _server = start_server(addr, svc).await;
+
+        let mut a = client(addr).await;
+        let mut b = client(addr).await;
+        send_all(&mut a, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+        send_all(
+            &mut b,
+            &[r#"{"type":"publish","topic":"room","data":"hi"}"#],
+        )
+        .await;
+        assert_eq!(
+            recv_text(&mut a).await,
+            r#"{"type":"publish","topic":"room","data":"hi"}"#
+        );
+        assert_eq!(recorder.connections.load(Ordering::Relaxed), 2);
+        // subscribe, publish and two pings
+        assert_eq!(recorder.inbound.load(Ordering::Relaxed), 4);
+        assert_eq!(recorder.outbound.load(Ordering::Relaxed), 1);
+
+        a.close(None).await.unwrap();
+        b.close(None).await.unwrap();
+        timeout(Duration::from_secs(1), async {
+            while recorder.connections.load(Ordering::Relaxed) != 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn protocol_pongs_keep_connection_alive() {
+        let addr = "127.0.0.1:16709";
+        let svc = SignalingService::new().with_ping_timeout(Duration::from_millis(50));
+        let _server = start_server(addr, svc).await;
+        let mut ws = client(addr).await;
+
+        // answer every ping for several ping intervals
+        let res = timeout(Duration::from_millis(300), async {
+            while let Some(msg) = ws.next().await {
+                match msg.unwrap() {
+                    Message::Ping(payload) => ws.send(Message::Pong(payload)).await.unwrap(),
+                    other => panic!("unexpected message: {other:?}"),
+                }
+            }
+        })
+        .await;
+        assert!(res.is_err(), "connection has been closed");
+
+        send_all(&mut ws, &[]).await;
+    }
+
+    #[tokio::test]
+    async fn protocol_pings_are_answered_with_pongs() {
+        let addr = "127.0.0.1:16723";
+        let _server = start_server(addr, SignalingService::new()).await;
+        let mut ws = client(addr).await;
+
+        ws.send(Message::Ping(b"payload".to_vec())).await.unwrap();
+        ws.send(Message::Text(r#"{"type":"ping"}"#.into()))
+            .await
+            .unwrap();
+        let mut pongs = Vec::new();
+        loop {
+            let msg = timeout(Duration::from_secs(1), ws.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            match msg {
+                Message::Pong(payload) => pongs.push(payload),
+                Message::Ping(_) => {}
+                Message::Text(txt) => {
+                    assert_eq!(txt, r#"{"type":"pong"}"#);
+                    break;
+                }
+                other => panic!("unexpected message: {other:?}"),
+            }
+        }
+        assert_eq!(pongs, vec![b"payload".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn unauthorized_topics_are_rejected() {
+        #[derive(Clone)]
+        struct Prefix(String);
+
+        async fn handler(
+            ws: WebSocketUpgrade,
+            headers: HeaderMap,
+            State(svc): State<SignalingService>,
+        ) -> impl IntoResponse {
+            let mut extensions = Extensions::new();
+            if let Some(prefix) = headers.get("x-room-prefix") {
+                extensions.insert(Prefix(prefix.to_str().unwrap().to_string()));
+            }
+            ws.on_upgrade(move |socket| async move {
+                let _ = signaling_conn_with(socket, svc, extensions).await;
+            })
+        }
+
+        async fn connect(addr: &str, prefix: &str) -> Client {
+            let mut req = format!("ws://{addr}/signaling")
+                .into_client_request()
+                .unwrap();
+            req.headers_mut()
+                .insert("x-room-prefix", prefix.parse().unwrap());
+            let (ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+            ws
+        }
+
+        let addr = "127.0.0.1:16710";
+        let svc = SignalingService::new().with_topic_authorizer(
+            |_action, topic, extensions| async move {
+                let prefix = extensions.get::<Prefix>();
+                prefix.is_some_and(|prefix| topic.starts_with(&prefix.0))
+            },
+        );
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let app = Router::new()
+            .route("/signaling", get(handler))
+            .with_state(svc.clone());
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let mut a = connect(addr, "team-").await;
+        let mut b = connect(addr, "team-").await;
+        let mut c = connect(addr, "other-").await;
+        send_all(
+            &mut a,
+            &[r#"{"type":"subscribe","topics":["team-1","other-1"]}"#],
+        )
+        .await;
+        send_all(&mut c, &[r#"{"type":"subscribe","topics":["team-1"]}"#]).await;
+        assert_eq!(svc.topics().await, vec![("team-1".into(), 1)]);
+
+        // unauthorized publish is dropped, so the next signal received is an authorized one
+        send_all(
+            &mut c,
+            &[r#"{"type":"publish","topic":"team-1","data":"c"}"#],
+        )
+        .await;
+        send_all(
+            &mut b,
+            &[r#"{"type":"publish","topic":"team-1","data":"b"}"#],
+        )
+        .await;
+        assert_eq!(
+            recv_text(&mut a).await,
+            r#"{"type":"publish","topic":"team-1","data":"b"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn connection_context_is_passed_to_authorizer() {
+        #[derive(Clone)]
+        struct User(u64);
+
+        async fn handler(
+            ws: WebSocketUpgrade,
+            State(svc): State<SignalingService>,
+        ) -> impl IntoResponse {
+            static NEXT_USER: AtomicU64 = AtomicU64::new(1);
+            let user = User(NEXT_USER.fetch_add(1, Ordering::Relaxed));
+            ws.on_upgrade(move |socket| async move {
+                let _ = signaling_conn_with_ctx(socket, svc, user).await;
+            })
+        }
+
+        let addr = "127.0.0.1:16725";
+        let svc = SignalingService::new().with_topic_authorizer(
+            |_action, topic, extensions| async move {
+                let user = extensions.get::<User>().unwrap();
+                *topic == *format!("user-{}", user.0)
+            },
+        );
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let app = Router::new()
+            .route("/signaling", get(handler))
+            .with_state(svc.clone());
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let mut first = client(addr).await;
+        send_all(
+            &mut first,
+            &[r#"{"type":"subscribe","topics":["user-1","user-2"]}"#],
+        )
+        .await;
+        let mut second = client(addr).await;
+        send_all(
+            &mut second,
+            &[r#"{"type":"subscribe","topics":["user-1","user-2"]}"#],
+        )
+        .await;
+        let mut topics = svc.topics().await;
+        topics.sort();
+        assert_eq!(topics, vec![("user-1".into(), 1), ("user-2".into(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn duplicate_subscriptions_dont_lock_topics() {
+        let addr = "127.0.0.1:16726";
+        let svc = SignalingService::new();
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut a = client(addr).await;
+        send_all(&mut a, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+        {
+            // a subscription requiring a write lock would get stuck until the guard is dropped
+            let _guard = svc.topics.read().await;
+            send_all(
+                &mut a,
+                &[r#"{"type":"subscribe","topics":["room","room"]}"#],
+            )
+            .await;
+        }
+        assert_eq!(svc.topics().await, vec![("room".into(), 1)]);
+    }
+
+    #[tokio::test]
+    async fn invalid_topic_names_are_ignored() {
+        let addr = "127.0.0.1:16711";
+        let svc = SignalingService::new().with_max_topic_length(16);
+        let _server = start_server(addr, svc.clone()).await;
+
+        let long = "a".repeat(17);
+        let mut a = client(addr).await;
+        let subscribe =
+            format!(r#"{{"type":"subscribe","topics":["{long}","room\nfake log line","room"]}}"#);
+        send_all(&mut a, &[&subscribe]).await;
+        assert_eq!(svc.topics().await, vec![("room".into(), 1)]);
+
+        // publishes to invalid topics are not forwarded
+        let mut b = client(addr).await;
+        let long_publish = format!(r#"{{"type":"publish","topic":"{long}","data":"x"}}"#);
+        send_all(
+            &mut b,
+            &[
+                &long_publish,
+                r#"{"type":"publish","topic":"room\r","data":"x"}"#,
+                r#"{"type":"publish","topic":"room","data":"y"}"#,
+            ],
+        )
+        .await;
+        assert_eq!(
+            recv_text(&mut a).await,
+            r#"{"type":"publish","topic":"room","data":"y"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_reports_deliveries() {
+        let addr = "127.0.0.1:16712";
+        let svc = SignalingService::new();
+        let _server = start_server(addr, svc.clone()).await;
+
+        let report = svc
+            .publish_acked("room", AxumMessage::text("hello"))
+            .await
+            .unwrap();
+        assert_eq!(report, PublishReport::default());
+
+        let mut a = client(addr).await;
+        let mut b = client(addr).await;
+        let subscribe = r#"{"type":"subscribe","topics":["room"]}"#;
+        send_all(&mut a, &[subscribe]).await;
+        send_all(&mut b, &[subscribe]).await;
+
+        let report = svc
+            .publish_acked("room", AxumMessage::text("hello"))
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            PublishReport {
+                delivered: 2,
+                failed: 0,
+                total: 2,
+            }
+        );
+        assert_eq!(recv_text(&mut a).await, "hello");
+        assert_eq!(recv_text(&mut b).await, "hello");
+    }
+
+    #[tokio::test]
+    async fn wedged_peers_dont_block_publishing() {
+        let addr = "127.0.0.1:16713";
+        let svc = SignalingService::new().with_send_timeout(Duration::from_millis(200));
+        let _server = start_server(addr, svc.clone()).await;
+
+        let subscribe = r#"{"type":"subscribe","topics":["room"]}"#;
+        // never reads anything after subscribing, so its socket buffers fill up eventually
+        let mut wedged = client(addr).await;
+        send_all(&mut wedged, &[subscribe]).await;
+        let mut good = client(addr).await;
+        send_all(&mut good, &[subscribe]).await;
+        let reader = tokio::spawn(async move {
+            let mut received = 0;
+            loop {
+                match recv(&mut good).await.into_text().unwrap().as_str() {
+                    "done" => return received,
+                    _ => received += 1,
+                }
+            }
+        });
+
+        let payload = "x".repeat(1024 * 1024);
+        let mut published = 0;
+        loop {
+            let report = timeout(
+                Duration::from_secs(1),
+                svc.publish_acked("room", AxumMessage::text(payload.clone())),
+            )
+            .await
+            .expect("publish got stuck on a wedged peer")
+            .unwrap();
+            published += 1;
+            assert_eq!(report.total, 2);
+            if report.failed == 1 {
+                break;
+            }
+            assert!(published < 500, "wedged peer never failed");
+        }
+        assert_eq!(svc.topics().await, vec![("room".into(), 1)]);
+
+        svc.publish("room", AxumMessage::text("done"))
+            .await
+            .unwrap();
+        assert_eq!(reader.await.unwrap(), published);
+        drop(wedged);
+    }
+
+    #[tokio::test]
+    async fn namespaces_are_isolated() {
+        let root = SignalingService::new();
+        let app_a = root.namespaced("app-a");
+        let app_b = root.namespaced("app-b");
+        let _server_a = start_server("127.0.0.1:16714", app_a.clone()).await;
+        let _server_b = start_server("127.0.0.1:16715", app_b.clone()).await;
+
+        let subscribe = r#"{"type":"subscribe","topics":["room1"]}"#;
+        let mut a = client("127.0.0.1:16714").await;
+        let mut b = client("127.0.0.1:16715").await;
+        send_all(&mut a, &[subscribe]).await;
+        send_all(&mut b, &[subscribe]).await;
+        assert_eq!(app_a.topics().await, vec![("room1".into(), 1)]);
+        assert_eq!(app_b.topics().await, vec![("room1".into(), 1)]);
+        assert_eq!(root.topic_count().await, 0);
+
+        let publish = r#"{"type":"publish","topic":"room1","data":"a"}"#;
+        let mut publisher = client("127.0.0.1:16714").await;
+        send_all(&mut publisher, &[publish]).await;
+        assert_eq!(recv_text(&mut a).await, publish);
+        app_b
+            .publish("room1", AxumMessage::text("b"))
+            .await
+            .unwrap();
+        assert_eq!(recv_text(&mut b).await, "b");
+        assert!(timeout(Duration::from_millis(200), recv(&mut a))
+            .await
+            .is_err());
+        assert!(timeout(Duration::from_millis(200), recv(&mut b))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn unsupported_versions_are_rejected() {
+        let addr = "127.0.0.1:16716";
+        let svc = SignalingService::new().with_supported_versions([2, 3]);
+        let _server = start_server(addr, svc.clone()).await;
+        let subscribe = r#"{"type":"subscribe","topics":["room"]}"#;
+
+        let mut outdated = client(addr).await;
+        let mut no_handshake = client(addr).await;
+        let hello = r#"{"type":"hello","version":1}"#;
+        outdated.send(Message::Text(hello.into())).await.unwrap();
+        no_handshake
+            .send(Message::Text(subscribe.into()))
+            .await
+            .unwrap();
+        for peer in [&mut outdated, &mut no_handshake] {
+            loop {
+                match timeout(Duration::from_secs(1), peer.next()).await.unwrap() {
+                    Some(Ok(Message::Ping(_))) => continue,
+                    Some(Ok(Message::Close(Some(frame)))) => {
+                        assert_eq!(u16::from(frame.code), 4002);
+                        assert_eq!(frame.reason, "unsupported protocol version");
+                        break;
+                    }
+                    other => panic!("expected close frame, got {other:?}"),
+                }
+            }
+        }
+
+        let mut current = client(addr).await;
+        send_all(
+            &mut current,
+            &[r#"{"type":"hello","version":3}"#, subscribe],
+        )
+        .await;
+        sleep(Duration::from_millis(100)).await;
+        let report = svc
+            .publish_acked("room", axum::extract::ws::Message::text("hello"))
+            .await
+            .unwrap();
+        assert_eq!(report.total, 1);
+        assert_eq!(recv_text(&mut current).await, "hello");
+    }
+
+    #[tokio::test]
+    async fn publisher_echo_can_be_disabled() {
+        let addr = "127.0.0.1:16717";
+        let svc = SignalingService::new().with_publisher_echo(false);
+        let _server = start_server(addr, svc).await;
+
+        let mut publisher = client(addr).await;
+        let mut other = client(addr).await;
+        let subscribe = r#"{"type":"subscribe","topics":["room"]}"#;
+        send_all(&mut publisher, &[subscribe]).await;
+        send_all(&mut other, &[subscribe]).await;
+
+        // send_all fails if anything but a pong is received back, like an echo of the publish
+        let publish = r#"{"type":"publish","topic":"room","data":"hi"}"#;
+        send_all(&mut publisher, &[publish]).await;
+        assert_eq!(recv_text(&mut other).await, publish);
+    }
+
+    #[tokio::test]
+    async fn stats_track_connections_and_publishes() {
+        let addr = "127.0.0.1:16718";
+        let svc = SignalingService::new();
+        let _server = start_server(addr, svc.clone()).await;
+        let stats = svc.stats().await;
+        assert_eq!(
+            (stats.connections, stats.topics, stats.published),
+            (0, 0, 0)
+        );
+
+        let mut a = client(addr).await;
+        let mut b = client(addr).await;
+        send_all(&mut a, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+        send_all(
+            &mut b,
+            &[r#"{"type":"publish","topic":"room","data":"hi"}"#],
+        )
+        .await;
+        svc.publish("other", axum::extract::ws::Message::text("hello"))
+            .await
+            .unwrap();
+        let stats = svc.namespaced("app").stats().await;
+        assert_eq!(
+            (stats.connections, stats.topics, stats.published),
+            (2, 0, 2)
+        );
+        let stats = svc.stats().await;
+        assert_eq!(
+            (stats.connections, stats.topics, stats.published),
+            (2, 1, 2)
+        );
+        assert!(stats.uptime > Duration::ZERO);
+
+        a.close(None).await.unwrap();
+        drop(b);
+        timeout(Duration::from_secs(1), async {
+            while svc.stats().await.connections != 0 {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(svc.stats().await.topics, 0);
+    }
+
+    #[tokio::test]
+    async fn drained_topic_notifies_subscribers_before_closing() {
+        let addr = "127.0.0.1:16719";
+        let svc = SignalingService::new()
+            .with_ping_timeout(Duration::ZERO)
+            .with_drain_grace_period(Duration::from_millis(200));
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut a = client(addr).await;
+        send_all(&mut a, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+
+        let drain = {
+            let svc = svc.clone();
+            tokio::spawn(async move {
+                let notice = axum::extract::ws::Message::text(r#"{"type":"migrate"}"#);
+                svc.drain_topic("room", notice).await.unwrap()
+            })
+        };
+        assert_eq!(recv_text(&mut a).await, r#"{"type":"migrate"}"#);
+        let notified = Instant::now();
+        match timeout(Duration::from_secs(1), a.next()).await.unwrap() {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 1001),
+            other => panic!("expected close frame, got {other:?}"),
+        }
+        // the close came after the grace period (with some leeway for scheduling)
+        assert!(notified.elapsed() >= Duration::from_millis(150));
+        let report = drain.await.unwrap();
+        assert_eq!((report.delivered, report.total), (1, 1));
+        assert_eq!(svc.topic_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn msgpack_connections_stick_to_negotiated_format() {
+        let addr = "127.0.0.1:16720";
+        let _server = start_server(addr, SignalingService::new()).await;
+        let mut packed = msgpack_client(addr).await;
+        let mut json = client(addr).await;
+        let decode = |msg: Message| {
+            let msg = AxumMessage::Binary(msg.into_data().into());
+            SignalFormat::MessagePack.decode(&msg).unwrap()
+        };
+
+        packed
+            .send(msgpack(&Signal::subscribe(["room"])))
+            .await
+            .unwrap();
+        packed.send(msgpack(&Signal::Ping)).await.unwrap();
+        let pong = decode(recv(&mut packed).await);
+        assert_eq!(Signal::from_message(&pong).unwrap(), Signal::Pong);
+        send_all(&mut json, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+
+        // published messages are converted for subscribers using the other format
+        let publish = r#"{"type":"publish","topic":"room","data":{"sdp":"x"}}"#;
+        json.send(Message::Text(publish.into())).await.unwrap();
+        assert_eq!(recv_text(&mut json).await, publish);
+        let received = decode(recv(&mut packed).await);
+        let data = RawValue::from_string(r#"{"sdp":"x"}"#.into()).unwrap();
+        assert_eq!(
+            Signal::from_message(&received).unwrap(),
+            Signal::publish("room", &data)
+        );
+        packed
+            .send(msgpack(&Signal::publish("room", &data)))
+            .await
+            .unwrap();
+        let received = AxumMessage::Text(recv_text(&mut json).await.into());
+        assert_eq!(
+            Signal::from_message(&received).unwrap(),
+            Signal::publish("room", &data)
+        );
+        decode(recv(&mut packed).await);
+
+        // a JSON message on a MessagePack connection is rejected
+        packed.send(Message::Text(publish.into())).await.unwrap();
+        loop {
+            match timeout(Duration::from_secs(1), packed.next())
+                .await
+                .unwrap()
+            {
+                Some(Ok(Message::Close(Some(frame)))) => {
+                    assert_eq!(u16::from(frame.code), 1002);
+                    break;
+                }
+                Some(Ok(Message::Ping(_))) => continue,
+                other => panic!("expected a close frame, got {other:?}"),
+            }
+        }
+        send_all(&mut json, &[]).await;
+    }
+
+    #[tokio::test]
+    async fn publish_many_delivers_once_per_connection() {
+        let addr = "127.0.0.1:16721";
+        let svc = SignalingService::new();
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut both = client(addr).await;
+        let mut one = client(addr).await;
+        let mut other = client(addr).await;
+        send_all(&mut both, &[r#"{"type":"subscribe","topics":["a","b"]}"#]).await;
+        send_all(&mut one, &[r#"{"type":"subscribe","topics":["b"]}"#]).await;
+        send_all(&mut other, &[r#"{"type":"subscribe","topics":["c"]}"#]).await;
+
+        let report = svc
+            .publish_many(&["a", "b", "missing"], AxumMessage::text("hello"))
+            .await
+            .unwrap();
+        assert_eq!(
+            report,
+            PublishReport {
+                delivered: 2,
+                failed: 0,
+                total: 2,
+            }
+        );
+        assert_eq!(recv_text(&mut both).await, "hello");
+        assert_eq!(recv_text(&mut one).await, "hello");
+        // a connection subscribed to both topics got the message only once
+        send_all(&mut both, &[]).await;
+        send_all(&mut other, &[]).await;
+        assert_eq!(svc.stats().await.published, 1);
+    }
+
+    #[tokio::test]
+    async fn connection_topics_are_listed() {
+        let addr = "127.0.0.1:16722";
+        let conn_ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = conn_ids.clone();
+        let svc =
+            SignalingService::new().with_topic_authorizer(move |_action, _topic, extensions| {
+                let conn_id = *extensions.get::<ConnectionId>().unwrap();
+                seen.lock().unwrap().push(conn_id);
+                async move { true }
+            });
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut a = client(addr).await;
+        send_all(&mut a, &[r#"{"type":"subscribe","topics":["a","b"]}"#]).await;
+        let mut b = client(addr).await;
+        send_all(&mut b, &[r#"{"type":"subscribe","topics":["b"]}"#]).await;
+        let conn_ids: Vec<ConnectionId> = conn_ids.lock().unwrap().clone();
+        let (a_id, b_id) = (conn_ids[0], conn_ids[2]);
+        assert_eq!(conn_ids[1], a_id);
+        assert_ne!(a_id, b_id);
+
+        let mut topics = svc.connection_topics(a_id).await;
+        topics.sort();
+        assert_eq!(topics, vec![Arc::from("a"), Arc::from("b")]);
+        assert_eq!(svc.connection_topics(b_id).await, vec![Arc::from("b")]);
+
+        send_all(&mut a, &[r#"{"type":"unsubscribe","topics":["b"]}"#]).await;
+        assert_eq!(svc.connection_topics(a_id).await, vec![Arc::from("a")]);
+        let unknown = ConnectionId::from(u64::from(b_id) + 1000);
+        assert!(svc.connection_topics(unknown).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn graceful_close_delivers_queued_messages() {
+        let addr = "127.0.0.1:16724";
+        let svc = SignalingService::new().with_ping_timeout(Duration::ZERO);
+        let _server = start_server(addr, svc.clone()).await;
+
+        let mut a = client(addr).await;
+        send_all(&mut a, &[r#"{"type":"subscribe","topics":["room"]}"#]).await;
+
+        let payload = "x".repeat(1024 * 1024);
+        let publish = {
+            let svc = svc.clone();
+            let payload = payload.clone();
+            tokio::spawn(async move { svc.publish("room", AxumMessage::text(payload)).await })
+        };
+        // let the publish start sending before closing
+        tokio::task::yield_now().await;
+        let close = tokio::spawn(svc.clone().close_graceful(Duration::from_secs(5)));
+
+        assert_eq!(recv_text(&mut a).await.len(), payload.len());
+        match timeout(Duration::from_secs(1), a.next()).await.unwrap() {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(u16::from(frame.code), 1001),
+            other => panic!("expected close frame, got {other:?}"),
+        }
+        publish.await.unwrap().unwrap();
+        close.await.unwrap().unwrap();
+        assert_eq!(svc.topic_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn fan_out_sends_concurrently() {
+        let receivers: Vec<usize> = (0..10).collect();
+        let start = Instant::now();
+        let sent = fan_out(&receivers, |i| async move {
+            sleep(Duration::from_millis(100)).await;
+            if i % 2 == 0 {
+                Ok(())
+            } else {
+                Err(Error::Closed)
+            }
+        })
+        .await;
+        // sending one by one would take a second
+        assert!(start.elapsed() < Duration::from_millis(500));
+        let failed: Vec<usize> = sent
+            .into_iter()
+            .filter(|(_, res)| res.is_err())
+            .map(|(i, _)| *i)
+            .collect();
+        assert_eq!(failed, vec![1, 3, 5, 7, 9]);
+    }
+
+    #[test]
+    fn signals_roundtrip_wire_format() {
+        let data = RawValue::from_string(r#"{"to":"peer-1","signal":{"sdp":"x"}}"#.into()).unwrap();
+        let cases = [
+            (
+                Signal::publish("room", &data),
+                r#"{"type":"publish","topic":"room","data":{"to":"peer-1","signal":{"sdp":"x"}}}"#,
+            ),
+            (
+                Signal::subscribe(["a", "b"]),
+                r#"{"type":"subscribe","topics":["a","b"]}"#,
+            ),
+            (
+                Signal::unsubscribe(vec![String::from("a")]),
+                r#"{"type":"unsubscribe","topics":["a"]}"#,
+            ),
+            (Signal::Ping, r#"{"type":"ping"}"#),
+            (Signal::Pong, r#"{"type":"pong"}"#),
+            (
+                Signal::Hello { version: 2 },
+                r#"{"type":"hello","version":2}"#,
+            ),
+        ];
+        for (signal, json) in cases {
+            let msg = signal.to_message().unwrap();
+            assert_eq!(msg, AxumMessage::Text(json.into()));
+            assert_eq!(Signal::from_message(&msg).unwrap(), signal);
+        }
+
+        let binary = AxumMessage::Binary(r#"{"type":"ping"}"#.as_bytes().to_vec().into());
+        assert_eq!(Signal::from_message(&binary).unwrap(), Signal::Ping);
+        assert!(Signal::from_message(&AxumMessage::Text("{not a signal".into())).is_err());
+        assert!(Signal::from_message(&AxumMessage::Ping(Vec::new().into())).is_err());
+    }
+
+    #[test]
+    fn owned_signals_outlive_messages() {
+        // keys are sorted, as conversion to MessagePack doesn't preserve their order
+        let signals: Vec<OwnedSignal> = [
+            r#"{"type":"publish","topic":"room","data":{"signal":{"sdp":"x"},"to":"peer-1"}}"#,
+            r#"{"type":"subscribe","topics":["a","b\u0000"]}"#,
+            r#"{"type":"unsubscribe","topics":["a"]}"#,
+            r#"{"type":"ping"}"#,
+            r#"{"type":"hello","version":2}"#,
+        ]
+        .into_iter()
+        .map(|json| {
+            let msg = AxumMessage::Text(json.into());
+            Signal::from_message(&msg).unwrap().into_owned()
+        })
+        .collect();
+
+        let OwnedSignal::Publish { topic, data } = &signals[0] else {
+            panic!("expected a publish signal, got {:?}", signals[0]);
+        };
+        assert_eq!(topic, "room");
+        assert_eq!(
+            data.as_ref().unwrap().get(),
+            r#"{"signal":{"sdp":"x"},"to":"peer-1"}"#
+        );
+        let msg = signals[0].to_message().unwrap();
+        assert_eq!(
+            msg.to_text().unwrap(),
+            r#"{"type":"publish","topic":"room","data":{"signal":{"sdp":"x"},"to":"peer-1"}}"#
+        );
+        assert_eq!(signals[1].as_signal(), Signal::subscribe(["a", "b\0"]));
+        for signal in signals {
+            let msg = signal.to_message().unwrap();
+            assert_eq!(OwnedSignal::from_message(&msg).unwrap(), signal);
+            let packed = signal.to_message_as(SignalFormat::MessagePack).unwrap();
+            let decoded = SignalFormat::MessagePack.decode(&packed).unwrap();
+            assert_eq!(
+                OwnedSignal::from(Signal::from_message(&decoded).unwrap()),
+                signal
+            );
+        }
+    }
+
+    #[test]
+    fn signals_roundtrip_msgpack_format() {
+        // keys are sorted, as conversion doesn't preserve their order
+        let data = RawValue::from_string(r#"{"signal":{"sdp":"x"},"to":"peer-1"}"#.into()).unwrap();
+        let signals = [
+            Signal::publish("room", &data),
+            Signal::subscribe(["a", "b"]),
+            Signal::unsubscribe(["a"]),
+            Signal::Ping,
+            Signal::Pong,
+            Signal::Hello { version: 2 },
+        ];
+        for signal in signals {
+            let json = signal.to_message().unwrap();
+            assert_eq!(signal.to_message_as(SignalFormat::Json).unwrap(), json);
+            assert_eq!(SignalFormat::Json.decode(&json).unwrap(), json);
+
+            let packed = signal.to_message_as(SignalFormat::MessagePack).unwrap();
+            let AxumMessage::Binary(bytes) = &packed else {
+                panic!("expected a binary message, got {packed:?}");
+            };
+            assert!(bytes.len() < json.to_text().unwrap().len());
+            let decoded = SignalFormat::MessagePack.decode(&packed).unwrap();
+            assert_eq!(Signal::from_message(&decoded).unwrap(), signal);
+        }
+
+        let text = AxumMessage::Text(r#"{"type":"ping"}"#.into());
+        assert!(SignalFormat::MessagePack.decode(&text).is_err());
+        let garbage = AxumMessage::Binary(vec![0xc1].into());
+        assert!(SignalFormat::MessagePack.decode(&garbage).is_err());
+        assert_eq!(
+            SignalFormat::from_protocol(Some(MSGPACK_PROTOCOL)),
+            SignalFormat::MessagePack
+        );
+        assert_eq!(
+            SignalFormat::from_protocol(Some("other")),
+            SignalFormat::Json
+        );
+        assert_eq!(SignalFormat::from_protocol(None), SignalFormat::Json);
+    }
 }
@@ -0,0 +1,371 @@
+use futures_util::{Sink, Stream};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::ops::Range;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+use yrs::block::ClientID;
+use yrs::sync::Error;
+use yrs::{DeleteSet, Doc, ReadTxn, StateVector, Transact};
+
+/// Asserts that two documents have converged to the same state. Panics with a readable list of
+/// differences otherwise.
+///
+/// Documents are compared by their state vectors and delete sets, normalized so that equivalent
+/// states - ie. ones produced by applying the same updates in a different order - are considered
+/// equal even if their binary encodings differ. Updates waiting for missing dependencies on either
+/// side are reported as a difference as well.
+pub fn assert_converged(a: &Doc, b: &Doc) {
+    if let Some(diff) = diff(a, b) {
+        panic!("documents have not converged:\n{diff}");
+    }
+}
+
+fn diff(a: &Doc, b: &Doc) -> Option<String> {
+    let ta = a.transact();
+    let tb = b.transact();
+    let mut out = String::new();
+
+    let sva = clocks(&ta.state_vector());
+    let svb = clocks(&tb.state_vector());
+    for client_id in sva.keys().chain(svb.keys()).collect::<BTreeSet<_>>() {
+        let ca = sva.get(client_id).copied().unwrap_or_default();
+        let cb = svb.get(client_id).copied().unwrap_or_default();
+        if ca != cb {
+            writeln!(out, "  client {client_id}: clock {ca} != {cb}").unwrap();
+        }
+    }
+
+    let dsa = deleted(&ta.snapshot().delete_set);
+    let dsb = deleted(&tb.snapshot().delete_set);
+    for client_id in dsa.keys().chain(dsb.keys()).collect::<BTreeSet<_>>() {
+        let ra = dsa.get(client_id).map(Vec::as_slice).unwrap_or_default();
+        let rb = dsb.get(client_id).map(Vec::as_slice).unwrap_or_default();
+        if ra != rb {
+            writeln!(out, "  client {client_id}: deleted {ra:?} != {rb:?}").unwrap();
+        }
+    }
+
+    if let Some(pending) = ta.store().pending_update() {
+        writeln!(
+            out,
+            "  left has pending update missing {:?}",
+            pending.missing
+        )
+        .unwrap();
+    }
+    if let Some(pending) = tb.store().pending_update() {
+        writeln!(
+            out,
+            "  right has pending update missing {:?}",
+            pending.missing
+        )
+        .unwrap();
+    }
+
+    if out.is_empty() {
+        None
+    } else {
+        Some(out)
+    }
+}
+
+fn clocks(sv: &StateVector) -> BTreeMap<ClientID, u32> {
+    sv.iter()
+        .filter(|(_, &clock)| clock != 0)
+        .map(|(&client_id, &clock)| (client_id, clock))
+        .collect()
+}
+
+/// Returns deleted clock ranges per client, sorted and with adjacent ranges merged.
+fn deleted(ds: &DeleteSet) -> BTreeMap<ClientID, Vec<Range<u32>>> {
+    let mut result = BTreeMap::new();
+    for (&client_id, ranges) in ds.iter() {
+        let mut ranges: Vec<Range<u32>> = ranges.iter().cloned().collect();
+        ranges.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range<u32>> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        if !merged.is_empty() {
+            result.insert(client_id, merged);
+        }
+    }
+    result
+}
+
+/// Creates a pair of connected in-memory transports, each made of a [MemorySink] and
+/// a [MemoryStream]. Messages sent through a sink of one end are received by a stream of the other
+/// one, with at most `capacity` messages buffered per direction.
+///
+/// Both ends implement the same [Sink] and [Stream] interfaces as
+/// [AxumSink](crate::ws::AxumSink) and [AxumStream](crate::ws::AxumStream), so that one of them can
+/// be subscribed to a [BroadcastGroup](crate::broadcast::BroadcastGroup) while the other one is
+/// driven by a test, eg. via [Connection](crate::conn::Connection), without running a server.
+///
+/// # Example
+///
+/// ```rust
+/// use std::sync::Arc;
+/// use tokio::sync::{Mutex, RwLock};
+/// use yrs::sync::Awareness;
+/// use yrs::Doc;
+/// use yrs_axum::broadcast::BroadcastGroup;
+/// use yrs_axum::conn::Connection;
+/// use yrs_axum::test_util::duplex;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let awareness = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+/// let group = BroadcastGroup::new(awareness, 16).await;
+///
+/// let ((server_sink, server_stream), (client_sink, client_stream)) = duplex(16);
+/// let _sub = group.subscribe(Arc::new(Mutex::new(server_sink)), server_stream);
+///
+/// let client = Arc::new(RwLock::new(Awareness::new(Doc::new())));
+/// let _conn = Connection::new(client, client_sink, client_stream);
+/// # }
+/// ```
+pub fn duplex(capacity: usize) -> ((MemorySink, MemoryStream), (MemorySink, MemoryStream)) {
+    let (a_tx, a_rx) = mpsc::channel(capacity);
+    let (b_tx, b_rx) = mpsc::channel(capacity);
+    let (a_inbox, b_inbox) = (a_tx.downgrade(), b_tx.downgrade());
+    let a = (MemorySink::new(b_tx, a_inbox), MemoryStream(a_rx));
+    let b = (MemorySink::new(a_tx, b_inbox), MemoryStream(b_rx));
+    (a, b)
+}
+
+/// A sending half of an in-memory transport created by [duplex].
+///
+/// Like a websocket transport, it answers pings on its own: an empty
+/// [PING_MSG](crate::broadcast::PING_MSG) is never delivered to the other end, instead it's
+/// reported back as a pong by the [MemoryStream] of the same end. Closing or dropping a sink ends
+/// the [MemoryStream] of the other end.
+#[derive(Debug)]
+pub struct MemorySink {
+    peer: PollSender<Vec<u8>>,
+    inbox: mpsc::WeakSender<Vec<u8>>,
+}
+
+impl MemorySink {
+    fn new(peer: mpsc::Sender<Vec<u8>>, inbox: mpsc::WeakSender<Vec<u8>>) -> Self {
+        MemorySink {
+            peer: PollSender::new(peer),
+            inbox,
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for MemorySink {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.peer
+            .poll_reserve(cx)
+            .map_err(|e| Error::Other(e.into()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
+        if item.is_empty() {
+            // answered right away, a pong is dropped if the inbox is full - just like a congested
+            // connection, which didn't respond in time
+            if let Some(inbox) = self.inbox.upgrade() {
+                let _ = inbox.try_send(Vec::new());
+            }
+            // release a slot reserved by poll_ready
+            self.peer.abort_send();
+            return Ok(());
+        }
+        self.peer
+            .send_item(item)
+            .map_err(|e| Error::Other(e.into()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.peer.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A receiving half of an in-memory transport created by [duplex]. Pongs are reported as empty
+/// messages, the same way as by [AxumStream](crate::ws::AxumStream).
+#[derive(Debug)]
+pub struct MemoryStream(mpsc::Receiver<Vec<u8>>);
+
+impl Stream for MemoryStream {
+    type Item = Result<Vec<u8>, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx).map(|msg| msg.map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::broadcast::{BroadcastGroup, Subscription, PING_MSG};
+    use crate::conn::Connection;
+    use crate::test_util::{assert_converged, duplex, MemorySink, MemoryStream};
+    use crate::AwarenessRef;
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::{Mutex, RwLock};
+    use tokio::time::{sleep, timeout};
+    use yrs::sync::{Awareness, Message, SyncMessage};
+    use yrs::updates::decoder::Decode;
+    use yrs::updates::encoder::Encode;
+    use yrs::{
+        Doc, GetString, ReadTxn, StateVector, Text, TextRef, Transact, TransactionMut, Update,
+    };
+
+    fn sync(from: &Doc, to: &Doc) {
+        let sv = to.transact().state_vector();
+        let update = from.transact().encode_state_as_update_v1(&sv);
+        to.transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap());
+    }
+
+    type Peer = Connection<MemorySink, MemoryStream>;
+
+    fn peer(group: &BroadcastGroup, client_id: u64) -> (Peer, Subscription) {
+        let ((server_sink, server_stream), (sink, stream)) = duplex(16);
+        let sub = group.subscribe(Arc::new(Mutex::new(server_sink)), server_stream);
+        let awareness = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(client_id))));
+        (Connection::new(awareness, sink, stream), sub)
+    }
+
+    async fn content(awareness: &AwarenessRef) -> String {
+        let awareness = awareness.read().await;
+        let text = awareness.doc().get_or_insert_text("test");
+        let txn = awareness.doc().transact();
+        text.get_string(&txn)
+    }
+
+    async fn wait_for(awareness: &AwarenessRef, expected: &str) {
+        timeout(Duration::from_secs(5), async {
+            while content(awareness).await != expected {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap_or_else(|_| panic!("peer didn't receive {expected:?}"));
+    }
+
+    /// Changes a text of a `peer` and sends the resulting update to the group, as changes made on
+    /// the client side are not propagated automatically.
+    async fn edit(peer: &Peer, f: impl FnOnce(&TextRef, &mut TransactionMut)) {
+        let update = {
+            let awareness = peer.awareness().write().await;
+            let doc = awareness.doc();
+            let text = doc.get_or_insert_text("test");
+            let mut txn = doc.transact_mut();
+            let sv = txn.state_vector();
+            f(&text, &mut txn);
+            txn.encode_state_as_update_v1(&sv)
+        };
+        let msg = Message::Sync(SyncMessage::Update(update)).encode_v1();
+        peer.send(msg).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn peers_sync_text_through_group() {
+        let server = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(server.clone(), 16).await;
+        let (a, _sub_a) = peer(&group, 2);
+        let (b, _sub_b) = peer(&group, 3);
+
+        edit(&a, |text, txn| text.push(txn, "hello")).await;
+        wait_for(b.awareness(), "hello").await;
+        edit(&b, |text, txn| text.push(txn, " world")).await;
+        wait_for(a.awareness(), "hello world").await;
+        wait_for(&server, "hello world").await;
+
+        let a = a.awareness().read().await;
+        let b = b.awareness().read().await;
+        assert_converged(a.doc(), b.doc());
+        assert_converged(a.doc(), server.read().await.doc());
+    }
+
+    #[tokio::test]
+    async fn concurrent_edits_converge_through_group() {
+        let server = Arc::new(RwLock::new(Awareness::new(Doc::with_client_id(1))));
+        let group = BroadcastGroup::new(server.clone(), 16).await;
+        let (a, _sub_a) = peer(&group, 2);
+        let (b, _sub_b) = peer(&group, 3);
+
+        edit(&a, |text, txn| text.push(txn, "abc")).await;
+        wait_for(b.awareness(), "abc").await;
+        // both peers edit the same text without waiting for each other
+        tokio::join!(
+            edit(&a, |text, txn| text.insert(txn, 0, "123")),
+            edit(&b, |text, txn| text.remove_range(txn, 1, 1)),
+        );
+        wait_for(a.awareness(), "123ac").await;
+        wait_for(b.awareness(), "123ac").await;
+
+        let a = a.awareness().read().await;
+        let b = b.awareness().read().await;
+        assert_converged(a.doc(), b.doc());
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_by_transport() {
+        let ((mut sink, mut stream), (mut peer_sink, mut peer_stream)) = duplex(1);
+        sink.send(PING_MSG.to_vec()).await.unwrap();
+        sink.send(b"data".to_vec()).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), PING_MSG);
+        assert_eq!(peer_stream.next().await.unwrap().unwrap(), b"data");
+
+        peer_sink.close().await.unwrap();
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn concurrent_changes_converge() {
+        let a = Doc::with_client_id(1);
+        let b = Doc::with_client_id(2);
+        let ta = a.get_or_insert_text("test");
+        let tb = b.get_or_insert_text("test");
+        ta.push(&mut a.transact_mut(), "abc");
+        tb.push(&mut b.transact_mut(), "def");
+        ta.remove_range(&mut a.transact_mut(), 1, 1);
+
+        sync(&a, &b);
+        sync(&b, &a);
+
+        assert_converged(&a, &b);
+    }
+
+    #[test]
+    #[should_panic(expected = "client 1: deleted [1..2] != []")]
+    fn missing_deletion_is_reported() {
+        let a = Doc::with_client_id(1);
+        let text = a.get_or_insert_text("test");
+        text.push(&mut a.transact_mut(), "abc");
+        let b = Doc::with_client_id(2);
+        sync(&a, &b);
+
+        let update = {
+            let sv = StateVector::default();
+            a.transact().encode_state_as_update_v1(&sv)
+        };
+        text.remove_range(&mut a.transact_mut(), 1, 1);
+        // b only receives state from before the removal
+        b.transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap());
+
+        assert_converged(&a, &b);
+    }
+}
@@ -1,11 +1,26 @@
+use crate::auth::{AuthError, IdentityProvider, PeerPermissions};
+use crate::broadcast::{
+    has_close_reason, take_close_reason, BroadcastGroup, Subscription, DEFAULT_MAX_MESSAGE_SIZE,
+};
 use crate::conn::Connection;
 use crate::AwarenessRef;
+use axum::extract::ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+#[cfg(feature = "compression")]
+use axum::http::HeaderValue;
 use futures_util::stream::{SplitSink, SplitStream};
-use futures_util::{Stream, StreamExt};
+use futures_util::{ready, Stream, StreamExt};
+use std::borrow::Cow;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
-use axum::extract::ws::{WebSocket, Message};
-use yrs::sync::Error;
+use tokio::sync::Mutex;
+use yrs::sync::{Error, Message as YMessage};
+use yrs::updates::decoder::Decode;
+use yrs::updates::encoder::Encode;
 
 /// Connection Wrapper over a [WebSocket], which implements a Yjs/Yrs awareness and update exchange
 /// protocol.
@@ -18,8 +33,8 @@ pub struct AxumConn(Connection<AxumSink, AxumStream>);
 
 impl AxumConn {
     pub fn new(awareness: AwarenessRef, socket: WebSocket) -> Self {
-        let (sink, stream) = socket.split();
-        let conn = Connection::new(awareness, AxumSink(sink), AxumStream(stream));
+        let (sink, stream) = split(socket);
+        let conn = Connection::new(awareness, sink, stream);
         AxumConn(conn)
     }
 }
@@ -86,8 +101,8 @@ impl core::future::Future for AxumConn {
 /// async fn peer(ws: WebSocket, bcast: Arc<BroadcastGroup>) {
 ///     let (sink, stream) = ws.split();
 ///     // convert axum web socket into compatible sink/stream
-///     let sink = Arc::new(Mutex::new(AxumSink(sink)));
-///     let stream = AxumStream(stream);
+///     let sink = Arc::new(Mutex::new(AxumSink::from(sink)));
+///     let stream = AxumStream::from(stream);
 ///     // subscribe to broadcast group
 ///     let sub = bcast.subscribe(sink, stream);
 ///     // wait for subscribed connection to close itself
@@ -97,19 +112,28 @@ impl core::future::Future for AxumConn {
 ///     }
 /// }
 /// ```
-#[repr(transparent)]
 #[derive(Debug)]
-pub struct AxumSink(pub SplitSink<WebSocket, Message>);
+pub struct AxumSink(pub SplitSink<WebSocket, Message>, Codec);
+
+#[cfg(feature = "compression")]
+impl AxumSink {
+    /// Compresses messages sent to a peer, which has negotiated a compressed subprotocol - see
+    /// [Compression::negotiated]. A `None` compression sends messages as they are.
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.1 = Codec(compression);
+        self
+    }
+}
 
 impl From<SplitSink<WebSocket, Message>> for AxumSink {
     fn from(sink: SplitSink<WebSocket, Message>) -> Self {
-        AxumSink(sink)
+        AxumSink(sink, Codec::default())
     }
 }
 
-impl Into<SplitSink<WebSocket, Message>> for AxumSink {
-    fn into(self) -> SplitSink<WebSocket, Message> {
-        self.0
+impl From<AxumSink> for SplitSink<WebSocket, Message> {
+    fn from(sink: AxumSink) -> Self {
+        sink.0
     }
 }
 
@@ -125,7 +149,13 @@ impl futures_util::Sink<Vec<u8>> for AxumSink {
     }
 
     fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> Result<(), Self::Error> {
-        if let Err(e) = Pin::new(&mut self.0).start_send(Message::binary(item)) {
+        let msg = if item.is_empty() {
+            // see broadcast::PING_MSG
+            Message::Ping(Default::default())
+        } else {
+            Message::binary(self.1.encode(item)?)
+        };
+        if let Err(e) = Pin::new(&mut self.0).start_send(msg) {
             Err(Error::Other(e.into()))
         } else {
             Ok(())
@@ -141,6 +171,21 @@ impl futures_util::Sink<Vec<u8>> for AxumSink {
     }
 
     fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if has_close_reason() {
+            // closed by a broadcast group: tell the peer why, before closing the socket
+            match Pin::new(&mut self.0).poll_ready(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => tracing::debug!("failed to send close frame: {e}"),
+                Poll::Ready(Ok(())) => {
+                    if let Some(reason) = take_close_reason() {
+                        let frame = Message::Close(Some(reason.into()));
+                        if let Err(e) = Pin::new(&mut self.0).start_send(frame) {
+                            tracing::debug!("failed to send close frame: {e}");
+                        }
+                    }
+                }
+            }
+        }
         match Pin::new(&mut self.0).poll_close(cx) {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Err(e)) => Poll::Ready(Err(Error::Other(e.into()))),
@@ -152,6 +197,11 @@ impl futures_util::Sink<Vec<u8>> for AxumSink {
 /// An axum websocket stream wrapper, that implements futures `Stream` in a way, that makes it compatible
 /// with y-sync protocol, so that it can be used by y-sync crate [BroadcastGroup].
 ///
+/// Messages bigger than [AxumStream::with_max_message_size] are reported as errors, which end
+/// a subscription, without being copied. Since axum buffers whole messages before passing them
+/// on, use its `WebSocketUpgrade::max_message_size` as well to reject oversized frames before
+/// they are buffered. The limit applies to decompressed messages as well.
+///
 /// # Examples
 ///
 /// ```rust
@@ -199,8 +249,8 @@ impl futures_util::Sink<Vec<u8>> for AxumSink {
 /// async fn peer(ws: WebSocket, bcast: Arc<BroadcastGroup>) {
 ///     let (sink, stream) = ws.split();
 ///     // convert axum web socket into compatible sink/stream
-///     let sink = Arc::new(Mutex::new(AxumSink(sink)));
-///     let stream = AxumStream(stream);
+///     let sink = Arc::new(Mutex::new(AxumSink::from(sink)));
+///     let stream = AxumStream::from(stream);
 ///     // subscribe to broadcast group
 ///     let sub = bcast.subscribe(sink, stream);
 ///     // wait for subscribed connection to close itself
@@ -211,17 +261,35 @@ impl futures_util::Sink<Vec<u8>> for AxumSink {
 /// }
 /// ```
 #[derive(Debug)]
-pub struct AxumStream(pub SplitStream<WebSocket>);
+pub struct AxumStream(pub SplitStream<WebSocket>, usize, Codec);
+
+impl AxumStream {
+    /// Sets a maximum binary size of a message accepted from a peer, defaults to
+    /// [DEFAULT_MAX_MESSAGE_SIZE]. A zero `max` means no limit.
+    pub fn with_max_message_size(mut self, max: usize) -> Self {
+        self.1 = max;
+        self
+    }
+
+    /// Decompresses messages received from a peer, which has negotiated a compressed
+    /// subprotocol - see [Compression::negotiated]. A `None` compression passes messages as they
+    /// are.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.2 = Codec(compression);
+        self
+    }
+}
 
 impl From<SplitStream<WebSocket>> for AxumStream {
     fn from(stream: SplitStream<WebSocket>) -> Self {
-        AxumStream(stream)
+        AxumStream(stream, DEFAULT_MAX_MESSAGE_SIZE, Codec::default())
     }
 }
 
-impl Into<SplitStream<WebSocket>> for AxumStream {
-    fn into(self) -> SplitStream<WebSocket> {
-        self.0
+impl From<AxumStream> for SplitStream<WebSocket> {
+    fn from(stream: AxumStream) -> Self {
+        stream.0
     }
 }
 
@@ -229,45 +297,591 @@ impl Stream for AxumStream {
     type Item = Result<Vec<u8>, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match Pin::new(&mut self.0).poll_next(cx) {
-            Poll::Pending => Poll::Pending,
-            Poll::Ready(None) => Poll::Ready(None),
-            Poll::Ready(Some(res)) => match res {
-                Ok(item) => Poll::Ready(Some(Ok(item.into_data().to_vec()))),
-                Err(e) => Poll::Ready(Some(Err(Error::Other(e.into())))),
-            },
+        loop {
+            match ready!(Pin::new(&mut self.0).poll_next(cx)) {
+                // a close frame ends the stream, its payload isn't a y-sync message
+                None | Some(Ok(Message::Close(_))) => return Poll::Ready(None),
+                // pings are answered by axum on its own
+                Some(Ok(Message::Ping(_))) => continue,
+                // reported as an empty message, see broadcast::PING_MSG
+                Some(Ok(Message::Pong(_))) => return Poll::Ready(Some(Ok(Vec::new()))),
+                Some(Ok(item)) => {
+                    let data = item.into_data();
+                    if self.1 != 0 && data.len() > self.1 {
+                        let (len, max) = (data.len(), self.1);
+                        tracing::warn!(
+                            "rejecting message of {len} bytes over the limit of {max} bytes"
+                        );
+                        let e = crate::error::Error::LimitExceeded(format!(
+                            "message of {len} bytes exceeds the size limit"
+                        ));
+                        return Poll::Ready(Some(Err(e.into())));
+                    }
+                    return Poll::Ready(Some(self.2.decode(&data, self.1)));
+                }
+                Some(Err(e)) => return Poll::Ready(Some(Err(Error::Other(e.into())))),
+            }
+        }
+    }
+}
+
+/// Splits a `socket` into [AxumSink] and [AxumStream], which compress messages if the socket has
+/// negotiated compression.
+fn split(socket: WebSocket) -> (AxumSink, AxumStream) {
+    #[cfg(feature = "compression")]
+    let compression = Compression::negotiated(socket.protocol());
+    let (sink, stream) = socket.split();
+    let (sink, stream) = (AxumSink::from(sink), AxumStream::from(stream));
+    #[cfg(feature = "compression")]
+    let (sink, stream) = (
+        sink.with_compression(compression),
+        stream.with_compression(compression),
+    );
+    (sink, stream)
+}
+
+/// Framing of binary messages exchanged by [AxumSink] and [AxumStream], which depends on
+/// a compression negotiated with a peer.
+#[derive(Debug, Clone, Copy, Default)]
+struct Codec(#[cfg(feature = "compression")] Option<Compression>);
+
+impl Codec {
+    fn encode(&self, msg: Vec<u8>) -> Result<Vec<u8>, Error> {
+        #[cfg(feature = "compression")]
+        if let Some(compression) = &self.0 {
+            return compression.compress(msg);
+        }
+        Ok(msg)
+    }
+
+    #[cfg_attr(not(feature = "compression"), allow(unused_variables))]
+    fn decode(&self, frame: &[u8], max: usize) -> Result<Vec<u8>, Error> {
+        #[cfg(feature = "compression")]
+        if self.0.is_some() {
+            return Compression::decompress(frame, max);
+        }
+        Ok(frame.to_vec())
+    }
+}
+
+/// Splits a `socket` into a typed y-sync message sink and stream - see [AxumMessageSink] and
+/// [AxumMessageStream].
+pub fn message_codec(socket: WebSocket) -> (AxumMessageSink, AxumMessageStream) {
+    let (sink, stream) = socket.split();
+    (AxumMessageSink(sink), AxumMessageStream(stream))
+}
+
+/// An axum websocket sink wrapper, which encodes y-sync protocol [messages](YMessage) into binary
+/// websocket frames understood by y-websocket clients. Every frame carries exactly one message,
+/// starting with a var-int message type:
+///
+/// - `0` (sync) followed by a sync step: `0` - SyncStep1 with an encoded state vector, `1` -
+///   SyncStep2 with an update, or `2` - an update.
+/// - `1` (awareness) followed by an encoded awareness update.
+/// - `2` (auth) followed by an optional permission denied reason.
+/// - `3` (query awareness) with no payload.
+///
+/// Unlike [AxumSink], which passes already encoded messages, this allows to implement
+/// y-websocket server logic directly on top of [YMessage] values.
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct AxumMessageSink(pub SplitSink<WebSocket, Message>);
+
+impl futures_util::Sink<YMessage> for AxumMessageSink {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.0)
+            .poll_ready(cx)
+            .map_err(|e| Error::Other(e.into()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: YMessage) -> Result<(), Self::Error> {
+        Pin::new(&mut self.0)
+            .start_send(Message::binary(item.encode_v1()))
+            .map_err(|e| Error::Other(e.into()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.0)
+            .poll_flush(cx)
+            .map_err(|e| Error::Other(e.into()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.0)
+            .poll_close(cx)
+            .map_err(|e| Error::Other(e.into()))
+    }
+}
+
+/// An axum websocket stream wrapper, which decodes binary websocket frames sent by y-websocket
+/// clients into y-sync protocol [messages](YMessage). See [AxumMessageSink] for the frame layout.
+///
+/// Control frames (ping/pong) and text frames are skipped. The stream ends once a close frame has
+/// been received. Frames, which cannot be decoded, are reported as errors.
+#[repr(transparent)]
+#[derive(Debug)]
+pub struct AxumMessageStream(pub SplitStream<WebSocket>);
+
+impl Stream for AxumMessageStream {
+    type Item = Result<YMessage, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match ready!(Pin::new(&mut self.0).poll_next(cx)) {
+                None | Some(Ok(Message::Close(_))) => return Poll::Ready(None),
+                Some(Ok(Message::Binary(data))) => {
+                    return Poll::Ready(Some(YMessage::decode_v1(&data).map_err(Error::from)))
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Poll::Ready(Some(Err(Error::Other(e.into())))),
+            }
         }
     }
 }
 
+/// Information about a remote client of a web socket connection, which takes `X-Forwarded-For`
+/// and `X-Forwarded-Proto` headers set by trusted reverse proxies into account. It can be passed
+/// to a connection handler - eg. as a `metadata` of [subscribe_authorized] - so that logs and rate
+/// limiters can key on the real client rather than a proxy.
+///
+/// A peer address is available to axum handlers via `ConnectInfo<SocketAddr>` extractor, when
+/// the server is started with `into_make_service_with_connect_info::<SocketAddr>()`.
+///
+/// # Example
+///
+/// ```rust
+/// use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+/// use axum::extract::{ConnectInfo, ws::WebSocketUpgrade};
+/// use axum::http::HeaderMap;
+/// use axum::response::IntoResponse;
+/// use yrs_axum::ws::ClientInfo;
+///
+/// // address of a reverse proxy terminating TLS in front of this server
+/// const PROXIES: [IpAddr; 1] = [IpAddr::V4(Ipv4Addr::LOCALHOST)];
+///
+/// async fn ws_handler(
+///     ws: WebSocketUpgrade,
+///     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+///     headers: HeaderMap,
+/// ) -> impl IntoResponse {
+///     let client = ClientInfo::resolve(addr, &headers, &PROXIES);
+///     ws.on_upgrade(move |socket| async move {
+///         println!("client {} connected (secure: {})", client.client_ip, client.secure);
+///         // .. handle the socket
+///     })
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Address of a directly connected peer, which may be a reverse proxy.
+    pub peer_addr: SocketAddr,
+    /// IP address of the client, as reported by trusted proxies.
+    pub client_ip: IpAddr,
+    /// Whether the client connected over TLS (`wss://`), as reported by trusted proxies. Servers
+    /// terminating TLS on their own should set it themselves.
+    pub secure: bool,
+}
+
+impl ClientInfo {
+    /// Creates information about a client connected directly, without any proxies.
+    pub fn new(peer_addr: SocketAddr) -> Self {
+        ClientInfo {
+            peer_addr,
+            client_ip: peer_addr.ip(),
+            secure: false,
+        }
+    }
+
+    /// Resolves a client of a connection from a `peer_addr` using forwarding `headers`. Headers
+    /// are taken into account only if a `peer_addr` is one of `trusted_proxies`, as they can be
+    /// set by anyone otherwise. A client IP is the rightmost `X-Forwarded-For` entry, which is not
+    /// a trusted proxy itself.
+    pub fn resolve(peer_addr: SocketAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> Self {
+        let mut info = Self::new(peer_addr);
+        if !trusted_proxies.contains(&peer_addr.ip()) {
+            return info;
+        }
+        let forwarded_for = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .collect::<Vec<_>>();
+        if let Some(ip) = forwarded_for
+            .iter()
+            .rev()
+            .find(|ip| !trusted_proxies.contains(ip))
+            .or(forwarded_for.first())
+        {
+            info.client_ip = *ip;
+        }
+        // the first proxy in chain is the one which has been connected by the client
+        if let Some(proto) = headers
+            .get("x-forwarded-proto")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(',').next())
+        {
+            info.secure = matches!(proto.trim(), "https" | "wss");
+        }
+        info
+    }
+}
+
+/// A list of web socket subprotocols accepted by an endpoint, in decreasing order of preference.
+/// Some clients refuse connections, which don't confirm one of the subprotocols they requested.
+///
+/// # Example
+///
+/// ```rust
+/// use axum::extract::ws::WebSocketUpgrade;
+/// use axum::response::Response;
+/// use yrs_axum::ws::Subprotocols;
+///
+/// async fn ws_handler(ws: WebSocketUpgrade) -> Response {
+///     let subprotocols = Subprotocols::new(["yjs/2", "yjs"]);
+///     subprotocols.negotiate(ws).on_upgrade(|socket| async move {
+///         // chosen subprotocol - if any - is available via socket.protocol()
+///     })
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Subprotocols(Vec<Cow<'static, str>>);
+
+impl Subprotocols {
+    pub fn new<I>(protocols: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<Cow<'static, str>>,
+    {
+        Subprotocols(protocols.into_iter().map(Into::into).collect())
+    }
+
+    /// Returns accepted subprotocols in decreasing order of preference.
+    pub fn protocols(&self) -> &[Cow<'static, str>] {
+        &self.0
+    }
+
+    /// Configures a web socket upgrade to choose the most preferred of these subprotocols, which
+    /// has been requested by the client, and confirm it in the `Sec-WebSocket-Protocol` response
+    /// header. If the client didn't request any of them, no subprotocol is chosen.
+    pub fn negotiate<F>(&self, ws: WebSocketUpgrade<F>) -> WebSocketUpgrade<F> {
+        ws.protocols(self.0.clone())
+    }
+
+    /// Accepts a compressed variant of every subprotocol - eg. `yjs+deflate` for `yjs` - in
+    /// preference to the plain one, so that clients requesting it get their messages compressed
+    /// with a [Compression]. With no subprotocols, only [Compression::SUBPROTOCOL] is accepted.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(self) -> Self {
+        if self.0.is_empty() {
+            return Subprotocols::new([Compression::SUBPROTOCOL]);
+        }
+        let protocols = self.0.into_iter().flat_map(|protocol| {
+            let compressed = format!("{protocol}{}", Compression::SUBPROTOCOL_SUFFIX);
+            [Cow::Owned(compressed), protocol]
+        });
+        Subprotocols::new(protocols)
+    }
+}
+
+/// Default size in bytes, starting from which messages are compressed - see
+/// [Compression::with_min_size].
+#[cfg(feature = "compression")]
+pub const DEFAULT_MIN_COMPRESSED_SIZE: usize = 1024;
+
+/// Deflate compression of messages exchanged with a peer, which has chosen a compressed
+/// subprotocol - see [Subprotocols::with_compression]. It has to be passed to both [AxumSink] and
+/// [AxumStream] of a connection, which [AxumConn] and [subscribe_authorized] do on their own. It's
+/// transparent to a [BroadcastGroup], which keeps exchanging plain y-sync messages.
+///
+/// Every binary frame of a compressed connection starts with a single byte: `0` if the rest of
+/// the frame is a plain y-sync message, or `1` if it's a y-sync message compressed with raw
+/// deflate (RFC 1951), which browsers can inflate with `DecompressionStream("deflate-raw")`.
+/// Messages smaller than [Compression::with_min_size] - like updates produced while typing - and
+/// ones which don't get any smaller are sent plain.
+///
+/// # Example
+///
+/// ```rust
+/// use axum::extract::ws::WebSocketUpgrade;
+/// use axum::response::Response;
+/// use futures_util::StreamExt;
+/// use yrs_axum::ws::{AxumSink, AxumStream, Compression, Subprotocols};
+///
+/// async fn ws_handler(ws: WebSocketUpgrade) -> Response {
+///     // accepts "yjs+deflate" and "yjs" subprotocols
+///     let subprotocols = Subprotocols::new(["yjs"]).with_compression();
+///     subprotocols.negotiate(ws).on_upgrade(|socket| async move {
+///         let compression = Compression::negotiated(socket.protocol());
+///         let (sink, stream) = socket.split();
+///         let sink = AxumSink::from(sink).with_compression(compression);
+///         let stream = AxumStream::from(stream).with_compression(compression);
+///         // .. subscribe sink and stream to a broadcast group
+///     })
+/// }
+/// ```
+#[cfg(feature = "compression")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    level: u32,
+    min_size: usize,
+}
+
+#[cfg(feature = "compression")]
+impl Compression {
+    /// Suffix of compressed variants of subprotocols, eg. `yjs+deflate`.
+    pub const SUBPROTOCOL_SUFFIX: &'static str = "+deflate";
+
+    /// Compressed subprotocol accepted by endpoints, which don't accept any other subprotocols.
+    pub const SUBPROTOCOL: &'static str = "deflate";
+
+    /// Tag of a frame carrying a plain message.
+    const PLAIN: u8 = 0;
+
+    /// Tag of a frame carrying a deflated message.
+    const DEFLATED: u8 = 1;
+
+    /// Returns a default compression if a `protocol` chosen for a connection - see
+    /// `WebSocket::protocol` - is a compressed one, or `None` otherwise.
+    pub fn negotiated(protocol: Option<&HeaderValue>) -> Option<Self> {
+        let protocol = protocol?.to_str().ok()?;
+        if protocol == Self::SUBPROTOCOL || protocol.ends_with(Self::SUBPROTOCOL_SUFFIX) {
+            Some(Self::default())
+        } else {
+            None
+        }
+    }
+
+    /// Sets a compression level from 0 (fastest) to 9 (smallest), defaults to 6.
+    pub fn with_level(mut self, level: u32) -> Self {
+        self.level = level.min(9);
+        self
+    }
+
+    /// Sets a size in bytes, starting from which messages are compressed. Defaults to
+    /// [DEFAULT_MIN_COMPRESSED_SIZE].
+    pub fn with_min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Encodes a `msg` into a frame of a compressed connection, eg. for clients which don't use
+    /// an [AxumSink].
+    pub fn compress(&self, msg: Vec<u8>) -> Result<Vec<u8>, Error> {
+        use std::io::Write;
+
+        if msg.len() >= self.min_size {
+            let level = flate2::Compression::new(self.level);
+            let mut encoder = flate2::write::DeflateEncoder::new(vec![Self::DEFLATED], level);
+            encoder.write_all(&msg)?;
+            let frame = encoder.finish()?;
+            if frame.len() <= msg.len() {
+                return Ok(frame);
+            }
+        }
+        let mut frame = Vec::with_capacity(msg.len() + 1);
+        frame.push(Self::PLAIN);
+        frame.extend_from_slice(&msg);
+        Ok(frame)
+    }
+
+    /// Decodes a `frame` of a compressed connection into a message of at most `max` bytes, or
+    /// any size if `max` is 0.
+    pub fn decompress(frame: &[u8], max: usize) -> Result<Vec<u8>, Error> {
+        use std::io::Read;
+
+        match frame.split_first() {
+            None => Ok(Vec::new()),
+            Some((&Self::PLAIN, msg)) => Ok(msg.to_vec()),
+            Some((&Self::DEFLATED, data)) => {
+                let limit = if max == 0 { u64::MAX } else { max as u64 + 1 };
+                let mut msg = Vec::new();
+                flate2::read::DeflateDecoder::new(data)
+                    .take(limit)
+                    .read_to_end(&mut msg)?;
+                if max != 0 && msg.len() > max {
+                    tracing::warn!("rejecting message inflated over the limit of {max} bytes");
+                    let e = crate::error::Error::LimitExceeded(
+                        "decompressed message exceeds the size limit".into(),
+                    );
+                    return Err(e.into());
+                }
+                Ok(msg)
+            }
+            Some((&tag, _)) => Err(Error::Unsupported(tag)),
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            level: 6,
+            min_size: DEFAULT_MIN_COMPRESSED_SIZE,
+        }
+    }
+}
+
+/// Runs an `authorize` callback with connection `metadata` - eg. a token extracted from request
+/// headers by the caller - and subscribes an authorized `socket` to a broadcast group with
+/// permissions it returned.
+///
+/// A socket, which failed authorization, is closed with a policy violation close code (1008) and
+/// a reason taken from returned [AuthError].
+///
+/// # Example
+///
+/// ```rust
+/// use std::sync::Arc;
+/// use axum::extract::ws::{WebSocket, WebSocketUpgrade};
+/// use axum::extract::State;
+/// use axum::http::HeaderMap;
+/// use axum::response::IntoResponse;
+/// use yrs_axum::auth::{AuthError, PeerPermissions};
+/// use yrs_axum::broadcast::BroadcastGroup;
+/// use yrs_axum::ws::subscribe_authorized;
+///
+/// async fn ws_handler(
+///     ws: WebSocketUpgrade,
+///     headers: HeaderMap,
+///     State(bcast): State<Arc<BroadcastGroup>>,
+/// ) -> impl IntoResponse {
+///     ws.on_upgrade(move |socket| async move {
+///         let res = subscribe_authorized(&bcast, socket, headers, |headers| async move {
+///             match headers.get("authorization") {
+///                 Some(token) if token == "Bearer secret" => Ok(PeerPermissions::ReadWrite),
+///                 _ => Err(AuthError::new("invalid token")),
+///             }
+///         })
+///         .await;
+///         if let Ok(sub) = res {
+///             let _ = sub.completed().await;
+///         }
+///     })
+/// }
+/// ```
+pub async fn subscribe_authorized<M, F, Fut>(
+    bcast: &BroadcastGroup,
+    mut socket: WebSocket,
+    metadata: M,
+    authorize: F,
+) -> Result<Subscription, AuthError>
+where
+    F: FnOnce(M) -> Fut,
+    Fut: Future<Output = Result<PeerPermissions, AuthError>>,
+{
+    match authorize(metadata).await {
+        Ok(permissions) => {
+            let (sink, stream) = split(socket);
+            Ok(bcast.subscribe_as(Arc::new(Mutex::new(sink)), stream, permissions))
+        }
+        Err(e) => {
+            let frame = CloseFrame {
+                code: close_code::POLICY,
+                reason: close_reason(&e.reason).into(),
+            };
+            if let Err(e) = socket.send(Message::Close(Some(frame))).await {
+                tracing::debug!("failed to close unauthorized connection: {e}");
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Resolves an identity of a subscriber from its upgrade request `parts` via a `provider` and
+/// subscribes a `socket` to a broadcast group, attaching the identity to the returned
+/// [Subscription]. A subscriber without identity is subscribed anonymously.
+///
+/// # Example
+///
+/// ```rust
+/// use std::sync::Arc;
+/// use axum::extract::ws::WebSocketUpgrade;
+/// use axum::extract::State;
+/// use axum::http::request::Parts;
+/// use axum::response::IntoResponse;
+/// use yrs_axum::auth::QueryParam;
+/// use yrs_axum::broadcast::BroadcastGroup;
+/// use yrs_axum::ws::subscribe_identified;
+///
+/// async fn ws_handler(
+///     ws: WebSocketUpgrade,
+///     State(bcast): State<Arc<BroadcastGroup>>,
+///     parts: Parts,
+/// ) -> impl IntoResponse {
+///     ws.on_upgrade(move |socket| async move {
+///         let sub = subscribe_identified(&bcast, socket, &parts, &QueryParam::new("user")).await;
+///         if let Some(user) = sub.identity() {
+///             println!("{user} joined");
+///         }
+///         let _ = sub.completed().await;
+///     })
+/// }
+/// ```
+pub async fn subscribe_identified<P>(
+    bcast: &BroadcastGroup,
+    socket: WebSocket,
+    parts: &Parts,
+    provider: &P,
+) -> Subscription
+where
+    P: IdentityProvider,
+{
+    let identity = provider.identify(parts).await;
+    let (sink, stream) = split(socket);
+    let sub = bcast.subscribe(Arc::new(Mutex::new(sink)), stream);
+    match identity {
+        Some(identity) => sub.with_identity(identity),
+        None => sub,
+    }
+}
+
+/// Truncates a close frame reason to 123 bytes - a limit imposed by web socket protocol.
+fn close_reason(reason: &str) -> &str {
+    let mut len = reason.len().min(123);
+    while !reason.is_char_boundary(len) {
+        len -= 1;
+    }
+    &reason[..len]
+}
+
 #[cfg(test)]
 mod test {
-    use crate::broadcast::BroadcastGroup;
+    use crate::auth::{AuthError, PeerPermissions};
+    use crate::broadcast::{BroadcastGroup, DisconnectReason};
     use crate::conn::Connection;
-    use crate::ws::{AxumSink, AxumStream};
+    use crate::ws::{AxumSink, AxumStream, ClientInfo};
+    use axum::http::HeaderMap;
+    use axum::{
+        extract::ws::{WebSocket, WebSocketUpgrade},
+        extract::State,
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
     use futures_util::stream::{SplitSink, SplitStream};
     use futures_util::{ready, SinkExt, Stream, StreamExt};
+    use std::net::{IpAddr, SocketAddr};
     use std::pin::Pin;
     use std::sync::Arc;
     use std::task::{Context, Poll};
     use std::time::Duration;
     use tokio::net::TcpStream;
-    use tokio::sync::{Mutex, Notify, RwLock};
+    use tokio::sync::{mpsc, Mutex, Notify, RwLock};
     use tokio::task;
     use tokio::task::JoinHandle;
     use tokio::time::{sleep, timeout};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
     use tokio_tungstenite::tungstenite::Message;
     use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
-    use axum::{
-        Router,
-        routing::get,
-        extract::ws::{WebSocket, WebSocketUpgrade},
-        extract::State,
-        response::IntoResponse,
-    };
-    use yrs::sync::{Awareness, Error};
+    use yrs::sync::{Awareness, Error, Message as YMessage, SyncMessage};
+    use yrs::updates::decoder::Decode;
     use yrs::updates::encoder::Encode;
-    use yrs::{Doc, GetString, Subscription, Text, Transact};
+    use yrs::{Doc, GetString, ReadTxn, Subscription, Text, Transact, Update};
 
     async fn start_server(
         addr: &str,
@@ -295,8 +909,8 @@ mod test {
 
     async fn peer(ws: WebSocket, bcast: Arc<BroadcastGroup>) {
         let (sink, stream) = ws.split();
-        let sink = Arc::new(Mutex::new(AxumSink(sink)));
-        let stream = AxumStream(stream);
+        let sink = Arc::new(Mutex::new(AxumSink::from(sink)));
+        let stream = AxumStream::from(stream);
         let sub = bcast.subscribe(sink, stream);
         match sub.completed().await {
             Ok(_) => println!("broadcasting for channel finished successfully"),
@@ -599,4 +1213,584 @@ mod test {
             assert_eq!(str, "abcdef".to_string());
         }
     }
+
+    async fn auth_handler(
+        ws: WebSocketUpgrade,
+        headers: axum::http::HeaderMap,
+        State(bcast): State<Arc<BroadcastGroup>>,
+    ) -> impl IntoResponse {
+        ws.on_upgrade(move |socket| async move {
+            let authorize = |headers: axum::http::HeaderMap| async move {
+                match headers.get("authorization") {
+                    Some(token) if token == "Bearer secret" => Ok(PeerPermissions::ReadWrite),
+                    _ => Err(AuthError::new("invalid token")),
+                }
+            };
+            let res = crate::ws::subscribe_authorized(&bcast, socket, headers, authorize).await;
+            if let Ok(sub) = res {
+                let _ = sub.completed().await;
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn unauthorized_peers_are_rejected() {
+        let bcast = Arc::new(BroadcastGroup::from_doc(Doc::new(), 10).await);
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:16604")
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/auth", get(auth_handler))
+            .with_state(bcast.clone());
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let (mut ws, _) = tokio_tungstenite::connect_async("ws://localhost:16604/auth")
+            .await
+            .unwrap();
+        match timeout(TIMEOUT, ws.next()).await.unwrap() {
+            Some(Ok(Message::Close(Some(frame)))) => {
+                assert_eq!(u16::from(frame.code), 1008);
+                assert_eq!(frame.reason, "invalid token");
+            }
+            other => panic!("expected close frame, got {other:?}"),
+        }
+        assert!(bcast.is_empty());
+
+        let mut req = "ws://localhost:16604/auth".into_client_request().unwrap();
+        req.headers_mut()
+            .insert("authorization", "Bearer secret".parse().unwrap());
+        let (_ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+        timeout(TIMEOUT, bcast.first_subscriber()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn subprotocol_is_negotiated() {
+        async fn handler(ws: WebSocketUpgrade) -> impl IntoResponse {
+            crate::ws::Subprotocols::new(["yjs/2", "yjs"])
+                .negotiate(ws)
+                .on_upgrade(|_socket| async {})
+        }
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:16605")
+            .await
+            .unwrap();
+        let app = Router::new().route("/subprotocol", get(handler));
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let mut req = "ws://localhost:16605/subprotocol"
+            .into_client_request()
+            .unwrap();
+        req.headers_mut()
+            .insert("sec-websocket-protocol", "foo, yjs".parse().unwrap());
+        let (_ws, res) = tokio_tungstenite::connect_async(req).await.unwrap();
+        assert_eq!(res.headers()["sec-websocket-protocol"], "yjs");
+    }
+
+    #[tokio::test]
+    async fn message_codec_performs_sync_handshake() {
+        async fn handler(ws: WebSocketUpgrade, State(doc): State<Doc>) -> impl IntoResponse {
+            ws.on_upgrade(move |socket| async move {
+                let (mut sink, mut stream) = crate::ws::message_codec(socket);
+                let sv = doc.transact().state_vector();
+                sink.send(YMessage::Sync(SyncMessage::SyncStep1(sv)))
+                    .await
+                    .unwrap();
+                while let Some(Ok(msg)) = stream.next().await {
+                    match msg {
+                        YMessage::Sync(SyncMessage::SyncStep1(sv)) => {
+                            let update = doc.transact().encode_state_as_update_v1(&sv);
+                            let reply = YMessage::Sync(SyncMessage::SyncStep2(update));
+                            sink.send(reply).await.unwrap();
+                        }
+                        YMessage::Sync(SyncMessage::SyncStep2(update))
+                        | YMessage::Sync(SyncMessage::Update(update)) => {
+                            let update = Update::decode_v1(&update).unwrap();
+                            doc.transact_mut().apply_update(update);
+                        }
+                        _ => {}
+                    }
+                }
+            })
+        }
+
+        let server_doc = Doc::new();
+        let text = server_doc.get_or_insert_text("test");
+        text.push(&mut server_doc.transact_mut(), "abc");
+
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:16606")
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/codec", get(handler))
+            .with_state(server_doc.clone());
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let (mut ws, _) = tokio_tungstenite::connect_async("ws://localhost:16606/codec")
+            .await
+            .unwrap();
+        let client_doc = Doc::new();
+        let client_text = client_doc.get_or_insert_text("test");
+
+        // server initiates the handshake with its own state vector
+        let msg = recv_message(&mut ws).await;
+        let sv = match msg {
+            YMessage::Sync(SyncMessage::SyncStep1(sv)) => sv,
+            other => panic!("expected SyncStep1, got {:?}", other),
+        };
+        assert_eq!(sv, server_doc.transact().state_vector());
+
+        // client asks for the missing state and receives it in SyncStep2
+        let step1 = YMessage::Sync(SyncMessage::SyncStep1(client_doc.transact().state_vector()));
+        ws.send(Message::binary(step1.encode_v1())).await.unwrap();
+        match recv_message(&mut ws).await {
+            YMessage::Sync(SyncMessage::SyncStep2(update)) => {
+                let update = Update::decode_v1(&update).unwrap();
+                client_doc.transact_mut().apply_update(update);
+            }
+            other => panic!("expected SyncStep2, got {:?}", other),
+        }
+        assert_eq!(client_text.get_string(&client_doc.transact()), "abc");
+
+        // client changes are propagated back as updates
+        let update = {
+            let mut txn = client_doc.transact_mut();
+            client_text.push(&mut txn, "def");
+            txn.encode_update_v1()
+        };
+        let msg = YMessage::Sync(SyncMessage::Update(update));
+        ws.send(Message::binary(msg.encode_v1())).await.unwrap();
+
+        timeout(Duration::from_secs(5), async {
+            while text.get_string(&server_doc.transact()) != "abcdef" {
+                sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    async fn recv_message(ws: &mut WebSocketStream<MaybeTlsStream<TcpStream>>) -> YMessage {
+        loop {
+            match ws.next().await.unwrap().unwrap() {
+                Message::Binary(data) => return YMessage::decode_v1(&data).unwrap(),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                other => panic!("unexpected frame: {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn evicted_peers_receive_close_reason() {
+        let bcast = BroadcastGroup::from_doc(Doc::new(), 10)
+            .await
+            .with_max_message_size(16);
+        let _server = start_server("0.0.0.0:16607", Arc::new(bcast))
+            .await
+            .unwrap();
+
+        let (mut ws, _) = tokio_tungstenite::connect_async("ws://localhost:16607/my-room")
+            .await
+            .unwrap();
+        ws.send(Message::binary(vec![0; 64])).await.unwrap();
+        loop {
+            match timeout(TIMEOUT, ws.next()).await.unwrap() {
+                Some(Ok(Message::Close(Some(frame)))) => {
+                    assert_eq!(u16::from(frame.code), 1009);
+                    assert_eq!(frame.reason, "message too big");
+                    break;
+                }
+                Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                other => panic!("expected close frame, got {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_rejected_by_stream() {
+        type Results = mpsc::UnboundedSender<Result<DisconnectReason, crate::error::Error>>;
+
+        async fn handler(
+            ws: WebSocketUpgrade,
+            State((bcast, results)): State<(Arc<BroadcastGroup>, Results)>,
+        ) -> impl IntoResponse {
+            ws.on_upgrade(move |socket| async move {
+                let (sink, stream) = socket.split();
+                let sink = Arc::new(Mutex::new(AxumSink::from(sink)));
+                let stream = AxumStream::from(stream).with_max_message_size(16);
+                let res = bcast.subscribe(sink, stream).completed().await;
+                let _ = results.send(res);
+            })
+        }
+
+        let bcast = Arc::new(BroadcastGroup::from_doc(Doc::new(), 10).await);
+        let (results, mut completed) = mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:16608")
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/my-room", get(handler))
+            .with_state((bcast.clone(), results));
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let (mut other, _) = tokio_tungstenite::connect_async("ws://localhost:16608/my-room")
+            .await
+            .unwrap();
+        let (mut ws, _) = tokio_tungstenite::connect_async("ws://localhost:16608/my-room")
+            .await
+            .unwrap();
+        ws.send(Message::binary(vec![0; 64])).await.unwrap();
+        let res = timeout(TIMEOUT, completed.recv()).await.unwrap().unwrap();
+        assert_eq!(DisconnectReason::of(&res), DisconnectReason::TransportError);
+        assert!(res
+            .unwrap_err()
+            .to_string()
+            .contains("exceeds the size limit"));
+
+        // other peer is still subscribed and synced
+        assert_eq!(bcast.subscriber_count(), 1);
+        let sync_step1 = YMessage::Sync(SyncMessage::SyncStep1(Default::default()));
+        other
+            .send(Message::binary(sync_step1.encode_v1()))
+            .await
+            .unwrap();
+        let reply = timeout(TIMEOUT, recv_message(&mut other)).await.unwrap();
+        assert!(matches!(reply, YMessage::Sync(SyncMessage::SyncStep2(_))));
+    }
+
+    #[test]
+    fn client_info_trusts_only_configured_proxies() {
+        let proxy: SocketAddr = "10.0.0.1:4000".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7, 10.0.0.2".parse().unwrap());
+        headers.insert("x-forwarded-proto", "https".parse().unwrap());
+        let proxies: [IpAddr; 2] = ["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+
+        let info = ClientInfo::resolve(proxy, &headers, &proxies);
+        assert_eq!(info.peer_addr, proxy);
+        assert_eq!(info.client_ip, "203.0.113.7".parse::<IpAddr>().unwrap());
+        assert!(info.secure);
+
+        // headers sent by a client connected directly are ignored
+        let client: SocketAddr = "198.51.100.3:5000".parse().unwrap();
+        let info = ClientInfo::resolve(client, &headers, &proxies);
+        assert_eq!(info, ClientInfo::new(client));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compressed_subprotocols_are_preferred() {
+        use crate::ws::{Compression, Subprotocols};
+        use axum::http::HeaderValue;
+
+        let subprotocols = Subprotocols::new(["yjs/2", "yjs"]).with_compression();
+        assert_eq!(
+            subprotocols.protocols(),
+            ["yjs/2+deflate", "yjs/2", "yjs+deflate", "yjs"]
+        );
+        let subprotocols = Subprotocols::default().with_compression();
+        assert_eq!(subprotocols.protocols(), [Compression::SUBPROTOCOL]);
+
+        let negotiated =
+            |protocol| Compression::negotiated(Some(&HeaderValue::from_static(protocol)));
+        assert_eq!(negotiated("yjs+deflate"), Some(Compression::default()));
+        assert_eq!(negotiated("deflate"), Some(Compression::default()));
+        assert_eq!(negotiated("yjs"), None);
+        assert_eq!(Compression::negotiated(None), None);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn only_large_compressible_messages_are_deflated() {
+        use crate::ws::Compression;
+
+        let compression = Compression::default().with_min_size(64);
+        let small = b"hello world".to_vec();
+        let frame = compression.compress(small.clone()).unwrap();
+        assert_eq!(frame[0], Compression::PLAIN);
+        assert_eq!(Compression::decompress(&frame, 0).unwrap(), small);
+
+        let text = "the quick brown fox jumps over the lazy dog ".repeat(100);
+        let frame = compression.compress(text.clone().into_bytes()).unwrap();
+        assert_eq!(frame[0], Compression::DEFLATED);
+        assert!(frame.len() < text.len() / 10);
+        assert_eq!(Compression::decompress(&frame, 0).unwrap(), text.as_bytes());
+
+        // pseudo-random bytes don't get any smaller
+        let mut seed = 1u32;
+        let noise: Vec<u8> = (0..4096)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed >> 24) as u8
+            })
+            .collect();
+        let frame = compression.compress(noise.clone()).unwrap();
+        assert_eq!(frame[0], Compression::PLAIN);
+        assert_eq!(Compression::decompress(&frame, 0).unwrap(), noise);
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn decompressed_messages_are_size_limited() {
+        use crate::ws::Compression;
+
+        let frame = Compression::default().compress(vec![0; 1 << 20]).unwrap();
+        assert!(frame.len() < 2048);
+        let e = Compression::decompress(&frame, 1000).unwrap_err();
+        assert!(e.to_string().contains("exceeds the size limit"), "{e}");
+        assert_eq!(
+            Compression::decompress(&frame, 1 << 20).unwrap().len(),
+            1 << 20
+        );
+        assert_eq!(Compression::decompress(&frame, 0).unwrap().len(), 1 << 20);
+
+        let e = Compression::decompress(&[7, 1, 2, 3], 0).unwrap_err();
+        assert!(matches!(e, Error::Unsupported(7)), "{e}");
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn compression_is_negotiated_per_connection() {
+        use std::io::{Read, Write};
+
+        async fn handler(
+            ws: WebSocketUpgrade,
+            State(bcast): State<Arc<BroadcastGroup>>,
+        ) -> impl IntoResponse {
+            let subprotocols = crate::ws::Subprotocols::new(["yjs"]).with_compression();
+            subprotocols
+                .negotiate(ws)
+                .on_upgrade(move |socket| async move {
+                    let authorize = |_| async { Ok(PeerPermissions::ReadWrite) };
+                    let res = crate::ws::subscribe_authorized(&bcast, socket, (), authorize).await;
+                    if let Ok(sub) = res {
+                        let _ = sub.completed().await;
+                    }
+                })
+        }
+
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text("test");
+        let content = "lorem ipsum dolor sit amet ".repeat(200);
+        text.push(&mut doc.transact_mut(), &content);
+        let bcast = Arc::new(BroadcastGroup::from_doc(doc, 10).await);
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:16609")
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/compressed", get(handler))
+            .with_state(bcast.clone());
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        for (protocol, compressed) in [("yjs+deflate", true), ("yjs", false)] {
+            let mut req = "ws://localhost:16609/compressed"
+                .into_client_request()
+                .unwrap();
+            req.headers_mut()
+                .insert("sec-websocket-protocol", protocol.parse().unwrap());
+            let (mut ws, res) = tokio_tungstenite::connect_async(req).await.unwrap();
+            assert_eq!(res.headers()["sec-websocket-protocol"], protocol);
+
+            // compressed connections prefix every frame with a tag: 0 - plain, 1 - deflated
+            let encode = |msg: YMessage| {
+                let msg = msg.encode_v1();
+                if compressed {
+                    let mut encoder =
+                        flate2::write::DeflateEncoder::new(vec![1], flate2::Compression::default());
+                    encoder.write_all(&msg).unwrap();
+                    encoder.finish().unwrap()
+                } else {
+                    msg
+                }
+            };
+            let step1 = YMessage::Sync(SyncMessage::SyncStep1(Default::default()));
+            ws.send(Message::binary(encode(step1))).await.unwrap();
+            let update = timeout(TIMEOUT, async {
+                loop {
+                    let frame = match ws.next().await.unwrap().unwrap() {
+                        Message::Binary(frame) => frame,
+                        _ => continue,
+                    };
+                    let msg = match (compressed, frame[0]) {
+                        (false, _) => frame.clone(),
+                        (true, 0) => frame[1..].to_vec(),
+                        (true, tag) => {
+                            assert_eq!(tag, 1);
+                            let mut msg = Vec::new();
+                            flate2::read::DeflateDecoder::new(&frame[1..])
+                                .read_to_end(&mut msg)
+                                .unwrap();
+                            msg
+                        }
+                    };
+                    if let YMessage::Sync(SyncMessage::SyncStep2(update)) =
+                        YMessage::decode_v1(&msg).unwrap()
+                    {
+                        assert_eq!(frame.len() < msg.len() / 10, compressed);
+                        return update;
+                    }
+                }
+            })
+            .await
+            .unwrap();
+            let client_doc = Doc::new();
+            let client_text = client_doc.get_or_insert_text("test");
+            let update = Update::decode_v1(&update).unwrap();
+            client_doc.transact_mut().apply_update(update);
+            assert!(client_text
+                .get_string(&client_doc.transact())
+                .starts_with(&content));
+
+            // updates sent by a client are decompressed as well
+            let update = {
+                let mut txn = client_doc.transact_mut();
+                client_text.push(&mut txn, &protocol.repeat(100));
+                txn.encode_update_v1()
+            };
+            let msg = YMessage::Sync(SyncMessage::Update(update));
+            ws.send(Message::binary(encode(msg))).await.unwrap();
+            let awareness = bcast.awareness().clone();
+            timeout(TIMEOUT, async {
+                loop {
+                    {
+                        let awareness = awareness.read().await;
+                        let doc = awareness.doc();
+                        let text = doc.get_or_insert_text("test");
+                        if text
+                            .get_string(&doc.transact())
+                            .ends_with(&protocol.repeat(100))
+                        {
+                            break;
+                        }
+                    }
+                    sleep(Duration::from_millis(50)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn compressed_connections_are_closed_with_reason() {
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+        use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+        type Results = mpsc::UnboundedSender<Result<DisconnectReason, crate::error::Error>>;
+
+        async fn handler(
+            ws: WebSocketUpgrade,
+            State((bcast, results)): State<(Arc<BroadcastGroup>, Results)>,
+        ) -> impl IntoResponse {
+            let subprotocols = crate::ws::Subprotocols::new(["yjs"]).with_compression();
+            subprotocols
+                .negotiate(ws)
+                .on_upgrade(move |socket| async move {
+                    let (sink, stream) = crate::ws::split(socket);
+                    let sink = Arc::new(Mutex::new(sink));
+                    let res = bcast.subscribe(sink, stream).completed().await;
+                    let _ = results.send(res);
+                })
+        }
+
+        let bcast = Arc::new(BroadcastGroup::from_doc(Doc::new(), 10).await);
+        let (results, mut completed) = mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:16610")
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/compressed", get(handler))
+            .with_state((bcast.clone(), results));
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let mut req = "ws://localhost:16610/compressed"
+            .into_client_request()
+            .unwrap();
+        req.headers_mut()
+            .insert("sec-websocket-protocol", "yjs+deflate".parse().unwrap());
+        let (mut ws, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+        timeout(TIMEOUT, bcast.first_subscriber()).await.unwrap();
+        let frame = CloseFrame {
+            code: CloseCode::Normal,
+            reason: "bye".into(),
+        };
+        ws.close(Some(frame)).await.unwrap();
+
+        let res = timeout(TIMEOUT, completed.recv()).await.unwrap().unwrap();
+        assert_eq!(res.unwrap(), DisconnectReason::PeerClosed);
+    }
+
+    #[tokio::test]
+    async fn subscribers_are_identified_by_query_param() {
+        use crate::auth::{QueryParam, SubscriberId};
+        use axum::http::request::Parts;
+
+        type Identities = mpsc::UnboundedSender<(Option<SubscriberId>, Option<SubscriberId>)>;
+
+        async fn handler(
+            ws: WebSocketUpgrade,
+            State((bcast, identities)): State<(Arc<BroadcastGroup>, Identities)>,
+            parts: Parts,
+        ) -> impl IntoResponse {
+            ws.on_upgrade(move |socket| async move {
+                let provider = QueryParam::new("user");
+                let sub = crate::ws::subscribe_identified(&bcast, socket, &parts, &provider).await;
+                let identity = sub.identity().cloned();
+                let _ = identities.send((identity, sub.handle().identity().cloned()));
+                let _ = sub.completed().await;
+            })
+        }
+
+        let bcast = Arc::new(BroadcastGroup::from_doc(Doc::new(), 10).await);
+        let (identities, mut identified) = mpsc::unbounded_channel();
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:16611")
+            .await
+            .unwrap();
+        let app = Router::new()
+            .route("/identified", get(handler))
+            .with_state((bcast.clone(), identities));
+        let _server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service())
+                .await
+                .unwrap();
+        });
+
+        let (_alice, _) =
+            tokio_tungstenite::connect_async("ws://localhost:16611/identified?user=alice%20b")
+                .await
+                .unwrap();
+        let alice = Some(SubscriberId::new("alice b"));
+        let identity = timeout(TIMEOUT, identified.recv()).await.unwrap();
+        assert_eq!(identity, Some((alice.clone(), alice)));
+
+        let (_anonymous, _) = tokio_tungstenite::connect_async("ws://localhost:16611/identified")
+            .await
+            .unwrap();
+        let identity = timeout(TIMEOUT, identified.recv()).await.unwrap();
+        assert_eq!(identity, Some((None, None)));
+        assert_eq!(bcast.subscriber_count(), 2);
+    }
 }